@@ -0,0 +1,128 @@
+//! Listening sockets handed over by a service manager.
+//!
+//! With systemd socket activation (`LISTEN_FDS`) or a launchd job whose
+//! `Sockets` dictionary has a `Listeners` entry, the service manager owns the
+//! port and starts tbl on the first connection. tbl then serves on the socket
+//! it was given instead of binding one itself, and stays in the foreground so
+//! the service manager keeps track of it.
+
+use std::net::TcpListener;
+
+use anyhow::Result;
+
+/// Name of the launchd `Sockets` entry tbl asks for.
+#[cfg(target_os = "macos")]
+const LAUNCHD_SOCKET: &str = "Listeners";
+
+/// The listening TCP socket passed by systemd or launchd, if any. Only one
+/// is served; extra sockets are closed.
+pub fn listener() -> Result<Option<TcpListener>> {
+    let mut listeners = systemd_listeners()?;
+    if listeners.is_empty() {
+        listeners = launchd_listeners()?;
+    }
+    if listeners.len() > 1 {
+        eprintln!(
+            "  Got {} sockets from the service manager; serving the first",
+            listeners.len()
+        );
+    }
+    Ok(listeners.into_iter().next())
+}
+
+/// Whether systemd passed sockets to this process: `LISTEN_PID` names it.
+pub fn offered() -> bool {
+    std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id())
+}
+
+/// Sockets from systemd: `LISTEN_FDS` descriptors from 3 on, if `LISTEN_PID`
+/// names this process.
+#[cfg(unix)]
+fn systemd_listeners() -> Result<Vec<TcpListener>> {
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    if !offered() {
+        return Ok(Vec::new());
+    }
+    let count: i32 = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passed these descriptors to this process and
+            // nothing else has taken them
+            unsafe {
+                // Keep them out of git and notifier commands
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                tcp_listener(fd)
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn systemd_listeners() -> Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+/// Sockets from the launchd job's `Listeners` entry.
+#[cfg(target_os = "macos")]
+fn launchd_listeners() -> Result<Vec<TcpListener>> {
+    use std::ffi::CString;
+
+    extern "C" {
+        fn launch_activate_socket(
+            name: *const libc::c_char,
+            fds: *mut *mut libc::c_int,
+            cnt: *mut libc::size_t,
+        ) -> libc::c_int;
+    }
+
+    let name = CString::new(LAUNCHD_SOCKET)?;
+    let mut fds: *mut libc::c_int = std::ptr::null_mut();
+    let mut count: libc::size_t = 0;
+    // SAFETY: `name` is NUL-terminated and the out-pointers are valid
+    match unsafe { launch_activate_socket(name.as_ptr(), &mut fds, &mut count) } {
+        0 => {}
+        // Not started by launchd, or the job has no such socket
+        libc::ESRCH | libc::ENOENT => return Ok(Vec::new()),
+        err => anyhow::bail!(
+            "Failed to get the launchd socket: {}",
+            std::io::Error::from_raw_os_error(err)
+        ),
+    }
+    // SAFETY: on success launchd returns `count` descriptors in a malloc'd
+    // array the caller frees
+    let raw = unsafe { std::slice::from_raw_parts(fds, count) }.to_vec();
+    unsafe { libc::free(fds.cast()) };
+    raw.into_iter()
+        // SAFETY: the descriptors were handed to this process
+        .map(|fd| unsafe { tcp_listener(fd) })
+        .collect()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn launchd_listeners() -> Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+/// Take ownership of `fd`, which must be a listening TCP socket.
+///
+/// # Safety
+///
+/// `fd` must be an open descriptor that nothing else owns.
+#[cfg(unix)]
+unsafe fn tcp_listener(fd: std::os::fd::RawFd) -> Result<TcpListener> {
+    use anyhow::Context;
+    use std::os::fd::FromRawFd;
+
+    let listener = TcpListener::from_raw_fd(fd);
+    listener
+        .local_addr()
+        .with_context(|| format!("Socket {fd} from the service manager is not a TCP socket"))?;
+    Ok(listener)
+}
@@ -0,0 +1,138 @@
+//! JSON error envelope shared by all API versions.
+//!
+//! Every failing `/api/*` request answers with an [`ErrorBody`]; handlers
+//! return `Result<_, ApiError>` and use `?` on anyhow/io errors, which map to
+//! `internal_error`, except writes refused by a quota, which map to
+//! `quota_exceeded`.
+
+use std::io;
+
+use axum::{
+    extract::rejection::JsonRejection,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{quota::QuotaExceeded, AuthError};
+
+/// Error body returned by `/api/*` endpoints on failure.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Stable machine-readable error code, e.g. `key_not_found`
+    pub code: &'static str,
+    /// Human-readable description
+    pub message: String,
+    /// Optional structured context
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    details: Option<serde_json::Value>,
+    /// Ask the browser for Basic credentials (`WWW-Authenticate`)
+    challenge: bool,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+            challenge: false,
+        }
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code, message)
+    }
+
+    pub fn internal(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, code, message)
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code,
+            message: self.message,
+            details: self.details,
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        if self.challenge {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"tbl\""),
+            );
+        }
+        response
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        if let Some(exceeded) = e.downcast_ref::<QuotaExceeded>() {
+            return Self::new(
+                StatusCode::INSUFFICIENT_STORAGE,
+                "quota_exceeded",
+                exceeded.to_string(),
+            )
+            .with_details(serde_json::json!({
+                "store": exceeded.store,
+                "bytes": exceeded.bytes,
+                "quota": exceeded.quota,
+            }));
+        }
+        Self::internal("internal_error", format!("{e:#}"))
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        Self::internal("internal_error", e.to_string())
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::BasicAuthRequired => Self {
+                challenge: true,
+                ..Self::new(
+                    StatusCode::UNAUTHORIZED,
+                    "basic_auth_required",
+                    "basic auth required",
+                )
+            },
+            AuthError::InvalidToken => Self::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "missing or invalid auth cookie",
+            ),
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(rejection.status(), "invalid_body", rejection.body_text())
+    }
+}
@@ -0,0 +1,17 @@
+//! HTTP API, one module per version mounted under `/api/<version>`.
+//!
+//! A new version gets its own module and nested router so older clients keep
+//! working while handlers evolve.
+
+use std::sync::Arc;
+
+use axum::Router;
+
+use crate::AppState;
+
+pub mod error;
+pub mod v1;
+
+pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new().nest(&format!("/api/{}", v1::VERSION), v1::router(state))
+}
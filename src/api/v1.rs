@@ -0,0 +1,2552 @@
+//! Version 1 of the HTTP API, mounted under `/api/v1`.
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{
+        rejection::JsonRejection,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Json, OriginalUri, Path as UrlPath, Query, Request, State,
+    },
+    http::{header, request::Parts, HeaderMap, HeaderName, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, post},
+    Router,
+};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+use super::error::{ApiError, ErrorBody};
+use crate::{
+    blocking, cancel_shutdown,
+    capability::Capability,
+    catalog::{self, CatalogApp, CatalogPin},
+    check_web_access, client_ip, commit_workspace, emit_event, ensure_repo_job,
+    forge::{self, Provider},
+    git_ready, is_peer,
+    jobs::{JobKind, JobRecord},
+    keep_full_history, load_run_info, manifest,
+    memory::{MemoryUsage, Usage},
+    metered, now_unix, parse_duration, pause, peer, peer_ip, preview, preview_job, previews_source,
+    quota::{self, StorageUsage},
+    redact_credentials, releases, remove_stored_file, repo_name, request_shutdown, roll_back,
+    run_dir, save_run_info, schedule_shutdown, scheduled_shutdown,
+    search::SearchHit,
+    source,
+    state::{AuditQuery, AuditRecord},
+    subscribe_events, token, update_failed, update_finished,
+    uptime::{self, Run, UptimeStats},
+    valid_kv_namespace, workspace_commit, workspace_job, AppState, AuthSession, ConnectedClient,
+    Event, HistoryEntry, KvData, PublicUrl, ScheduledShutdown, SessionInfo, ShutdownMode,
+};
+
+/// Version segment of this API; `tbl.js` is versioned in lockstep.
+pub const VERSION: &str = "v1";
+
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+const MAX_AUDIT_LIMIT: usize = 1000;
+
+const DEFAULT_RUNS_LIMIT: usize = 20;
+
+/// Largest `/broadcast` body; messages are meant for small state updates.
+const MAX_BROADCAST_BYTES: usize = 16 * 1024;
+
+/// Type of the events `/broadcast` sends.
+const BROADCAST_EVENT: &str = "broadcast";
+
+pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    // Routes over the app's own content follow its `access` policy
+    let app = Router::new()
+        .route("/search", get(search_handler))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Capability::Search),
+            require_capability,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_app_access,
+        ));
+
+    // Routes other tbl instances sync from accept the `peer_token` too
+    let peer = Router::new()
+        .route("/peer/manifest", get(peer_manifest_handler))
+        .route("/peer/files/*path", get(peer_file_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_peer));
+
+    // Routes of optional subsystems answer 403 while they are disabled
+    let kv = Router::new()
+        .route("/kv/export", get(kv_export_handler))
+        .route("/kv/import", post(kv_import_handler))
+        .route(
+            "/kv/:namespace",
+            get(kv_list_handler).delete(kv_reset_handler),
+        )
+        .route(
+            "/kv/:namespace/:key",
+            get(kv_get_handler)
+                .put(kv_put_handler)
+                .delete(kv_delete_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Capability::Kv),
+            require_capability,
+        ));
+    let files = Router::new()
+        .route("/files", get(files_root_handler))
+        .route(
+            "/files/*path",
+            get(files_get_handler)
+                .put(files_put_handler)
+                .delete(files_delete_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Capability::Files),
+            require_capability,
+        ));
+    let events = Router::new()
+        .route("/events", get(events_handler))
+        .route("/events/sse", get(events_sse_handler))
+        .route("/broadcast", post(broadcast_handler))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Capability::Events),
+            require_capability,
+        ));
+
+    Router::new()
+        .route("/ping", get(ping_handler))
+        .route("/history", get(history_handler))
+        .route("/switch", post(switch_handler))
+        .route("/shutdown", post(shutdown_handler))
+        .route("/shutdown/cancel", post(shutdown_cancel_handler))
+        .route("/info", get(info_handler))
+        .route("/uptime", get(uptime_handler))
+        .route("/update", post(update_handler))
+        .route("/unshallow", post(unshallow_handler))
+        .route("/sync/last", get(sync_last_handler))
+        .route("/sync/pause", post(sync_pause_handler))
+        .route("/sync/resume", post(sync_resume_handler))
+        .route("/releases", get(releases_handler))
+        .route("/releases/:id/rollback", post(rollback_handler))
+        .route("/previews", get(previews_handler))
+        .route("/previews/:branch", delete(preview_delete_handler))
+        .route("/previews/:branch/sync", post(preview_sync_handler))
+        .route("/providers", get(providers_handler))
+        .route("/providers/:provider/repos", get(provider_repos_handler))
+        .route("/providers/:provider/orgs", get(provider_orgs_handler))
+        .route("/catalog", get(catalog_handler))
+        .route("/capabilities", get(capabilities_handler))
+        .route("/sessions", get(sessions_handler))
+        .route("/sessions/:id", delete(session_revoke_handler))
+        .route("/tokens/rotate", post(tokens_rotate_handler))
+        .route("/clients", get(clients_handler))
+        .route("/audit", get(audit_handler))
+        .route("/storage", get(storage_handler))
+        .route("/jobs", get(jobs_list_handler))
+        .route("/jobs/:id", get(jobs_get_handler))
+        .route("/jobs/:id/log", get(jobs_log_handler))
+        .merge(kv)
+        .merge(files)
+        .route_layer(middleware::from_fn_with_state(state.clone(), record_audit))
+        // Broadcasts change nothing, and would flood the audit log
+        .merge(events)
+        // Every route above requires a session
+        .route_layer(middleware::from_extractor_with_state::<AuthSession, _>(
+            state,
+        ))
+        .route("/openapi.json", get(openapi_handler))
+        .merge(app)
+        .merge(peer)
+        .fallback(not_found_handler)
+}
+
+// =============================================================================
+// Request/Response Types
+// =============================================================================
+
+#[derive(Deserialize, ToSchema)]
+struct SwitchRequest {
+    git_url: String,
+    /// The catalog app `git_url` is a release of, kept as `catalog_app`
+    #[serde(default)]
+    catalog_app: Option<CatalogPin>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SwitchResponse {
+    status: &'static str,
+    git_url: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct HistoryResponse {
+    current: Option<String>,
+    history: Vec<HistoryEntry>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct InfoResponse {
+    version: &'static str,
+    api_version: &'static str,
+    pid: u32,
+    tls: bool,
+    /// URL of tbl's root as the caller reached it, e.g. `https://example.com/tbl`
+    base_url: String,
+    started_at: u64,
+    uptime_secs: u64,
+    app_name: Option<String>,
+    git_url: Option<String>,
+    commit: Option<String>,
+    /// Set while automatic syncs are paused
+    sync_paused: Option<pause::SyncPause>,
+    /// Set while an automatic sync waits for an unmetered connection
+    sync_deferred: Option<metered::SyncDeferral>,
+    /// What the app's `tbl.requires.yaml` asks for but this instance lacks;
+    /// its pages answer `503` while this is not empty
+    unmet_requirements: Vec<String>,
+    /// Estimated memory of the in-memory stores and their caps, in bytes
+    memory: MemoryUsage,
+    /// Set while a shutdown is scheduled
+    shutdown: Option<ScheduledShutdown>,
+    /// Restarts and uptime since the first start
+    uptime: UptimeStats,
+}
+
+#[derive(Deserialize)]
+struct UptimeQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UptimeResponse {
+    stats: UptimeStats,
+    /// Newest first, the current run included
+    runs: Vec<Run>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UpdateResponse {
+    status: &'static str,
+    commit: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema, Default)]
+struct SyncPauseRequest {
+    /// Shown in status output, e.g. "demo on Friday"
+    reason: Option<String>,
+    /// Resume by itself after this long, e.g. "2h"
+    #[serde(rename = "for")]
+    duration: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SyncPauseResponse {
+    /// The pause in effect; null once resumed
+    paused: Option<pause::SyncPause>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReleaseInfo {
+    /// Creation time as `YYYYMMDD-HHMMSS`, local time
+    id: String,
+    /// Commit or source version of the content
+    version: String,
+    url: String,
+    created: u64,
+    files: usize,
+    /// Total size of the files in bytes
+    size: u64,
+    /// Whether the workspace serves this release
+    current: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReleasesResponse {
+    /// Newest first
+    releases: Vec<ReleaseInfo>,
+    /// Releases kept, from `keep_releases`
+    keep: usize,
+    /// Bytes all releases take on disk together, sharing unchanged files
+    disk_size: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PreviewInfo {
+    branch: String,
+    /// Where the branch is served, e.g. `/preview/feature%2Flogin/`
+    url: String,
+    /// Commit checked out; absent until the preview is first opened
+    commit: Option<String>,
+    /// Unix time the branch was last fetched
+    synced_at: Option<u64>,
+}
+
+impl PreviewInfo {
+    fn new(url: &PublicUrl, branch: String, checkout: Option<preview::Preview>) -> Self {
+        Self {
+            url: url.path(&format!("/preview/{}/", preview::encode(&branch))),
+            branch,
+            commit: checkout.as_ref().map(|checkout| checkout.commit.clone()),
+            synced_at: checkout.map(|checkout| checkout.synced_at),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct PreviewsResponse {
+    /// Every branch of the repository, by name
+    previews: Vec<PreviewInfo>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ProviderInfo {
+    /// `github`, `gitlab`, or `gitea`
+    name: &'static str,
+    /// Web address of the instance
+    url: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ProvidersResponse {
+    /// Providers with a token in the `providers` config section
+    providers: Vec<ProviderInfo>,
+}
+
+#[derive(Deserialize)]
+struct ProviderReposQuery {
+    org: Option<String>,
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ProviderReposResponse {
+    /// Most recently updated first
+    repos: Vec<forge::Repo>,
+    page: u32,
+    /// Page to ask for next; null on the last
+    next_page: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ProviderOrgsResponse {
+    orgs: Vec<forge::Org>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CatalogResponse {
+    /// The configured `catalog_url`, credentials masked
+    url: String,
+    /// Apps in the catalog's order, each `source` absolute
+    apps: Vec<CatalogApp>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct KvKeysResponse {
+    namespace: String,
+    keys: Vec<String>,
+}
+
+/// `?ttl=` of KV and files writes.
+#[derive(Deserialize)]
+struct TtlQuery {
+    /// Time to live such as "30m" or "7d"; removed by the sweeper after it
+    ttl: Option<String>,
+}
+
+impl TtlQuery {
+    /// When the written data expires, as a Unix timestamp.
+    fn expires_at(&self) -> Result<Option<u64>, ApiError> {
+        self.ttl
+            .as_deref()
+            .map(|ttl| {
+                parse_duration(ttl)
+                    .map(|ttl| now_unix() + ttl.as_secs())
+                    .map_err(|e| ApiError::bad_request("invalid_ttl", format!("{e:#}")))
+            })
+            .transpose()
+    }
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Deliver the recent events after this ID first
+    last_event_id: Option<u64>,
+    /// Client ID whose own broadcasts to leave out
+    client: Option<String>,
+    /// Page the client has open, for `/clients`
+    page: Option<String>,
+}
+
+/// A message for the app's other clients.
+#[derive(Deserialize, ToSchema)]
+struct BroadcastRequest {
+    /// Lets subscribers tell kinds of messages apart
+    #[serde(default)]
+    channel: Option<String>,
+    /// Any JSON
+    message: serde_json::Value,
+    /// Sender's client ID, as passed to `/events`; its streams skip the message
+    #[serde(default)]
+    client: Option<String>,
+    /// Page the sender has open, as passed to `/events`; only streams of the
+    /// same app get the message. Taken from `Referer` when absent
+    #[serde(default)]
+    page: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KvExportQuery {
+    /// Comma-separated namespaces; all when absent
+    namespace: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KvImportQuery {
+    /// Remove the other keys of the imported namespaces first
+    #[serde(default)]
+    replace: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+struct KvImportResponse {
+    namespaces: Vec<String>,
+    /// Keys written
+    keys: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct FileEntry {
+    name: String,
+    dir: bool,
+    size: u64,
+    modified: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SessionsResponse {
+    current: Option<String>,
+    sessions: Vec<SessionInfo>,
+}
+
+#[derive(Deserialize, ToSchema, Default)]
+struct TokensRotateRequest {
+    /// How long tokens of the previous keys keep working, e.g. "1d"; 7 days
+    /// when absent, "0" to sign everyone out at once
+    grace: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct TokensRotateResponse {
+    /// ID of the key that signs from now on
+    key: u32,
+    /// Unix timestamp (seconds) from which tokens of the previous keys no
+    /// longer work
+    previous_until: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ClientsResponse {
+    /// In the order they connected
+    clients: Vec<ConnectedClient>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct AuditResponse {
+    /// Newest first
+    records: Vec<AuditRecord>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PingResponse {
+    status: &'static str,
+}
+
+#[derive(Serialize, ToSchema)]
+struct JobsResponse {
+    /// Newest first
+    jobs: Vec<JobRecord>,
+}
+
+#[derive(Deserialize, ToSchema, Default)]
+struct ShutdownRequest {
+    /// `graceful` lets open requests finish; `now` closes every connection
+    #[serde(default)]
+    mode: ShutdownMode,
+    /// Stop this many seconds from now instead, replacing an earlier schedule
+    delay_secs: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ShutdownResponse {
+    /// `shutting_down`, or `scheduled` with a delay
+    status: &'static str,
+    mode: ShutdownMode,
+    /// Unix timestamp (seconds) of a scheduled shutdown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    at: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ShutdownCancelResponse {
+    /// The shutdown called off; null if none was scheduled
+    cancelled: Option<ScheduledShutdown>,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SearchResponse {
+    query: String,
+    /// Best match first
+    hits: Vec<SearchHit>,
+}
+
+// =============================================================================
+// OpenAPI Document
+// =============================================================================
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "tbl API",
+        description = "Local API of the tbl web launcher. Authenticate with the `tbl_token` \
+                       cookie set by `/bootstrap`, plus HTTP Basic auth when configured."
+    ),
+    paths(
+        ping_handler,
+        info_handler,
+        uptime_handler,
+        shutdown_handler,
+        shutdown_cancel_handler,
+        history_handler,
+        switch_handler,
+        update_handler,
+        unshallow_handler,
+        sync_last_handler,
+        sync_pause_handler,
+        sync_resume_handler,
+        peer_manifest_handler,
+        peer_file_handler,
+        releases_handler,
+        rollback_handler,
+        previews_handler,
+        preview_sync_handler,
+        preview_delete_handler,
+        providers_handler,
+        provider_repos_handler,
+        provider_orgs_handler,
+        catalog_handler,
+        capabilities_handler,
+        events_handler,
+        events_sse_handler,
+        broadcast_handler,
+        sessions_handler,
+        session_revoke_handler,
+        tokens_rotate_handler,
+        clients_handler,
+        audit_handler,
+        storage_handler,
+        jobs_list_handler,
+        jobs_get_handler,
+        jobs_log_handler,
+        search_handler,
+        kv_export_handler,
+        kv_import_handler,
+        kv_list_handler,
+        kv_reset_handler,
+        kv_get_handler,
+        kv_put_handler,
+        kv_delete_handler,
+        files_root_handler,
+        files_get_handler,
+        files_put_handler,
+        files_delete_handler,
+    ),
+    components(schemas(Event, ErrorBody)),
+    modifiers(&SecuritySchemes),
+    security(("cookie" = []), ("cookie" = [], "basic" = []))
+)]
+struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "cookie",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("tbl_token"))),
+        );
+        components.add_security_scheme(
+            "basic",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+        );
+        components.add_security_scheme(
+            "peer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+/// OpenAPI handler: the generated OpenAPI 3 document for this API version
+///
+/// Served without authentication so client generators can fetch it directly.
+async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+// =============================================================================
+// Handlers
+// =============================================================================
+
+/// Ping handler: authenticated health check endpoint
+#[utoipa::path(
+    get,
+    path = "/api/v1/ping",
+    tag = "server",
+    responses(
+        (status = 200, description = "Server is up", body = PingResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn ping_handler() -> Json<PingResponse> {
+    Json(PingResponse { status: "ok" })
+}
+
+/// History handler: list previously used repositories
+#[utoipa::path(
+    get,
+    path = "/api/v1/history",
+    tag = "workspace",
+    responses(
+        (status = 200, description = "Current URL and history", body = HistoryResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn history_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<HistoryResponse>, ApiError> {
+    let cfg = state.config.read().unwrap().clone();
+    Ok(Json(HistoryResponse {
+        current: cfg.git_url,
+        history: cfg.history,
+    }))
+}
+
+/// Switch handler: authenticated endpoint to repoint the workspace to a new repo
+#[utoipa::path(
+    post,
+    path = "/api/v1/switch",
+    tag = "workspace",
+    request_body = SwitchRequest,
+    responses(
+        (status = 200, description = "Workspace switched", body = SwitchResponse),
+        (status = 400, description = "Malformed body or missing git URL", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 500, description = "Git missing or clone failed", body = ErrorBody),
+    )
+)]
+async fn switch_handler(
+    State(state): State<Arc<AppState>>,
+    req: Result<Json<SwitchRequest>, JsonRejection>,
+) -> Result<Json<SwitchResponse>, ApiError> {
+    let Json(req) = req?;
+
+    let url = req.git_url.trim().to_string();
+    if url.is_empty() {
+        return Err(ApiError::bad_request("missing_git_url", "missing git URL"));
+    }
+
+    git_ready(&url).await.map_err(git_unavailable)?;
+    ensure_repo_job(&state, JobKind::Switch, &url)
+        .await
+        .map_err(|e| git_failed(e, &url))?;
+
+    commit_workspace(&state, &url, req.catalog_app).await;
+    emit_event(
+        &state,
+        "workspace.switched",
+        serde_json::json!({ "git_url": url }),
+    );
+
+    Ok(Json(SwitchResponse {
+        status: "switched",
+        git_url: url,
+    }))
+}
+
+/// Shutdown handler: authenticated endpoint to stop the server, now or later
+#[utoipa::path(
+    post,
+    path = "/api/v1/shutdown",
+    tag = "server",
+    request_body(content = ShutdownRequest, description = "Optional mode and delay"),
+    responses(
+        (status = 200, description = "Shutdown initiated or scheduled", body = ShutdownResponse),
+        (status = 400, description = "Malformed body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn shutdown_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    body: Bytes,
+) -> Result<Json<ShutdownResponse>, ApiError> {
+    let req: ShutdownRequest = if body.iter().all(u8::is_ascii_whitespace) {
+        ShutdownRequest::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| ApiError::bad_request("invalid_body", e.to_string()))?
+    };
+
+    if let Some(delay) = req.delay_secs.filter(|&delay| delay > 0) {
+        let scheduled = schedule_shutdown(&state, Duration::from_secs(delay), req.mode);
+        tracing::info!(
+            user = ?auth.user,
+            session = ?auth.session,
+            at = scheduled.at,
+            mode = ?req.mode,
+            "Shutdown scheduled"
+        );
+        emit_event(
+            &state,
+            "server.shutdown_scheduled",
+            serde_json::json!({ "at": scheduled.at, "mode": req.mode, "delay_secs": delay }),
+        );
+        return Ok(Json(ShutdownResponse {
+            status: "scheduled",
+            mode: req.mode,
+            at: Some(scheduled.at),
+        }));
+    }
+
+    tracing::info!(user = ?auth.user, session = ?auth.session, mode = ?req.mode, "Shutdown requested");
+    request_shutdown(&state, "shutdown_request", req.mode).await;
+
+    Ok(Json(ShutdownResponse {
+        status: "shutting_down",
+        mode: req.mode,
+        at: None,
+    }))
+}
+
+/// Shutdown cancel handler: call off a scheduled shutdown
+#[utoipa::path(
+    post,
+    path = "/api/v1/shutdown/cancel",
+    tag = "server",
+    responses(
+        (status = 200, description = "Scheduled shutdown called off, or none was scheduled", body = ShutdownCancelResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn shutdown_cancel_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<ShutdownCancelResponse> {
+    let cancelled = cancel_shutdown(&state);
+    if let Some(scheduled) = cancelled {
+        tracing::info!(user = ?auth.user, session = ?auth.session, at = scheduled.at, "Scheduled shutdown cancelled");
+        emit_event(
+            &state,
+            "server.shutdown_cancelled",
+            serde_json::json!({ "at": scheduled.at, "mode": scheduled.mode }),
+        );
+    }
+    Json(ShutdownCancelResponse { cancelled })
+}
+
+/// Info handler: version, uptime, and workspace details
+#[utoipa::path(
+    get,
+    path = "/api/v1/info",
+    tag = "server",
+    responses(
+        (status = 200, description = "Server details", body = InfoResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn info_handler(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+) -> Result<Json<InfoResponse>, ApiError> {
+    let git_url = state.config.read().unwrap().git_url.clone();
+    Ok(Json(InfoResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        api_version: VERSION,
+        pid: std::process::id(),
+        tls: state.tls,
+        base_url: url.base_url(),
+        started_at: state.started_at,
+        uptime_secs: now_unix().saturating_sub(state.started_at),
+        app_name: git_url.as_deref().and_then(repo_name),
+        git_url,
+        commit: workspace_commit(&state).await,
+        sync_paused: pause::current(&state.config_dir),
+        sync_deferred: metered::deferred(&state.config_dir),
+        unmet_requirements: state.unmet_requirements.read().unwrap().clone(),
+        memory: memory_usage(&state),
+        shutdown: scheduled_shutdown(&state),
+        uptime: uptime::stats(&state.db, true, now_unix())?,
+    }))
+}
+
+/// Uptime handler: restarts, how each run ended, and the uptime in all
+#[utoipa::path(
+    get,
+    path = "/api/v1/uptime",
+    tag = "server",
+    params(
+        ("limit" = Option<usize>, Query, description = "Maximum number of runs (default 20, at most 500)"),
+    ),
+    responses(
+        (status = 200, description = "Uptime totals and recent runs", body = UptimeResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn uptime_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<UptimeQuery>,
+) -> Result<Json<UptimeResponse>, ApiError> {
+    let now = now_unix();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_RUNS_LIMIT)
+        .min(uptime::MAX_RUNS);
+    Ok(Json(UptimeResponse {
+        stats: uptime::stats(&state.db, true, now)?,
+        runs: uptime::runs(&state.db, true, now, limit)?,
+    }))
+}
+
+fn memory_usage(state: &AppState) -> MemoryUsage {
+    MemoryUsage {
+        search_index: Usage {
+            bytes: state.search.read().unwrap().memory_usage(),
+            limit: state.memory.search_index,
+        },
+        kv: Usage {
+            bytes: state.kv.memory_usage(),
+            limit: state.memory.kv,
+        },
+        jobs: Usage {
+            bytes: state.jobs.memory_usage(),
+            limit: state.memory.jobs,
+        },
+    }
+}
+
+/// Update handler: fetch the configured repository and reset the checkout
+#[utoipa::path(
+    post,
+    path = "/api/v1/update",
+    tag = "workspace",
+    responses(
+        (status = 200, description = "Checkout updated", body = UpdateResponse),
+        (status = 400, description = "No git URL configured", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 500, description = "Git missing or update failed", body = ErrorBody),
+    )
+)]
+async fn update_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<UpdateResponse>, ApiError> {
+    let Some(url) = state.config.read().unwrap().git_url.clone() else {
+        return Err(ApiError::bad_request("no_git_url", "no git URL configured"));
+    };
+
+    git_ready(&url).await.map_err(git_unavailable)?;
+
+    emit_event(
+        &state,
+        "update.started",
+        serde_json::json!({ "git_url": url }),
+    );
+    let before = workspace_commit(&state).await;
+    if let Err(e) = ensure_repo_job(&state, JobKind::Sync, &url).await {
+        update_failed(&state, &url, &e);
+        return Err(git_failed(e, &url));
+    }
+
+    let commit = workspace_commit(&state).await;
+    update_finished(&state, &url, commit.clone(), commit != before);
+
+    Ok(Json(UpdateResponse {
+        status: "updated",
+        commit,
+    }))
+}
+
+/// Unshallow handler: fetch the full history of the checkout and keep it
+///
+/// Sets `clone_depth` to 0, so later updates don't cut the history again.
+#[utoipa::path(
+    post,
+    path = "/api/v1/unshallow",
+    tag = "workspace",
+    responses(
+        (status = 200, description = "Full history fetched", body = UpdateResponse),
+        (status = 400, description = "No git URL configured, or the source is not a git repository", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 500, description = "Git missing or fetch failed", body = ErrorBody),
+    )
+)]
+async fn unshallow_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<UpdateResponse>, ApiError> {
+    let Some(url) = state.config.read().unwrap().git_url.clone() else {
+        return Err(ApiError::bad_request("no_git_url", "no git URL configured"));
+    };
+    let provider = source::provider(&url);
+    if !provider.uses_git() {
+        return Err(ApiError::bad_request(
+            "not_a_repository",
+            format!(
+                "the {} source is not a git repository and has no history",
+                provider.name()
+            ),
+        ));
+    }
+
+    git_ready(&url).await.map_err(git_unavailable)?;
+    keep_full_history(&state).await;
+
+    emit_event(
+        &state,
+        "update.started",
+        serde_json::json!({ "git_url": url }),
+    );
+    let before = workspace_commit(&state).await;
+    if let Err(e) = ensure_repo_job(&state, JobKind::Unshallow, &url).await {
+        update_failed(&state, &url, &e);
+        return Err(git_failed(e, &url));
+    }
+
+    let commit = workspace_commit(&state).await;
+    update_finished(&state, &url, commit.clone(), commit != before);
+
+    Ok(Json(UpdateResponse {
+        status: "unshallowed",
+        commit,
+    }))
+}
+
+/// Last sync handler: the files the last sync added, removed, or changed
+///
+/// Files are compared by SHA-256 with the content before the sync. Rollbacks
+/// are reported the same way.
+#[utoipa::path(
+    get,
+    path = "/api/v1/sync/last",
+    tag = "workspace",
+    responses(
+        (status = 200, description = "Changes of the last sync", body = manifest::SyncReport),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "No sync recorded yet", body = ErrorBody),
+    )
+)]
+async fn sync_last_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<manifest::SyncReport>, ApiError> {
+    let config_dir = state.config_dir.clone();
+    blocking(move || manifest::last(&config_dir))
+        .await
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("no_sync", "no sync recorded yet"))
+}
+
+/// Peer manifest handler: every file served, with its size and SHA-256
+///
+/// For other tbl instances syncing from this one (`tbl://` sources), which
+/// authenticate with the `peer_token` as a bearer token. Without it the
+/// app's `access` policy applies, as for `/web/`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/peer/manifest",
+    tag = "peer",
+    security(("peer" = []), ("cookie" = [])),
+    responses(
+        (status = 200, description = "Files of the workspace", body = peer::PeerManifest),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "Nothing synced yet", body = ErrorBody),
+    )
+)]
+async fn peer_manifest_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<peer::PeerManifest>, ApiError> {
+    if !state.web.root().is_dir() {
+        return Err(ApiError::not_found("no_content", "nothing synced yet"));
+    }
+    let config_dir = state.config_dir.clone();
+    blocking(move || peer::manifest(&config_dir))
+        .await
+        .map(Json)
+        .map_err(|e| ApiError::internal("manifest_failed", format!("{e:#}")))
+}
+
+/// Peer file handler: the content of a file listed by the peer manifest
+#[utoipa::path(
+    get,
+    path = "/api/v1/peer/files/{path}",
+    tag = "peer",
+    security(("peer" = []), ("cookie" = [])),
+    params(("path" = String, Path, description = "Path relative to the workspace")),
+    responses(
+        (status = 200, description = "File content", content_type = "application/octet-stream"),
+        (status = 400, description = "Invalid path", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "File not found", body = ErrorBody),
+    )
+)]
+async fn peer_file_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Response, ApiError> {
+    let target = state
+        .web
+        .path(&path)
+        .filter(|_| peer::is_shared(&path))
+        .ok_or_else(|| ApiError::bad_request("invalid_path", "invalid path"))?;
+    if !target.is_file() {
+        return Err(file_not_found(&path));
+    }
+    match state.web.read(&path) {
+        Ok(bytes) => {
+            Ok(([(header::CONTENT_TYPE, "application/octet-stream")], bytes).into_response())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(file_not_found(&path)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Sync pause handler: stop scheduled syncs, and syncs at startup, until resumed
+///
+/// The pause is saved, so it holds across restarts. Explicit updates still
+/// run. The body is optional.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sync/pause",
+    tag = "workspace",
+    request_body(content = SyncPauseRequest, description = "Optional reason and duration"),
+    responses(
+        (status = 200, description = "Syncs paused", body = SyncPauseResponse),
+        (status = 400, description = "Malformed body or duration", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn sync_pause_handler(
+    State(state): State<Arc<AppState>>,
+    body: Bytes,
+) -> Result<Json<SyncPauseResponse>, ApiError> {
+    let req: SyncPauseRequest = if body.iter().all(u8::is_ascii_whitespace) {
+        SyncPauseRequest::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| ApiError::bad_request("invalid_body", e.to_string()))?
+    };
+    let duration = req
+        .duration
+        .as_deref()
+        .map(parse_duration)
+        .transpose()
+        .map_err(|e| ApiError::bad_request("invalid_duration", format!("{e:#}")))?
+        .filter(|duration| !duration.is_zero());
+
+    let paused = pause::pause(
+        &state.config_dir,
+        req.reason,
+        duration.map(|duration| duration.as_secs()),
+    )?;
+    tracing::info!(reason = ?paused.reason, until = ?paused.until, "automatic syncs paused");
+    emit_event(&state, "sync.paused", serde_json::json!(paused));
+
+    Ok(Json(SyncPauseResponse {
+        paused: Some(paused),
+    }))
+}
+
+/// Sync resume handler: let scheduled syncs run again
+///
+/// Does not sync by itself; the next scheduled one does.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sync/resume",
+    tag = "workspace",
+    responses(
+        (status = 200, description = "Syncs resumed, or were not paused", body = SyncPauseResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn sync_resume_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SyncPauseResponse>, ApiError> {
+    let was_paused = pause::resume(&state.config_dir)?;
+    if was_paused {
+        tracing::info!("automatic syncs resumed");
+        emit_event(&state, "sync.resumed", serde_json::json!({}));
+    }
+    Ok(Json(SyncPauseResponse { paused: None }))
+}
+
+/// Releases handler: content saved by earlier syncs, for rollback
+#[utoipa::path(
+    get,
+    path = "/api/v1/releases",
+    tag = "workspace",
+    responses(
+        (status = 200, description = "Releases kept", body = ReleasesResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn releases_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ReleasesResponse>, ApiError> {
+    let keep = state.config.read().unwrap().keep_releases.unwrap_or(0);
+    let commit = workspace_commit(&state).await;
+    let config_dir = state.config_dir.clone();
+    let (list, disk_size) = blocking(move || {
+        (
+            releases::list(&config_dir),
+            releases::pool_size(&config_dir),
+        )
+    })
+    .await;
+
+    let mut current = commit;
+    let releases = list
+        .into_iter()
+        .map(|release| ReleaseInfo {
+            // Only the newest release with the commit is the one served
+            current: current
+                .take_if(|commit| *commit == release.version)
+                .is_some(),
+            id: release.id,
+            version: release.version,
+            url: release.url,
+            created: release.created,
+            files: release.files,
+            size: release.size,
+        })
+        .collect();
+    Ok(Json(ReleasesResponse {
+        releases,
+        keep,
+        disk_size,
+    }))
+}
+
+/// Rollback handler: serve a saved release until the next sync
+///
+/// The release is named by its ID or the start of its version. The next
+/// update fetches the latest content again in full.
+#[utoipa::path(
+    post,
+    path = "/api/v1/releases/{id}/rollback",
+    tag = "workspace",
+    params(("id" = String, Path, description = "Release ID, or the start of its version")),
+    responses(
+        (status = 200, description = "Release restored", body = UpdateResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "No such release, or more than one matches", body = ErrorBody),
+        (status = 500, description = "Restoring failed", body = ErrorBody),
+    )
+)]
+async fn rollback_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Json<UpdateResponse>, ApiError> {
+    let release = releases::find(&state.config_dir, &id)
+        .map_err(|e| ApiError::not_found("no_release", format!("{e:#}")))?;
+
+    emit_event(
+        &state,
+        "update.started",
+        serde_json::json!({ "git_url": release.url, "release": release.id }),
+    );
+    let config_dir = state.config_dir.clone();
+    let url = release.url.clone();
+    let target = release.id.clone();
+    let result = workspace_job(&state, JobKind::Rollback, &target, move |_, job| {
+        roll_back(&config_dir, &release, job)
+    })
+    .await;
+    if let Err(e) = result {
+        update_failed(&state, &url, &e);
+        return Err(ApiError::internal("rollback_failed", format!("{e:#}")));
+    }
+
+    let commit = workspace_commit(&state).await;
+    update_finished(&state, &url, commit.clone(), true);
+
+    Ok(Json(UpdateResponse {
+        status: "rolled_back",
+        commit,
+    }))
+}
+
+/// Previews handler: the branches served under `/preview/`
+///
+/// Lists the branches of the repository, with the commit of those opened
+/// so far. Checkouts of branches deleted on the remote are removed.
+#[utoipa::path(
+    get,
+    path = "/api/v1/previews",
+    tag = "workspace",
+    responses(
+        (status = 200, description = "Branches of the repository", body = PreviewsResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "Previews are off, or the source is not git", body = ErrorBody),
+        (status = 500, description = "Listing the branches failed", body = ErrorBody),
+    )
+)]
+async fn previews_handler(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+) -> Result<Json<PreviewsResponse>, ApiError> {
+    let git_url = previews_enabled(&state)?;
+    let config_dir = state.config_dir.clone();
+    let settings = source::Settings::of(&state.config.read().unwrap());
+    let (branches, checkouts) = blocking(move || -> anyhow::Result<_> {
+        let branches = preview::branches(&settings, &git_url)?;
+        preview::prune(&config_dir, &git_url, &branches)?;
+        Ok((branches, preview::list(&config_dir)))
+    })
+    .await
+    .map_err(|e| ApiError::internal("git_failed", format!("{e:#}")))?;
+
+    let previews = branches
+        .into_iter()
+        .map(|branch| {
+            let checkout = checkouts.iter().find(|c| c.branch == branch).cloned();
+            PreviewInfo::new(&url, branch, checkout)
+        })
+        .collect();
+    Ok(Json(PreviewsResponse { previews }))
+}
+
+/// Preview sync handler: clone or fetch a branch's preview now
+#[utoipa::path(
+    post,
+    path = "/api/v1/previews/{branch}/sync",
+    tag = "workspace",
+    params(("branch" = String, Path, description = "Branch name, percent-encoded")),
+    responses(
+        (status = 200, description = "Branch checked out", body = PreviewInfo),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "Previews are off, or no such branch", body = ErrorBody),
+        (status = 500, description = "Git failed", body = ErrorBody),
+    )
+)]
+async fn preview_sync_handler(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+    UrlPath(branch): UrlPath<String>,
+) -> Result<Json<PreviewInfo>, ApiError> {
+    let git_url = previews_enabled(&state)?;
+    let branches = {
+        let settings = source::Settings::of(&state.config.read().unwrap());
+        let git_url = git_url.clone();
+        blocking(move || preview::branches(&settings, &git_url)).await
+    }
+    .map_err(|e| ApiError::internal("git_failed", format!("{e:#}")))?;
+    if !branches.contains(&branch) {
+        return Err(ApiError::not_found(
+            "no_branch",
+            format!("No branch {branch}"),
+        ));
+    }
+
+    let checkout = preview_job(&state, &git_url, &branch, true)
+        .await
+        .map_err(|e| ApiError::internal("git_failed", format!("{e:#}")))?;
+    Ok(Json(PreviewInfo::new(&url, branch, Some(checkout))))
+}
+
+/// Preview delete handler: remove a branch's checkout
+///
+/// Opening the preview again clones the branch afresh.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/previews/{branch}",
+    tag = "workspace",
+    params(("branch" = String, Path, description = "Branch name, percent-encoded")),
+    responses(
+        (status = 204, description = "Checkout removed"),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "Previews are off, or the branch has no checkout", body = ErrorBody),
+    )
+)]
+async fn preview_delete_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(branch): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    previews_enabled(&state)?;
+    let _turn = state.preview_jobs.lock().await;
+    let config_dir = state.config_dir.clone();
+    let removed = {
+        let branch = branch.clone();
+        blocking(move || match preview::find(&config_dir, &branch) {
+            Some(_) => preview::remove(&config_dir, &branch),
+            None => Ok(false),
+        })
+        .await?
+    };
+    if !removed {
+        return Err(ApiError::not_found(
+            "no_preview",
+            format!("No checkout of {branch}"),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The git URL of the previews, or `previews_off` when there are none.
+fn previews_enabled(state: &AppState) -> Result<String, ApiError> {
+    previews_source(state).ok_or_else(|| {
+        ApiError::not_found(
+            "previews_off",
+            "Previews are off; set previews with a git source to serve branches",
+        )
+    })
+}
+
+/// Providers handler: the git hosts repositories can be picked from
+#[utoipa::path(
+    get,
+    path = "/api/v1/providers",
+    tag = "providers",
+    responses(
+        (status = 200, description = "Configured providers", body = ProvidersResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn providers_handler(State(state): State<Arc<AppState>>) -> Json<ProvidersResponse> {
+    let config = state.config.read().unwrap().providers.clone();
+    let config = config.unwrap_or_default();
+    let providers = config
+        .configured()
+        .into_iter()
+        .filter_map(|provider| {
+            Some(ProviderInfo {
+                name: provider.name(),
+                url: forge::web_url(provider, config.account(provider)?),
+            })
+        })
+        .collect();
+    Json(ProvidersResponse { providers })
+}
+
+/// Provider repos handler: repositories the provider's token can see
+///
+/// With `org`, only those of that organization (GitLab group, subgroups
+/// included). Pages hold `per_page` repositories, most recently updated
+/// first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/providers/{provider}/repos",
+    tag = "providers",
+    params(
+        ("provider" = String, Path, description = "`github`, `gitlab`, or `gitea`"),
+        ("org" = Option<String>, Query, description = "Organization or group to list"),
+        ("page" = Option<u32>, Query, description = "Page, from 1"),
+        ("per_page" = Option<u32>, Query, description = "Repositories per page (default 30, at most 100)"),
+    ),
+    responses(
+        (status = 200, description = "A page of repositories", body = ProviderReposResponse),
+        (status = 400, description = "Page 0", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "No token for this provider", body = ErrorBody),
+        (status = 502, description = "The provider refused or failed", body = ErrorBody),
+    )
+)]
+async fn provider_repos_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(name): UrlPath<String>,
+    Query(query): Query<ProviderReposQuery>,
+) -> Result<Json<ProviderReposResponse>, ApiError> {
+    let (provider, account) = provider_account(&state, &name)?;
+    let page = query.page.unwrap_or(1);
+    if page == 0 {
+        return Err(ApiError::bad_request("invalid_page", "Pages start at 1"));
+    }
+    let per_page = query
+        .per_page
+        .unwrap_or(forge::DEFAULT_PER_PAGE)
+        .clamp(1, forge::MAX_PER_PAGE);
+    let org = query.org.as_deref().filter(|org| !org.is_empty());
+    let result = forge::repos(provider, &account, org, page, per_page)
+        .await
+        .map_err(provider_failed)?;
+    Ok(Json(ProviderReposResponse {
+        repos: result.repos,
+        page,
+        next_page: result.next_page,
+    }))
+}
+
+/// Provider orgs handler: organizations (GitLab groups) to filter repos by
+#[utoipa::path(
+    get,
+    path = "/api/v1/providers/{provider}/orgs",
+    tag = "providers",
+    params(("provider" = String, Path, description = "`github`, `gitlab`, or `gitea`")),
+    responses(
+        (status = 200, description = "Organizations of the token's user", body = ProviderOrgsResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "No token for this provider", body = ErrorBody),
+        (status = 502, description = "The provider refused or failed", body = ErrorBody),
+    )
+)]
+async fn provider_orgs_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(name): UrlPath<String>,
+) -> Result<Json<ProviderOrgsResponse>, ApiError> {
+    let (provider, account) = provider_account(&state, &name)?;
+    let orgs = forge::orgs(provider, &account)
+        .await
+        .map_err(provider_failed)?;
+    Ok(Json(ProviderOrgsResponse { orgs }))
+}
+
+/// The provider `name` and its account, or `no_provider` without a token.
+fn provider_account(state: &AppState, name: &str) -> Result<(Provider, forge::Account), ApiError> {
+    let account = Provider::parse(name).and_then(|provider| {
+        let config = state.config.read().unwrap();
+        let account = config.providers.as_ref()?.account(provider)?.clone();
+        Some((provider, account))
+    });
+    account.ok_or_else(|| {
+        ApiError::not_found(
+            "no_provider",
+            format!("No token for {name}; add one to the providers config section"),
+        )
+    })
+}
+
+fn provider_failed(e: anyhow::Error) -> ApiError {
+    ApiError::new(StatusCode::BAD_GATEWAY, "provider_failed", format!("{e:#}"))
+}
+
+/// Catalog handler: the apps of the configured catalog, to switch to with
+/// `/switch`
+#[utoipa::path(
+    get,
+    path = "/api/v1/catalog",
+    tag = "workspace",
+    responses(
+        (status = 200, description = "Apps of the catalog", body = CatalogResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "No catalog_url configured", body = ErrorBody),
+        (status = 502, description = "The catalog could not be read", body = ErrorBody),
+    )
+)]
+async fn catalog_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<CatalogResponse>, ApiError> {
+    let url = state.config.read().unwrap().catalog_url.clone();
+    let url = url.ok_or_else(|| {
+        ApiError::not_found("no_catalog", "No catalog configured; set catalog_url")
+    })?;
+    let source = url.clone();
+    let catalog = blocking(move || catalog::fetch(&source))
+        .await
+        .map_err(|e| {
+            ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "catalog_failed",
+                redact_credentials(&format!("{e:#}")),
+            )
+        })?;
+    Ok(Json(CatalogResponse {
+        url: redact_credentials(&url),
+        apps: catalog.apps,
+    }))
+}
+
+/// Capabilities handler: which optional subsystems served apps can use here
+///
+/// Every capability tbl knows is listed, so apps can feature-detect instead
+/// of failing on instances that disable some or predate them.
+#[utoipa::path(
+    get,
+    path = "/api/v1/capabilities",
+    tag = "server",
+    responses(
+        (status = 200, description = "Whether each capability is enabled, by name", body = BTreeMap<String, bool>),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn capabilities_handler(State(state): State<Arc<AppState>>) -> Json<BTreeMap<String, bool>> {
+    let disabled = state.config.read().unwrap().disable.clone();
+    Json(
+        Capability::ALL
+            .iter()
+            .map(|capability| (capability.name().to_string(), capability.enabled(&disabled)))
+            .collect(),
+    )
+}
+
+/// Events handler: WebSocket stream of server events as JSON text frames
+#[utoipa::path(
+    get,
+    path = "/api/v1/events",
+    tag = "events",
+    params(
+        ("last_event_id" = Option<u64>, Query, description = "Deliver the recent events after this ID first"),
+        ("client" = Option<String>, Query, description = "Client ID whose own broadcasts to leave out"),
+        ("page" = Option<String>, Query, description = "Page the client has open, for `/api/v1/clients`"),
+    ),
+    responses(
+        (status = 101, description = "WebSocket upgrade; each text frame is an `Event`"),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn events_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventsQuery>,
+    parts: Parts,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let connected = connected_client(&state, &parts, &query, "websocket");
+    let (rx, missed) = subscribe_events(&state, query.last_event_id);
+    Ok(ws.on_upgrade(move |socket| {
+        stream_events(socket, rx, missed, query.client, connected, state)
+    }))
+}
+
+async fn stream_events(
+    mut socket: WebSocket,
+    mut rx: broadcast::Receiver<Event>,
+    missed: Vec<Event>,
+    client: Option<String>,
+    connected: ConnectedClient,
+    state: Arc<AppState>,
+) {
+    let app = connected.app.clone();
+    let _open = OpenStream::new(state, connected);
+    for event in missed {
+        if !delivers(&event, client.as_deref(), app.as_deref()) {
+            continue;
+        }
+        let Ok(text) = serde_json::to_string(&event) else {
+            continue;
+        };
+        if socket.send(Message::Text(text)).await.is_err() || ends_stream(&event) {
+            return;
+        }
+    }
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Ok(event) if !delivers(&event, client.as_deref(), app.as_deref()) => {}
+                Ok(event) => {
+                    let Ok(text) = serde_json::to_string(&event) else { continue };
+                    if socket.send(Message::Text(text)).await.is_err() || ends_stream(&event) {
+                        break;
+                    }
+                }
+                // Slow client: skip what it missed and keep streaming
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// Events SSE handler: the same stream as Server-Sent Events, for clients
+/// and proxies that handle them better than WebSockets
+#[utoipa::path(
+    get,
+    path = "/api/v1/events/sse",
+    tag = "events",
+    params(
+        ("Last-Event-ID" = Option<u64>, Header, description = "Deliver the recent events after this ID first; sent by `EventSource` when it reconnects"),
+        ("last_event_id" = Option<u64>, Query, description = "Same as `Last-Event-ID`, for the first connection"),
+        ("client" = Option<String>, Query, description = "Client ID whose own broadcasts to leave out"),
+        ("page" = Option<String>, Query, description = "Page the client has open, for `/api/v1/clients`"),
+    ),
+    responses(
+        (status = 200, description = "`text/event-stream`; each message's data is an `Event`, its `id` the event's ID"),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn events_sse_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventsQuery>,
+    parts: Parts,
+) -> impl IntoResponse {
+    let last_event_id = parts
+        .headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .or(query.last_event_id);
+    let (rx, missed) = subscribe_events(&state, last_event_id);
+    let connected = connected_client(&state, &parts, &query, "sse");
+    let app = connected.app.clone();
+    let open = OpenStream::new(state, connected);
+    let events = stream::unfold(
+        (rx, missed.into_iter(), (query.client, app), open, false),
+        |(mut rx, mut missed, (client, app), open, ended)| async move {
+            if ended {
+                return None;
+            }
+            let event = loop {
+                let event = match missed.next() {
+                    Some(event) => event,
+                    None => match rx.recv().await {
+                        Ok(event) => event,
+                        // Slow client: skip what it missed and keep streaming
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                };
+                if delivers(&event, client.as_deref(), app.as_deref()) {
+                    break event;
+                }
+            };
+            let sse = sse_event(&event);
+            let ended = ends_stream(&event);
+            Some((
+                Ok::<_, Infallible>(sse),
+                (rx, missed, (client, app), open, ended),
+            ))
+        },
+    );
+    (
+        // Keep nginx from buffering the stream
+        [(HeaderName::from_static("x-accel-buffering"), "no")],
+        Sse::new(events).keep_alive(KeepAlive::default()),
+    )
+}
+
+/// Whether `event` is the last one a stream delivers: once the server is
+/// stopping, open streams close so a graceful shutdown needn't wait for them.
+fn ends_stream(event: &Event) -> bool {
+    event.kind == "server.stopping"
+}
+
+/// Whether a stream of `client` with `app` open gets `event`: broadcasts
+/// reach only the other clients of the sender's app.
+fn delivers(event: &Event, client: Option<&str>, app: Option<&str>) -> bool {
+    if event.kind != BROADCAST_EVENT {
+        return true;
+    }
+    let sender = event.data.get("client").and_then(|sender| sender.as_str());
+    let sender_app = event.data.get("app").and_then(|app| app.as_str());
+    sender_app == app && (client.is_none() || sender != client)
+}
+
+/// Broadcast handler: relay a small JSON message to the app's other clients
+#[utoipa::path(
+    post,
+    path = "/api/v1/broadcast",
+    tag = "events",
+    request_body = BroadcastRequest,
+    responses(
+        (status = 204, description = "Message sent to the events subscribers of the sender's app, as a `broadcast` event"),
+        (status = 400, description = "Malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 413, description = "Message over 16 KiB", body = ErrorBody),
+    )
+)]
+async fn broadcast_handler(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    if body.len() > MAX_BROADCAST_BYTES {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "message_too_large",
+            format!("Broadcasts are limited to {MAX_BROADCAST_BYTES} bytes"),
+        ));
+    }
+    let request: BroadcastRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request("invalid_body", e.to_string()))?;
+    let referer = headers
+        .get(header::REFERER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|referer| referer.parse::<Uri>().ok())
+        .map(|referer| referer.path().to_string());
+    let app = request.page.or(referer).map(|page| page_app(&url, &page));
+    emit_event(
+        &state,
+        BROADCAST_EVENT,
+        serde_json::json!({
+            "channel": request.channel,
+            "message": request.message,
+            "client": request.client,
+            "app": app,
+        }),
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn sse_event(event: &Event) -> sse::Event {
+    sse::Event::default()
+        .id(event.id.to_string())
+        .data(serde_json::to_string(event).unwrap_or_default())
+}
+
+/// Who is opening an events stream over `transport`.
+fn connected_client(
+    state: &AppState,
+    parts: &Parts,
+    query: &EventsQuery,
+    transport: &str,
+) -> ConnectedClient {
+    let auth = parts
+        .extensions
+        .get::<AuthSession>()
+        .cloned()
+        .unwrap_or_default();
+    let url = PublicUrl::of(state, &parts.headers, peer_ip(&parts.extensions));
+    let app = query.page.as_deref().map(|page| page_app(&url, page));
+    ConnectedClient {
+        id: state.next_client_id.fetch_add(1, Ordering::Relaxed),
+        ip: client_ip(state, &parts.headers, &parts.extensions).map(|ip| ip.to_string()),
+        user_agent: parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        app,
+        page: query.page.clone(),
+        client: query.client.clone(),
+        session: auth.session,
+        user: auth.user,
+        transport: transport.to_string(),
+        connected_at: now_unix(),
+    }
+}
+
+/// The app the page at the URL path `page` belongs to: `web` or
+/// `preview/<branch>`.
+fn page_app(url: &PublicUrl, page: &str) -> String {
+    let path = page.strip_prefix(url.prefix.as_str()).unwrap_or(page);
+    match path.strip_prefix("/preview/") {
+        Some(rest) => format!("preview/{}", rest.split('/').next().unwrap_or_default()),
+        None => "web".to_string(),
+    }
+}
+
+/// An open event stream, listed by `/clients` and keeping the server from
+/// stopping for idleness while it lasts. Announces the client with
+/// `client.joined` and `client.left`.
+struct OpenStream(Arc<AppState>, u64);
+
+impl OpenStream {
+    fn new(state: Arc<AppState>, client: ConnectedClient) -> Self {
+        let id = client.id;
+        let data = serde_json::to_value(&client).unwrap_or_default();
+        state.clients.lock().unwrap().insert(id, client);
+        emit_event(&state, "client.joined", data);
+        Self(state, id)
+    }
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        let client = self.0.clients.lock().unwrap().remove(&self.1);
+        self.0.last_activity.store(now_unix(), Ordering::Relaxed);
+        if let Some(client) = client {
+            let data = serde_json::to_value(&client).unwrap_or_default();
+            emit_event(&self.0, "client.left", data);
+        }
+    }
+}
+
+/// Clients handler: tabs and other clients with an events stream open
+#[utoipa::path(
+    get,
+    path = "/api/v1/clients",
+    tag = "sessions",
+    responses(
+        (status = 200, description = "Connected clients", body = ClientsResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn clients_handler(State(state): State<Arc<AppState>>) -> Json<ClientsResponse> {
+    Json(ClientsResponse {
+        clients: state.clients.lock().unwrap().values().cloned().collect(),
+    })
+}
+
+/// Sessions handler: list browser contexts that completed the bootstrap flow
+#[utoipa::path(
+    get,
+    path = "/api/v1/sessions",
+    tag = "sessions",
+    responses(
+        (status = 200, description = "Known sessions", body = SessionsResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn sessions_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Result<Json<SessionsResponse>, ApiError> {
+    Ok(Json(SessionsResponse {
+        current: auth.session,
+        sessions: state.db.sessions()?,
+    }))
+}
+
+/// Session revoke handler: sign a browser out by revoking its token
+#[utoipa::path(
+    delete,
+    path = "/api/v1/sessions/{id}",
+    tag = "sessions",
+    params(("id" = String, Path, description = "Session ID")),
+    responses(
+        (status = 204, description = "Session removed; its token no longer works"),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "Unknown session", body = ErrorBody),
+    )
+)]
+async fn session_revoke_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    if !state.db.delete_session(&id)? {
+        return Err(
+            ApiError::not_found("session_not_found", "session not found")
+                .with_details(serde_json::json!({ "id": id })),
+        );
+    }
+    state.tokens.revoke(&id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Tokens rotate handler: sign tokens with a new server key
+///
+/// Tokens of the previous keys keep working for the grace period, so
+/// browsers can be signed in again before theirs stop working. The login
+/// link in `pid.yaml` is replaced by one signed with the new key. The body
+/// is optional.
+#[utoipa::path(
+    post,
+    path = "/api/v1/tokens/rotate",
+    tag = "sessions",
+    request_body(content = TokensRotateRequest, description = "Optional grace period"),
+    responses(
+        (status = 200, description = "Key rotated", body = TokensRotateResponse),
+        (status = 400, description = "Malformed body or grace period", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn tokens_rotate_handler(
+    State(state): State<Arc<AppState>>,
+    body: Bytes,
+) -> Result<Json<TokensRotateResponse>, ApiError> {
+    let req: TokensRotateRequest = if body.iter().all(u8::is_ascii_whitespace) {
+        TokensRotateRequest::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| ApiError::bad_request("invalid_body", e.to_string()))?
+    };
+    let grace = req
+        .grace
+        .as_deref()
+        .map(parse_duration)
+        .transpose()
+        .map_err(|e| ApiError::bad_request("invalid_duration", format!("{e:#}")))?
+        .unwrap_or(token::DEFAULT_GRACE);
+
+    let key = state.tokens.rotate(grace)?;
+    let run_dir = run_dir(&state.config_dir);
+    if let Some(mut info) = load_run_info(&run_dir) {
+        let previous = std::mem::replace(&mut info.auth_token, state.tokens.issue());
+        save_run_info(&run_dir, &info)?;
+        state.tokens.revoke_token(&previous)?;
+    }
+    tracing::info!(key, grace = grace.as_secs(), "token key rotated");
+    Ok(Json(TokensRotateResponse {
+        key,
+        previous_until: now_unix() + grace.as_secs(),
+    }))
+}
+
+/// Audit handler: requests that changed something through the API
+#[utoipa::path(
+    get,
+    path = "/api/v1/audit",
+    tag = "sessions",
+    params(
+        ("before" = Option<i64>, Query, description = "Only records with a smaller ID, to page back"),
+        ("since" = Option<u64>, Query, description = "Only records at or after this Unix timestamp"),
+        ("path" = Option<String>, Query, description = "Only requests to paths starting with this"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of records (default 100, at most 1000)"),
+    ),
+    responses(
+        (status = 200, description = "Matching records", body = AuditResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn audit_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditResponse>, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .min(MAX_AUDIT_LIMIT);
+    Ok(Json(AuditResponse {
+        records: state.db.audit_records(&query, limit)?,
+    }))
+}
+
+/// Storage handler: what the KV namespaces and files area hold, against
+/// their quotas
+#[utoipa::path(
+    get,
+    path = "/api/v1/storage",
+    tag = "storage",
+    responses(
+        (status = 200, description = "Usage of the KV store and files area", body = StorageUsage),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn storage_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<StorageUsage>, ApiError> {
+    let usage = blocking(move || {
+        let files = state.files_usage.measure(state.files.root());
+        quota::usage(&state.db, files, &state.quotas)
+    })
+    .await?;
+    Ok(Json(usage))
+}
+
+/// Jobs list handler: recent background jobs such as syncs and clones
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs",
+    tag = "jobs",
+    responses(
+        (status = 200, description = "Recent jobs", body = JobsResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn jobs_list_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<JobsResponse>, ApiError> {
+    Ok(Json(JobsResponse {
+        jobs: state.jobs.list(),
+    }))
+}
+
+/// Job handler: state, progress, and outcome of one job
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{id}",
+    tag = "jobs",
+    params(("id" = String, Path, description = "Job ID")),
+    responses(
+        (status = 200, description = "The job", body = JobRecord),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "No such job", body = ErrorBody),
+    )
+)]
+async fn jobs_get_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Json<JobRecord>, ApiError> {
+    state
+        .jobs
+        .get(&id)
+        .map(Json)
+        .ok_or_else(|| job_not_found(&id))
+}
+
+/// Job log handler: output of the commands a job ran, as plain text
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{id}/log",
+    tag = "jobs",
+    params(("id" = String, Path, description = "Job ID")),
+    responses(
+        (status = 200, description = "Job output", body = String, content_type = "text/plain"),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "No such job", body = ErrorBody),
+    )
+)]
+async fn jobs_log_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Response, ApiError> {
+    let log = state.jobs.log(&id).ok_or_else(|| job_not_found(&id))?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], log).into_response())
+}
+
+/// Search handler: full-text search over the HTML and Markdown pages of the app
+///
+/// Follows the app's `access` setting rather than requiring a session, since
+/// it only reveals what `/web/` serves.
+#[utoipa::path(
+    get,
+    path = "/api/v1/search",
+    tag = "search",
+    params(
+        ("q" = String, Query, description = "Words to find; the last one also matches as a prefix"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of hits (default 20, at most 100)"),
+    ),
+    responses(
+        (status = 200, description = "Matching pages", body = SearchResponse),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn search_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SearchQuery>,
+) -> Json<SearchResponse> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .min(MAX_SEARCH_LIMIT);
+    let hits = state.search.read().unwrap().search(&query.q, limit);
+    Json(SearchResponse {
+        query: query.q,
+        hits,
+    })
+}
+
+/// KV export handler: keys and values of some or all namespaces
+#[utoipa::path(
+    get,
+    path = "/api/v1/kv/export",
+    tag = "kv",
+    params(("namespace" = Option<String>, Query, description = "Comma-separated namespaces; all when absent")),
+    responses(
+        (status = 200, description = "Namespaces with their keys and values", body = KvData),
+        (status = 400, description = "Invalid namespace", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn kv_export_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<KvExportQuery>,
+) -> Result<Json<KvData>, ApiError> {
+    let namespaces: Vec<String> = query
+        .namespace
+        .iter()
+        .flat_map(|list| list.split(','))
+        .filter(|namespace| !namespace.is_empty())
+        .map(str::to_string)
+        .collect();
+    for namespace in &namespaces {
+        check_namespace(namespace)?;
+    }
+
+    Ok(Json(state.kv.export(&namespaces)?))
+}
+
+/// KV import handler: store the keys of an export
+#[utoipa::path(
+    post,
+    path = "/api/v1/kv/import",
+    tag = "kv",
+    params(("replace" = Option<bool>, Query, description = "Remove the other keys of the imported namespaces first")),
+    request_body = KvData,
+    responses(
+        (status = 200, description = "Keys stored", body = KvImportResponse),
+        (status = 400, description = "Invalid namespace or malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 507, description = "A namespace would exceed its quota", body = ErrorBody),
+    )
+)]
+async fn kv_import_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<KvImportQuery>,
+    data: Result<Json<KvData>, JsonRejection>,
+) -> Result<Json<KvImportResponse>, ApiError> {
+    let Json(data) = data?;
+    for namespace in data.namespaces.keys() {
+        check_namespace(namespace)?;
+    }
+
+    let keys = state.kv.import(&data, query.replace)?;
+
+    let namespaces: Vec<String> = data.namespaces.into_keys().collect();
+    for namespace in &namespaces {
+        emit_event(
+            &state,
+            "kv.changed",
+            serde_json::json!({ "namespace": namespace }),
+        );
+    }
+    Ok(Json(KvImportResponse { namespaces, keys }))
+}
+
+/// KV list handler: keys stored in a namespace
+#[utoipa::path(
+    get,
+    path = "/api/v1/kv/{namespace}",
+    tag = "kv",
+    params(("namespace" = String, Path, description = "Namespace name")),
+    responses(
+        (status = 200, description = "Keys in the namespace", body = KvKeysResponse),
+        (status = 400, description = "Invalid namespace", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn kv_list_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(namespace): UrlPath<String>,
+) -> Result<Json<KvKeysResponse>, ApiError> {
+    check_namespace(&namespace)?;
+
+    let keys = state.kv.keys(&namespace)?;
+    Ok(Json(KvKeysResponse { namespace, keys }))
+}
+
+/// KV reset handler: remove every key of a namespace
+#[utoipa::path(
+    delete,
+    path = "/api/v1/kv/{namespace}",
+    tag = "kv",
+    params(("namespace" = String, Path, description = "Namespace name")),
+    responses(
+        (status = 204, description = "Namespace emptied"),
+        (status = 400, description = "Invalid namespace", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn kv_reset_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(namespace): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    check_namespace(&namespace)?;
+
+    if state.kv.clear(&namespace)? > 0 {
+        emit_event(
+            &state,
+            "kv.changed",
+            serde_json::json!({ "namespace": namespace }),
+        );
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// KV get handler: the stored JSON value for a key
+#[utoipa::path(
+    get,
+    path = "/api/v1/kv/{namespace}/{key}",
+    tag = "kv",
+    params(
+        ("namespace" = String, Path, description = "Namespace name"),
+        ("key" = String, Path, description = "Key"),
+    ),
+    responses(
+        (status = 200, description = "Stored JSON value", body = serde_json::Value),
+        (status = 400, description = "Invalid namespace", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "Key not found", body = ErrorBody),
+    )
+)]
+async fn kv_get_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath((namespace, key)): UrlPath<(String, String)>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    check_namespace(&namespace)?;
+
+    match state.kv.get(&namespace, &key)? {
+        Some(value) => Ok(Json(value)),
+        None => Err(key_not_found(&namespace, &key)),
+    }
+}
+
+/// KV put handler: store a JSON value under a key
+#[utoipa::path(
+    put,
+    path = "/api/v1/kv/{namespace}/{key}",
+    tag = "kv",
+    params(
+        ("namespace" = String, Path, description = "Namespace name"),
+        ("key" = String, Path, description = "Key"),
+        ("ttl" = Option<String>, Query, description = "Remove the key after this long, e.g. 1h"),
+    ),
+    request_body = serde_json::Value,
+    responses(
+        (status = 204, description = "Value stored"),
+        (status = 400, description = "Invalid namespace or TTL, or malformed JSON body", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 507, description = "The namespace would exceed its quota", body = ErrorBody),
+    )
+)]
+async fn kv_put_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath((namespace, key)): UrlPath<(String, String)>,
+    Query(query): Query<TtlQuery>,
+    value: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<StatusCode, ApiError> {
+    check_namespace(&namespace)?;
+    let expires_at = query.expires_at()?;
+    let Json(value) = value?;
+
+    state.kv.set(&namespace, &key, value, expires_at)?;
+
+    emit_event(
+        &state,
+        "kv.changed",
+        serde_json::json!({ "namespace": namespace, "key": key }),
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// KV delete handler: remove a key
+#[utoipa::path(
+    delete,
+    path = "/api/v1/kv/{namespace}/{key}",
+    tag = "kv",
+    params(
+        ("namespace" = String, Path, description = "Namespace name"),
+        ("key" = String, Path, description = "Key"),
+    ),
+    responses(
+        (status = 204, description = "Key removed"),
+        (status = 400, description = "Invalid namespace", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "Key not found", body = ErrorBody),
+    )
+)]
+async fn kv_delete_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath((namespace, key)): UrlPath<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    check_namespace(&namespace)?;
+
+    if !state.kv.delete(&namespace, &key)? {
+        return Err(key_not_found(&namespace, &key));
+    }
+
+    emit_event(
+        &state,
+        "kv.changed",
+        serde_json::json!({ "namespace": namespace, "key": key }),
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Files root handler: list the top level of the files area
+#[utoipa::path(
+    get,
+    path = "/api/v1/files",
+    tag = "files",
+    responses(
+        (status = 200, description = "Top-level entries", body = Vec<FileEntry>),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+    )
+)]
+async fn files_root_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<FileEntry>>, ApiError> {
+    match list_dir(state.files.root()) {
+        Ok(entries) => Ok(Json(entries)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Json(Vec::new())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Files get handler: file contents, or a JSON listing for directories
+#[utoipa::path(
+    get,
+    path = "/api/v1/files/{path}",
+    tag = "files",
+    params(("path" = String, Path, description = "Relative file or directory path")),
+    responses(
+        (status = 200, description = "File contents, or a listing for directories", body = Vec<FileEntry>),
+        (status = 400, description = "Invalid path", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "File not found", body = ErrorBody),
+    )
+)]
+async fn files_get_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Response, ApiError> {
+    let target = files_target(&state, &path)?;
+
+    if target.is_dir() {
+        return Ok(Json(list_dir(&target)?).into_response());
+    }
+
+    match state.files.read(&path) {
+        Ok(bytes) => {
+            let mime = mime_guess::from_path(&target).first_or_octet_stream();
+            Ok((
+                [(header::CONTENT_TYPE, mime.essence_str().to_string())],
+                bytes,
+            )
+                .into_response())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(file_not_found(&path)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Files put handler: write the request body to a file, creating parent directories
+#[utoipa::path(
+    put,
+    path = "/api/v1/files/{path}",
+    tag = "files",
+    params(
+        ("path" = String, Path, description = "Relative file path"),
+        ("ttl" = Option<String>, Query, description = "Remove the file after this long, e.g. 7d"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 204, description = "File written"),
+        (status = 400, description = "Invalid path or TTL", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 507, description = "The files area would exceed its quota", body = ErrorBody),
+    )
+)]
+async fn files_put_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(path): UrlPath<String>,
+    Query(query): Query<TtlQuery>,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let target = files_target(&state, &path)?;
+    let expires_at = query.expires_at()?;
+
+    let writing = state.clone();
+    blocking(move || {
+        writing.files_usage.change(writing.files.root(), |usage| {
+            let old = fs::metadata(&target).ok().filter(|meta| meta.is_file());
+            let bytes = usage
+                .0
+                .saturating_sub(old.as_ref().map_or(0, |meta| meta.len()))
+                + body.len() as u64;
+            quota::check("files", writing.quotas.files, usage.0, bytes)?;
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&target, &body)?;
+            *usage = (bytes, usage.1 + usize::from(old.is_none()));
+            Ok(())
+        })
+    })
+    .await?;
+    state
+        .db
+        .set_file_expiry(path.trim_matches('/'), expires_at)?;
+
+    emit_event(&state, "files.changed", serde_json::json!({ "path": path }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Files delete handler: remove a file or directory tree
+#[utoipa::path(
+    delete,
+    path = "/api/v1/files/{path}",
+    tag = "files",
+    params(("path" = String, Path, description = "Relative file or directory path")),
+    responses(
+        (status = 204, description = "Removed"),
+        (status = 400, description = "Invalid path", body = ErrorBody),
+        (status = 401, description = "Missing or invalid authentication", body = ErrorBody),
+        (status = 404, description = "File not found", body = ErrorBody),
+    )
+)]
+async fn files_delete_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    let target = files_target(&state, &path)?;
+
+    let removing = state.clone();
+    if !blocking(move || remove_stored_file(&removing, &target)).await? {
+        return Err(file_not_found(&path));
+    }
+    state.db.forget_file_expiry(path.trim_matches('/'))?;
+    emit_event(&state, "files.changed", serde_json::json!({ "path": path }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Fallback for unknown `/api/v1/*` routes, so API clients always get JSON
+async fn not_found_handler(OriginalUri(uri): OriginalUri) -> ApiError {
+    ApiError::not_found("not_found", format!("no such endpoint: {}", uri.path()))
+}
+
+// =============================================================================
+// Helpers
+// =============================================================================
+
+/// Apply the app's `access` policy, for endpoints that only expose what
+/// `/web/` serves anyway.
+async fn require_app_access(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    check_web_access(&state, &request)?;
+    Ok(next.run(request).await)
+}
+
+/// Answer `403 capability_disabled` while `capability` is in `disable`.
+async fn require_capability(
+    State((state, capability)): State<(Arc<AppState>, Capability)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if state.config.read().unwrap().disable.contains(&capability) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "capability_disabled",
+            format!("{} is disabled on this instance", capability.name()),
+        )
+        .with_details(serde_json::json!({ "capability": capability.name() })));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Add every request that may change something, i.e. all but `GET` and
+/// `HEAD`, to the audit log once it is answered.
+async fn record_audit(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    if method == Method::GET || method == Method::HEAD {
+        return next.run(request).await;
+    }
+    let path = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or_else(|| request.uri().path(), |uri| uri.path())
+        .to_string();
+    let auth = request
+        .extensions()
+        .get::<AuthSession>()
+        .cloned()
+        .unwrap_or_default();
+    let client = client_ip(&state, request.headers(), request.extensions());
+
+    let response = next.run(request).await;
+    let record = AuditRecord {
+        id: 0,
+        time: now_unix(),
+        method: method.to_string(),
+        path,
+        status: response.status().as_u16(),
+        user: auth.user,
+        session: auth.session,
+        client: client.map(|ip| ip.to_string()),
+    };
+    if let Err(e) = state.db.audit(&record) {
+        tracing::warn!("Failed to write the audit log: {e:#}");
+    }
+    response
+}
+
+/// Let other tbl instances in with the `peer_token`, and everyone else as
+/// the app's `access` policy allows: the peer routes reveal no more than
+/// `/web/` serves.
+async fn require_peer(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if !is_peer(&state, request.headers()) {
+        check_web_access(&state, &request)?;
+    }
+    Ok(next.run(request).await)
+}
+
+fn check_namespace(namespace: &str) -> Result<(), ApiError> {
+    if valid_kv_namespace(namespace) {
+        Ok(())
+    } else {
+        Err(ApiError::bad_request(
+            "invalid_namespace",
+            "invalid namespace",
+        ))
+    }
+}
+
+fn files_target(state: &AppState, path: &str) -> Result<PathBuf, ApiError> {
+    state
+        .files
+        .path(path)
+        .ok_or_else(|| ApiError::bad_request("invalid_path", "invalid path"))
+}
+
+fn key_not_found(namespace: &str, key: &str) -> ApiError {
+    ApiError::not_found("key_not_found", "key not found")
+        .with_details(serde_json::json!({ "namespace": namespace, "key": key }))
+}
+
+fn job_not_found(id: &str) -> ApiError {
+    ApiError::not_found("job_not_found", "job not found")
+        .with_details(serde_json::json!({ "id": id }))
+}
+
+fn file_not_found(path: &str) -> ApiError {
+    ApiError::not_found("file_not_found", "file not found")
+        .with_details(serde_json::json!({ "path": path }))
+}
+
+fn git_unavailable(e: anyhow::Error) -> ApiError {
+    ApiError::internal("git_unavailable", e.to_string())
+}
+
+fn git_failed(e: anyhow::Error, url: &str) -> ApiError {
+    ApiError::internal("git_failed", format!("{e:#}"))
+        .with_details(serde_json::json!({ "git_url": url }))
+}
+
+fn list_dir(dir: &Path) -> io::Result<Vec<FileEntry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        entries.push(FileEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            dir: meta.is_dir(),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+            modified,
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
@@ -0,0 +1,307 @@
+//! Title, description, and icon of the served app.
+//!
+//! Read from the checkout's `index.html` and web app manifest after every
+//! clone or update, and cached in the run directory by commit so the app
+//! directory can show stopped profiles without parsing their sites again.
+
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    jail::Jail,
+    search::{collapse_whitespace, decode_entities},
+};
+
+pub const CACHE_FILE: &str = "app.json";
+/// Icons larger than this are not inlined.
+const MAX_ICON_SIZE: u64 = 256 * 1024;
+/// Manifest locations tried when `index.html` doesn't link one.
+const MANIFEST_NAMES: &[&str] = &["manifest.json", "manifest.webmanifest", "site.webmanifest"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AppMeta {
+    /// Commit the metadata was read from
+    pub commit: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Icon as a `data:` URI, so pages stay self-contained
+    pub icon: Option<String>,
+    /// Icon file below the web root, for desktop notifications
+    pub icon_file: Option<String>,
+}
+
+impl AppMeta {
+    /// The cached metadata if it was read from `commit`, else read again
+    /// and cached.
+    pub fn cached(cache: &Path, web_root: &Path, commit: &str) -> Self {
+        let hit = fs::read(cache)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<AppMeta>(&json).ok())
+            .filter(|meta| meta.commit == commit);
+        if let Some(meta) = hit {
+            return meta;
+        }
+
+        let meta = Self::read(web_root, commit);
+        let written = cache
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(cache, serde_json::to_vec_pretty(&meta).unwrap_or_default()));
+        if let Err(e) = written {
+            tracing::warn!("Failed to cache app metadata in {:?}: {e}", cache);
+        }
+        meta
+    }
+
+    /// Parse `index.html` and the manifest it links (or one at the root).
+    /// The manifest's `name` and largest icon win over the page's `<title>`
+    /// and `<link rel="icon">`; the page's description wins over the
+    /// manifest's.
+    pub fn read(web_root: &Path, commit: &str) -> Self {
+        let web = Jail::new(web_root);
+        let is_file = |path: &Path| web.path(path).is_some_and(|path| path.is_file());
+        let html = web.read_to_string("index.html").unwrap_or_default();
+        let page = Page::parse(&html);
+
+        let manifest_path = page
+            .manifest
+            .as_deref()
+            .and_then(|href| resolve(&web, href))
+            .or_else(|| {
+                MANIFEST_NAMES
+                    .iter()
+                    .map(PathBuf::from)
+                    .find(|name| is_file(name))
+            });
+        let manifest = manifest_path
+            .as_ref()
+            .and_then(|path| web.read(path).ok())
+            .and_then(|json| serde_json::from_slice::<Manifest>(&json).ok())
+            .unwrap_or_default();
+        // Manifest icons are relative to the manifest
+        let manifest_dir = manifest_path
+            .as_deref()
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let manifest_icon = manifest
+            .largest_icon()
+            .and_then(|src| resolve(&web, &join_href(&manifest_dir, src)));
+
+        let icon_file = manifest_icon
+            .or_else(|| page.icon.as_deref().and_then(|href| resolve(&web, href)))
+            .or_else(|| {
+                let favicon = PathBuf::from("favicon.ico");
+                is_file(&favicon).then_some(favicon)
+            })
+            .filter(|path| {
+                web.path(path)
+                    .and_then(|path| fs::metadata(path).ok())
+                    .is_some_and(|m| m.len() <= MAX_ICON_SIZE)
+            });
+        let icon = icon_file.as_ref().and_then(|path| {
+            let bytes = web.read(path).ok()?;
+            Some(format!(
+                "data:{};base64,{}",
+                crate::image_mime(path),
+                BASE64.encode(bytes)
+            ))
+        });
+
+        AppMeta {
+            commit: commit.to_string(),
+            title: non_empty(manifest.name.or(manifest.short_name)).or(page.title),
+            description: page.description.or(non_empty(manifest.description)),
+            icon,
+            icon_file: icon_file.map(|path| path.to_string_lossy().replace('\\', "/")),
+        }
+    }
+}
+
+/// What `index.html` says about itself.
+#[derive(Default)]
+struct Page {
+    title: Option<String>,
+    description: Option<String>,
+    icon: Option<String>,
+    manifest: Option<String>,
+}
+
+impl Page {
+    fn parse(html: &str) -> Self {
+        // ASCII lowercasing keeps byte offsets, so positions found in
+        // `lower` index `html` too
+        let lower = html.to_ascii_lowercase();
+        let mut page = Page::default();
+
+        if let Some(start) = lower.find("<title") {
+            let start = lower[start..].find('>').map(|i| start + i + 1);
+            let end = start.and_then(|start| lower[start..].find("</title").map(|i| start + i));
+            if let (Some(start), Some(end)) = (start, end) {
+                page.title = non_empty(Some(collapse_whitespace(&decode_entities(
+                    &html[start..end],
+                ))));
+            }
+        }
+
+        let mut apple_icon = None;
+        for tag in tags(html, &lower, "meta") {
+            let name = attr(tag, "name").or_else(|| attr(tag, "property"));
+            let name = name.map(|n| n.to_ascii_lowercase());
+            if matches!(name.as_deref(), Some("description" | "og:description"))
+                && page.description.is_none()
+            {
+                page.description = non_empty(attr(tag, "content"));
+            }
+        }
+        for tag in tags(html, &lower, "link") {
+            let rel = attr(tag, "rel").unwrap_or_default().to_ascii_lowercase();
+            let rels: Vec<&str> = rel.split_whitespace().collect();
+            let href = attr(tag, "href");
+            if rels.contains(&"manifest") && page.manifest.is_none() {
+                page.manifest = href;
+            } else if rels.contains(&"icon") && page.icon.is_none() {
+                page.icon = href;
+            } else if rels.contains(&"apple-touch-icon") && apple_icon.is_none() {
+                apple_icon = href;
+            }
+        }
+        page.icon = page.icon.or(apple_icon);
+        page
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct Manifest {
+    name: Option<String>,
+    short_name: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    icons: Vec<ManifestIcon>,
+}
+
+#[derive(Deserialize)]
+struct ManifestIcon {
+    src: String,
+    /// e.g. "192x192" or "48x48 96x96"; "any" for scalable icons
+    #[serde(default)]
+    sizes: String,
+}
+
+impl Manifest {
+    fn largest_icon(&self) -> Option<&str> {
+        self.icons
+            .iter()
+            .max_by_key(|icon| {
+                icon.sizes
+                    .split_whitespace()
+                    .map(|size| match size {
+                        "any" => u32::MAX,
+                        size => size
+                            .split_once(['x', 'X'])
+                            .and_then(|(w, _)| w.parse().ok())
+                            .unwrap_or(0),
+                    })
+                    .max()
+                    .unwrap_or(0)
+            })
+            .map(|icon| icon.src.as_str())
+    }
+}
+
+/// Attribute text of each `<name ...>` tag.
+fn tags<'a>(html: &'a str, lower: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> {
+    let open = format!("<{name}");
+    let mut pos = 0;
+    std::iter::from_fn(move || loop {
+        let start = pos + lower[pos..].find(&open)? + open.len();
+        let end = start + lower[start..].find('>')?;
+        pos = end;
+        // `<link` must not match `<linker`
+        if html[start..].starts_with(|c: char| c.is_whitespace() || c == '/') {
+            return Some(&html[start..end]);
+        }
+    })
+}
+
+/// Value of attribute `name` in a tag's attribute text.
+fn attr(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq]
+            .trim_end()
+            .rsplit(|c: char| c.is_whitespace())
+            .next()
+            .unwrap_or_default();
+        let after = rest[eq + 1..].trim_start();
+        let (value, next) = match after.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let body = &after[1..];
+                let end = body.find(quote).unwrap_or(body.len());
+                (&body[..end], &body[(end + 1).min(body.len())..])
+            }
+            _ => {
+                let end = after
+                    .find(|c: char| c.is_whitespace() || c == '/')
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        if key.eq_ignore_ascii_case(name) {
+            return Some(decode_entities(value).trim().to_string());
+        }
+        rest = next;
+    }
+    None
+}
+
+/// `href` relative to the directory `base` (both below the web root).
+fn join_href(base: &Path, href: &str) -> String {
+    if href.starts_with('/') || href.contains(':') {
+        return href.to_string();
+    }
+    let base = base.to_string_lossy().replace('\\', "/");
+    if base.is_empty() {
+        href.to_string()
+    } else {
+        format!("{base}/{href}")
+    }
+}
+
+/// Path below the web root a same-site `href` points at, if the file exists.
+/// Links to other sites and paths climbing out of the web root, directly or
+/// through a symlink, are ignored.
+fn resolve(web: &Jail, href: &str) -> Option<PathBuf> {
+    if href.starts_with("//") || href.contains("://") || href.starts_with("data:") {
+        return None;
+    }
+    let path = href.split(['?', '#']).next()?;
+    let path = path.trim_start_matches('/');
+    let mut out = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !out.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    web.path(&out)
+        .is_some_and(|path| path.is_file())
+        .then_some(out)
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
@@ -0,0 +1,408 @@
+//! Archive sources: a `.tar.gz`, `.tgz`, or `.tar` served over HTTP(S) in
+//! place of a git repository.
+//!
+//! The archive is downloaded to `<config_dir>/archive/download.part` and only
+//! unpacked into the workspace once complete. A dropped connection is retried
+//! with backoff, and each retry (or the next run, after a restart) resumes
+//! with a `Range` request where the previous attempt stopped, as long as the
+//! server's `ETag` or `Last-Modified` shows the file is still the same.
+//! Updates first ask whether the archive changed at all (`If-None-Match`,
+//! `If-Modified-Since`).
+
+use std::{
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use reqwest::{
+    blocking::{Client, Response},
+    header, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format_bytes,
+    secret::redact_credentials,
+    source::{self, Context, SourceProvider},
+};
+
+/// Attempts per download before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A connection that sends nothing for this long is dropped and resumed.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the job's progress is updated while downloading.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Archives served over HTTP(S).
+pub struct Archive;
+
+impl SourceProvider for Archive {
+    fn name(&self) -> &'static str {
+        "archive"
+    }
+
+    fn handles(&self, url: &str) -> bool {
+        is_archive_url(url)
+    }
+
+    fn sync(&self, ctx: &Context, url: &str) -> Result<()> {
+        sync(ctx, url)
+    }
+
+    fn installed_url(&self, web_dir: &Path) -> Option<String> {
+        installed(web_dir).map(|version| version.url)
+    }
+
+    /// ETag (without quotes) or `Last-Modified` date of the archive.
+    fn version(&self, web_dir: &Path) -> Option<String> {
+        let installed = installed(web_dir)?;
+        match &installed.etag {
+            Some(etag) => Some(etag.trim_start_matches("W/").trim_matches('"').to_string()),
+            None => installed.last_modified,
+        }
+    }
+}
+
+/// Whether `url` is an archive rather than a git repository: an HTTP(S) URL
+/// whose path ends in `.tar.gz`, `.tgz`, or `.tar`.
+fn is_archive_url(url: &str) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return false;
+    }
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    [".tar.gz", ".tgz", ".tar"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+}
+
+/// An archive as the server described it. Saved as `state.json` for the one
+/// unpacked in the workspace and `download.json` for a partial download.
+#[derive(Serialize, Deserialize, Clone)]
+struct Version {
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+}
+
+impl Version {
+    fn of(url: &str, response: &Response) -> Self {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            url: url.to_string(),
+            etag: header(header::ETAG),
+            last_modified: header(header::LAST_MODIFIED),
+        }
+    }
+
+    /// What tells this version apart from the next: the ETag, else the date.
+    fn validator(&self) -> Option<&str> {
+        self.etag.as_deref().or(self.last_modified.as_deref())
+    }
+
+    fn load(path: &Path) -> Option<Self> {
+        serde_json::from_slice(&fs::read(path).ok()?).ok()
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// State kept next to the workspace `web_dir`.
+fn state_dir(web_dir: &Path) -> PathBuf {
+    web_dir.with_file_name("archive")
+}
+
+/// The archive unpacked in `web_dir`, if it holds one rather than a checkout.
+fn installed(web_dir: &Path) -> Option<Version> {
+    if !web_dir.is_dir() || web_dir.join(".git").exists() {
+        return None;
+    }
+    Version::load(&state_dir(web_dir).join("state.json"))
+}
+
+/// Download `url` unless the archive in `web_dir` is still current, and
+/// replace the workspace with its content. A failed download leaves the
+/// workspace as it was.
+fn sync(ctx: &Context, url: &str) -> Result<()> {
+    let web_dir = &ctx.web_dir;
+    let dir = state_dir(web_dir);
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let part = dir.join("download.part");
+    let current = installed(web_dir).filter(|version| version.url == url);
+
+    // The blocking client refuses to run on a thread of the async runtime,
+    // which the sync before the server starts is on
+    let downloaded = std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let client = Client::builder()
+                    .user_agent(concat!("tbl/", env!("CARGO_PKG_VERSION")))
+                    .timeout(STALL_TIMEOUT)
+                    .build()?;
+                download(&client, url, &part, current.as_ref(), ctx)
+            })
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })?;
+    let Some(downloaded) = downloaded else {
+        ctx.log("Archive not modified");
+        return Ok(());
+    };
+
+    ctx.progress("unpacking");
+    unpack(&part, url, web_dir)?;
+    downloaded.save(&dir.join("state.json"))?;
+    let _ = fs::remove_file(&part);
+    let _ = fs::remove_file(part.with_extension("json"));
+    Ok(())
+}
+
+/// Why an attempt failed: worth another try, or not.
+enum Failure {
+    Retry(anyhow::Error),
+    Fatal(anyhow::Error),
+}
+
+/// Download `url` into `part`, retrying with backoff. `None` when the server
+/// says `current` is still the latest.
+fn download(
+    client: &Client,
+    url: &str,
+    part: &Path,
+    current: Option<&Version>,
+    ctx: &Context,
+) -> Result<Option<Version>> {
+    let mut backoff = FIRST_BACKOFF;
+    for attempt in 1.. {
+        let e = match try_download(client, url, part, current, ctx) {
+            Ok(outcome) => return Ok(outcome),
+            Err(Failure::Fatal(e)) => return Err(e),
+            Err(Failure::Retry(e)) if attempt == MAX_ATTEMPTS => {
+                return Err(e.context(format!("download failed after {MAX_ATTEMPTS} attempts")))
+            }
+            Err(Failure::Retry(e)) => e,
+        };
+        ctx.log(&format!(
+            "Attempt {attempt} of {MAX_ATTEMPTS} failed: {}; retrying in {}s",
+            redact_credentials(&format!("{e:#}")),
+            backoff.as_secs()
+        ));
+        ctx.progress(&format!("retrying in {}s", backoff.as_secs()));
+        let until = Instant::now() + backoff;
+        while Instant::now() < until {
+            if ctx.cancelled() {
+                anyhow::bail!("download cancelled: tbl is shutting down");
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    unreachable!()
+}
+
+/// One attempt: resume the partial download in `part` when the server still
+/// has the same file, start over otherwise.
+fn try_download(
+    client: &Client,
+    url: &str,
+    part: &Path,
+    current: Option<&Version>,
+    ctx: &Context,
+) -> Result<Option<Version>, Failure> {
+    let fatal = |e: anyhow::Error| Failure::Fatal(e);
+    let meta = part.with_extension("json");
+    let partial = Version::load(&meta)
+        .filter(|version| version.url == url && version.validator().is_some() && part.exists());
+    let offset = match &partial {
+        Some(_) => fs::metadata(part).map(|m| m.len()).unwrap_or(0),
+        None => 0,
+    };
+
+    let mut request = client.get(url);
+    match (&partial, current) {
+        (Some(partial), _) if offset > 0 => {
+            ctx.log(&format!("Resuming download at {}", format_bytes(offset)));
+            request = request
+                .header(header::RANGE, format!("bytes={offset}-"))
+                .header(header::IF_RANGE, partial.validator().unwrap_or_default());
+        }
+        (_, Some(current)) => {
+            if let Some(etag) = &current.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(date) = &current.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, date);
+            }
+        }
+        _ => {}
+    }
+
+    let response = request
+        .send()
+        .map_err(|e| Failure::Retry(anyhow::Error::new(e.without_url())))?;
+    let status = response.status();
+    let resumed = match status {
+        StatusCode::NOT_MODIFIED => return Ok(None),
+        StatusCode::OK => false,
+        StatusCode::PARTIAL_CONTENT if offset > 0 && range_start(&response) == Some(offset) => true,
+        StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+            // The partial download is no use; start over on the next attempt
+            let _ = fs::remove_file(part);
+            let _ = fs::remove_file(&meta);
+            return Err(Failure::Retry(anyhow::anyhow!(
+                "server did not resume the download ({status})"
+            )));
+        }
+        status
+            if status.is_server_error()
+                || status == StatusCode::TOO_MANY_REQUESTS
+                || status == StatusCode::REQUEST_TIMEOUT =>
+        {
+            return Err(Failure::Retry(anyhow::anyhow!("server answered {status}")));
+        }
+        status => return Err(fatal(anyhow::anyhow!("server answered {status}"))),
+    };
+
+    let version = match partial.filter(|_| resumed) {
+        Some(partial) => partial,
+        None => {
+            let version = Version::of(url, &response);
+            version.save(&meta).map_err(fatal)?;
+            version
+        }
+    };
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(part)
+        .with_context(|| format!("Failed to open {}", part.display()))
+        .map_err(fatal)?;
+
+    let start = if resumed { offset } else { 0 };
+    let total = response.content_length().map(|len| start + len);
+    let written = copy_with_progress(response, &mut file, start, total, ctx)?;
+    if total.is_some_and(|total| written != total) {
+        return Err(Failure::Retry(anyhow::anyhow!(
+            "connection closed after {} of {}",
+            format_bytes(written),
+            format_bytes(total.unwrap_or_default())
+        )));
+    }
+    Ok(Some(version))
+}
+
+/// First byte of a `206` response's `Content-Range: bytes <start>-<end>/<size>`.
+fn range_start(response: &Response) -> Option<u64> {
+    let range = response
+        .headers()
+        .get(header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// Append the body to `file`, reporting the share done as the job's progress.
+/// Returns the size of the file.
+fn copy_with_progress(
+    mut response: Response,
+    file: &mut fs::File,
+    start: u64,
+    total: Option<u64>,
+    ctx: &Context,
+) -> Result<u64, Failure> {
+    let mut written = start;
+    let mut buf = vec![0; 64 * 1024];
+    let mut reported = Instant::now() - PROGRESS_INTERVAL;
+    loop {
+        if ctx.cancelled() {
+            return Err(Failure::Fatal(anyhow::anyhow!(
+                "download cancelled: tbl is shutting down"
+            )));
+        }
+        let read = match response.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Failure::Retry(anyhow::Error::new(e))),
+        };
+        file.write_all(&buf[..read])
+            .context("Failed to write the download")
+            .map_err(Failure::Fatal)?;
+        written += read as u64;
+
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            reported = Instant::now();
+            ctx.progress(&match total {
+                Some(total) if total > 0 => format!(
+                    "downloading {}% of {}",
+                    written * 100 / total,
+                    format_bytes(total)
+                ),
+                _ => format!("downloading {}", format_bytes(written)),
+            });
+        }
+    }
+    file.flush()
+        .context("Failed to write the download")
+        .map_err(Failure::Fatal)?;
+    Ok(written)
+}
+
+/// Unpack the archive in `part` and swap it in for `web_dir`. An archive whose
+/// entries all sit in one top-level directory, as in GitHub's, is served from
+/// inside that directory.
+fn unpack(part: &Path, url: &str, web_dir: &Path) -> Result<()> {
+    let staging = web_dir.with_file_name("web.unpacking");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    let file =
+        fs::File::open(part).with_context(|| format!("Failed to open {}", part.display()))?;
+    let path = url.to_ascii_lowercase();
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let unpacked = if path.ends_with(".tar") {
+        tar::Archive::new(file).unpack(&staging)
+    } else {
+        tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(&staging)
+    };
+    if let Err(e) = unpacked {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e).context("Failed to unpack the archive");
+    }
+
+    let entries: Vec<PathBuf> = fs::read_dir(&staging)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect();
+    let root = match entries.as_slice() {
+        [only] if only.is_dir() => only.clone(),
+        _ => staging.clone(),
+    };
+
+    let swapped = source::swap_workspace(&root, web_dir);
+    let _ = fs::remove_dir_all(&staging);
+    swapped
+}
@@ -0,0 +1,401 @@
+//! Backups of a profile (`tbl backup`, the `backup` config section).
+//!
+//! A backup is a `.tar.gz` with a snapshot of the state database, taken with
+//! `VACUUM INTO` so it is consistent while the server writes, and the rest of
+//! the profile's config dir: config, templates, stored files (`data/`), and
+//! sync state. The checkout (`web/`) is included on request; releases and
+//! previews are left out, since syncing brings them back. Symlinks are kept
+//! only in the checkout, the one place a restore accepts them. Scheduled
+//! backups are named `auto-<time>.tar.gz`, and only the newest `backup.keep`
+//! of them are kept. A restore first saves the state it replaces as
+//! `pre-restore-<time>.tar.gz`, keeping the newest [`PRE_RESTORE_KEEP`].
+
+use std::{
+    fs,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cron::CronSchedule,
+    now_unix,
+    state::{StateDb, STATE_DB},
+};
+
+/// First entry of a backup.
+pub const MANIFEST: &str = "tbl-backup.json";
+
+/// Scheduled backups kept unless `backup.keep` says otherwise.
+const DEFAULT_KEEP: usize = 7;
+
+/// File name prefix of scheduled backups.
+pub const AUTO_PREFIX: &str = "auto";
+
+/// File name prefix of the backups taken before a restore.
+pub const PRE_RESTORE_PREFIX: &str = "pre-restore";
+
+/// Backups taken before a restore that are kept.
+pub const PRE_RESTORE_KEEP: usize = 3;
+
+/// Config dir entries never backed up: backups themselves, runtime state,
+/// logs, other profiles, and what a sync re-creates. `web` is the exception
+/// when asked for.
+const SKIP_DIRS: &[&str] = &[
+    "backups", "run", "log", "crash", "profiles", "releases", "previews", "web",
+];
+
+/// `backup` config section.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BackupConfig {
+    /// Cron expression in local time for backups while running, e.g. "0 3 * * *"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    /// Scheduled backups kept (default 7)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep: Option<usize>,
+    /// Include the checkout in scheduled backups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub web: Option<bool>,
+}
+
+impl BackupConfig {
+    pub fn schedule(&self) -> Result<Option<CronSchedule>> {
+        self.schedule
+            .as_deref()
+            .map(CronSchedule::parse)
+            .transpose()
+            .context("Invalid backup.schedule")
+    }
+
+    pub fn keep(&self) -> usize {
+        self.keep.unwrap_or(DEFAULT_KEEP)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    /// tbl version that wrote the backup
+    pub version: String,
+    pub created: u64,
+    pub profile: String,
+    /// Whether the checkout is included
+    #[serde(default)]
+    pub web: bool,
+}
+
+/// What [`create`] wrote.
+pub struct Created {
+    /// Files in the backup
+    pub files: usize,
+    /// Symlinks left out, relative to the config dir: only the checkout may
+    /// hold them, or the backup couldn't be restored
+    pub skipped_links: Vec<PathBuf>,
+}
+
+/// Where backups go unless told otherwise.
+pub fn dir(config_dir: &Path) -> PathBuf {
+    config_dir.join("backups")
+}
+
+/// `backups/<prefix>-<local time>.tar.gz` in the config dir.
+pub fn default_path(config_dir: &Path, prefix: &str) -> PathBuf {
+    let time = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    dir(config_dir).join(format!("{prefix}-{time}.tar.gz"))
+}
+
+/// Write a backup of the profile at `config_dir` to `path`, with the
+/// checkout when `web` is set.
+pub fn create(
+    config_dir: &Path,
+    run_dir: &Path,
+    profile: &str,
+    path: &Path,
+    web: bool,
+) -> Result<Created> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    let name = path
+        .file_name()
+        .context("backup path has no file name")?
+        .to_string_lossy();
+    let snapshot = parent.join(format!(".{name}.{STATE_DB}.tmp"));
+    let partial = parent.join(format!(".{name}.tmp"));
+    let _ = fs::remove_file(&snapshot);
+
+    let written = (|| -> Result<Created> {
+        StateDb::open(config_dir, run_dir)?
+            .snapshot(&snapshot)
+            .context("Failed to snapshot the state database")?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(&partial)
+            .with_context(|| format!("Failed to create {}", partial.display()))?;
+        let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        ));
+        // Links in the checkout stay links rather than pulling in their targets
+        archive.follow_symlinks(false);
+
+        let manifest = Manifest {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            created: now_unix(),
+            profile: profile.to_string(),
+            web,
+        };
+        let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest_json.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created);
+        header.set_cksum();
+        archive.append_data(&mut header, MANIFEST, manifest_json.as_slice())?;
+        archive.append_path_with_name(&snapshot, STATE_DB)?;
+
+        let mut created = Created {
+            files: 1,
+            skipped_links: Vec::new(),
+        };
+        let mut entries: Vec<fs::DirEntry> = fs::read_dir(config_dir)
+            .with_context(|| format!("Failed to read {}", config_dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .collect();
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let file_name = entry.file_name();
+            let name = file_name.to_string_lossy();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if SKIP_DIRS.contains(&name.as_ref()) && !(web && name == "web") {
+                    continue;
+                }
+                append_dir(&mut archive, Path::new(&*name), &entry.path(), &mut created)?;
+            } else if file_type.is_symlink() {
+                if !SKIP_DIRS.contains(&name.as_ref()) {
+                    created.skipped_links.push(PathBuf::from(&*name));
+                }
+            } else if file_type.is_file() && !name.starts_with(STATE_DB) && !name.ends_with(".tmp")
+            {
+                created.files += 1;
+                archive
+                    .append_path_with_name(entry.path(), &*name)
+                    .with_context(|| format!("Failed to add {}", entry.path().display()))?;
+            }
+        }
+        archive
+            .into_inner()
+            .and_then(|gz| gz.finish())
+            .with_context(|| format!("Failed to write {}", partial.display()))?;
+        fs::rename(&partial, path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(created)
+    })();
+    let _ = fs::remove_file(&snapshot);
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    written
+}
+
+/// Add the directory `dir` to `archive` as `name`, leaving out symlinks
+/// outside `web/` as [`restore`] would refuse them.
+fn append_dir<W: std::io::Write>(
+    archive: &mut tar::Builder<W>,
+    name: &Path,
+    dir: &Path,
+    created: &mut Created,
+) -> Result<()> {
+    archive
+        .append_dir(name, dir)
+        .with_context(|| format!("Failed to add {}", dir.display()))?;
+    let mut entries: Vec<fs::DirEntry> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let entry_name = name.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            append_dir(archive, &entry_name, &entry.path(), created)?;
+            continue;
+        }
+        if file_type.is_symlink() && !entry_name.starts_with("web") {
+            created.skipped_links.push(entry_name);
+            continue;
+        }
+        created.files += 1;
+        archive
+            .append_path_with_name(entry.path(), &entry_name)
+            .with_context(|| format!("Failed to add {}", entry.path().display()))?;
+    }
+    Ok(())
+}
+
+/// Remove the oldest backups named `<prefix>-<time>.tar.gz` beyond `keep`,
+/// returning how many.
+pub fn rotate(config_dir: &Path, prefix: &str, keep: usize) -> usize {
+    let prefix = format!("{prefix}-");
+    let mut rotated: Vec<PathBuf> = list(config_dir)
+        .into_iter()
+        .map(|backup| backup.path)
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(&prefix))
+        })
+        .collect();
+    // Newest first; the names sort by time
+    rotated.sort_by(|a, b| b.cmp(a));
+    let mut removed = 0;
+    for old in rotated.iter().skip(keep) {
+        match fs::remove_file(old) {
+            Ok(()) => removed += 1,
+            Err(e) => tracing::warn!("Failed to remove old backup {:?}: {e}", old),
+        }
+    }
+    removed
+}
+
+/// A backup in the config dir's `backups/`.
+pub struct BackupFile {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Unix timestamp (seconds)
+    pub modified: u64,
+}
+
+/// Backups in the config dir, oldest first.
+pub fn list(config_dir: &Path) -> Vec<BackupFile> {
+    let mut backups: Vec<BackupFile> = fs::read_dir(dir(config_dir))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tar.gz"))
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+            Some(BackupFile {
+                path: entry.path(),
+                size: meta.len(),
+                modified: meta
+                    .modified()
+                    .ok()?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .ok()?
+                    .as_secs(),
+            })
+        })
+        .collect();
+    backups.sort_by_key(|backup| backup.modified);
+    backups
+}
+
+/// Whether a backup may contain `name`: a relative path without `..`
+/// outside the directories backups leave out, and in `web/` only when the
+/// checkout was backed up.
+fn is_backup_path(name: &Path, web: bool) -> bool {
+    let mut parts = name.components();
+    let Some(Component::Normal(first)) = parts.next() else {
+        return false;
+    };
+    let first = first.to_string_lossy();
+    parts.all(|part| matches!(part, Component::Normal(_)))
+        && (!SKIP_DIRS.contains(&first.as_ref()) || (web && first == "web"))
+}
+
+/// The manifest of the backup at `path`.
+pub fn manifest(path: &Path) -> Result<Manifest> {
+    let file =
+        fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    for entry in archive.entries()? {
+        let mut entry =
+            entry.with_context(|| format!("{} is not a valid backup", path.display()))?;
+        if entry.path()? == Path::new(MANIFEST) {
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            return serde_json::from_str(&json).context("Invalid tbl-backup.json");
+        }
+    }
+    anyhow::bail!("{} is not a tbl backup", path.display())
+}
+
+/// Replace the state of the profile at `config_dir` with the backup at
+/// `path`. The server must not be running.
+pub fn restore(config_dir: &Path, path: &Path) -> Result<Manifest> {
+    let open = || -> Result<tar::Archive<flate2::read::GzDecoder<fs::File>>> {
+        let file =
+            fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(tar::Archive::new(flate2::read::GzDecoder::new(file)))
+    };
+
+    // Check the whole backup before changing anything
+    let mut manifest: Option<Manifest> = None;
+    let mut names = Vec::new();
+    for entry in open()?.entries()? {
+        let mut entry =
+            entry.with_context(|| format!("{} is not a valid backup", path.display()))?;
+        let name = entry.path()?.into_owned();
+        if name == Path::new(MANIFEST) {
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            manifest = Some(serde_json::from_str(&json).context("Invalid tbl-backup.json")?);
+            continue;
+        }
+        let entry_type = entry.header().entry_type();
+        let link_in_web = entry_type.is_symlink() && name.starts_with("web");
+        if !(entry_type.is_file() || entry_type.is_dir() || link_in_web) {
+            anyhow::bail!("Refusing to restore {}: unexpected entry", name.display());
+        }
+        names.push(name);
+    }
+    let manifest = manifest.with_context(|| format!("{} is not a tbl backup", path.display()))?;
+    if let Some(name) = names
+        .iter()
+        .find(|name| !is_backup_path(name, manifest.web))
+    {
+        anyhow::bail!("Refusing to restore {}: unexpected entry", name.display());
+    }
+    if !names.iter().any(|name| name == Path::new(STATE_DB)) {
+        anyhow::bail!("{} holds no state database", path.display());
+    }
+
+    // What the backup holds replaces what is there, rather than merging
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = fs::remove_file(config_dir.join(format!("{STATE_DB}{suffix}")));
+    }
+    let mut replaced = vec!["data"];
+    if manifest.web {
+        replaced.push("web");
+    }
+    for dir in replaced {
+        match fs::remove_dir_all(config_dir.join(dir)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Failed to clear {dir}/"));
+            }
+            _ => {}
+        }
+    }
+
+    fs::create_dir_all(config_dir)?;
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        if entry.path()? != Path::new(MANIFEST) {
+            entry.unpack_in(config_dir)?;
+        }
+    }
+    Ok(manifest)
+}
@@ -0,0 +1,131 @@
+//! `tbl bench`: a load generator for the profile's server, to measure how
+//! fast it serves the app's files and what an option changes about that.
+//!
+//! A number of connections request the given URLs round-robin for a fixed
+//! time, each reading every response to the end. The report has the
+//! requests per second, the transfer rate, and latency percentiles.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use reqwest::Client;
+
+use crate::format_bytes;
+
+/// What a run measured.
+pub struct Report {
+    pub requests: usize,
+    /// Requests that failed or were answered with an error status
+    pub errors: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Latencies of the successful requests, sorted
+    latencies: Vec<Duration>,
+}
+
+/// Request `urls` round-robin over `concurrency` connections for `duration`.
+pub async fn run(
+    client: Client,
+    urls: Vec<String>,
+    concurrency: usize,
+    duration: Duration,
+) -> Report {
+    let urls = Arc::new(urls);
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let deadline = started + duration;
+
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let (client, urls, next) = (client.clone(), urls.clone(), next.clone());
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                let (mut errors, mut bytes) = (0, 0);
+                while Instant::now() < deadline {
+                    let url = &urls[next.fetch_add(1, Ordering::Relaxed) % urls.len()];
+                    let sent = Instant::now();
+                    let body = match client.get(url).send().await {
+                        Ok(response) if response.status().is_success() => response.bytes().await,
+                        Ok(_) | Err(_) => {
+                            errors += 1;
+                            continue;
+                        }
+                    };
+                    match body {
+                        Ok(body) => {
+                            latencies.push(sent.elapsed());
+                            bytes += body.len() as u64;
+                        }
+                        Err(_) => errors += 1,
+                    }
+                }
+                (latencies, errors, bytes)
+            })
+        })
+        .collect();
+
+    let mut report = Report {
+        requests: 0,
+        errors: 0,
+        bytes: 0,
+        elapsed: Duration::ZERO,
+        latencies: Vec::new(),
+    };
+    for worker in workers {
+        if let Ok((latencies, errors, bytes)) = worker.await {
+            report.latencies.extend(latencies);
+            report.errors += errors;
+            report.bytes += bytes;
+        }
+    }
+    report.elapsed = started.elapsed();
+    report.requests = report.latencies.len() + report.errors;
+    report.latencies.sort();
+    report
+}
+
+impl Report {
+    /// Latency below which `percent` of the successful requests finished.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let index = ((last as f64) * percent / 100.0).round() as usize;
+        self.latencies.get(index).copied()
+    }
+
+    pub fn print(&self) {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        let ms = |latency: Option<Duration>| {
+            latency.map_or_else(
+                || "-".to_string(),
+                |latency| format!("{:.2} ms", latency.as_secs_f64() * 1000.0),
+            )
+        };
+        println!();
+        println!(
+            "  Requests:  {} in {:.1}s ({:.1}/s), {} error{}",
+            self.requests,
+            secs,
+            self.requests as f64 / secs,
+            self.errors,
+            if self.errors == 1 { "" } else { "s" }
+        );
+        println!(
+            "  Transfer:  {} ({}/s)",
+            format_bytes(self.bytes),
+            format_bytes((self.bytes as f64 / secs) as u64)
+        );
+        println!(
+            "  Latency:   p50 {}  p90 {}  p99 {}  max {}",
+            ms(self.percentile(50.0)),
+            ms(self.percentile(90.0)),
+            ms(self.percentile(99.0)),
+            ms(self.latencies.last().copied())
+        );
+        println!();
+    }
+}
@@ -0,0 +1,564 @@
+//! App bundles: a signed `.tblapp` file holding an app, for handing out
+//! vetted apps without network access.
+//!
+//! A bundle is a tar file of four members:
+//!
+//! - `bundle.json`: name, title, description, and version of the app
+//! - `content.tar.gz`: the files of the web root
+//! - `manifest.json`: SHA-256 of the two above and of every file of the content
+//! - `signature.json`: Ed25519 signature of `manifest.json`, and its key
+//!
+//! `tbl bundle` makes one from the workspace, signed with the key in
+//! `~/.config/tbl/keys/bundle.key`, created on first use. A bundle is then a
+//! source like any other, a file or an `http(s)://` URL ending in `.tblapp`:
+//! `tbl install` points the workspace at it, and every sync checks that it is
+//! signed by a trusted key (listed in `~/.config/tbl/keys/trusted`, or the
+//! user's own) and that each file matches its checksum before the content is
+//! swapped in.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    manifest::{self, Entry},
+    now_unix,
+    source::{self, Context, SourceProvider},
+};
+
+/// File name extension of bundles.
+pub const EXTENSION: &str = "tblapp";
+
+/// Version of the bundle layout written by `tbl bundle`.
+const FORMAT: u32 = 1;
+
+const INFO: &str = "bundle.json";
+const CONTENT: &str = "content.tar.gz";
+const MANIFEST: &str = "manifest.json";
+const SIGNATURE: &str = "signature.json";
+
+/// Members other than the content are read into memory; anything bigger is
+/// not a bundle tbl wrote.
+const MAX_MEMBER_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A download that sends nothing for this long is dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a bundle says about its app.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BundleInfo {
+    pub format: u32,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Commit or source version the content was bundled from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Unix time the bundle was made
+    pub created_at: u64,
+}
+
+impl BundleInfo {
+    pub fn new(name: String) -> Self {
+        Self {
+            format: FORMAT,
+            name,
+            title: None,
+            description: None,
+            version: None,
+            created_at: now_unix(),
+        }
+    }
+
+    /// Title, else name, for messages.
+    pub fn label(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Checksums of a bundle's members and content; what the signature covers.
+#[derive(Serialize, Deserialize)]
+struct BundleManifest {
+    /// SHA-256 of `bundle.json`
+    info: String,
+    /// SHA-256 of `content.tar.gz`
+    content: String,
+    files: BTreeMap<String, Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleSignature {
+    /// Public key, as `ed25519:<base64>`
+    key: String,
+    /// Signature of `manifest.json`, base64
+    signature: String,
+}
+
+/// A bundle whose signature and checksums were checked.
+pub struct Verified {
+    pub info: BundleInfo,
+    /// Key it is signed with, as `ed25519:<base64>`
+    pub key: String,
+    /// Whether the key is trusted
+    pub trusted: bool,
+    files: BTreeMap<String, Entry>,
+    /// SHA-256 of the content archive
+    content: String,
+}
+
+impl Verified {
+    pub fn files(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.files.values().map(|entry| entry.size).sum()
+    }
+}
+
+// =============================================================================
+// Keys
+// =============================================================================
+
+fn keys_dir(base_config_dir: &Path) -> PathBuf {
+    base_config_dir.join("keys")
+}
+
+/// The key bundles are signed with, created on first use; `true` when it
+/// was just created.
+pub fn signing_key(base_config_dir: &Path) -> Result<(Ed25519KeyPair, bool)> {
+    let path = keys_dir(base_config_dir).join("bundle.key");
+    if let Ok(pkcs8) = fs::read(&path) {
+        let key = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| anyhow::anyhow!("Invalid signing key {}: {e}", path.display()))?;
+        return Ok((key, false));
+    }
+
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| anyhow::anyhow!("Failed to generate a signing key"))?;
+    fs::create_dir_all(keys_dir(base_config_dir))?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(pkcs8.as_ref()))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+        .map_err(|e| anyhow::anyhow!("Invalid signing key: {e}"))?;
+    Ok((key, true))
+}
+
+/// `key`'s public half, as `ed25519:<base64>`.
+pub fn public_key(key: &Ed25519KeyPair) -> String {
+    format!("ed25519:{}", BASE64.encode(key.public_key().as_ref()))
+}
+
+/// Keys whose bundles are installed without asking: those listed in
+/// `keys/trusted`, one per line after an optional comment, and the user's own.
+fn trusted_keys(base_config_dir: &Path) -> Vec<String> {
+    let mut keys: Vec<String> = fs::read_to_string(keys_dir(base_config_dir).join("trusted"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|key| !key.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if let Ok(pkcs8) = fs::read(keys_dir(base_config_dir).join("bundle.key")) {
+        if let Ok(own) = Ed25519KeyPair::from_pkcs8(&pkcs8) {
+            keys.push(public_key(&own));
+        }
+    }
+    keys
+}
+
+/// Add `key` to `keys/trusted`, noting `label` after it.
+pub fn trust(base_config_dir: &Path, key: &str, label: &str) -> Result<()> {
+    let dir = keys_dir(base_config_dir);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("trusted");
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{key} {label}").with_context(|| format!("Failed to write {}", path.display()))
+}
+
+// =============================================================================
+// Making Bundles
+// =============================================================================
+
+/// Bundle the files of `web_dir`, leaving out `.git`, into `out`, signed
+/// with `key`. Returns the number of files and their total size.
+pub fn create(
+    web_dir: &Path,
+    info: &BundleInfo,
+    key: &Ed25519KeyPair,
+    out: &Path,
+) -> Result<(usize, u64)> {
+    let files = manifest::scan(web_dir)?;
+    let partial = out.with_extension(format!("{EXTENSION}.partial"));
+    let content = out.with_extension(format!("{EXTENSION}.content"));
+    let result = write_bundle(web_dir, info, key, &files, &partial, &content).and_then(|()| {
+        fs::rename(&partial, out).with_context(|| format!("Failed to write {}", out.display()))
+    });
+    let _ = fs::remove_file(&content);
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result?;
+    Ok((files.len(), files.values().map(|entry| entry.size).sum()))
+}
+
+fn write_bundle(
+    web_dir: &Path,
+    info: &BundleInfo,
+    key: &Ed25519KeyPair,
+    files: &manifest::Manifest,
+    partial: &Path,
+    content: &Path,
+) -> Result<()> {
+    let file = fs::File::create(content)
+        .with_context(|| format!("Failed to create {}", content.display()))?;
+    let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+        file,
+        flate2::Compression::default(),
+    ));
+    for path in files.keys() {
+        archive
+            .append_path_with_name(web_dir.join(path), path)
+            .with_context(|| format!("Failed to add {path}"))?;
+    }
+    archive
+        .into_inner()
+        .and_then(|gz| gz.finish())
+        .context("Failed to write the content")?;
+
+    let info_json = serde_json::to_vec_pretty(info)?;
+    let manifest_json = serde_json::to_vec_pretty(&BundleManifest {
+        info: hex::encode(Sha256::digest(&info_json)),
+        content: manifest::hash_file(content)?.1,
+        files: files.clone(),
+    })?;
+    let signature_json = serde_json::to_vec_pretty(&BundleSignature {
+        key: public_key(key),
+        signature: BASE64.encode(key.sign(&manifest_json).as_ref()),
+    })?;
+
+    let file = fs::File::create(partial)
+        .with_context(|| format!("Failed to create {}", partial.display()))?;
+    let mut bundle = tar::Builder::new(file);
+    let mut append = |name: &str, size: u64, data: &mut dyn Read| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(info.created_at);
+        header.set_cksum();
+        bundle
+            .append_data(&mut header, name, data)
+            .with_context(|| format!("Failed to add {name}"))
+    };
+    append(INFO, info_json.len() as u64, &mut info_json.as_slice())?;
+    append(
+        CONTENT,
+        fs::metadata(content)?.len(),
+        &mut fs::File::open(content)?,
+    )?;
+    append(
+        MANIFEST,
+        manifest_json.len() as u64,
+        &mut manifest_json.as_slice(),
+    )?;
+    append(
+        SIGNATURE,
+        signature_json.len() as u64,
+        &mut signature_json.as_slice(),
+    )?;
+    bundle
+        .into_inner()
+        .and_then(|mut file| file.flush())
+        .with_context(|| format!("Failed to write {}", partial.display()))
+}
+
+// =============================================================================
+// Reading Bundles
+// =============================================================================
+
+/// Check the signature and checksums of the bundle at `path`, copying its
+/// content archive to `content`. An untrusted key is reported, not refused.
+pub fn verify(path: &Path, base_config_dir: &Path, content: &Path) -> Result<Verified> {
+    let file =
+        fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut members = BTreeMap::new();
+    let mut content_hash = None;
+    for entry in tar::Archive::new(file)
+        .entries()
+        .with_context(|| format!("{} is not a bundle", path.display()))?
+    {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if name == CONTENT {
+            content_hash = Some(copy_hashed(&mut entry, content)?);
+        } else if [INFO, MANIFEST, SIGNATURE].contains(&name.as_str()) {
+            if entry.size() > MAX_MEMBER_SIZE {
+                anyhow::bail!("{name} in the bundle is too big");
+            }
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            members.insert(name, data);
+        }
+    }
+    let member = |name: &str| {
+        members
+            .get(name)
+            .with_context(|| format!("{} is not a bundle: no {name}", path.display()))
+    };
+    let (info_json, manifest_json) = (member(INFO)?, member(MANIFEST)?);
+    let signature: BundleSignature =
+        serde_json::from_slice(member(SIGNATURE)?).context("Invalid signature.json")?;
+
+    let public = signature
+        .key
+        .strip_prefix("ed25519:")
+        .and_then(|key| BASE64.decode(key).ok())
+        .context("Unsupported signing key")?;
+    let signed = BASE64
+        .decode(&signature.signature)
+        .context("Invalid signature")?;
+    UnparsedPublicKey::new(&ED25519, public)
+        .verify(manifest_json, &signed)
+        .map_err(|_| anyhow::anyhow!("The bundle's signature does not match; it was altered"))?;
+
+    let manifest: BundleManifest =
+        serde_json::from_slice(manifest_json).context("Invalid manifest.json")?;
+    let content_hash = content_hash.context("The bundle has no content")?;
+    if manifest.info != hex::encode(Sha256::digest(info_json)) || manifest.content != content_hash {
+        anyhow::bail!("The bundle does not match its signed manifest; it was altered");
+    }
+    let info: BundleInfo = serde_json::from_slice(info_json).context("Invalid bundle.json")?;
+    if info.format > FORMAT {
+        anyhow::bail!("The bundle needs a newer version of tbl");
+    }
+
+    Ok(Verified {
+        trusted: trusted_keys(base_config_dir).contains(&signature.key),
+        key: signature.key,
+        info,
+        files: manifest.files,
+        content: content_hash,
+    })
+}
+
+/// Copy `from` to `to`, returning its SHA-256.
+fn copy_hashed(from: &mut impl Read, to: &Path) -> Result<String> {
+    let mut file =
+        fs::File::create(to).with_context(|| format!("Failed to create {}", to.display()))?;
+    let mut digest = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = from.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        digest.update(&buf[..read]);
+        file.write_all(&buf[..read])?;
+    }
+    Ok(hex::encode(digest.finalize()))
+}
+
+// =============================================================================
+// Source Provider
+// =============================================================================
+
+/// Bundles on this machine or on a web server.
+pub struct Bundle;
+
+/// The bundle installed in the workspace; saved as `bundle/state.json`.
+#[derive(Serialize, Deserialize)]
+struct Installed {
+    url: String,
+    /// The bundle's version, else the start of its content's SHA-256
+    version: String,
+    /// SHA-256 of the content archive, to tell a changed bundle
+    content: String,
+}
+
+impl Installed {
+    fn load(web_dir: &Path) -> Option<Self> {
+        if !web_dir.is_dir() || web_dir.join(".git").exists() {
+            return None;
+        }
+        let path = web_dir.with_file_name("bundle").join("state.json");
+        serde_json::from_slice(&fs::read(path).ok()?).ok()
+    }
+}
+
+impl SourceProvider for Bundle {
+    fn name(&self) -> &'static str {
+        "bundle"
+    }
+
+    fn handles(&self, url: &str) -> bool {
+        is_bundle(url)
+    }
+
+    fn sync(&self, ctx: &Context, url: &str) -> Result<()> {
+        sync(ctx, url)
+    }
+
+    fn installed_url(&self, web_dir: &Path) -> Option<String> {
+        Installed::load(web_dir).map(|installed| installed.url)
+    }
+
+    /// The version the app was bundled from.
+    fn version(&self, web_dir: &Path) -> Option<String> {
+        Installed::load(web_dir).map(|installed| installed.version)
+    }
+}
+
+/// The file `url` names, when it is an absolute path or `file://` URL ending
+/// in `.tblapp`.
+pub fn bundle_path(url: &str) -> Option<PathBuf> {
+    let url = url.trim();
+    let path = Path::new(url.strip_prefix("file://").unwrap_or(url));
+    let is_bundle = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(EXTENSION));
+    (is_bundle && path.is_absolute()).then(|| path.to_path_buf())
+}
+
+/// Whether `url` is a bundle, on this machine or on a web server.
+pub fn is_bundle(url: &str) -> bool {
+    bundle_path(url).is_some() || is_remote(url)
+}
+
+/// Whether `url` is an `http(s)://` URL of a `.tblapp` file.
+pub fn is_remote(url: &str) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    (path.starts_with("http://") || path.starts_with("https://"))
+        && path.ends_with(&format!(".{EXTENSION}"))
+}
+
+/// Download the bundle at `url` to `to`.
+pub fn download(url: &str, to: &Path) -> Result<()> {
+    // The blocking client refuses to run on a thread of the async runtime
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let client = reqwest::blocking::Client::builder()
+                    .user_agent(concat!("tbl/", env!("CARGO_PKG_VERSION")))
+                    .timeout(STALL_TIMEOUT)
+                    .build()?;
+                let mut response = client.get(url).send()?.error_for_status()?;
+                copy_hashed(&mut response, to).map(drop)
+            })
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+    .with_context(|| format!("Failed to download {url}"))
+}
+
+/// Verify the bundle at `url` and swap its content in for the workspace,
+/// unless it is the one already installed. Nothing changes unless the
+/// bundle is signed by a trusted key and every file matches its checksum.
+fn sync(ctx: &Context, url: &str) -> Result<()> {
+    let web_dir = &ctx.web_dir;
+    let dir = web_dir.with_file_name(Bundle.name());
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let content = dir.join(CONTENT);
+    let path = match bundle_path(url) {
+        Some(path) => path,
+        None => {
+            ctx.progress("downloading");
+            let path = dir.join(format!("download.{EXTENSION}"));
+            download(url, &path)?;
+            path
+        }
+    };
+
+    ctx.progress("verifying signature");
+    let base = crate::base_config_dir()?;
+    let verified = verify(&path, &base, &content);
+    if is_remote(url) {
+        let _ = fs::remove_file(&path);
+    }
+    let verified = match verified {
+        Ok(verified) if verified.trusted => verified,
+        Ok(verified) => {
+            let _ = fs::remove_file(&content);
+            anyhow::bail!(
+                "{url} is signed by {}, which is not trusted; install it with `tbl install --trust`",
+                verified.key
+            );
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&content);
+            return Err(e);
+        }
+    };
+    let current = Installed::load(web_dir).filter(|installed| installed.url == url);
+    if current.is_some_and(|installed| installed.content == verified.content) {
+        let _ = fs::remove_file(&content);
+        ctx.log("Bundle not modified");
+        return Ok(());
+    }
+
+    ctx.progress("unpacking");
+    let staging = web_dir.with_file_name("web.unpacking");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    let unpacked = fs::File::open(&content)
+        .and_then(|file| tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(&staging))
+        .context("Failed to unpack the bundle")
+        .and_then(|()| {
+            ctx.progress("checking files");
+            if manifest::scan(&staging)? != verified.files {
+                anyhow::bail!("The bundle's files do not match its signed manifest");
+            }
+            Ok(())
+        })
+        .and_then(|()| source::swap_workspace(&staging, web_dir));
+    let _ = fs::remove_file(&content);
+    if let Err(e) = unpacked {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    let installed = Installed {
+        url: url.to_string(),
+        version: verified
+            .info
+            .version
+            .clone()
+            .unwrap_or_else(|| verified.content[..40].to_string()),
+        content: verified.content,
+    };
+    fs::write(
+        dir.join("state.json"),
+        serde_json::to_vec_pretty(&installed)?,
+    )
+    .context("Failed to record the bundle")
+}
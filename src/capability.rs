@@ -0,0 +1,63 @@
+//! Optional subsystems of tbl that served apps use: turned off with the
+//! `disable` config key, reported by `/api/v1/capabilities` so apps can
+//! feature-detect, and required in an app's `tbl.requires.yaml`.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    /// Key-value store (`/api/v1/kv`)
+    Kv,
+    /// File storage (`/api/v1/files`)
+    Files,
+    /// Server events (`/api/v1/events`)
+    Events,
+    /// Full-text search of the app (`/api/v1/search`)
+    Search,
+    /// Running commands on the host; not provided by this version
+    #[value(skip)]
+    Commands,
+    /// Forwarding requests to other servers; not provided by this version
+    #[value(skip)]
+    Proxy,
+}
+
+impl Capability {
+    pub const ALL: &[Capability] = &[
+        Self::Kv,
+        Self::Files,
+        Self::Events,
+        Self::Search,
+        Self::Commands,
+        Self::Proxy,
+    ];
+
+    /// Name as in the config and the API, e.g. `kv`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Kv => "kv",
+            Self::Files => "files",
+            Self::Events => "events",
+            Self::Search => "search",
+            Self::Commands => "commands",
+            Self::Proxy => "proxy",
+        }
+    }
+
+    /// The capability called `name`.
+    pub fn named(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.name() == name)
+    }
+
+    /// Whether this version of tbl has the subsystem at all.
+    pub fn provided(self) -> bool {
+        !matches!(self, Self::Commands | Self::Proxy)
+    }
+
+    /// Whether the subsystem is there and not in `disabled`.
+    pub fn enabled(self, disabled: &[Capability]) -> bool {
+        self.provided() && !disabled.contains(&self)
+    }
+}
@@ -0,0 +1,202 @@
+//! App catalog: a JSON index of curated apps at `catalog_url`, to pick from
+//! instead of knowing their URLs.
+//!
+//! The index is a static file, on a web server or on disk:
+//!
+//! ```json
+//! { "apps": [ { "name": "handbook", "title": "Handbook",
+//!               "description": "...", "version": "2.1",
+//!               "source": "https://github.com/acme/handbook" } ] }
+//! ```
+//!
+//! `source` is anything `git_url` takes: a git repository, an archive, a
+//! `.tblapp` bundle, ... A relative `source` is resolved against the
+//! catalog's own URL, so a catalog can sit next to the bundles it lists.
+//!
+//! `version` and `source` are the release installed by default; `releases`
+//! lists others, each with a `channel`, for apps pinned to a version
+//! constraint or following `beta` (see [`CatalogPin`]). `tbl upgrade` moves
+//! a pinned app to the newest release its constraint and channel allow.
+
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{Context as _, Result};
+use clap::ValueEnum;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::semver::{Version, VersionReq};
+
+/// A catalog that does not answer within this long is given up on.
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The apps of a catalog.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Catalog {
+    #[serde(default)]
+    pub apps: Vec<CatalogApp>,
+}
+
+/// An app listed in the catalog.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct CatalogApp {
+    /// Short name, e.g. for `tbl catalog install <name>`
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Version the catalog lists, for display
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Where the app is synced from, as `git_url` takes it
+    pub source: String,
+    /// Other releases to pin to, in any order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub releases: Vec<Release>,
+}
+
+/// A release of a catalog app.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct Release {
+    /// Semantic version, e.g. `2.2.0-beta.1`
+    pub version: String,
+    /// Where the release is synced from, as `git_url` takes it
+    pub source: String,
+    #[serde(default)]
+    pub channel: Channel,
+}
+
+/// Which releases of an app are offered.
+#[derive(
+    Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// Releases only
+    #[default]
+    Stable,
+    /// Pre-releases and betas too
+    Beta,
+}
+
+/// The catalog app a profile serves, with the releases it may move to;
+/// the `catalog_app` config key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct CatalogPin {
+    /// Name of the app in the catalog
+    pub name: String,
+    /// Constraint on the version, e.g. `^2.1`; any when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// `stable` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<Channel>,
+    /// Version installed by the last `tbl catalog install` or `tbl upgrade`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed: Option<String>,
+}
+
+impl CatalogPin {
+    /// The parsed version constraint, if any.
+    pub fn requirement(&self) -> Result<Option<VersionReq>> {
+        self.version.as_deref().map(str::parse).transpose()
+    }
+}
+
+impl Catalog {
+    /// The app called `name`, ignoring case.
+    pub fn find(&self, name: &str) -> Option<&CatalogApp> {
+        self.apps
+            .iter()
+            .find(|app| app.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl CatalogApp {
+    /// The newest release `channel` offers that meets `req`: among
+    /// `releases` and the default one. Releases whose version doesn't parse
+    /// are skipped.
+    pub fn select(&self, req: Option<&VersionReq>, channel: Channel) -> Option<(Version, &str)> {
+        let default = self
+            .version
+            .as_deref()
+            .map(|version| (version, self.source.as_str(), Channel::Stable));
+        default
+            .into_iter()
+            .chain(self.releases.iter().map(|release| {
+                (
+                    release.version.as_str(),
+                    release.source.as_str(),
+                    release.channel,
+                )
+            }))
+            .filter_map(|(version, source, offered)| {
+                let version: Version = version.parse().ok()?;
+                let stable = offered == Channel::Stable && !version.is_prerelease();
+                let allowed = stable || channel == Channel::Beta;
+                (allowed && req.is_none_or(|req| req.matches(&version)))
+                    .then_some((version, source))
+            })
+            .max_by(|a, b| a.0.cmp(&b.0))
+    }
+}
+
+/// Read the catalog at `url`, an `http(s)://` URL, a `file://` URL, or a
+/// path, with every `source` made absolute.
+pub fn fetch(url: &str) -> Result<Catalog> {
+    let url = url.trim();
+    let is_web = url.starts_with("http://") || url.starts_with("https://");
+    let data = if is_web {
+        // The blocking client refuses to run on a thread of the async runtime
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| -> Result<Vec<u8>> {
+                    let client = reqwest::blocking::Client::builder()
+                        .user_agent(concat!("tbl/", env!("CARGO_PKG_VERSION")))
+                        .timeout(TIMEOUT)
+                        .build()?;
+                    let response = client.get(url).send()?.error_for_status()?;
+                    Ok(response.bytes()?.to_vec())
+                })
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+        .with_context(|| format!("Failed to fetch the catalog {url}"))?
+    } else {
+        let path = url.strip_prefix("file://").unwrap_or(url);
+        fs::read(path).with_context(|| format!("Failed to read the catalog {path}"))?
+    };
+
+    let mut catalog: Catalog =
+        serde_json::from_slice(&data).with_context(|| format!("Invalid catalog {url}"))?;
+    for app in &mut catalog.apps {
+        app.source = resolve(url, is_web, &app.source);
+        for release in &mut app.releases {
+            release.source = resolve(url, is_web, &release.source);
+        }
+    }
+    Ok(catalog)
+}
+
+/// `source` as listed by the catalog at `base`, relative to it unless it
+/// has a scheme, an scp-like host, or is an absolute path.
+fn resolve(base: &str, is_web: bool, source: &str) -> String {
+    let source = source.trim();
+    if source.contains(':') || source.starts_with('/') {
+        return source.to_string();
+    }
+    if is_web {
+        return Url::parse(base)
+            .and_then(|base| base.join(source))
+            .map_or_else(|_| source.to_string(), String::from);
+    }
+    let base = Path::new(base.strip_prefix("file://").unwrap_or(base));
+    let base = fs::canonicalize(base).unwrap_or_else(|_| base.to_path_buf());
+    let path = base.parent().unwrap_or(Path::new("")).join(source);
+    fs::canonicalize(&path)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,347 @@
+//! Limits on open connections (`max_connections`, `max_connections_per_ip`),
+//! so one misbehaving client can't use up the daemon's file descriptors.
+//!
+//! Every accepted connection takes a slot, given back when it closes. A
+//! connection over a limit is closed as soon as it is accepted, so it holds
+//! no file descriptor for long; over plain HTTP it first gets
+//! `503 Service Unavailable` with `Retry-After`, so the client learns to back
+//! off instead of seeing a reset. Connections from
+//! the `trusted_proxies` count only toward the total: behind a proxy they
+//! carry every client.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::{Future, Ready},
+    io::{self, IoSlice, Read, Write},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::ConnectInfo,
+    http::Request,
+    response::Response,
+    BoxError, Router,
+};
+use axum_server::accept::Accept;
+use socket2::{SockRef, Socket};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tower::Service;
+
+use crate::{proxy::TrustedProxies, sendfile};
+
+/// Open connections allowed unless `max_connections` says otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 512;
+
+/// Open connections allowed from one address unless `max_connections_per_ip`
+/// says otherwise.
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 64;
+
+/// Seconds a refused client is told to wait.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// How often refusals are logged at most.
+const WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Caps on open connections; `None` is no cap.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub total: Option<usize>,
+    pub per_ip: Option<usize>,
+}
+
+/// Which limit a connection ran into.
+#[derive(Debug, Clone, Copy)]
+enum Exceeded {
+    Total,
+    PerIp,
+}
+
+/// The connections open now, checked against the limits.
+pub struct Connections {
+    limits: Limits,
+    /// Addresses exempt from the per-address cap
+    proxies: TrustedProxies,
+    open: Mutex<Open>,
+}
+
+#[derive(Default)]
+struct Open {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    warned_at: Option<Instant>,
+}
+
+impl Connections {
+    pub fn new(limits: Limits, proxies: TrustedProxies) -> Arc<Self> {
+        Arc::new(Self {
+            limits,
+            proxies,
+            open: Mutex::new(Open::default()),
+        })
+    }
+
+    /// Take a slot for a connection from `ip`, unless that exceeds a limit.
+    fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<Slot, Exceeded> {
+        let ip = ip.to_canonical();
+        let capped = !self.proxies.contains(ip);
+        let mut open = self.open.lock().unwrap();
+        let from_ip = open.per_ip.get(&ip).copied().unwrap_or(0);
+        let exceeded = if self.limits.total.is_some_and(|max| open.total >= max) {
+            Some(Exceeded::Total)
+        } else if capped && self.limits.per_ip.is_some_and(|max| from_ip >= max) {
+            Some(Exceeded::PerIp)
+        } else {
+            None
+        };
+        if let Some(exceeded) = exceeded {
+            if open
+                .warned_at
+                .is_none_or(|at| at.elapsed() >= WARN_INTERVAL)
+            {
+                open.warned_at = Some(Instant::now());
+                match exceeded {
+                    Exceeded::Total => tracing::warn!(
+                        "Refusing connections: {} are open (max_connections)",
+                        open.total
+                    ),
+                    Exceeded::PerIp => tracing::warn!(
+                        "Refusing connections from {ip}: {from_ip} are open (max_connections_per_ip)"
+                    ),
+                }
+            }
+            return Err(exceeded);
+        }
+        open.total += 1;
+        *open.per_ip.entry(ip).or_default() += 1;
+        Ok(Slot {
+            connections: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// A connection's place in [`Connections`], given back on drop.
+struct Slot {
+    connections: Arc<Connections>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(count) = open.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Checks each accepted connection against the limits before anything is
+/// read from it, and before the TLS handshake when `tls` is set.
+#[derive(Clone)]
+pub struct Acceptor {
+    connections: Arc<Connections>,
+    tls: bool,
+}
+
+impl Acceptor {
+    pub fn new(connections: Arc<Connections>, tls: bool) -> Self {
+        Self { connections, tls }
+    }
+}
+
+impl Accept<TcpStream, Connection> for Acceptor {
+    type Stream = Limited;
+    type Service = Connection;
+    type Future = Ready<io::Result<(Limited, Connection)>>;
+
+    fn accept(&self, stream: TcpStream, mut service: Connection) -> Self::Future {
+        let slot = stream.peer_addr().map_err(|e| e.kind()).and_then(|addr| {
+            self.connections
+                .acquire(addr.ip())
+                .map_err(|exceeded| refuse(&stream, exceeded, self.tls))
+        });
+        let sendfile = sendfile::Handle::default();
+        if !self.tls && sendfile::Handle::supported() {
+            service.sendfile = Some(sendfile.clone());
+        }
+        std::future::ready(match slot {
+            Ok(slot) => Ok((
+                Limited {
+                    stream,
+                    sendfile,
+                    _slot: slot,
+                },
+                service,
+            )),
+            Err(kind) => Err(kind.into()),
+        })
+    }
+}
+
+/// Answer a connection over a limit and let it close: a plain HTTP client
+/// gets a `503` it can act on, a TLS one only the closed connection. The
+/// response is tiny, so it is written without waiting on the client.
+fn refuse(stream: &TcpStream, exceeded: Exceeded, tls: bool) -> io::ErrorKind {
+    if !tls {
+        // Straight to the socket: tokio has seen no readiness for it yet
+        let socket = SockRef::from(stream);
+        let mut socket: &Socket = &socket;
+        // Unread bytes would turn the close into a reset and lose the answer
+        let mut request = [0u8; 4096];
+        while matches!(socket.read(&mut request), Ok(n) if n > 0) {}
+        let _ = socket.write(refusal(exceeded).as_bytes());
+    }
+    io::ErrorKind::ConnectionRefused
+}
+
+fn refusal(exceeded: Exceeded) -> String {
+    let message = match exceeded {
+        Exceeded::Total => "Too many open connections; try again shortly\n",
+        Exceeded::PerIp => "Too many open connections from your address; try again shortly\n",
+    };
+    format!(
+        "HTTP/1.1 503 Service Unavailable\r\n\
+         Retry-After: {RETRY_AFTER_SECS}\r\n\
+         Connection: close\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         {message}",
+        message.len()
+    )
+}
+
+/// An accepted connection holding its slot until it closes.
+pub struct Limited {
+    stream: TcpStream,
+    /// Large files to write with `sendfile`
+    sendfile: sendfile::Handle,
+    _slot: Slot,
+}
+
+impl AsyncRead for Limited {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Limited {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Some(poll) = this
+            .sendfile
+            .poll_write(&mut this.stream, cx, &[IoSlice::new(buf)])
+        {
+            return poll;
+        }
+        Pin::new(&mut this.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Some(poll) = this.sendfile.poll_write(&mut this.stream, cx, bufs) {
+            return poll;
+        }
+        Pin::new(&mut this.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Serves `app` on each connection, with its address as
+/// `ConnectInfo<SocketAddr>`.
+#[derive(Clone)]
+pub struct MakeService {
+    app: Router,
+}
+
+impl MakeService {
+    pub fn new(app: Router) -> Self {
+        Self { app }
+    }
+}
+
+impl Service<SocketAddr> for MakeService {
+    type Response = Connection;
+    type Error = Infallible;
+    type Future = Ready<Result<Connection, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, addr: SocketAddr) -> Self::Future {
+        std::future::ready(Ok(Connection {
+            app: self.app.clone(),
+            addr,
+            sendfile: None,
+        }))
+    }
+}
+
+/// The service of one connection.
+#[derive(Clone)]
+pub struct Connection {
+    app: Router,
+    addr: SocketAddr,
+    /// Given to requests when their responses may use `sendfile`
+    sendfile: Option<sendfile::Handle>,
+}
+
+impl<B> Service<Request<B>> for Connection
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let mut request = request.map(Body::new);
+        request.extensions_mut().insert(ConnectInfo(self.addr));
+        if let Some(sendfile) = &self.sendfile {
+            request.extensions_mut().insert(sendfile.clone());
+        }
+        Box::pin(self.app.call(request))
+    }
+}
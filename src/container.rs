@@ -0,0 +1,141 @@
+//! Running as a container (`--container`, `tbl docker-entrypoint`).
+//!
+//! In a container tbl serves in the foreground: no daemon, no browser, no
+//! config file (settings come from the environment and flags), logs on
+//! stdout, and the port from `PORT` on all interfaces. `SIGTERM` stops it
+//! gracefully.
+//!
+//! `tbl docker-entrypoint` is the image's entry point. Started as PID 1, it
+//! runs the server as its child and acts as init: it passes on `SIGTERM`,
+//! `SIGINT`, and `SIGHUP`, which the kernel would otherwise drop for PID 1,
+//! and reaps the processes git leaves behind.
+
+use std::process::Command;
+
+use anyhow::{Context as _, Result};
+
+/// Port served on when `PORT` is not set.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Whether container mode is on: `--container` or `TBL_CONTAINER`.
+pub fn enabled(flag: bool) -> bool {
+    flag || std::env::var("TBL_CONTAINER")
+        .is_ok_and(|value| matches!(value.as_str(), "1" | "true" | "yes" | "on"))
+}
+
+/// Address served on unless `--addr` or `TBL_ADDR` says otherwise: every
+/// interface, on `PORT`.
+pub fn default_addr() -> Result<String> {
+    let port = match std::env::var("PORT") {
+        Ok(port) => port
+            .trim()
+            .parse::<u16>()
+            .with_context(|| format!("Invalid PORT {port:?}"))?,
+        Err(_) => DEFAULT_PORT,
+    };
+    Ok(format!("0.0.0.0:{port}"))
+}
+
+/// Wait for `SIGTERM` or Ctrl-C.
+pub async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = term.recv() => return,
+                _ = tokio::signal::ctrl_c() => return,
+            }
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// `tbl docker-entrypoint`: serve in container mode with `args`, and exit
+/// with the server's status. Must run before any other thread starts, so
+/// the signals it passes on reach it.
+pub fn entrypoint(args: &[String]) -> Result<i32> {
+    let exe = std::env::current_exe().context("cannot get current exe")?;
+    let mut cmd = Command::new(exe);
+    cmd.arg("--container").args(args);
+    if std::process::id() == 1 {
+        return supervise(cmd);
+    }
+    exec(cmd)
+}
+
+#[cfg(unix)]
+fn exec(mut cmd: Command) -> Result<i32> {
+    use std::os::unix::process::CommandExt;
+    Err(cmd.exec()).context("Failed to start tbl")
+}
+
+#[cfg(not(unix))]
+fn exec(mut cmd: Command) -> Result<i32> {
+    let status = cmd.status().context("Failed to start tbl")?;
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Run `cmd` as PID 1 would: pass signals on to it, reap every child that
+/// exits, and return its exit status once it is gone.
+#[cfg(unix)]
+fn supervise(mut cmd: Command) -> Result<i32> {
+    use std::os::unix::process::CommandExt;
+
+    const FORWARDED: [libc::c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+
+    // Blocked signals wait for `sigwait` rather than being dropped
+    // SAFETY: the set is initialised by sigemptyset before use
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        for signal in FORWARDED.into_iter().chain([libc::SIGCHLD]) {
+            libc::sigaddset(&mut set, signal);
+        }
+        if libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to block signals");
+        }
+    }
+    // SAFETY: pthread_sigmask is async-signal-safe and touches no parent state
+    unsafe {
+        cmd.pre_exec(move || {
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+            Ok(())
+        });
+    }
+    let server = cmd.spawn().context("Failed to start tbl")?.id() as libc::pid_t;
+
+    loop {
+        let mut signal = 0;
+        // SAFETY: `set` and `signal` are valid for the call
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+            continue;
+        }
+        if signal != libc::SIGCHLD {
+            // SAFETY: kill has no memory preconditions
+            unsafe { libc::kill(server, signal) };
+            continue;
+        }
+        // Orphans are re-parented to PID 1, so reap whatever has exited
+        loop {
+            let mut status = 0;
+            // SAFETY: `status` is valid for the call
+            let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+            if pid <= 0 {
+                break;
+            }
+            if pid == server {
+                return Ok(if libc::WIFSIGNALED(status) {
+                    128 + libc::WTERMSIG(status)
+                } else {
+                    libc::WEXITSTATUS(status)
+                });
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn supervise(cmd: Command) -> Result<i32> {
+    exec(cmd)
+}
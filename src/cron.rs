@@ -0,0 +1,170 @@
+//! Cron expressions such as `0 6 * * *` for `sync_schedule`, evaluated in
+//! local time.
+//!
+//! The five fields are minute, hour, day of month, month, and day of week.
+//! Each takes `*`, a value, a range `a-b`, a step `*/n` or `a-b/n`, or a list
+//! of these separated by `,`; months and days also take names (`jan`, `mon`).
+//! As in cron, a day matches if either day field does when both are
+//! restricted. `@hourly`, `@daily`, `@weekly`, `@monthly`, and `@yearly`
+//! stand for the usual expressions.
+
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate};
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Days searched for the next run; covers a 29th of February.
+const SEARCH_DAYS: i64 = 5 * 366;
+
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    spec: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    /// Indexed by day of month, 1-based
+    days: Vec<bool>,
+    /// Indexed by month, 1-based
+    months: Vec<bool>,
+    /// Indexed by days from Sunday
+    weekdays: Vec<bool>,
+    /// Whether each day field was given as `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let expanded = match spec.trim().to_ascii_lowercase().as_str() {
+            "@hourly" => "0 * * * *".to_string(),
+            "@daily" | "@midnight" => "0 0 * * *".to_string(),
+            "@weekly" => "0 0 * * 0".to_string(),
+            "@monthly" => "0 0 1 * *".to_string(),
+            "@yearly" | "@annually" => "0 0 1 1 *".to_string(),
+            other if other.starts_with('@') => bail!("unknown schedule {spec:?}"),
+            other => other.to_string(),
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!(
+                "expected 5 fields (minute hour day month weekday), got {}",
+                fields.len()
+            );
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7, &DAY_NAMES).context("invalid day of week")?;
+        // 7 is Sunday too
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+        Ok(Self {
+            spec: spec.trim().to_string(),
+            minutes: parse_field(minute, 0, 59, &[]).context("invalid minute")?,
+            hours: parse_field(hour, 0, 23, &[]).context("invalid hour")?,
+            days: parse_field(day, 1, 31, &[]).context("invalid day of month")?,
+            months: parse_field(month, 1, 12, &MONTH_NAMES).context("invalid month")?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    /// First time after `now` the schedule fires, to the minute.
+    pub fn next_after(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let today = now.date_naive();
+        (0..SEARCH_DAYS)
+            .map(|offset| today + Duration::days(offset))
+            .filter(|date| self.matches_day(*date))
+            .find_map(|date| {
+                (0..24u32)
+                    .filter(|hour| self.hours[*hour as usize])
+                    .flat_map(|hour| {
+                        (0..60u32)
+                            .filter(|minute| self.minutes[*minute as usize])
+                            .map(move |minute| (hour, minute))
+                    })
+                    // Times skipped by a DST change don't fire that day
+                    .filter_map(|(hour, minute)| {
+                        date.and_hms_opt(hour, minute, 0)?
+                            .and_local_timezone(Local)
+                            .earliest()
+                    })
+                    .find(|time| *time > now)
+            })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if !self.months[date.month() as usize] {
+            return false;
+        }
+        let day = self.days[date.day() as usize];
+        let weekday = self.weekdays[date.weekday().num_days_from_sunday() as usize];
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            (false, true) => day,
+            (true, false) => weekday,
+            (true, true) => true,
+        }
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+/// One field as a table indexed by value, from 0 to `max`.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<Vec<bool>> {
+    let mut values = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .with_context(|| format!("bad step in {part:?}"))?;
+                if step == 0 {
+                    bail!("step in {part:?} must be at least 1");
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (parse_value(from, names)?, parse_value(to, names)?),
+            // `5/15` runs from 5 to the end, as in most crons
+            None if step > 1 => (parse_value(range, names)?, max),
+            None => {
+                let value = parse_value(range, names)?;
+                (value, value)
+            }
+        };
+        if from < min || to > max || from > to {
+            bail!("{part:?} is out of range {min}-{max}");
+        }
+        for value in (from..=to).step_by(step as usize) {
+            values[value as usize] = true;
+        }
+    }
+    Ok(values)
+}
+
+/// A number, or a name from `names` standing for its index (plus one for
+/// months, which start at 1).
+fn parse_value(value: &str, names: &[&str]) -> Result<u32> {
+    if let Ok(number) = value.parse() {
+        return Ok(number);
+    }
+    let offset = if names.len() == 12 { 1 } else { 0 };
+    names
+        .iter()
+        .position(|name| *name == value)
+        .map(|index| index as u32 + offset)
+        .with_context(|| format!("unknown value {value:?}"))
+}
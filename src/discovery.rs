@@ -0,0 +1,442 @@
+//! Finding tbl instances on the local network.
+//!
+//! A server with `announce` answers multicast DNS queries (DNS-SD) for
+//! `_tbl._tcp.local` with its port and a TXT record naming the app, its
+//! version, and the base path, and announces itself once when it starts.
+//! `tbl clone-from-peer` asks the network, lists the instances that answer,
+//! and syncs from one of them as a `tbl://` source (see `peer`). Only IPv4
+//! is used, on the interface the OS picks for multicast.
+//!
+//! The handful of DNS records involved are encoded here rather than through a
+//! full resolver; other mDNS responders on the machine keep working, as the
+//! port is shared.
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, time::Instant};
+
+/// The DNS-SD service tbl instances register as.
+pub const SERVICE: &str = "_tbl._tcp.local";
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// How long answers may be cached, in seconds.
+const TTL: u32 = 120;
+
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on the class of a record that replaces, rather than adds to, what
+/// caches hold under its name.
+const CACHE_FLUSH: u16 = 0x8000;
+/// The same bit on the class of a question: answer the asker directly.
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+/// What a server announces about itself.
+#[derive(Clone, Debug)]
+pub struct Announcement {
+    /// Instance name, e.g. the machine's host name
+    pub instance: String,
+    pub port: u16,
+    pub app: Option<String>,
+    /// Commit or source version of the content
+    pub version: Option<String>,
+    /// `base_path`, empty at the root
+    pub base_path: String,
+    pub tls: bool,
+}
+
+/// An instance that answered on the network.
+#[derive(Clone, Debug, Default)]
+pub struct Found {
+    pub instance: String,
+    pub addr: Option<IpAddr>,
+    pub port: u16,
+    pub app: Option<String>,
+    pub version: Option<String>,
+    pub base_path: String,
+    pub tls: bool,
+}
+
+impl Found {
+    /// The `tbl://` URL to sync from this instance, with `token` if it asks
+    /// for one.
+    pub fn url(&self, token: Option<&str>) -> Option<String> {
+        let addr = self.addr?;
+        let scheme = if self.tls { "tbls" } else { "tbl" };
+        let token = token.map(|token| format!("{token}@")).unwrap_or_default();
+        let addr = SocketAddr::new(addr, self.port);
+        Some(format!("{scheme}://{token}{addr}{}", self.base_path))
+    }
+}
+
+/// Instance name for this machine and `profile`: the host name, followed by
+/// the profile unless it is the default one.
+pub fn instance_name(profile: &str, default_profile: &str) -> String {
+    let host = hostname::get()
+        .ok()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let host = host.split('.').next().unwrap_or_default();
+    let host = if host.is_empty() { "tbl" } else { host };
+    if profile == default_profile {
+        host.to_string()
+    } else {
+        format!("{host} ({profile})")
+    }
+}
+
+/// Answer queries for [`SERVICE`] until the socket fails, describing the
+/// server with `describe` each time, as its version changes with syncs.
+pub async fn announce(describe: impl Fn() -> Announcement) -> Result<()> {
+    let socket = multicast_socket().context("Failed to listen for mDNS queries")?;
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT));
+    // Unsolicited, so browsers already listening see the server at once
+    socket.send_to(&response(0, &describe()), group).await?;
+
+    let mut buf = vec![0; 9000];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Some(query) = parse_query(&buf[..len]) else {
+            continue;
+        };
+        // Queries from a port other than 5353 come from simple resolvers,
+        // which only listen for a reply to themselves
+        let (id, to) = if from.port() != MDNS_PORT {
+            (query.id, from)
+        } else if query.unicast {
+            (0, from)
+        } else {
+            (0, group)
+        };
+        if let Err(e) = socket.send_to(&response(id, &describe()), to).await {
+            tracing::debug!("mDNS reply to {from} failed: {e}");
+        }
+    }
+}
+
+/// Ask the network for [`SERVICE`] and collect the instances that answer
+/// within `wait`, by instance name.
+pub async fn browse(wait: Duration) -> Result<Vec<Found>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket
+        .send_to(&query(), (MDNS_GROUP, MDNS_PORT))
+        .await
+        .context("Failed to send the mDNS query")?;
+
+    let mut found = BTreeMap::new();
+    let deadline = Instant::now() + wait;
+    let mut buf = vec![0; 9000];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        for instance in parse_response(&buf[..len]) {
+            let addr = Some(from.ip());
+            found.insert(instance.instance.clone(), Found { addr, ..instance });
+        }
+    }
+    Ok(found.into_values().collect())
+}
+
+/// A UDP socket on the mDNS port, shared with other responders, that has
+/// joined the mDNS group.
+fn multicast_socket() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+// =============================================================================
+// Wire Format
+// =============================================================================
+
+/// A question for [`SERVICE`].
+struct Query {
+    id: u16,
+    /// The asker wants the answer sent to it rather than the group
+    unicast: bool,
+}
+
+/// The question for [`SERVICE`] in `packet`, if it is a query asking for it.
+fn parse_query(packet: &[u8]) -> Option<Query> {
+    let mut reader = Reader::new(packet)?;
+    if reader.flags & 0x8000 != 0 {
+        return None;
+    }
+    for _ in 0..reader.questions {
+        let name = reader.name()?;
+        let kind = reader.u16()?;
+        let class = reader.u16()?;
+        if name.eq_ignore_ascii_case(SERVICE) && matches!(kind, TYPE_PTR | TYPE_ANY) {
+            return Some(Query {
+                id: reader.id,
+                unicast: class & UNICAST_RESPONSE != 0,
+            });
+        }
+    }
+    None
+}
+
+/// The instances described by the records of a response.
+fn parse_response(packet: &[u8]) -> Vec<Found> {
+    let Some(mut reader) = Reader::new(packet).filter(|reader| reader.flags & 0x8000 != 0) else {
+        return Vec::new();
+    };
+    let mut instances = BTreeMap::new();
+    if read_records(&mut reader, &mut instances).is_none() {
+        tracing::debug!("malformed mDNS response");
+    }
+    instances
+        .into_values()
+        .filter(|found| found.port != 0)
+        .collect()
+}
+
+/// Fill `instances` from the records of a response, up to where it is
+/// malformed.
+fn read_records(reader: &mut Reader, instances: &mut BTreeMap<String, Found>) -> Option<()> {
+    let suffix = format!(".{SERVICE}");
+    let instance_of = |name: &str| {
+        let at = name.len().checked_sub(suffix.len())?;
+        name.get(at..)?
+            .eq_ignore_ascii_case(&suffix)
+            .then(|| name[..at].to_string())
+    };
+    for _ in 0..reader.questions {
+        reader.name()?;
+        reader.skip(4)?;
+    }
+    for _ in 0..reader.records {
+        let name = reader.name()?;
+        let kind = reader.u16()?;
+        reader.skip(6)?;
+        let len = reader.u16()? as usize;
+        let end = reader.pos + len;
+        let entry = |instances: &mut BTreeMap<String, Found>, instance: String| {
+            instances.entry(instance.clone()).or_insert_with(|| Found {
+                instance,
+                ..Found::default()
+            });
+        };
+        match (kind, instance_of(&name)) {
+            (TYPE_PTR, None) if name.eq_ignore_ascii_case(SERVICE) => {
+                if let Some(instance) = instance_of(&reader.name()?) {
+                    entry(instances, instance);
+                }
+            }
+            (TYPE_SRV, Some(instance)) => {
+                reader.skip(4)?;
+                let port = reader.u16()?;
+                entry(instances, instance.clone());
+                instances.get_mut(&instance)?.port = port;
+            }
+            (TYPE_TXT, Some(instance)) => {
+                entry(instances, instance.clone());
+                let found = instances.get_mut(&instance)?;
+                while reader.pos < end {
+                    let len = *reader.bytes(1)?.first()? as usize;
+                    let text = String::from_utf8_lossy(reader.bytes(len)?).into_owned();
+                    let (key, value) = text.split_once('=').unwrap_or((&text, ""));
+                    match key {
+                        "app" => found.app = Some(value.to_string()),
+                        "version" => found.version = Some(value.to_string()),
+                        "path" => found.base_path = value.to_string(),
+                        "tls" => found.tls = value == "1",
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+        reader.pos = end;
+    }
+    Some(())
+}
+
+/// A query for [`SERVICE`], asking for answers to be sent back directly.
+fn query() -> Vec<u8> {
+    let mut packet = header(0, 0, 1, 0);
+    push_name(&mut packet, SERVICE);
+    push_u16(&mut packet, TYPE_PTR);
+    push_u16(&mut packet, CLASS_IN | UNICAST_RESPONSE);
+    packet
+}
+
+/// The PTR, SRV, and TXT records of `server`, as a response with `id`.
+fn response(id: u16, server: &Announcement) -> Vec<u8> {
+    let instance = format!("{}.{SERVICE}", server.instance.replace('.', "-"));
+    let host = format!(
+        "{}.local",
+        server.instance.split([' ', '.']).next().unwrap_or("tbl")
+    );
+    let mut packet = header(id, 0x8400, 0, 3);
+
+    push_record(&mut packet, SERVICE, TYPE_PTR, CLASS_IN, |data| {
+        push_name(data, &instance)
+    });
+    push_record(
+        &mut packet,
+        &instance,
+        TYPE_SRV,
+        CLASS_IN | CACHE_FLUSH,
+        |data| {
+            push_u16(data, 0);
+            push_u16(data, 0);
+            push_u16(data, server.port);
+            push_name(data, &host);
+        },
+    );
+    push_record(
+        &mut packet,
+        &instance,
+        TYPE_TXT,
+        CLASS_IN | CACHE_FLUSH,
+        |data| {
+            let entries = [
+                server.app.as_ref().map(|app| format!("app={app}")),
+                server
+                    .version
+                    .as_ref()
+                    .map(|version| format!("version={version}")),
+                Some(format!("path={}", server.base_path)),
+                Some(format!("tls={}", u8::from(server.tls))),
+            ];
+            for entry in entries.into_iter().flatten() {
+                let entry = &entry.as_bytes()[..entry.len().min(255)];
+                data.push(entry.len() as u8);
+                data.extend_from_slice(entry);
+            }
+        },
+    );
+    packet
+}
+
+fn header(id: u16, flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(512);
+    for value in [id, flags, questions, answers, 0, 0] {
+        push_u16(&mut packet, value);
+    }
+    packet
+}
+
+fn push_record(
+    packet: &mut Vec<u8>,
+    name: &str,
+    kind: u16,
+    class: u16,
+    data: impl FnOnce(&mut Vec<u8>),
+) {
+    push_name(packet, name);
+    push_u16(packet, kind);
+    push_u16(packet, class);
+    packet.extend_from_slice(&TTL.to_be_bytes());
+    let mut rdata = Vec::new();
+    data(&mut rdata);
+    push_u16(packet, rdata.len() as u16);
+    packet.extend_from_slice(&rdata);
+}
+
+/// `name` as DNS labels. The first label of an instance name may hold
+/// spaces and other characters, but not dots, and at most 63 bytes.
+fn push_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label);
+    }
+    packet.push(0);
+}
+
+fn push_u16(packet: &mut Vec<u8>, value: u16) {
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Reads a DNS message after its header.
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+    id: u16,
+    flags: u16,
+    questions: u16,
+    /// Answers and additional records together
+    records: u16,
+}
+
+impl<'a> Reader<'a> {
+    fn new(packet: &'a [u8]) -> Option<Self> {
+        let mut reader = Self {
+            packet,
+            pos: 0,
+            id: 0,
+            flags: 0,
+            questions: 0,
+            records: 0,
+        };
+        reader.id = reader.u16()?;
+        reader.flags = reader.u16()?;
+        reader.questions = reader.u16()?;
+        let answers = reader.u16()?;
+        let authority = reader.u16()?;
+        let additional = reader.u16()?;
+        reader.records = answers.saturating_add(authority).saturating_add(additional);
+        Some(reader)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.packet.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A name at the current position, following compression pointers.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        // Pointers only point backwards, but a malformed packet may loop
+        for _ in 0..128 {
+            let len = *self.packet.get(pos)? as usize;
+            match len {
+                0 => {
+                    self.pos = end.unwrap_or(pos + 1);
+                    return Some(labels.join("."));
+                }
+                0xc0.. => {
+                    let low = *self.packet.get(pos + 1)? as usize;
+                    end.get_or_insert(pos + 2);
+                    pos = ((len & 0x3f) << 8) | low;
+                }
+                _ => {
+                    let label = self.packet.get(pos + 1..pos + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,662 @@
+//! Repositories on GitHub, GitLab, and Gitea, listed with a personal access
+//! token so the setup page can offer them to pick from.
+//!
+//! The `providers` config section holds a token for each service, the URL of
+//! a self-hosted instance, and the CA bundle its certificate is signed with
+//! when that is a private one. Git commands that fetch from a configured host
+//! get the token as an `Authorization` header and the CA bundle through the
+//! environment, so a private repository picked from the list clones without
+//! credentials in its URL or on the command line.
+//!
+//! `tbl login github` gets a GitHub token through the OAuth device flow
+//! instead, and stores it in the OS keyring rather than the config file.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use reqwest::{header, Certificate, Url};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{config_path, git_config_env, secret::Secret, vault};
+
+/// Time limit for a single request to a provider's API.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Repositories per page unless asked otherwise, and the most a page takes.
+pub const DEFAULT_PER_PAGE: u32 = 30;
+pub const MAX_PER_PAGE: u32 = 100;
+
+/// `providers` config section.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProvidersConfig {
+    #[serde(default, skip_serializing_if = "is_unsaved")]
+    pub github: Option<Account>,
+    #[serde(default, skip_serializing_if = "is_unsaved")]
+    pub gitlab: Option<Account>,
+    #[serde(default, skip_serializing_if = "is_unsaved")]
+    pub gitea: Option<Account>,
+}
+
+/// Access to one provider.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Account {
+    /// Personal access token, e.g. `ghp_...` or `glpat-...`; one stored by
+    /// `tbl login` stays in the keyring
+    #[serde(default, skip_serializing_if = "is_kept_elsewhere")]
+    pub token: Secret,
+    /// Web address of a self-hosted instance, e.g. `https://gitlab.example.com`;
+    /// github.com, gitlab.com, or gitea.com when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// PEM bundle of the CAs the instance's certificate is checked against,
+    /// besides the system ones; relative to the config directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<String>,
+    /// Client ID of the OAuth app `tbl login` authorizes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// `ca_cert` resolved against the config directory
+    #[serde(skip)]
+    ca_path: Option<PathBuf>,
+}
+
+/// A token from the keyring, or none at all.
+fn is_kept_elsewhere(token: &Secret) -> bool {
+    token.is_transient() || token.expose().is_empty()
+}
+
+/// An account with nothing to write to the config file.
+fn is_unsaved(account: &Option<Account>) -> bool {
+    account.as_ref().is_none_or(|account| {
+        is_kept_elsewhere(&account.token)
+            && account.url.is_none()
+            && account.ca_cert.is_none()
+            && account.client_id.is_none()
+    })
+}
+
+/// A service repositories can be listed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    GitHub,
+    GitLab,
+    Gitea,
+}
+
+impl Provider {
+    pub const ALL: [Provider; 3] = [Provider::GitHub, Provider::GitLab, Provider::Gitea];
+
+    /// Name in the config and the API, e.g. `github`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::GitHub => "github",
+            Self::GitLab => "gitlab",
+            Self::Gitea => "gitea",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|provider| provider.name() == name)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::GitHub => "GitHub",
+            Self::GitLab => "GitLab",
+            Self::Gitea => "Gitea",
+        }
+    }
+
+    /// User name git sends with the token over HTTPS. Gitea takes any.
+    fn git_user(self) -> &'static str {
+        match self {
+            Self::GitHub => "x-access-token",
+            Self::GitLab | Self::Gitea => "oauth2",
+        }
+    }
+}
+
+impl ProvidersConfig {
+    /// The account of `provider`, if it has a token.
+    pub fn account(&self, provider: Provider) -> Option<&Account> {
+        self.entry(provider)
+            .filter(|account| !account.token.expose().is_empty())
+    }
+
+    /// The account of `provider`, with a token or not.
+    fn entry(&self, provider: Provider) -> Option<&Account> {
+        match provider {
+            Provider::GitHub => self.github.as_ref(),
+            Provider::GitLab => self.gitlab.as_ref(),
+            Provider::Gitea => self.gitea.as_ref(),
+        }
+    }
+
+    fn entry_mut(&mut self, provider: Provider) -> &mut Account {
+        match provider {
+            Provider::GitHub => self.github.get_or_insert_with(Account::default),
+            Provider::GitLab => self.gitlab.get_or_insert_with(Account::default),
+            Provider::Gitea => self.gitea.get_or_insert_with(Account::default),
+        }
+    }
+
+    /// Fill in the tokens `tbl login` stored in the keyring for providers
+    /// without one in the config, and find the `ca_cert` bundles.
+    pub fn resolve(mut self, config_dir: &Path) -> Result<Self> {
+        for provider in Provider::ALL {
+            if self.account(provider).is_none() {
+                if let Some(token) = vault::token_get(config_dir, provider.name()) {
+                    self.entry_mut(provider).token = token;
+                }
+            }
+            if let Some(account) = self.entry(provider).filter(|a| a.ca_cert.is_some()) {
+                let ca_cert = account.ca_cert.clone().unwrap_or_default();
+                let path = config_path(config_dir, &ca_cert)
+                    .with_context(|| format!("Invalid providers.{}.ca_cert", provider.name()))?;
+                self.entry_mut(provider).ca_path = Some(path);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Whether no provider is set up at all.
+    pub fn is_empty(&self) -> bool {
+        Provider::ALL
+            .into_iter()
+            .all(|provider| self.entry(provider).is_none())
+    }
+
+    /// Providers with a token, in a fixed order.
+    pub fn configured(&self) -> Vec<Provider> {
+        Provider::ALL
+            .into_iter()
+            .filter(|provider| self.account(*provider).is_some())
+            .collect()
+    }
+
+    pub fn redact_secrets(&mut self) {
+        for account in [&mut self.github, &mut self.gitlab, &mut self.gitea]
+            .into_iter()
+            .flatten()
+        {
+            if !account.token.expose().is_empty() {
+                account.token = Secret::new("<redacted>");
+            }
+        }
+    }
+
+    /// Let a git command reach `url` on a configured provider: with its
+    /// token, unless the URL carries credentials of its own, and trusting
+    /// its CA bundle.
+    pub fn authorize<'c>(&self, cmd: &'c mut Command, url: &str) -> &'c mut Command {
+        let bare = without_credentials(url);
+        let Some((provider, account)) = Provider::ALL.into_iter().find_map(|provider| {
+            let account = self.entry(provider)?;
+            let base = format!("{}/", web_url(provider, account));
+            bare.starts_with(&base).then_some((provider, account))
+        }) else {
+            return cmd;
+        };
+        let web = web_url(provider, account);
+        if !account.token.expose().is_empty() && bare == url {
+            let credentials = format!("{}:{}", provider.git_user(), account.token.expose());
+            git_config_env(
+                cmd,
+                &format!("http.{web}/.extraHeader"),
+                &format!("Authorization: Basic {}", BASE64.encode(credentials)),
+            );
+        }
+        if let Some(ca_path) = &account.ca_path {
+            git_config_env(
+                cmd,
+                &format!("http.{web}/.sslCAInfo"),
+                &ca_path.display().to_string(),
+            );
+        }
+        cmd
+    }
+}
+
+/// A repository to pick.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct Repo {
+    /// Owner and name, e.g. `acme/site` (`group/subgroup/site` on GitLab)
+    pub name: String,
+    pub description: Option<String>,
+    /// HTTPS URL to clone
+    pub clone_url: String,
+    pub ssh_url: Option<String>,
+    /// Page of the repository on the provider's site
+    pub web_url: String,
+    pub private: bool,
+    pub default_branch: Option<String>,
+    /// Last push or activity, as RFC 3339
+    pub updated_at: Option<String>,
+}
+
+/// An organization (a group on GitLab) to filter repositories by.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct Org {
+    /// Login of the organization, or the full path of the group
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// One page of repositories, most recently updated first.
+pub struct RepoPage {
+    pub repos: Vec<Repo>,
+    /// Number of the following page; none on the last
+    pub next_page: Option<u32>,
+}
+
+/// Repositories the token can see, or those of `org`.
+pub async fn repos(
+    provider: Provider,
+    account: &Account,
+    org: Option<&str>,
+    page: u32,
+    per_page: u32,
+) -> Result<RepoPage> {
+    let mut url = api_url(provider, account)?;
+    match (provider, org) {
+        (Provider::GitHub | Provider::Gitea, None) => push(&mut url, &["user", "repos"]),
+        (Provider::GitHub | Provider::Gitea, Some(org)) => push(&mut url, &["orgs", org, "repos"]),
+        (Provider::GitLab, None) => {
+            push(&mut url, &["projects"]);
+            url.query_pairs_mut().append_pair("membership", "true");
+        }
+        (Provider::GitLab, Some(group)) => {
+            push(&mut url, &["groups", group, "projects"]);
+            url.query_pairs_mut()
+                .append_pair("include_subgroups", "true");
+        }
+    }
+    match provider {
+        Provider::GitHub => {
+            url.query_pairs_mut().append_pair("sort", "updated");
+        }
+        Provider::GitLab => {
+            url.query_pairs_mut()
+                .append_pair("order_by", "last_activity_at");
+        }
+        // Gitea lists repositories by ID and has no choice of order
+        Provider::Gitea => {}
+    }
+    url.query_pairs_mut()
+        .append_pair("page", &page.to_string())
+        .append_pair(
+            match provider {
+                Provider::Gitea => "limit",
+                _ => "per_page",
+            },
+            &per_page.to_string(),
+        );
+
+    let response = get(provider, account, url).await?;
+    let next_page = match provider {
+        Provider::GitHub | Provider::Gitea => response
+            .headers()
+            .get(header::LINK)
+            .and_then(|link| link.to_str().ok())
+            .is_some_and(|link| link.contains(r#"rel="next""#))
+            .then_some(page + 1),
+        Provider::GitLab => response
+            .headers()
+            .get("x-next-page")
+            .and_then(|next| next.to_str().ok())
+            .and_then(|next| next.parse().ok()),
+    };
+    let repos = match provider {
+        Provider::GitHub => response
+            .json::<Vec<GitHubRepo>>()
+            .await
+            .context("Unexpected answer from GitHub")?
+            .into_iter()
+            .map(|repo| Repo {
+                name: repo.full_name,
+                description: repo.description,
+                clone_url: repo.clone_url,
+                ssh_url: repo.ssh_url,
+                web_url: repo.html_url,
+                private: repo.private,
+                default_branch: repo.default_branch,
+                updated_at: repo.pushed_at,
+            })
+            .collect(),
+        Provider::GitLab => response
+            .json::<Vec<GitLabProject>>()
+            .await
+            .context("Unexpected answer from GitLab")?
+            .into_iter()
+            .map(|project| Repo {
+                name: project.path_with_namespace,
+                description: project.description.filter(|d| !d.is_empty()),
+                clone_url: project.http_url_to_repo,
+                ssh_url: project.ssh_url_to_repo,
+                web_url: project.web_url,
+                private: project.visibility != "public",
+                default_branch: project.default_branch,
+                updated_at: project.last_activity_at,
+            })
+            .collect(),
+        Provider::Gitea => response
+            .json::<Vec<GiteaRepo>>()
+            .await
+            .context("Unexpected answer from Gitea")?
+            .into_iter()
+            .map(|repo| Repo {
+                name: repo.full_name,
+                description: repo.description.filter(|d| !d.is_empty()),
+                clone_url: repo.clone_url,
+                ssh_url: repo.ssh_url,
+                web_url: repo.html_url,
+                private: repo.private,
+                default_branch: repo.default_branch,
+                updated_at: repo.updated_at,
+            })
+            .collect(),
+    };
+    Ok(RepoPage { repos, next_page })
+}
+
+/// Organizations (groups) the token's user belongs to.
+pub async fn orgs(provider: Provider, account: &Account) -> Result<Vec<Org>> {
+    let mut url = api_url(provider, account)?;
+    match provider {
+        Provider::GitHub | Provider::Gitea => push(&mut url, &["user", "orgs"]),
+        Provider::GitLab => {
+            push(&mut url, &["groups"]);
+            url.query_pairs_mut().append_pair("min_access_level", "10");
+        }
+    }
+    url.query_pairs_mut().append_pair(
+        match provider {
+            Provider::Gitea => "limit",
+            _ => "per_page",
+        },
+        &MAX_PER_PAGE.to_string(),
+    );
+
+    let response = get(provider, account, url).await?;
+    let orgs = match provider {
+        Provider::GitHub => response
+            .json::<Vec<GitHubOrg>>()
+            .await
+            .context("Unexpected answer from GitHub")?
+            .into_iter()
+            .map(|org| Org {
+                name: org.login,
+                description: org.description.filter(|d| !d.is_empty()),
+            })
+            .collect(),
+        Provider::GitLab => response
+            .json::<Vec<GitLabGroup>>()
+            .await
+            .context("Unexpected answer from GitLab")?
+            .into_iter()
+            .map(|group| Org {
+                name: group.full_path,
+                description: group.description.filter(|d| !d.is_empty()),
+            })
+            .collect(),
+        Provider::Gitea => response
+            .json::<Vec<GiteaOrg>>()
+            .await
+            .context("Unexpected answer from Gitea")?
+            .into_iter()
+            .map(|org| Org {
+                name: org.username,
+                description: org.description.filter(|d| !d.is_empty()),
+            })
+            .collect(),
+    };
+    Ok(orgs)
+}
+
+/// GET `url` with the account's token, failing with the provider's message
+/// unless it succeeds.
+async fn get(provider: Provider, account: &Account, url: Url) -> Result<reqwest::Response> {
+    let client = client(provider, account)?;
+    let mut request = client.get(url).bearer_auth(account.token.expose());
+    if provider == Provider::GitHub {
+        request = request.header(header::ACCEPT, "application/vnd.github+json");
+    }
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {}", provider.label()))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = response
+        .json::<serde_json::Value>()
+        .await
+        .ok()
+        .and_then(|body| {
+            ["message", "error"]
+                .iter()
+                .find_map(|key| body.get(key)?.as_str().map(str::to_string))
+        })
+        .unwrap_or_default();
+    anyhow::bail!("{} answered {status}: {message}", provider.label())
+}
+
+/// Scopes `tbl login github` asks for: private repositories and the
+/// organizations they belong to.
+const DEVICE_SCOPES: &str = "repo read:org";
+
+/// Code the user enters on GitHub to let tbl act for them.
+#[derive(Deserialize)]
+pub struct DeviceCode {
+    device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    /// Seconds until the code expires
+    pub expires_in: u64,
+    /// Seconds to wait between polls
+    #[serde(default = "default_poll_interval")]
+    interval: u64,
+}
+
+fn default_poll_interval() -> u64 {
+    5
+}
+
+#[derive(Deserialize)]
+struct DeviceAnswer {
+    access_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+    interval: Option<u64>,
+}
+
+/// Start the OAuth device flow of the app `client_id` on GitHub.
+pub async fn device_code(account: &Account, client_id: &str) -> Result<DeviceCode> {
+    let body = serde_json::json!({ "client_id": client_id, "scope": DEVICE_SCOPES });
+    let answer = device_post(account, "login/device/code", body).await?;
+    if let Some(error) = answer.get("error").and_then(|e| e.as_str()) {
+        let description = answer
+            .get("error_description")
+            .and_then(|d| d.as_str())
+            .unwrap_or(error);
+        anyhow::bail!("GitHub refused the device login: {description}");
+    }
+    serde_json::from_value(answer).context("Unexpected answer from GitHub")
+}
+
+/// Poll GitHub until the user has entered `code`, and return the token it
+/// grants.
+pub async fn device_token(account: &Account, client_id: &str, code: &DeviceCode) -> Result<Secret> {
+    let mut interval = code.interval;
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        let body = serde_json::json!({
+            "client_id": client_id,
+            "device_code": code.device_code,
+            "grant_type": "urn:ietf:params:oauth:grant-type:device_code",
+        });
+        let answer: DeviceAnswer =
+            serde_json::from_value(device_post(account, "login/oauth/access_token", body).await?)
+                .context("Unexpected answer from GitHub")?;
+        if let Some(token) = answer.access_token {
+            return Ok(Secret::new(token));
+        }
+        match answer.error.as_deref() {
+            Some("authorization_pending") => {}
+            Some("slow_down") => interval = answer.interval.unwrap_or(interval + 5),
+            Some("expired_token") => anyhow::bail!("The code expired; run the login again"),
+            Some("access_denied") => anyhow::bail!("The login was cancelled on GitHub"),
+            error => anyhow::bail!(
+                "GitHub refused the device login: {}",
+                answer
+                    .error_description
+                    .or(error.map(str::to_string))
+                    .unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// POST `body` to an OAuth endpoint of GitHub at `path`. These answer
+/// errors in the body, mostly with 200 OK.
+async fn device_post(
+    account: &Account,
+    path: &str,
+    body: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let url = format!("{}/{path}", web_url(Provider::GitHub, account));
+    client(Provider::GitHub, account)?
+        .post(url)
+        .header(header::ACCEPT, "application/json")
+        .json(&body)
+        .send()
+        .await
+        .context("Failed to reach GitHub")?
+        .json()
+        .await
+        .context("Unexpected answer from GitHub")
+}
+
+/// HTTP client for the provider's API, trusting the account's CA bundle.
+fn client(provider: Provider, account: &Account) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent(concat!("tbl/", env!("CARGO_PKG_VERSION")));
+    if let Some(ca_path) = &account.ca_path {
+        let key = format!("providers.{}.ca_cert", provider.name());
+        let pem = fs::read(ca_path).with_context(|| format!("Failed to read {key}"))?;
+        let certs = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("{key} is not a PEM bundle"))?;
+        builder = builder.tls_certs_merge(certs);
+    }
+    Ok(builder.build()?)
+}
+
+/// Web address of the provider, without a trailing slash.
+pub fn web_url(provider: Provider, account: &Account) -> String {
+    let url = account.url.as_deref().unwrap_or(match provider {
+        Provider::GitHub => "https://github.com",
+        Provider::GitLab => "https://gitlab.com",
+        Provider::Gitea => "https://gitea.com",
+    });
+    url.trim_end_matches('/').to_string()
+}
+
+/// Root of the provider's REST API.
+fn api_url(provider: Provider, account: &Account) -> Result<Url> {
+    let web = web_url(provider, account);
+    let api = match provider {
+        Provider::GitHub if web == "https://github.com" => "https://api.github.com".to_string(),
+        Provider::GitHub => format!("{web}/api/v3"),
+        Provider::GitLab => format!("{web}/api/v4"),
+        Provider::Gitea => format!("{web}/api/v1"),
+    };
+    Url::parse(&api).with_context(|| format!("Invalid providers.{}.url", provider.name()))
+}
+
+/// Append `segments` to the path of `url`, each percent-encoded, so a
+/// GitLab group path like `acme/web` stays one segment.
+fn push(url: &mut Url, segments: &[&str]) {
+    if let Ok(mut path) = url.path_segments_mut() {
+        path.pop_if_empty().extend(segments);
+    }
+}
+
+/// `url` without a user or password before its host.
+fn without_credentials(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    format!("{scheme}://{host}{path}")
+}
+
+#[derive(Deserialize)]
+struct GitHubRepo {
+    full_name: String,
+    description: Option<String>,
+    clone_url: String,
+    ssh_url: Option<String>,
+    html_url: String,
+    #[serde(default)]
+    private: bool,
+    default_branch: Option<String>,
+    pushed_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubOrg {
+    login: String,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitLabProject {
+    path_with_namespace: String,
+    description: Option<String>,
+    http_url_to_repo: String,
+    ssh_url_to_repo: Option<String>,
+    web_url: String,
+    #[serde(default)]
+    visibility: String,
+    default_branch: Option<String>,
+    last_activity_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitLabGroup {
+    full_path: String,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GiteaRepo {
+    full_name: String,
+    description: Option<String>,
+    clone_url: String,
+    ssh_url: Option<String>,
+    html_url: String,
+    #[serde(default)]
+    private: bool,
+    default_branch: Option<String>,
+    updated_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GiteaOrg {
+    username: String,
+    description: Option<String>,
+}
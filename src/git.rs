@@ -0,0 +1,246 @@
+//! Git sources: the workspace is a checkout of the repository, cloned once
+//! and then fetched and reset to the remote's `HEAD`.
+
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::Result;
+
+use crate::{
+    mirror, repo_identity, run_git,
+    secret::redact_credentials,
+    source::{Context, SourceProvider},
+};
+
+/// Any URL git can clone; the provider of last resort.
+pub struct Git;
+
+impl SourceProvider for Git {
+    fn name(&self) -> &'static str {
+        "git"
+    }
+
+    fn handles(&self, _url: &str) -> bool {
+        true
+    }
+
+    /// Clone `url`, or fetch and reset the checkout when it already tracks
+    /// `url`, falling back to the mirrors of `url` in `git_mirrors`. A failed
+    /// fetch or reset leaves the existing checkout in place. A failed update
+    /// of the git cache only means fetching straight from `url`.
+    fn sync(&self, ctx: &Context, url: &str) -> Result<()> {
+        let web_dir = &ctx.web_dir;
+        let depth = ctx.settings.depth;
+        let mirror = if ctx.settings.cache && mirror::path(url).is_some() {
+            mirror::update(url, &ctx.settings.providers, ctx.job)
+                .inspect_err(|e| tracing::warn!("{e:#}; fetching without the git cache"))
+                .ok()
+        } else {
+            None
+        };
+
+        if self.matches(web_dir, url) {
+            // Another spelling of the same repository: fetch from the new one,
+            // which may differ in protocol or credentials
+            if remote_url(web_dir).as_deref() != Some(url) {
+                set_remote_url(web_dir, url)?;
+            }
+
+            if let Some(mirror) = &mirror {
+                if let Err(e) = mirror::link(web_dir, mirror) {
+                    tracing::warn!("{e:#}; fetching without the git cache");
+                }
+            }
+
+            with_mirrors(ctx, url, |remote| {
+                let mut fetch = Command::new("git");
+                ctx.settings.providers.authorize(&mut fetch, remote);
+                fetch.arg("-C").arg(web_dir).arg("fetch");
+                if depth > 0 {
+                    fetch.arg("--depth").arg(depth.to_string());
+                } else if web_dir.join(".git").join("shallow").exists() {
+                    fetch.arg("--unshallow");
+                }
+                if remote == url {
+                    fetch.arg("origin");
+                } else {
+                    // Into the branches of origin, which `reset` goes by
+                    fetch.arg(remote).arg("+refs/heads/*:refs/remotes/origin/*");
+                }
+                let fetch = run_git(ctx.step(&mut fetch, "fetching")?, "fetch")?;
+                if !fetch.status.success() {
+                    anyhow::bail!("git fetch failed with status {}", fetch.status);
+                }
+                Ok(())
+            })?;
+
+            let reset = run_git(
+                ctx.step(
+                    Command::new("git")
+                        .arg("-C")
+                        .arg(web_dir)
+                        .arg("reset")
+                        .arg("--hard")
+                        .arg("origin/HEAD"),
+                    "resetting",
+                )?,
+                "reset",
+            )?;
+
+            if !reset.status.success() {
+                anyhow::bail!("git reset failed with status {}", reset.status);
+            }
+
+            return Ok(());
+        }
+
+        // Fresh clone
+        with_mirrors(ctx, url, |remote| {
+            if web_dir.exists() {
+                fs::remove_dir_all(web_dir)?;
+            }
+
+            let mut clone = Command::new("git");
+            ctx.settings.providers.authorize(&mut clone, remote);
+            clone.arg("clone");
+            if let Some(mirror) = &mirror {
+                clone.arg("--reference").arg(mirror);
+            }
+            if depth > 0 {
+                clone.arg("--depth").arg(depth.to_string());
+            }
+            let clone = run_git(
+                ctx.step(clone.arg(remote).arg(web_dir), "cloning")?,
+                "clone",
+            );
+
+            let status = match clone {
+                Ok(output) => output.status,
+                Err(e) => {
+                    // Don't leave a half-written checkout behind
+                    let _ = fs::remove_dir_all(web_dir);
+                    return Err(e);
+                }
+            };
+            if !status.success() {
+                anyhow::bail!("git clone failed with status {status}");
+            }
+            if remote != url {
+                // Keep origin on the repository itself, so the next fetch
+                // tries it first again
+                set_remote_url(web_dir, url)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn installed_url(&self, web_dir: &Path) -> Option<String> {
+        if !web_dir.join(".git").exists() {
+            return None;
+        }
+        remote_url(web_dir)
+    }
+
+    /// The commit checked out.
+    fn version(&self, web_dir: &Path) -> Option<String> {
+        if !web_dir.join(".git").exists() {
+            return None;
+        }
+        let output = run_git(
+            Command::new("git")
+                .arg("-C")
+                .arg(web_dir)
+                .arg("rev-parse")
+                .arg("HEAD")
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null()),
+            "rev-parse",
+        )
+        .ok()?;
+
+        if !output.status.success() {
+            return None;
+        }
+
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    fn matches(&self, web_dir: &Path, url: &str) -> bool {
+        self.installed_url(web_dir)
+            .is_some_and(|remote| remote == url || repo_identity(&remote) == repo_identity(url))
+    }
+
+    fn uses_git(&self) -> bool {
+        true
+    }
+}
+
+/// Run `attempt` with `url`, then with each of its mirrors in turn until
+/// one succeeds, and note the mirror that did.
+fn with_mirrors(
+    ctx: &Context,
+    url: &str,
+    mut attempt: impl FnMut(&str) -> Result<()>,
+) -> Result<()> {
+    let mut result = attempt(url);
+    for mirror in ctx.settings.mirrors_of(url) {
+        let Err(e) = &result else {
+            break;
+        };
+        if ctx.cancelled() {
+            break;
+        }
+        ctx.warn(&format!(
+            "{e:#}; trying the mirror {}",
+            redact_credentials(mirror)
+        ));
+        result = attempt(mirror);
+        if result.is_ok() {
+            ctx.used_mirror(mirror);
+        }
+    }
+    result
+}
+
+/// Point the checkout's `origin` at `url`.
+fn set_remote_url(web_dir: &Path, url: &str) -> Result<()> {
+    let set_url = run_git(
+        Command::new("git")
+            .arg("-C")
+            .arg(web_dir)
+            .arg("remote")
+            .arg("set-url")
+            .arg("origin")
+            .arg(url),
+        "remote set-url",
+    )?;
+    if !set_url.status.success() {
+        anyhow::bail!("git remote set-url failed with status {}", set_url.status);
+    }
+    Ok(())
+}
+
+/// URL of the checkout's `origin`.
+fn remote_url(web_dir: &Path) -> Option<String> {
+    let output = run_git(
+        Command::new("git")
+            .arg("-C")
+            .arg(web_dir)
+            .arg("remote")
+            .arg("get-url")
+            .arg("origin")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+        "remote get-url",
+    )
+    .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,126 @@
+//! Commands run around each sync, from the `hooks` config section.
+//!
+//! `pre_update` runs before the content is fetched; if it fails, the sync
+//! does not happen. `post_update` runs after a successful sync, e.g. to
+//! regenerate an index or tell another tool; if it fails, the sync still
+//! counts. Both run through the shell in the workspace, with their output in
+//! the job log, and are killed when they outlive `timeout`.
+
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{parse_duration, run_command_within, secret::redact_credentials, source::Context};
+
+/// Time a hook may run unless `hooks.timeout` says otherwise.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// `hooks` config section.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HooksConfig {
+    /// Shell command run before each sync; a failure cancels the sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_update: Option<String>,
+    /// Shell command run after each successful sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_update: Option<String>,
+    /// Time limit for each hook, e.g. "30s" (default 5m; "0" disables it)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+}
+
+impl HooksConfig {
+    /// The time limit of each hook; `None` for no limit.
+    pub fn timeout(&self) -> Result<Option<Duration>> {
+        match &self.timeout {
+            Some(value) => {
+                let timeout = parse_duration(value).context("Invalid hooks.timeout")?;
+                Ok((!timeout.is_zero()).then_some(timeout))
+            }
+            None => Ok(Some(DEFAULT_TIMEOUT)),
+        }
+    }
+}
+
+/// Run `pre_update`, if set, before syncing `url` over content at `old`.
+pub fn pre_update(ctx: &Context, url: &str, old: Option<&str>) -> Result<()> {
+    let Some(command) = &ctx.settings.hooks.pre_update else {
+        return Ok(());
+    };
+    run(
+        ctx,
+        "pre_update",
+        command,
+        &[
+            ("TBL_GIT_URL", redact_credentials(url)),
+            ("TBL_OLD_COMMIT", old.unwrap_or_default().to_string()),
+        ],
+    )
+}
+
+/// Run `post_update`, if set, after a sync of `url` took the content from
+/// `old` to `new`.
+pub fn post_update(
+    ctx: &Context,
+    url: &str,
+    old: Option<&str>,
+    new: Option<&str>,
+    report: Option<&Path>,
+) -> Result<()> {
+    let Some(command) = &ctx.settings.hooks.post_update else {
+        return Ok(());
+    };
+    let changed = if old == new { "0" } else { "1" };
+    let mut env = vec![
+        ("TBL_GIT_URL", redact_credentials(url)),
+        ("TBL_OLD_COMMIT", old.unwrap_or_default().to_string()),
+        ("TBL_NEW_COMMIT", new.unwrap_or_default().to_string()),
+        ("TBL_CHANGED", changed.to_string()),
+    ];
+    if let Some(report) = report {
+        env.push(("TBL_SYNC_REPORT", report.display().to_string()));
+    }
+    run(ctx, "post_update", command, &env)
+}
+
+fn run(ctx: &Context, name: &str, command: &str, env: &[(&str, String)]) -> Result<()> {
+    let timeout = ctx.settings.hooks.timeout()?;
+    // The workspace, unless a first sync is yet to create it
+    let dir = if ctx.web_dir.is_dir() {
+        ctx.web_dir.as_path()
+    } else {
+        ctx.config_dir
+    };
+
+    let mut cmd = shell(command);
+    cmd.current_dir(dir)
+        .stdin(Stdio::null())
+        .env("TBL_WEB_DIR", &ctx.web_dir)
+        .envs(env.iter().map(|(key, value)| (key, value)));
+    ctx.log(&format!("running {name} hook"));
+    let what = format!("{name} hook");
+    let output = run_command_within(ctx.step(&mut cmd, &what)?, &what, timeout)?;
+    if !output.status.success() {
+        anyhow::bail!("{name} hook failed ({})", output.status);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
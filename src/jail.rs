@@ -0,0 +1,102 @@
+//! Confining file access to one directory.
+//!
+//! Paths from clients, upstreams, bundles, and served repositories are
+//! resolved through a [`Jail`] before anything is read or written: only
+//! plain relative paths are accepted, and once symlinks are followed the
+//! result must still be inside the root. A checkout whose `secrets` links to
+//! `/etc` or `~/.ssh` then serves nothing from there.
+
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// A directory that file access can't leave.
+#[derive(Debug, Clone)]
+pub struct Jail {
+    root: PathBuf,
+}
+
+impl Jail {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// `rel` below the root, or `None` if it would leave it: `..`, absolute
+    /// paths, drive prefixes, or a symlink on the way that points outside.
+    /// The path need not exist, so it can be used to create files.
+    pub fn path(&self, rel: impl AsRef<Path>) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for component in rel.as_ref().components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        self.resolves_inside(&path).then_some(path)
+    }
+
+    /// Read the file at `rel`; a path leaving the root is `InvalidInput`.
+    pub fn read(&self, rel: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+        fs::read(self.checked(rel.as_ref())?)
+    }
+
+    /// Read the text file at `rel`; a path leaving the root is `InvalidInput`.
+    pub fn read_to_string(&self, rel: impl AsRef<Path>) -> io::Result<String> {
+        fs::read_to_string(self.checked(rel.as_ref())?)
+    }
+
+    fn checked(&self, rel: &Path) -> io::Result<PathBuf> {
+        self.path(rel).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside {}", rel.display(), self.root.display()),
+            )
+        })
+    }
+
+    /// Whether `path`, lexically below the root, stays inside it once
+    /// symlinks are followed. The deepest part of it that exists decides;
+    /// a dangling symlink doesn't.
+    fn resolves_inside(&self, path: &Path) -> bool {
+        let Ok(root) = fs::canonicalize(&self.root) else {
+            // Nothing there yet, so nothing to follow
+            return true;
+        };
+        path.ancestors()
+            .find(|existing| fs::symlink_metadata(existing).is_ok())
+            .is_none_or(|existing| {
+                fs::canonicalize(existing).is_ok_and(|real| real.starts_with(&root))
+            })
+    }
+}
+
+/// Percent-decode a URL path, so it names the file it would be served from.
+/// `None` if it doesn't decode to UTF-8.
+pub fn decode_url_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
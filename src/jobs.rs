@@ -0,0 +1,442 @@
+//! Background jobs such as workspace syncs and clones.
+//!
+//! Each job is a row of the `jobs` table in the state database (`state.db`).
+//! The output of the commands it runs goes to `jobs/<id>.log` in the run dir
+//! while it runs and into the row once it finishes. Records are written
+//! through on every state change, so `tbl jobs` and `/api/v1/jobs` show the
+//! same thing and the outcome of a job is still there after the server stops.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use rand::RngCore;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{now_unix, state::StateDb};
+
+/// Records kept; older ones are removed together with their logs.
+const MAX_JOBS: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    /// Fetch and reset the current workspace
+    Sync,
+    /// Clone a repository into the workspace
+    Switch,
+    /// Fetch the full history of a shallow checkout
+    Unshallow,
+    /// Replace the workspace with a saved release
+    Rollback,
+    /// Clone or fetch a branch served under `/preview/`
+    Preview,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed)
+    }
+}
+
+/// A background job as stored in the `jobs` table.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct JobRecord {
+    /// Sorts in creation order
+    pub id: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    /// What the job works on, e.g. the git URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Unix timestamps (seconds)
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    /// Current step while running, e.g. `fetching`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<String>,
+    /// Outcome details on success, e.g. the checked out commit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Job records of one profile, newest first.
+pub struct JobStore {
+    db: Arc<StateDb>,
+    /// Output of the jobs still running; moved into the database once they finish
+    log_dir: PathBuf,
+    jobs: Mutex<Vec<JobRecord>>,
+    /// Most bytes the records and logs may take before old jobs are removed
+    max_bytes: Option<u64>,
+}
+
+impl JobStore {
+    /// Load the records from `db`. Jobs still queued or running belong to a
+    /// server that is gone, so they are marked failed.
+    pub fn open(db: Arc<StateDb>, log_dir: PathBuf) -> Result<Self> {
+        let mut jobs = select(&db.conn())?;
+        let store = Self {
+            db,
+            log_dir,
+            jobs: Mutex::new(Vec::new()),
+            max_bytes: None,
+        };
+        for job in &mut jobs {
+            if !job.state.is_finished() {
+                job.state = JobState::Failed;
+                job.finished_at = Some(now_unix());
+                job.progress = None;
+                job.error = Some("interrupted: tbl stopped before the job finished".to_string());
+                let _ = store.persist(job);
+                store.store_log(&job.id);
+            }
+        }
+        *store.jobs.lock().unwrap() = jobs;
+        Ok(store)
+    }
+
+    /// Remove the oldest finished jobs whenever the records and logs take
+    /// more than `max_bytes`.
+    pub fn with_limit(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes;
+        self.prune(&mut self.jobs.lock().unwrap());
+        self
+    }
+
+    /// Estimate of the memory the records hold, plus their logs, which are
+    /// read whole when shown.
+    pub fn memory_usage(&self) -> u64 {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().map(|job| self.job_bytes(job)).sum()
+    }
+
+    pub fn list(&self) -> Vec<JobRecord> {
+        self.jobs.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobRecord> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|job| job.id == id)
+            .cloned()
+    }
+
+    /// Output of the commands a job ran (empty if none), or `None` for an unknown job.
+    pub fn log(&self, id: &str) -> Option<String> {
+        self.get(id)?;
+        Some(job_log(&self.db, &self.log_dir, id))
+    }
+
+    /// Record a new queued job.
+    pub fn create(&self, kind: JobKind, target: Option<String>) -> Result<JobRecord> {
+        // Millisecond prefix, so IDs sort in creation order
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let mut buf = [0u8; 2];
+        rand::rngs::OsRng.fill_bytes(&mut buf);
+        let job = JobRecord {
+            id: format!("{millis:011x}{}", hex::encode(buf)),
+            kind,
+            state: JobState::Queued,
+            target,
+            created_at: now_unix(),
+            started_at: None,
+            finished_at: None,
+            progress: None,
+            result: None,
+            error: None,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        self.persist(&job)?;
+        jobs.insert(0, job.clone());
+        let keep = MAX_JOBS.min(jobs.len());
+        for old in jobs.drain(keep..) {
+            self.remove(&old.id);
+        }
+        self.prune(&mut jobs);
+        Ok(job)
+    }
+
+    /// Remove the oldest finished jobs until the rest fit in `max_bytes`.
+    /// The newest job is always kept.
+    fn prune(&self, jobs: &mut Vec<JobRecord>) {
+        let Some(max) = self.max_bytes else {
+            return;
+        };
+        let mut total: u64 = jobs.iter().map(|job| self.job_bytes(job)).sum();
+        let mut i = jobs.len();
+        while total > max && i > 1 {
+            i -= 1;
+            if jobs[i].state.is_finished() {
+                total -= self.job_bytes(&jobs[i]);
+                let old = jobs.remove(i);
+                self.remove(&old.id);
+            }
+        }
+    }
+
+    fn job_bytes(&self, job: &JobRecord) -> u64 {
+        let record = serde_json::to_vec(job).map_or(0, |json| json.len() as u64);
+        let log = match fs::metadata(self.log_path(&job.id)) {
+            Ok(meta) => meta.len(),
+            Err(_) => self
+                .db
+                .conn()
+                .query_row(
+                    "SELECT COALESCE(length(log), 0) FROM jobs WHERE id = ?1",
+                    params![job.id],
+                    |row| row.get(0),
+                )
+                .unwrap_or(0),
+        };
+        record + log
+    }
+
+    fn remove(&self, id: &str) {
+        if let Err(e) = self
+            .db
+            .conn()
+            .execute("DELETE FROM jobs WHERE id = ?1", params![id])
+        {
+            tracing::warn!("Failed to remove job {id}: {e}");
+        }
+        let _ = fs::remove_file(self.log_path(id));
+    }
+
+    /// Apply `f` to a job and write it back, returning the updated record.
+    pub fn update(&self, id: &str, f: impl FnOnce(&mut JobRecord)) -> Option<JobRecord> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter_mut().find(|job| job.id == id)?;
+        f(job);
+        if let Err(e) = self.persist(job) {
+            tracing::warn!("Failed to save job {id}: {e:#}");
+        }
+        Some(job.clone())
+    }
+
+    fn persist(&self, job: &JobRecord) -> Result<()> {
+        insert(&self.db.conn(), job)
+    }
+
+    /// Move a finished job's log file into the database.
+    fn store_log(&self, id: &str) {
+        let path = self.log_path(id);
+        let Ok(log) = fs::read_to_string(&path) else {
+            return;
+        };
+        match self
+            .db
+            .conn()
+            .execute("UPDATE jobs SET log = ?2 WHERE id = ?1", params![id, log])
+        {
+            Ok(_) => {
+                let _ = fs::remove_file(&path);
+            }
+            Err(e) => tracing::warn!("Failed to save the log of job {id}: {e}"),
+        }
+    }
+
+    fn log_path(&self, id: &str) -> PathBuf {
+        self.log_dir.join(format!("{id}.log"))
+    }
+}
+
+/// Write a job record, keeping the log already stored with it.
+pub fn insert(conn: &Connection, job: &JobRecord) -> Result<()> {
+    conn.execute(
+        "INSERT INTO jobs (id, kind, state, target, created_at, started_at, finished_at,
+                           progress, result, error)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+         ON CONFLICT (id) DO UPDATE SET
+             state = excluded.state, target = excluded.target,
+             started_at = excluded.started_at, finished_at = excluded.finished_at,
+             progress = excluded.progress, result = excluded.result, error = excluded.error",
+        params![
+            job.id,
+            label(job.kind),
+            label(job.state),
+            job.target,
+            job.created_at,
+            job.started_at,
+            job.finished_at,
+            job.progress,
+            job.result.as_ref().map(|result| result.to_string()),
+            job.error,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Job records in the database, newest first, without touching them.
+pub fn select(conn: &Connection) -> Result<Vec<JobRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, kind, state, target, created_at, started_at, finished_at,
+                progress, result, error
+         FROM jobs ORDER BY id DESC",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+            row.get(6)?,
+            row.get(7)?,
+            row.get::<_, Option<String>>(8)?,
+            row.get(9)?,
+        ))
+    })?;
+    let mut jobs = Vec::new();
+    for row in rows {
+        let (id, kind, state, target, created_at, started_at, finished_at, progress, result, error) =
+            row?;
+        jobs.push(JobRecord {
+            id,
+            kind: serde_json::from_value(serde_json::Value::String(kind))?,
+            state: serde_json::from_value(serde_json::Value::String(state))?,
+            target,
+            created_at,
+            started_at,
+            finished_at,
+            progress,
+            result: result.map(|json| serde_json::from_str(&json)).transpose()?,
+            error,
+        });
+    }
+    Ok(jobs)
+}
+
+/// Output of a job: its log file while it runs, then what the database holds.
+pub fn job_log(db: &StateDb, log_dir: &Path, id: &str) -> String {
+    if let Ok(log) = fs::read_to_string(log_dir.join(format!("{id}.log"))) {
+        return log;
+    }
+    db.conn()
+        .query_row("SELECT log FROM jobs WHERE id = ?1", params![id], |row| {
+            row.get::<_, Option<String>>(0)
+        })
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Job records left as JSON files by earlier versions, for the import into
+/// the database.
+pub fn load_legacy_jobs(dir: &Path) -> Vec<JobRecord> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| serde_json::from_slice(&fs::read(path).ok()?).ok())
+        .collect()
+}
+
+/// Serialized (lowercase) name of a job kind or state.
+fn label(value: impl Serialize) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Handle passed to the code doing a job's work, for progress and output.
+#[derive(Clone)]
+pub struct JobHandle {
+    store: Arc<JobStore>,
+    id: String,
+}
+
+impl JobHandle {
+    pub fn new(store: Arc<JobStore>, id: String) -> Self {
+        Self { store, id }
+    }
+
+    pub fn start(&self) -> Option<JobRecord> {
+        self.store.update(&self.id, |job| {
+            job.state = JobState::Running;
+            job.started_at = Some(now_unix());
+        })
+    }
+
+    pub fn finish(&self, outcome: &Result<serde_json::Value>) -> Option<JobRecord> {
+        let record = self.store.update(&self.id, |job| {
+            job.finished_at = Some(now_unix());
+            job.progress = None;
+            match outcome {
+                Ok(result) => {
+                    job.state = JobState::Succeeded;
+                    job.result = Some(result.clone()).filter(|r| !r.is_null());
+                }
+                Err(e) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(format!("{e:#}"));
+                }
+            }
+        });
+        self.store.store_log(&self.id);
+        // Its log has grown since the job was created
+        self.store.prune(&mut self.store.jobs.lock().unwrap());
+        record
+    }
+
+    pub fn progress(&self, step: &str) {
+        self.store.update(&self.id, |job| {
+            job.progress = Some(step.to_string());
+        });
+    }
+
+    /// Append a line of tbl's own to the job log, e.g. a retried download.
+    pub fn log(&self, line: &str) {
+        let written = fs::create_dir_all(&self.store.log_dir).and_then(|()| {
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.store.log_path(&self.id))?;
+            writeln!(file, "{line}")
+        });
+        if let Err(e) = written {
+            tracing::warn!("Failed to write the log of job {}: {e}", self.id);
+        }
+    }
+
+    /// Append-mode handle on the job log, for a child process's output.
+    pub fn output(&self) -> Result<Stdio> {
+        fs::create_dir_all(&self.store.log_dir)?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.store.log_path(&self.id))?;
+        Ok(Stdio::from(file))
+    }
+}
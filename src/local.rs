@@ -0,0 +1,144 @@
+//! Local directory sources: a plain directory on this machine, not a git
+//! repository, copied into the workspace.
+//!
+//! Updates copy only files whose size or modification time changed and
+//! delete those gone from the directory. Each file is copied under a
+//! temporary name and then moved in place, so a page never reads one half
+//! copied.
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+
+use crate::source::{self, Context, Record, SourceProvider};
+
+/// Directories on this machine that are not git repositories.
+pub struct LocalDir;
+
+impl SourceProvider for LocalDir {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn handles(&self, url: &str) -> bool {
+        local_dir(url).is_some()
+    }
+
+    fn sync(&self, ctx: &Context, url: &str) -> Result<()> {
+        let dir = local_dir(url).with_context(|| format!("{url} is not a directory"))?;
+        let web_dir = &ctx.web_dir;
+        if web_dir.starts_with(&dir) {
+            anyhow::bail!("{} contains the workspace itself", dir.display());
+        }
+        source::claim_workspace(web_dir, self.name(), url)?;
+
+        ctx.progress("scanning");
+        let mut files = Vec::new();
+        list_files(&dir, Path::new(""), &mut files)?;
+        let changed: Vec<&PathBuf> = files
+            .iter()
+            .filter(|rel| !same_file(&dir.join(rel), &web_dir.join(rel)))
+            .collect();
+        for (i, rel) in changed.iter().enumerate() {
+            if ctx.cancelled() {
+                anyhow::bail!("copy cancelled: tbl is shutting down");
+            }
+            ctx.progress(&format!("copying {} of {}", i + 1, changed.len()));
+            copy_file(&dir.join(rel), &web_dir.join(rel))?;
+        }
+
+        ctx.progress("removing");
+        let wanted: BTreeSet<&PathBuf> = files.iter().collect();
+        let mut present = Vec::new();
+        list_files(web_dir, Path::new(""), &mut present)?;
+        for rel in present.iter().filter(|rel| !wanted.contains(rel)) {
+            let file = web_dir.join(rel);
+            fs::remove_file(&file)
+                .with_context(|| format!("Failed to remove {}", file.display()))?;
+            source::remove_empty_parents(web_dir, &file);
+        }
+
+        Record {
+            url: url.to_string(),
+            version: source::tree_digest(web_dir)?,
+        }
+        .save(web_dir, self.name())
+    }
+
+    fn installed_url(&self, web_dir: &Path) -> Option<String> {
+        Record::load(web_dir, self.name()).map(|record| record.url)
+    }
+
+    /// Digest of the files' names, sizes, and modification times.
+    fn version(&self, web_dir: &Path) -> Option<String> {
+        Record::load(web_dir, self.name()).map(|record| record.version)
+    }
+}
+
+/// The directory `url` names, when it is an absolute path or `file://` URL
+/// of an existing directory that git would not clone.
+fn local_dir(url: &str) -> Option<PathBuf> {
+    let url = url.trim();
+    let path = Path::new(url.strip_prefix("file://").unwrap_or(url));
+    if !path.is_absolute() || !path.is_dir() {
+        return None;
+    }
+    let is_repo = path.join(".git").exists()
+        || (path.join("HEAD").is_file() && path.join("objects").is_dir());
+    (!is_repo).then(|| path.to_path_buf())
+}
+
+/// Paths, relative to `root`, of the files below `root.join(rel)`. Links to
+/// directories are skipped, as they may loop.
+fn list_files(root: &Path, rel: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let dir = root.join(rel);
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let rel = rel.join(entry.file_name());
+        let Ok(meta) = fs::metadata(entry.path()) else {
+            continue;
+        };
+        if meta.is_dir() {
+            if !entry.file_type()?.is_symlink() {
+                list_files(root, &rel, files)?;
+            }
+        } else if meta.is_file() {
+            files.push(rel);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `copy` has the size and modification time of `original`.
+fn same_file(original: &Path, copy: &Path) -> bool {
+    match (fs::metadata(original), fs::metadata(copy)) {
+        (Ok(original), Ok(copy)) => {
+            original.len() == copy.len() && original.modified().ok() == copy.modified().ok()
+        }
+        _ => false,
+    }
+}
+
+/// Copy `from` to `to` under a temporary name and move it in place, keeping
+/// the modification time so the next sync sees it as unchanged.
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = to.with_file_name(format!(
+        "{}.tbl-copy",
+        to.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    fs::copy(from, &partial).with_context(|| format!("Failed to copy {}", from.display()))?;
+    if let Ok(modified) = fs::metadata(from).and_then(|meta| meta.modified()) {
+        let _ = fs::File::options()
+            .write(true)
+            .open(&partial)
+            .and_then(|file| file.set_modified(modified));
+    }
+    fs::rename(&partial, to).with_context(|| format!("Failed to write {}", to.display()))
+}
@@ -1,29 +1,112 @@
 use anyhow::{Context, Result};
+use appmeta::AppMeta;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::handler::Handler;
 use axum::{
-    extract::{Form, Query, State},
-    http::{header, HeaderMap, StatusCode},
+    extract::{ConnectInfo, Form, FromRequestParts, OriginalUri, Path as UrlPath, Query, State},
+    http::{header, request::Parts, HeaderMap, Method, StatusCode},
     response::{Html, IntoResponse, Redirect, Response},
-    routing::{get, post},
+    routing::get,
     Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
-use clap::Parser;
-use rand::RngCore;
+use capability::Capability;
+use catalog::{CatalogPin, Channel};
+use chrono::Local;
+use clap::{Parser, Subcommand, ValueEnum};
+use connections::Connections;
+use cron::CronSchedule;
+use jail::Jail;
+use jobs::{JobHandle, JobKind, JobRecord, JobStore};
+use metered::Metered;
+use minijinja::{context, AutoEscape, Environment};
+use proxy::TrustedProxies;
+use rand::{Rng, RngCore};
+use sandbox::Sandbox;
+use schedule::Schedule;
+use search::SearchIndex;
+use secret::{redact_credentials, Secret};
 use serde::{Deserialize, Serialize};
+use state::{SessionInfo, StateDb};
 use std::{
-    fs,
-    future::IntoFuture,
-    io::{Read, Write},
-    net::{SocketAddr, TcpStream},
-    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt, fs, io,
+    io::{IsTerminal, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
     process::{Command, Stdio},
-    sync::Arc,
-    time::Duration,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use timings::Timings;
+use tokio::sync::{broadcast, oneshot};
+use tower::ServiceExt;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    services::ServeDir,
+    trace::TraceLayer,
 };
-use tokio::sync::oneshot;
-use tokio::net::TcpListener;
-use tower_http::services::ServeDir;
+use utoipa::ToSchema;
+
+mod activation;
+mod api;
+mod appmeta;
+mod archive;
+mod backup;
+mod bench;
+mod bundle;
+mod capability;
+mod catalog;
+mod connections;
+mod container;
+mod cron;
+mod discovery;
+mod forge;
+mod git;
+mod hooks;
+mod jail;
+mod jobs;
+mod local;
+mod manifest;
+mod memory;
+mod metered;
+mod mirror;
+mod notify;
+mod pause;
+mod peer;
+mod preview;
+mod privileges;
+mod proxy;
+mod quota;
+mod releases;
+mod requires;
+mod s3;
+mod sandbox;
+mod schedule;
+mod search;
+mod secret;
+mod semver;
+mod sendfile;
+mod sftp;
+mod shortcut;
+mod source;
+mod state;
+mod timefmt;
+mod timings;
+mod token;
+#[cfg(all(feature = "tray", target_os = "linux"))]
+mod tray;
+mod uptime;
+mod vault;
 
 // =============================================================================
 // CLI Arguments
@@ -32,10 +115,17 @@ use tower_http::services::ServeDir;
 #[derive(Parser, Debug)]
 #[command(name = "tbl", version, about = "Tiny self-bootstrapping web launcher")]
 struct Cli {
-    /// Git URL of the web app to serve
-    #[arg(long)]
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Git URL of the web app to serve, or another source (archive, s3://, sftp://)
+    #[arg(long, visible_alias = "source")]
     git_url: Option<String>,
 
+    /// If tbl is already running with another --git-url, switch it to this one
+    #[arg(long, conflicts_with = "stop")]
+    take_over: bool,
+
     /// Address to bind to (e.g. 127.0.0.1:1234)
     /// The port is auto-detected starting from the specified value.
     #[arg(long)]
@@ -55,15 +145,498 @@ struct Cli {
 
     /// HTTP Basic auth password
     #[arg(long)]
-    basic_pass: Option<String>,
+    basic_pass: Option<Secret>,
+
+    /// Token other tbl instances sync from this one with (tbl:// sources)
+    #[arg(long)]
+    peer_token: Option<Secret>,
+
+    /// Announce this server on the local network (mDNS) for `tbl clone-from-peer`
+    #[arg(long)]
+    announce: bool,
+
+    /// URL or path of an app catalog to pick apps from (JSON index)
+    #[arg(long, value_name = "URL")]
+    catalog_url: Option<String>,
 
     /// Do not auto-open the browser
     #[arg(long)]
     no_browser: bool,
 
+    /// Print how long each phase of starting the server took
+    #[arg(long)]
+    timings: bool,
+
     /// Stop a running tbl server
     #[arg(long)]
     stop: bool,
+
+    /// With --stop: kill the process if the shutdown request fails
+    #[arg(long, requires = "stop")]
+    force: bool,
+
+    /// With --stop: close open connections at once instead of letting
+    /// requests finish
+    #[arg(long, requires = "stop")]
+    now: bool,
+
+    /// With --stop: stop after this long instead, e.g. "8h"
+    #[arg(long, requires = "stop", conflicts_with_all = ["at", "force"])]
+    after: Option<String>,
+
+    /// With --stop: stop at this local time instead, e.g. "18:00"; tomorrow
+    /// if it has passed
+    #[arg(long, requires = "stop", conflicts_with = "force")]
+    at: Option<String>,
+
+    /// With --stop: call off a stop scheduled with --after or --at
+    #[arg(long, requires = "stop", conflicts_with_all = ["now", "after", "at", "force"])]
+    cancel: bool,
+
+    /// Port to serve on instead of the one in --addr: a number, 0 for one
+    /// picked by the OS, or "random" for one from --port-range.
+    /// With --stop: stop the server on this port, whichever profile runs it
+    #[arg(long, conflicts_with = "all")]
+    port: Option<PortSpec>,
+
+    /// Ports "random" picks from (default 49152-65535)
+    #[arg(long, value_name = "FIRST-LAST")]
+    port_range: Option<String>,
+
+    /// Times to retry a busy port with backoff before taking the next one (default 4)
+    #[arg(long, value_name = "N")]
+    bind_retries: Option<usize>,
+
+    /// Open connections the server accepts in all (default 512; 0 for no limit)
+    #[arg(long, value_name = "N")]
+    max_connections: Option<usize>,
+
+    /// Open connections the server accepts from one address (default 64; 0 for no limit)
+    #[arg(long, value_name = "N")]
+    max_connections_per_ip: Option<usize>,
+
+    /// Serve everything below this path, e.g. /tbl behind a reverse proxy
+    #[arg(long, value_name = "PATH")]
+    base_path: Option<String>,
+
+    /// Proxies whose X-Forwarded-* and Forwarded headers are believed, as
+    /// comma-separated CIDRs (default: loopback)
+    #[arg(long, value_name = "CIDRS", value_delimiter = ',')]
+    trusted_proxies: Option<Vec<String>>,
+
+    /// With --stop: stop the servers of all profiles
+    #[arg(long, requires = "stop")]
+    all: bool,
+
+    /// Named profile with its own config, workspace, and server (env: TBL_PROFILE)
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Use the system-wide install in /etc/tbl and /var/lib/tbl instead of
+    /// the user's own (env: TBL_SYSTEM)
+    #[arg(long, global = true)]
+    system: bool,
+
+    /// Show times in UTC instead of local time (env: TBL_UTC)
+    #[arg(long, global = true)]
+    utc: bool,
+
+    /// Show a system tray icon for the server, starting it if needed
+    /// (needs a build with the `tray` feature)
+    #[arg(long, conflicts_with = "stop")]
+    tray: bool,
+
+    /// Supervise the daemon and restart it with backoff if it crashes
+    #[arg(long)]
+    watchdog: bool,
+
+    /// Shut down after this long without requests (e.g. 30m, 2h; 0 disables)
+    #[arg(long, value_name = "DURATION")]
+    idle_timeout: Option<String>,
+
+    /// Serve only during these hours, e.g. "08:00-18:00 Mon-Fri"
+    #[arg(long, value_name = "SCHEDULE")]
+    serve_hours: Option<String>,
+
+    /// What to do outside serving hours
+    #[arg(long, value_enum)]
+    outside_hours: Option<OutsideHours>,
+
+    /// Sync at the times of this cron expression, e.g. "0 6 * * *"
+    #[arg(long, value_name = "CRON")]
+    sync_schedule: Option<String>,
+
+    /// Serve the existing checkout immediately and update it in the background
+    #[arg(long)]
+    lazy_sync: bool,
+
+    /// Defer automatic syncs on a metered connection (default auto: ask the OS)
+    #[arg(long, value_enum, value_name = "WHEN", num_args = 0..=1, default_missing_value = "yes")]
+    metered: Option<Metered>,
+
+    /// Kill a git clone or fetch that runs longer than this (default 10m; 0 disables)
+    #[arg(long, value_name = "DURATION")]
+    git_timeout: Option<String>,
+
+    /// Proxy for git over HTTP(S), e.g. http://proxy.corp:3128
+    #[arg(long, value_name = "URL")]
+    git_proxy: Option<String>,
+
+    /// Commits of history to fetch (default 1; 0 fetches the full history)
+    #[arg(long, value_name = "N")]
+    clone_depth: Option<u32>,
+
+    /// Share one mirror of each repository across profiles (~/.cache/tbl/git)
+    #[arg(long)]
+    git_cache: bool,
+
+    /// Keep this many releases of the content for `tbl rollback` (default 0)
+    #[arg(long, value_name = "N")]
+    keep_releases: Option<usize>,
+
+    /// Serve every branch of the repository under /preview/<branch>/
+    #[arg(long)]
+    previews: bool,
+
+    /// Who may open the served app under /web
+    #[arg(long, value_enum)]
+    access: Option<Access>,
+
+    /// Serve /web without sign-in (same as --access public); the API stays protected
+    #[arg(long, conflicts_with = "access")]
+    public_web: bool,
+
+    /// What clients on other machines need on top of what local ones do
+    #[arg(long, value_enum)]
+    remote_auth: Option<RemoteAuth>,
+
+    /// Optional subsystems to turn off for served apps, comma-separated:
+    /// kv, files, events, search
+    #[arg(long, value_name = "CAPS", value_delimiter = ',', value_enum)]
+    disable: Option<Vec<Capability>>,
+
+    /// Serve as this user once the port is bound, when started as root with --system
+    #[arg(long, value_name = "NAME")]
+    user: Option<String>,
+
+    /// Confine the daemon with Landlock and seccomp (Linux)
+    #[arg(long, value_enum, value_name = "MODE")]
+    sandbox: Option<Sandbox>,
+
+    /// Serve in the foreground for a container: no browser, no config file,
+    /// logs on stdout, port from PORT (env: TBL_CONTAINER)
+    #[arg(long, conflicts_with_all = ["stop", "tray", "watchdog"])]
+    container: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Repoint the workspace to a previously used repository
+    Switch {
+        /// History index or git URL (lists the history when omitted)
+        target: Option<String>,
+    },
+    /// Show whether a server is running and the last crash, if any
+    Status {
+        /// Show the server on this port, whichever profile runs it
+        #[arg(long, conflicts_with = "all")]
+        port: Option<u16>,
+        /// List the servers of all profiles
+        #[arg(long)]
+        all: bool,
+        /// Also print the login URL, including the auth token
+        #[arg(long, conflicts_with = "all")]
+        reveal: bool,
+        /// Also list the files the last sync added, removed, or changed
+        #[arg(short, long, conflicts_with = "all")]
+        verbose: bool,
+    },
+    /// Check the environment and configuration for common problems
+    Doctor,
+    /// Let this tbl binary bind ports below 1024 without root (Linux; run with sudo)
+    Setcap {
+        /// Take the capability away again
+        #[arg(long)]
+        remove: bool,
+    },
+    /// List recent background jobs, or show one with its output
+    Jobs {
+        /// Job ID (lists recent jobs when omitted)
+        id: Option<String>,
+    },
+    /// Fetch the latest content for the current profile, or every profile
+    Sync {
+        /// Sync every profile that has a git URL
+        #[arg(long, conflicts_with = "once")]
+        all: bool,
+        /// Maximum number of apps synced at the same time
+        #[arg(long, value_name = "N")]
+        jobs: Option<usize>,
+        /// Give up on an app after this long (e.g. 90s, 5m)
+        #[arg(long, value_name = "DURATION")]
+        timeout: Option<String>,
+        /// Sync in this process alone, never through a running server, with
+        /// the settings a server would use; repeats on sync_schedule unless
+        /// --exit (for an init or sidecar container)
+        #[arg(long)]
+        once: bool,
+        /// With --once: exit after the first sync, failing if it failed
+        #[arg(long, requires = "once")]
+        exit: bool,
+    },
+    /// List tbl instances on the local network, or sync from one of them
+    CloneFromPeer {
+        /// Instance name or number (lists the instances when omitted)
+        name: Option<String>,
+        /// The instance's peer_token, when it asks for one
+        #[arg(long)]
+        token: Option<Secret>,
+        /// How long to wait for instances to answer
+        #[arg(long, value_name = "DURATION", default_value = "2s")]
+        wait: String,
+    },
+    /// Pack the served app into a signed .tblapp file for offline installs
+    Bundle {
+        /// File to write (default: ./<app>.tblapp)
+        output: Option<PathBuf>,
+    },
+    /// Serve the app in a .tblapp file made by `tbl bundle`
+    Install {
+        /// The .tblapp file, or its http(s) URL
+        file: String,
+        /// Trust the key the bundle is signed with from now on
+        #[arg(long)]
+        trust: bool,
+    },
+    /// List or install the apps of the configured catalog
+    Catalog {
+        #[command(subcommand)]
+        command: CatalogCommands,
+    },
+    /// Move the catalog app to the newest release its pin allows
+    Upgrade {
+        /// Only show whether a newer release is available
+        #[arg(long)]
+        check: bool,
+        /// Trust the key of a bundle the release comes as
+        #[arg(long)]
+        trust: bool,
+    },
+    /// Fetch the full history of the checkout and keep it on later syncs
+    Unshallow,
+    /// Measure how fast the server serves the app: requests per second and
+    /// latency percentiles
+    Bench {
+        /// Connections sending requests at the same time
+        #[arg(long, value_name = "N")]
+        concurrency: Option<usize>,
+        /// How long to send requests (e.g. 10s, 1m)
+        #[arg(long, value_name = "DURATION")]
+        duration: Option<String>,
+        /// Path below /web/ to request, repeatable (default: every file of
+        /// the app)
+        #[arg(long = "path", value_name = "PATH")]
+        paths: Vec<String>,
+    },
+    /// Stop automatic syncs (scheduled and at startup) until `tbl resume`
+    Pause {
+        /// Why, shown by `tbl status`
+        reason: Option<String>,
+        /// Resume by itself after this long (e.g. 2h)
+        #[arg(long = "for", value_name = "DURATION")]
+        duration: Option<String>,
+    },
+    /// Let automatic syncs run again
+    Resume,
+    /// List the releases kept for rollback (see --keep-releases)
+    Releases,
+    /// Serve a kept release until the next sync
+    Rollback {
+        /// Release ID, or the start of its commit
+        release: String,
+    },
+    /// Manage HTTP Basic auth credentials
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommands,
+    },
+    /// Sign in to a repository provider and keep its token in the OS keyring
+    Login {
+        /// Provider to sign in to (github)
+        provider: String,
+        /// Client ID of the OAuth app to authorize (defaults to
+        /// providers.github.client_id)
+        #[arg(long)]
+        client_id: Option<String>,
+    },
+    /// Remove the token `tbl login` stored for a provider
+    Logout {
+        /// Provider to sign out of (github, gitlab, or gitea)
+        provider: String,
+    },
+    /// Manage the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Pack the config of every profile into a .tar.gz for another machine
+    ExportConfig {
+        /// Bundle to write, e.g. tbl-config.tar.gz
+        path: PathBuf,
+        /// Include the TLS certificates and keys the profiles use
+        #[arg(long)]
+        tls: bool,
+    },
+    /// Unpack a bundle written by `tbl export-config`
+    ImportConfig {
+        /// Bundle to read
+        path: PathBuf,
+        /// Overwrite files that already exist
+        #[arg(long)]
+        force: bool,
+    },
+    /// Back up or restore the profile's state, config, and stored files
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommands,
+    },
+    /// Export, import, or reset the data apps keep in the KV store
+    Kv {
+        #[command(subcommand)]
+        command: KvCommands,
+    },
+    /// Rotate the key that signs access tokens, or sign a browser out
+    Token {
+        #[command(subcommand)]
+        command: TokenCommands,
+    },
+    /// Open the app in the browser, starting the server if needed
+    Open {
+        /// Use a window without tabs or address bar (Chromium-based browsers)
+        #[arg(long)]
+        app_mode: bool,
+    },
+    /// Manage desktop shortcuts that open the app
+    Shortcut {
+        #[command(subcommand)]
+        command: ShortcutCommands,
+    },
+    /// Entry point of the container image: serve with --container, as init
+    /// when PID 1
+    DockerEntrypoint {
+        /// Options passed on to tbl, e.g. --git-url
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CatalogCommands {
+    /// List the apps of the catalog
+    List,
+    /// Serve an app of the catalog here
+    Install {
+        /// Name of the app, as listed
+        name: String,
+        /// Pin to releases meeting this constraint (e.g. ^2.1), for `tbl upgrade`
+        #[arg(long, value_name = "REQ")]
+        version: Option<String>,
+        /// Follow this channel of releases
+        #[arg(long, value_enum)]
+        channel: Option<Channel>,
+        /// Trust the key of a bundle the app comes as
+        #[arg(long)]
+        trust: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuthCommands {
+    /// Prompt for a Basic auth password and store its argon2 hash
+    SetPassword {
+        /// Basic auth username (defaults to the configured one)
+        #[arg(long)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommands {
+    /// Encrypt the config into config.enc (or change its passphrase)
+    Encrypt {
+        /// Also store the passphrase in the OS keyring
+        #[arg(long)]
+        keyring: bool,
+    },
+    /// Turn config.enc back into a plain config.json
+    Decrypt,
+    /// Show each effective setting and where it comes from
+    Explain,
+}
+
+#[derive(Subcommand, Debug)]
+enum BackupCommands {
+    /// Write a snapshot of the state database, config, and stored files
+    Create {
+        /// Archive to write (default: backups/tbl-backup-<time>.tar.gz in the
+        /// config dir)
+        path: Option<PathBuf>,
+        /// Include the checkout (web/)
+        #[arg(long)]
+        web: bool,
+    },
+    /// Replace the profile's state with a backup; the server must be stopped
+    Restore {
+        /// Archive to read, or the name of one in the config dir's backups/
+        path: PathBuf,
+    },
+    /// List the backups in the config dir
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum KvCommands {
+    /// Write namespaces with their keys and values as JSON
+    Export {
+        /// Namespaces to export (default: all)
+        namespaces: Vec<String>,
+        /// File to write (default: standard output)
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Store the keys of a file written by `tbl kv export`
+    Import {
+        /// File to read, or - for standard input
+        path: PathBuf,
+        /// Remove the other keys of the imported namespaces first
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Remove every key of a namespace
+    Reset { namespace: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum TokenCommands {
+    /// Sign tokens with a new key; those of the old key work for a while longer
+    Rotate {
+        /// How long tokens of the old key keep working, e.g. "1d"; "0" signs
+        /// every browser out at once
+        #[arg(long, default_value = "7d")]
+        grace: String,
+    },
+    /// Revoke the token of a session, as listed by `/api/v1/sessions`
+    Revoke { session: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ShortcutCommands {
+    /// Add a launcher to the desktop's app menu that runs `tbl open --app-mode`
+    Create {
+        /// Shortcut name (defaults to the app's title)
+        #[arg(long)]
+        name: Option<String>,
+    },
 }
 
 // =============================================================================
@@ -74,22 +647,326 @@ struct Cli {
 struct TblConfig {
     git_url: Option<String>,
     addr: Option<String>,
+    /// Ports a `random` port in `addr` is picked from, e.g. "49152-65535"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port_range: Option<String>,
+    /// Times a busy port is tried again before the next one is taken
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bind_retries: Option<usize>,
+    /// Open connections accepted in all; 0 for no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+    /// Open connections accepted from one address; 0 for no limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_connections_per_ip: Option<usize>,
+    /// Threads running the server's tasks; one per CPU core when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    worker_threads: Option<usize>,
+    /// Threads for blocking work such as file reads and git
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_blocking_threads: Option<usize>,
+    /// Flush pipelined HTTP/1 responses together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http1_pipeline_flush: Option<bool>,
+    /// Largest HTTP/1 read and write buffer of a connection, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http1_max_buf_size: Option<usize>,
+    /// Path all of tbl's URLs live below, e.g. "/tbl"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base_path: Option<String>,
+    /// Addresses and CIDRs of reverse proxies whose forwarding headers are
+    /// believed; loopback when unset, nothing when empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trusted_proxies: Option<Vec<String>>,
     tls_cert: Option<String>,
     tls_key: Option<String>,
     basic_user: Option<String>,
-    basic_pass: Option<String>,
+    /// Plain text, or an argon2 hash written by `tbl auth set-password`
+    basic_pass: Option<Secret>,
+    /// Bearer token that lets other tbl instances sync from this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peer_token: Option<Secret>,
+    /// Answer mDNS queries for tbl instances on the local network
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    announce: Option<bool>,
+    /// JSON index of curated apps, for `tbl catalog` and the setup page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    catalog_url: Option<String>,
+    /// The catalog app the workspace serves and the releases it may move to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    catalog_app: Option<CatalogPin>,
+    /// `repo_identity` of `git_url`, the same for all spellings of the URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    repo_id: Option<String>,
+    /// Branch the remote's HEAD pointed at when `git_url` was last fetched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_branch: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<HistoryEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    branding: Option<Branding>,
+    /// One line about the app for the directory page listing all profiles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// Restart the daemon when it exits abnormally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    watchdog: Option<bool>,
+    /// Shut down after this long without requests, e.g. "30m"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idle_timeout: Option<String>,
+    /// Serving windows in local time, e.g. "08:00-18:00 Mon-Fri"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    serve_hours: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    outside_hours: Option<OutsideHours>,
+    /// Cron expression in local time for syncs while running, e.g. "0 6 * * *"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sync_schedule: Option<String>,
+    /// Start serving the existing checkout before fetching updates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lazy_sync: Option<bool>,
+    /// Whether the connection is metered, so automatic syncs wait
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metered: Option<Metered>,
+    /// Time limit for a single git command, e.g. "10m"; "0" disables it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    git_timeout: Option<String>,
+    /// Proxy git reaches HTTP(S) remotes through, e.g. "http://proxy.corp:3128"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    git_proxy: Option<String>,
+    /// Equivalent URLs of git repositories, by the repository's own URL,
+    /// tried in order when cloning or fetching from it fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    git_mirrors: Option<BTreeMap<String, Vec<String>>>,
+    /// Commits of history to clone and fetch; 0 means the full history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clone_depth: Option<u32>,
+    /// Fetch through a mirror in `~/.cache/tbl/git` shared by all profiles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    git_cache: Option<bool>,
+    /// Releases of the content kept for rollback; 0 keeps none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keep_releases: Option<usize>,
+    /// Serve a checkout of each branch under `/preview/<branch>/`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previews: Option<bool>,
+    /// Endpoint and credentials for `s3://` sources
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    s3: Option<s3::S3Config>,
+    /// Key and known hosts for `sftp://` sources
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sftp: Option<sftp::SftpConfig>,
+    /// Commands run before and after each sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hooks: Option<hooks::HooksConfig>,
+    /// Caps on the memory of the search index, KV store, and job records
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memory: Option<memory::MemoryConfig>,
+    /// Quotas on the KV namespaces and files area apps write to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota: Option<quota::QuotaConfig>,
+    /// Scheduled backups and how many are kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backup: Option<backup::BackupConfig>,
+    /// GitHub, GitLab, and Gitea tokens for picking and cloning repositories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    providers: Option<forge::ProvidersConfig>,
+    /// Parallelism of `tbl sync`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sync_jobs: Option<usize>,
+    /// Per-app time limit of `tbl sync`, e.g. "5m"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sync_timeout: Option<String>,
+    /// Desktop, webhook, and command notifiers fired on server events
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    notifications: Vec<notify::Notifier>,
+    /// Who may open the served app under `/web`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    access: Option<Access>,
+    /// Extra sign-in asked of clients on other machines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote_auth: Option<RemoteAuth>,
+    /// Optional subsystems turned off for served apps
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    disable: Vec<Capability>,
+    /// User to serve as after binding, when started as root (system scope)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    /// How the daemon is confined; `off` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sandbox: Option<Sandbox>,
+}
+
+/// Behaviour outside the `serve_hours` windows.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum OutsideHours {
+    /// Keep running but answer with a "sleeping" page
+    #[default]
+    Sleep,
+    /// Shut down; a service manager is expected to start tbl again
+    Stop,
+}
+
+/// Who may open the served app (`/web/*`). The API always requires the
+/// session cookie.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Access {
+    /// Only browsers signed in with the bootstrap link (and basic auth, if set)
+    #[default]
+    Token,
+    /// Anyone with the basic auth credentials, without the bootstrap link
+    Basic,
+    /// Anyone on the local network; other clients sign in as with `token`
+    Lan,
+    /// Anyone who can reach the server
+    Public,
+}
+
+/// Authentication by where a request comes from, so binding `0.0.0.0` does
+/// not have to weaken or tighten it for everyone.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum RemoteAuth {
+    /// Every client signs in the same way
+    #[default]
+    Session,
+    /// Basic auth is asked of clients on other machines only; loopback
+    /// clients get by with the session cookie
+    Basic,
+}
+
+/// Look and feel of the embedded launcher pages.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct Branding {
+    /// Product name shown instead of "tbl"
+    title: Option<String>,
+    /// Logo image: a file path (relative to the config dir) or a `data:` URI
+    logo: Option<String>,
+    /// CSS color used for buttons, spinners and highlights
+    accent_color: Option<String>,
+    /// Short line of text shown at the bottom of each page
+    footer: Option<String>,
+}
+
+/// A previously configured git URL, most recently used first.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct HistoryEntry {
+    git_url: String,
+    /// Unix timestamp (seconds) of the last time this URL was served
+    last_used: u64,
 }
 
+const MAX_HISTORY: usize = 10;
+
+/// Events buffered per subscriber before slow clients start missing some,
+/// and kept for streams that resume after a reconnect.
+const EVENT_BUFFER: usize = 64;
+
+/// How long shutdown waits for pending notifications.
+const NOTIFY_SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
 // =============================================================================
 // Application State
 // =============================================================================
 
 struct AppState {
-    auth_token: String,
-    web_root: PathBuf,
+    /// Signs and checks the login link and the browsers' `tbl_token` cookies
+    tokens: token::Tokens,
+    /// The checkout served under `/web`
+    web: Jail,
     config_dir: PathBuf,
-    config: TblConfig,
-    shutdown_tx: tokio::sync::Mutex<Option<oneshot::Sender<()>>>,
+    config: RwLock<TblConfig>,
+    /// Takes the reason given for stopping and how to stop
+    shutdown_tx: tokio::sync::Mutex<Option<oneshot::Sender<(&'static str, ShutdownMode)>>>,
+    /// Shutdown asked for later, with the task that will trigger it
+    scheduled_shutdown: Mutex<Option<PendingShutdown>>,
+    next_shutdown_id: AtomicU64,
+    /// Unix timestamp (seconds) when this server started
+    started_at: u64,
+    /// This run's ID in the run history (`uptime`)
+    run: i64,
+    events: broadcast::Sender<Event>,
+    next_event_id: AtomicU64,
+    /// The last `EVENT_BUFFER` events, replayed to streams that resume
+    recent_events: Mutex<VecDeque<Event>>,
+    /// Sessions, job records, KV data, and the audit log (`state.db`)
+    db: Arc<StateDb>,
+    kv: KvStore,
+    /// Root directory of the files API (`~/.config/tbl/data/files`)
+    files: Jail,
+    /// What `files` holds, against `quota.files`
+    files_usage: quota::FilesTally,
+    tls: bool,
+    /// Unix timestamp (seconds) of the last request, for the idle timeout
+    last_activity: AtomicU64,
+    /// Clients with an `/api/v1/events` stream open, by connection ID; a
+    /// listening tab counts as activity
+    clients: Mutex<BTreeMap<u64, ConnectedClient>>,
+    next_client_id: AtomicU64,
+    /// Serving windows; outside them requests get a "sleeping" response
+    schedule: Option<Schedule>,
+    /// Held while a clone or update runs, so workspace jobs queue up
+    workspace_jobs: tokio::sync::Mutex<()>,
+    /// Held while a preview branch is cloned or fetched
+    preview_jobs: tokio::sync::Mutex<()>,
+    /// Background job records
+    jobs: Arc<JobStore>,
+    /// Updates that failed in a row, for `update.failing`
+    update_failures: AtomicU32,
+    /// Last `Authorization` header that passed basic auth, so an argon2
+    /// hash isn't verified again on every request
+    basic_auth_verified: Mutex<Option<String>>,
+    /// Full-text index of the checkout, rebuilt after every clone or update
+    search: RwLock<SearchIndex>,
+    /// Caps from the `memory` config section
+    memory: memory::Limits,
+    /// Quotas from the `quota` config section
+    quotas: quota::Quotas,
+    /// Title, description, and icon of the checkout, shared with notifiers
+    app_meta: Arc<RwLock<Option<AppMeta>>>,
+    /// What the checkout's `tbl.requires.yaml` asks for but this instance
+    /// lacks; the app's pages are held back while it is not empty
+    unmet_requirements: RwLock<Vec<String>>,
+    /// Normalized `base_path`, e.g. `/tbl`; empty at the root
+    base_path: String,
+    trusted_proxies: TrustedProxies,
+}
+
+/// Server event delivered to `/api/v1/events` subscribers.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+struct Event {
+    id: u64,
+    #[serde(rename = "type")]
+    kind: String,
+    time: u64,
+    data: serde_json::Value,
+}
+
+/// A browser tab or other client with an events stream open, as listed by
+/// `/api/v1/clients`.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+struct ConnectedClient {
+    /// Identifies the connection while it lasts
+    id: u64,
+    /// Client address, behind `trusted_proxies` the forwarded one
+    ip: Option<String>,
+    user_agent: Option<String>,
+    /// `web` for the served app, `preview/<branch>` for a branch preview;
+    /// null when the client didn't say which page it has open
+    app: Option<String>,
+    /// Page the client has open, as `tbl.js` reports it
+    page: Option<String>,
+    /// `tbl.js` client ID, as its broadcasts carry it
+    client: Option<String>,
+    session: Option<String>,
+    /// Basic auth user, when basic auth is configured
+    user: Option<String>,
+    /// `websocket` or `sse`
+    transport: String,
+    /// Unix timestamp (seconds)
+    connected_at: u64,
 }
 
 // =============================================================================
@@ -100,8 +977,32 @@ struct AppState {
 struct RunInfo {
     pid: u32,
     port: u16,
-    auth_token: String,
+    auth_token: Secret,
     tls: bool,
+    /// Absolute path of the served certificate, pinned by `--stop` and other
+    /// commands that talk to the daemon over HTTPS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls_cert: Option<PathBuf>,
+    /// PID of the watchdog supervising this daemon, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    supervisor_pid: Option<u32>,
+    /// Times the watchdog has restarted the daemon
+    #[serde(default)]
+    restarts: u32,
+    /// `base_path` the server runs with, e.g. `/tbl`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    base_path: String,
+    /// How long the phases of starting this server took
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timings: Option<timings::Report>,
+}
+
+impl RunInfo {
+    /// URL of `path` (starting with `/`) on this server over loopback.
+    fn local_url(&self, path: &str) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{scheme}://127.0.0.1:{}{}{path}", self.port, self.base_path)
+    }
 }
 
 // =============================================================================
@@ -116,181 +1017,664 @@ struct BootstrapQuery {
 #[derive(Deserialize)]
 struct SetupForm {
     git_url: String,
-}
-
-#[derive(Serialize)]
-struct PingResponse {
-    status: &'static str,
-}
-
-#[derive(Serialize)]
-struct ShutdownResponse {
-    status: &'static str,
+    /// Name of the catalog app picked, with the version listed
+    catalog_app: Option<String>,
+    catalog_version: Option<String>,
 }
 
 // =============================================================================
 // Main Entry Point
 // =============================================================================
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let cli = Cli::parse();
+    // Signals to pass on have to be blocked before other threads start
+    if let Some(Commands::DockerEntrypoint { args }) = &cli.command {
+        std::process::exit(container::entrypoint(args)?);
+    }
+    init_profile(cli.profile.clone())?;
+    init_scope(cli.system)?;
+    timefmt::init(cli.utc);
+
+    // Landlock confines only the calling thread and the threads it starts
+    // later, so the daemon is confined before the runtime starts its workers
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    let serves = cli.command.is_none() && !cli.stop && !cli.tray;
+    let foreground = container::enabled(cli.container) || activation::offered();
+    if serves && (std::env::var("TBL_DAEMONIZED").is_ok() || foreground) {
+        let cfg = serving_config(&cli)?;
+        confine_daemon(&cfg)?;
+        tune_runtime(&mut runtime, &cfg)?;
+    }
+
+    runtime
+        .build()
+        .context("Failed to start the async runtime")?
+        .block_on(run(cli))
+}
 
+async fn run(cli: Cli) -> Result<()> {
     // Handle --stop before daemonization
     if cli.stop {
-        return handle_stop_command();
+        let port = match cli.port {
+            Some(PortSpec::Number(port)) if port != 0 => Some(port),
+            Some(_) => anyhow::bail!("--stop --port takes the port of a running server"),
+            None => None,
+        };
+        let mode = if cli.now {
+            ShutdownMode::Now
+        } else {
+            ShutdownMode::Graceful
+        };
+        let delay = match (&cli.after, &cli.at) {
+            (Some(after), _) => Some(parse_duration(after).context("Invalid --after")?),
+            (_, Some(at)) => Some(Duration::from_secs(
+                schedule::seconds_until(at, Local::now()).context("Invalid --at")?,
+            )),
+            _ => None,
+        };
+        let action = if cli.cancel {
+            StopAction::Cancel
+        } else if let Some(delay) = delay.filter(|delay| !delay.is_zero()) {
+            StopAction::Schedule { delay, mode }
+        } else {
+            StopAction::Stop {
+                force: cli.force,
+                mode,
+            }
+        };
+        return handle_stop_command(InstanceSelector::new(port, cli.all), action);
+    }
+
+    if let Some(command) = &cli.command {
+        return match command {
+            Commands::Switch { target } => handle_switch_command(target.as_deref()),
+            Commands::Status {
+                port,
+                all,
+                reveal,
+                verbose,
+            } => handle_status_command(InstanceSelector::new(*port, *all), *reveal, *verbose),
+            Commands::Doctor => handle_doctor_command(),
+            Commands::Setcap { remove } => handle_setcap_command(*remove),
+            Commands::Jobs { id } => handle_jobs_command(id.as_deref()),
+            Commands::Sync {
+                timeout,
+                once: true,
+                exit,
+                ..
+            } => handle_sync_once_command(&cli, timeout.as_deref(), *exit).await,
+            Commands::Sync {
+                all, jobs, timeout, ..
+            } => handle_sync_command(*all, *jobs, timeout.as_deref()).await,
+            Commands::CloneFromPeer { name, token, wait } => {
+                handle_clone_from_peer_command(name.as_deref(), token.as_ref(), wait).await
+            }
+            Commands::Bundle { output } => handle_bundle_command(output.as_deref()),
+            Commands::Install { file, trust } => handle_install_command(file, *trust).await,
+            Commands::Catalog { command } => handle_catalog_command(command).await,
+            Commands::Upgrade { check, trust } => handle_upgrade_command(*check, *trust).await,
+            Commands::Unshallow => handle_unshallow_command(),
+            Commands::Bench {
+                concurrency,
+                duration,
+                paths,
+            } => handle_bench_command(*concurrency, duration.as_deref(), paths).await,
+            Commands::Pause { reason, duration } => {
+                handle_pause_command(reason.clone(), duration.as_deref())
+            }
+            Commands::Resume => handle_resume_command(),
+            Commands::Releases => handle_releases_command(),
+            Commands::Rollback { release } => handle_rollback_command(release),
+            Commands::Auth {
+                command: AuthCommands::SetPassword { user },
+            } => handle_set_password_command(user.as_deref()),
+            Commands::Login {
+                provider,
+                client_id,
+            } => handle_login_command(provider, client_id.clone()).await,
+            Commands::Logout { provider } => handle_logout_command(provider),
+            Commands::Config {
+                command: ConfigCommands::Encrypt { keyring },
+            } => handle_config_encrypt_command(*keyring),
+            Commands::Config {
+                command: ConfigCommands::Decrypt,
+            } => handle_config_decrypt_command(),
+            Commands::Config {
+                command: ConfigCommands::Explain,
+            } => handle_config_explain_command(&cli),
+            Commands::ExportConfig { path, tls } => handle_export_config_command(path, *tls),
+            Commands::ImportConfig { path, force } => handle_import_config_command(path, *force),
+            Commands::Backup { command } => handle_backup_command(command),
+            Commands::Kv { command } => handle_kv_command(command),
+            Commands::Token { command } => handle_token_command(command),
+            Commands::Open { app_mode } => handle_open_command(*app_mode),
+            Commands::Shortcut {
+                command: ShortcutCommands::Create { name },
+            } => handle_shortcut_create_command(name.as_deref()),
+            Commands::DockerEntrypoint { .. } => unreachable!("handled before the runtime starts"),
+        };
+    }
+
+    if cli.tray {
+        return handle_tray();
+    }
+
+    let mut timings = Timings::start();
+
+    // Started by systemd or launchd on a socket it listens on, or in a
+    // container: serve in the foreground, as something else supervises the
+    // process
+    let activated = activation::listener().context("Socket activation failed")?;
+    let container = container::enabled(cli.container);
+    let daemonized = activated.is_some() || container || std::env::var("TBL_DAEMONIZED").is_ok();
+    let no_browser = cli.no_browser || container;
+
+    // Watchdog process: keep restarting the daemon until it exits cleanly
+    if std::env::var("TBL_SUPERVISOR").is_ok() && !daemonized {
+        return run_watchdog();
     }
 
     // Daemonize: re-exec in background if not already daemonized
-    if std::env::var("TBL_DAEMONIZED").is_err() {
+    if !daemonized {
         // Print banner before daemonizing so user sees it
         print_banner();
 
+        let config_dir = get_config_dir()?;
+        let run_dir = run_dir(&config_dir);
+        if let Some(info) = load_run_info(&run_dir).filter(|info| port_is_open(info.port)) {
+            return attach_or_take_over(&cli, &info);
+        }
+
+        // The detached daemon has no terminal to ask for the passphrase on
+        if vault::is_encrypted(&config_dir) {
+            vault::load(&config_dir)?;
+        }
+        let cwd = std::env::current_dir().context("Failed to get current directory")?;
+        let (cfg, _) = effective_config(&cli, load_config(&config_dir).unwrap_or_default(), &cwd);
+        serving_user(&cfg)?;
+        if cfg.sandbox == Some(Sandbox::Strict) {
+            sandbox::check()?;
+        }
+
         let exe = std::env::current_exe().context("cannot get current exe")?;
         let mut cmd = Command::new(exe);
         cmd.args(std::env::args().skip(1));
-        cmd.env("TBL_DAEMONIZED", "1");
+        if watchdog_enabled(&cli) {
+            cmd.env("TBL_SUPERVISOR", "1");
+        } else {
+            cmd.env("TBL_DAEMONIZED", "1");
+        }
+        // Detach from the terminal: output goes to the log file instead
+        let log_path = daemon_log_path(&config_dir);
+        let log = open_daemon_log(&log_path)?;
         cmd.stdin(Stdio::null())
-            .stdout(Stdio::inherit())  // Keep stdout for verbose output
-            .stderr(Stdio::inherit()); // Keep stderr for errors
-        let _child = cmd.spawn().context("failed to spawn tbl daemon")?;
-        // Parent exits; daemon continues
-        return Ok(());
+            .stdout(Stdio::from(log.try_clone()?))
+            .stderr(Stdio::from(log));
+        detach_process(&mut cmd);
+        let child = vault::spawn_with_passphrase(&mut cmd, vault::passphrase(&config_dir).as_ref())
+            .context("failed to spawn tbl daemon")?;
+        timings.phase("launcher");
+        // Parent reports the daemon's URL and exits; daemon continues
+        return wait_for_daemon(
+            child,
+            &run_dir,
+            &log_path,
+            cli.no_browser,
+            cli.timings.then_some(timings),
+        );
     }
 
+    init_logging(container);
+
     // Determine config directory: ~/.config/tbl
     let config_dir = get_config_dir()?;
     fs::create_dir_all(&config_dir)
         .with_context(|| format!("Failed to create config dir {:?}", config_dir))?;
 
-    // Change working directory to config dir
-    std::env::set_current_dir(&config_dir)
-        .with_context(|| format!("Failed to chdir to {:?}", config_dir))?;
+    install_crash_handler(&config_dir);
 
-    // Load config file if present (JSON, YAML, or TOML)
-    let file_cfg = load_config(&config_dir).unwrap_or_default();
+    if vault::is_encrypted(&config_dir) && !container {
+        vault::load(&config_dir)?;
+    }
 
-    // Environment variables
-    let env_git_url = std::env::var("TBL_GIT_URL").ok();
-    let env_addr = std::env::var("TBL_ADDR").ok();
-    let env_tls_cert = std::env::var("TBL_TLS_CERT").ok();
-    let env_tls_key = std::env::var("TBL_TLS_KEY").ok();
-    let env_basic_user = std::env::var("TBL_BASIC_USER").ok();
-    let env_basic_pass = std::env::var("TBL_BASIC_PASS").ok();
+    // Load config file if present (JSON, YAML, TOML, or encrypted)
+    let file_cfg = file_config(&config_dir, container)?;
 
     // Merge configuration with precedence: CLI > ENV > config file > defaults
-    let mut effective_cfg = TblConfig {
-        git_url: cli.git_url.clone().or(env_git_url).or(file_cfg.git_url),
-        addr: cli
-            .addr
-            .clone()
-            .or(env_addr)
-            .or(file_cfg.addr)
-            .or(Some("127.0.0.1:1234".to_string())),
-        tls_cert: cli.tls_cert.clone().or(env_tls_cert).or(file_cfg.tls_cert),
-        tls_key: cli.tls_key.clone().or(env_tls_key).or(file_cfg.tls_key),
-        basic_user: cli
-            .basic_user
-            .clone()
-            .or(env_basic_user)
-            .or(file_cfg.basic_user),
-        basic_pass: cli
-            .basic_pass
-            .clone()
-            .or(env_basic_pass)
-            .or(file_cfg.basic_pass),
-    };
+    let cwd = std::env::current_dir().context("Failed to get current directory")?;
+    let (mut effective_cfg, _) = effective_config(&cli, file_cfg, &cwd);
+    let providers = effective_cfg.providers.take().unwrap_or_default();
+    effective_cfg.providers =
+        Some(providers.resolve(&config_dir)?).filter(|providers| !providers.is_empty());
+
+    validate_config_paths(&config_dir, &effective_cfg)?;
+    let basic_auth = effective_cfg.basic_user.is_some() && effective_cfg.basic_pass.is_some();
+    if effective_cfg.access == Some(Access::Basic) && !basic_auth {
+        anyhow::bail!("access: basic needs basic_user and basic_pass");
+    }
+    if effective_cfg.remote_auth == Some(RemoteAuth::Basic) && !basic_auth {
+        anyhow::bail!("remote_auth: basic needs basic_user and basic_pass");
+    }
+    // Confined by `confine_daemon` on Linux; refused where that can't be done
+    if effective_cfg.sandbox == Some(Sandbox::Strict) {
+        sandbox::check()?;
+    }
+    init_git_timeout(effective_cfg.git_timeout.as_deref())?;
+    init_git_proxy(effective_cfg.git_proxy.clone());
+    let base_path = normalize_base_path(effective_cfg.base_path.as_deref().unwrap_or_default())
+        .context("Invalid base_path")?;
+    let trusted_proxies = match &effective_cfg.trusted_proxies {
+        Some(cidrs) => TrustedProxies::parse(cidrs),
+        None => TrustedProxies::parse(proxy::DEFAULT_TRUSTED_PROXIES),
+    }
+    .context("Invalid trusted_proxies")?;
+    if effective_cfg
+        .http1_max_buf_size
+        .is_some_and(|size| size < MIN_HTTP1_MAX_BUF_SIZE)
+    {
+        anyhow::bail!("http1_max_buf_size must be at least {MIN_HTTP1_MAX_BUF_SIZE}");
+    }
+    let connections = Connections::new(
+        connections::Limits {
+            total: Some(
+                effective_cfg
+                    .max_connections
+                    .unwrap_or(connections::DEFAULT_MAX_CONNECTIONS),
+            )
+            .filter(|&max| max > 0),
+            per_ip: Some(
+                effective_cfg
+                    .max_connections_per_ip
+                    .unwrap_or(connections::DEFAULT_MAX_CONNECTIONS_PER_IP),
+            )
+            .filter(|&max| max > 0),
+        },
+        trusted_proxies.clone(),
+    );
+
+    let idle_timeout = effective_cfg
+        .idle_timeout
+        .as_deref()
+        .map(parse_duration)
+        .transpose()
+        .context("Invalid idle_timeout")?
+        .filter(|timeout| !timeout.is_zero());
+
+    let schedule = effective_cfg
+        .serve_hours
+        .as_deref()
+        .map(Schedule::parse)
+        .transpose()
+        .context("Invalid serve_hours")?;
+    let outside_hours = effective_cfg.outside_hours.unwrap_or_default();
+    if let Some(hooks) = &effective_cfg.hooks {
+        hooks.timeout()?;
+    }
+    let memory_limits = effective_cfg.memory.clone().unwrap_or_default().limits()?;
+    let quotas = effective_cfg.quota.clone().unwrap_or_default().quotas()?;
+    let sync_schedule = effective_cfg
+        .sync_schedule
+        .as_deref()
+        .map(CronSchedule::parse)
+        .transpose()
+        .context("Invalid sync_schedule")?;
+    let backup_cfg = effective_cfg.backup.clone().unwrap_or_default();
+    let backup_schedule = backup_cfg.schedule()?;
+    if let Some(schedule) = schedule
+        .as_ref()
+        .filter(|schedule| outside_hours == OutsideHours::Stop && !schedule.is_open(Local::now()))
+    {
+        println!();
+        println!("  Outside serving hours ({schedule}); not starting.");
+        if let Some(next) = schedule.next_open(Local::now()) {
+            println!(
+                "  Next window opens {}.",
+                timefmt::human(&next, timefmt::Style::Weekday)
+            );
+        }
+        println!();
+        return Ok(());
+    }
 
+    timings.phase("config load");
     let tls_enabled = effective_cfg.tls_cert.is_some() && effective_cfg.tls_key.is_some();
 
     // Check for already-running daemon via pid.yaml
-    let run_dir = config_dir.join("run");
+    let run_dir = run_dir(&config_dir);
     let maybe_run_info = load_run_info(&run_dir);
 
+    let mut previous_token = None;
     if let Some(info) = maybe_run_info {
-        if port_is_open(info.port) {
+        // A socket from the service manager is open whether or not a server
+        // has it, and the service manager starts only one
+        if port_is_open(info.port) && activated.is_none() {
             // Server already running; open new browser context
-            let scheme = if info.tls { "https" } else { "http" };
-            let public_url = format!(
-                "{}://127.0.0.1:{}/bootstrap?token={}",
-                scheme, info.port, info.auth_token
-            );
-
-            println!();
-            println!("  tbl is already running");
-            println!("  ───────────────────────────────────────");
-            println!("  PID:    {}", info.pid);
-            println!("  Port:   {}", info.port);
-            println!("  TLS:    {}", if info.tls { "enabled" } else { "disabled" });
-            println!();
-            print_url_box(&public_url);
-
-            if !cli.no_browser {
-                println!("\n  Opening browser...");
-                if let Err(e) = webbrowser::open(&public_url) {
-                    eprintln!("  Failed to open browser: {e}");
-                    eprintln!("  Open the URL above manually to authenticate.");
-                }
-            } else {
-                println!("\n  Open the URL above to authenticate.");
-            }
-            println!();
-
+            attach_to_running(&info, no_browser);
             return Ok(());
         } else {
             // Stale pid.yaml; remove it
             clear_run_info(&run_dir);
+            previous_token = Some(info.auth_token);
+        }
+    }
+    timings.phase("port probe");
+
+    // Port auto-detection: the profile's reserved port, else the first free
+    // one from the configured base port. Port 0 and "random" bypass the
+    // reservations and get a new port every run; a socket from the service
+    // manager is used as is.
+    let addr_template = effective_cfg.addr.clone().unwrap();
+    let (host, port_spec) = split_host_port(&addr_template)?;
+    let socket_activated = activated.is_some();
+    let listener = match (activated, port_spec) {
+        (Some(listener), _) => listener,
+        // The port is published to the host, so it can't move
+        (None, PortSpec::Number(port)) if container => bind_port(&host, port)?,
+        (None, PortSpec::Number(0)) => bind_port(&host, 0)?,
+        (None, PortSpec::Number(base_port)) => bind_reserved_port(
+            &host,
+            base_port,
+            effective_cfg.bind_retries.unwrap_or(DEFAULT_BIND_RETRIES),
+        )?,
+        (None, PortSpec::Random) => {
+            let range = effective_cfg
+                .port_range
+                .as_deref()
+                .unwrap_or(DEFAULT_PORT_RANGE);
+            let range = parse_port_range(range).context("Invalid port_range")?;
+            bind_random_port(&host, range)?
         }
+    };
+    let addr = listener.local_addr()?;
+    let chosen_port = addr.port();
+    timings.phase("bind");
+
+    // The key may be readable by root only, so it is loaded before dropping
+    let tls_config = if tls_enabled {
+        let cert = config_path(&config_dir, effective_cfg.tls_cert.as_deref().unwrap())?;
+        let key = config_path(&config_dir, effective_cfg.tls_key.as_deref().unwrap())?;
+        Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .context("failed to load TLS cert/key")?,
+        )
+    } else {
+        None
+    };
+    if tls_enabled {
+        timings.phase("TLS load");
+    }
+    if let Some(account) = serving_user(&effective_cfg)? {
+        let cache_dir = mirror::cache_dir();
+        let mut dirs = vec![config_dir.as_path(), run_dir.as_path()];
+        dirs.extend(cache_dir.as_deref());
+        privileges::hand_over(&account, &dirs)?;
+        privileges::drop_to(&account)?;
+        tracing::info!(user = %account.name, "Dropped root privileges after binding {addr}");
     }
 
     // If git URL is known, ensure git is available and repo is present
-    if effective_cfg.git_url.is_some() {
+    if effective_cfg.git_url.as_deref().is_some_and(is_git_source) {
         ensure_git_available()?;
     }
 
     let web_root = config_dir.join("web");
 
-    if let Some(ref url) = effective_cfg.git_url {
-        ensure_repo(&config_dir, url)
-            .with_context(|| format!("Failed to ensure repo for URL {url}"))?;
+    // With lazy sync an existing checkout is served as-is and updated once
+    // the server is up; a missing or repointed checkout still clones first
+    let mut background_sync = None;
+    if let Some(url) = effective_cfg.git_url.clone() {
+        if pause::current(&config_dir).is_some() && checkout_matches(&web_root, &url) {
+            tracing::info!("Automatic syncs are paused; serving the existing content");
+        } else if checkout_matches(&web_root, &url)
+            && effective_cfg.metered.unwrap_or_default().is_metered()
+        {
+            metered::defer(&config_dir)?;
+            tracing::info!(
+                "On a metered connection; serving the existing content and syncing once unmetered"
+            );
+        } else if effective_cfg.lazy_sync.unwrap_or(false) && checkout_matches(&web_root, &url) {
+            background_sync = Some(url.clone());
+        } else if let Err(e) = ensure_repo(
+            &config_dir,
+            &url,
+            source::Settings::of(&effective_cfg),
+            None,
+        ) {
+            // An unreachable remote keeps serving the current content
+            if !checkout_matches(&web_root, &url) {
+                return Err(e).with_context(|| {
+                    format!("Failed to ensure repo for URL {}", redact_credentials(&url))
+                });
+            }
+            tracing::warn!("{e:#}; keeping the existing checkout");
+        }
+        record_history(&mut effective_cfg, &url);
+        // Whether updated or not, the checkout is one of `url` by now
+        remember_repo(&mut effective_cfg, &url, default_branch(&web_root));
+        timings.phase("git sync");
     }
 
-    // Generate a per-run secret token
-    let auth_token = generate_token();
+    // Sign a token for this run's login link. A watchdog restart keeps the
+    // previous one; otherwise it stops working, while browsers keep theirs
+    let db = StateDb::open(&config_dir, &run_dir)?;
+    let tokens = token::Tokens::load(db.clone())?;
+    let started_at = now_unix();
+    let run = uptime::start(&db, started_at)?;
+    let restarts = watchdog_restart_count();
+    let auth_token = match previous_token {
+        Some(token) if restarts > 0 && tokens.verify(token.expose()).is_some() => token,
+        previous => {
+            if let Some(token) = previous {
+                tokens.revoke_token(&token)?;
+            }
+            tokens.issue()
+        }
+    };
 
     // Create shutdown channel
-    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<(&'static str, ShutdownMode)>();
+
+    let (events_tx, _) = broadcast::channel(EVENT_BUFFER);
 
     let state = Arc::new(AppState {
-        auth_token: auth_token.clone(),
-        web_root: web_root.clone(),
+        tokens,
+        web: Jail::new(&web_root),
         config_dir: config_dir.clone(),
-        config: effective_cfg.clone(),
+        config: RwLock::new(effective_cfg.clone()),
         shutdown_tx: tokio::sync::Mutex::new(Some(shutdown_tx)),
+        scheduled_shutdown: Mutex::default(),
+        next_shutdown_id: AtomicU64::new(1),
+        started_at,
+        run,
+        events: events_tx,
+        next_event_id: AtomicU64::new(1),
+        recent_events: Mutex::default(),
+        db: db.clone(),
+        kv: KvStore::new(db.clone(), memory_limits.kv, quotas.clone()),
+        files: Jail::new(config_dir.join("data").join("files")),
+        files_usage: quota::FilesTally::default(),
+        tls: tls_enabled,
+        last_activity: AtomicU64::new(now_unix()),
+        clients: Mutex::default(),
+        next_client_id: AtomicU64::new(1),
+        schedule: schedule.clone(),
+        workspace_jobs: tokio::sync::Mutex::new(()),
+        preview_jobs: tokio::sync::Mutex::new(()),
+        jobs: Arc::new(JobStore::open(db, run_dir.join("jobs"))?.with_limit(memory_limits.jobs)),
+        update_failures: AtomicU32::new(0),
+        basic_auth_verified: Mutex::new(None),
+        search: RwLock::new(SearchIndex::default()),
+        memory: memory_limits,
+        quotas,
+        app_meta: Arc::default(),
+        unmet_requirements: RwLock::default(),
+        base_path: base_path.clone(),
+        trusted_proxies,
     });
 
-    let app = Router::new()
+    // Subscribe before anything is emitted, so `server.started` is delivered
+    let notifier = (!effective_cfg.notifications.is_empty()).then(|| {
+        let source = notify::Source {
+            title: effective_cfg
+                .branding
+                .as_ref()
+                .and_then(|branding| branding.title.clone())
+                .unwrap_or_else(|| "tbl".to_string()),
+            app: effective_cfg.git_url.as_deref().and_then(repo_name),
+            app_meta: state.app_meta.clone(),
+            web_root: web_root.clone(),
+            profile: profile_label().to_string(),
+            secrets: [
+                Some(auth_token.clone()),
+                effective_cfg.basic_pass.clone(),
+                effective_cfg.peer_token.clone(),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        };
+        tokio::spawn(notify::run(
+            state.events.subscribe(),
+            effective_cfg.notifications.clone(),
+            source,
+        ))
+    });
+
+    // Route groups, each with its own authentication; the rest is open
+    let web = Router::new()
         .route("/", get(index_handler))
+        .nest_service("/web", web_files_handler.with_state(state.clone()))
+        .route("/preview/:branch", get(preview_handler))
+        .route("/preview/:branch/", get(preview_handler))
+        .route("/preview/:branch/*path", get(preview_handler))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            fix_web_redirects,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            jail_web,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            enforce_requirements,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_web_access,
+        ));
+    let launcher = Router::new()
+        .route("/setup", get(setup_page_handler).post(setup_handler))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_session,
+        ));
+
+    let app = Router::new()
         .route("/bootstrap", get(bootstrap_handler))
-        .route("/setup", post(setup_handler))
-        .route("/api/v1/ping", get(ping_handler))
-        .route("/api/v1/shutdown", post(shutdown_handler))
+        .route(SIGNIN_PATH, get(signin_handler))
         .route("/tbl.js", get(tbl_js_handler))
-        .nest_service("/web", ServeDir::new(&web_root))
+        .route("/tbl.d.ts", get(tbl_dts_handler))
+        .merge(web)
+        .merge(launcher)
+        .merge(api::router(state.clone()))
+        .fallback(not_found_handler)
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            enforce_schedule,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            track_activity,
+        ))
         .with_state(state.clone());
+    // Under a base path only its root is answered outside it
+    let app = if base_path.is_empty() {
+        app
+    } else {
+        let home = format!("{base_path}/");
+        // `nest` answers "/tbl" but not "/tbl/", which is where proxies send
+        // the app's home page
+        let home_page = app
+            .clone()
+            .map_request(|mut request: axum::extract::Request| {
+                let query = request.uri().query().map(|q| format!("?{q}"));
+                if let Ok(uri) = format!("/{}", query.unwrap_or_default()).parse() {
+                    *request.uri_mut() = uri;
+                }
+                request
+            });
+        Router::new()
+            .route(
+                "/",
+                get({
+                    let home = home.clone();
+                    move || async move { Redirect::to(&home) }
+                }),
+            )
+            .route_service(&home, home_page)
+            .nest(&base_path, app)
+    };
+    let app = app
+        // Layers run bottom-up: assign the ID, open the span, echo the ID back
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with({
+            let state = state.clone();
+            move |request: &axum::extract::Request| request_span(&state, request)
+        }))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+    // Around the whole router, which adds `Allow` to its 405s last
+    let app = Router::new()
+        .fallback_service(app)
+        .layer(axum::middleware::from_fn(answer_options));
 
-    // Port auto-detection starting at configured base port
-    let addr_template = effective_cfg.addr.clone().unwrap();
-    let (host, base_port) = split_host_port(&addr_template)?;
-    let chosen_port = find_available_port(&host, base_port);
-    let addr: SocketAddr = format!("{}:{}", host, chosen_port)
-        .parse()
-        .with_context(|| format!("Invalid addr: {}:{}", host, chosen_port))?;
-
-    // Update effective config with chosen port
-    effective_cfg.addr = Some(format!("{}:{}", host, chosen_port));
+    if let Some(timeout) = idle_timeout {
+        tokio::spawn(watch_idle(state.clone(), timeout));
+    }
+    if container {
+        tokio::spawn({
+            let state = state.clone();
+            async move {
+                container::stop_signal().await;
+                tracing::info!("Stop signal received; shutting down");
+                request_shutdown(&state, "signal", ShutdownMode::Graceful).await;
+            }
+        });
+    }
+    if schedule.is_some() && outside_hours == OutsideHours::Stop {
+        tokio::spawn(watch_schedule(state.clone()));
+    }
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            refresh_app_meta(&state).await;
+            check_requirements(&state).await;
+            reindex(&state).await
+        }
+    });
+    if let Some(url) = background_sync {
+        tokio::spawn(sync_in_background(state.clone(), url));
+    }
+    if let Some(schedule) = sync_schedule {
+        tokio::spawn(watch_sync_schedule(state.clone(), schedule));
+    }
+    if let Some(schedule) = backup_schedule {
+        tokio::spawn(watch_backup_schedule(state.clone(), schedule, backup_cfg));
+    }
+    tokio::spawn(sweep_expired(state.clone()));
+    {
+        // Measured up front, so the first write and the setup page don't wait
+        let measuring = state.clone();
+        tokio::task::spawn_blocking(move || measuring.files_usage.measure(measuring.files.root()));
+    }
+    tokio::spawn(watch_deferred_sync(state.clone()));
+    tokio::spawn(record_heartbeats(state.clone()));
 
-    // Save config
+    // Save config; `addr` keeps the configured base port so the reservation
+    // in ports.yaml stays valid
     if let Err(e) = save_config(&config_dir, &effective_cfg) {
         eprintln!("Failed to save config: {e}");
     }
@@ -298,83 +1682,144 @@ async fn main() -> Result<()> {
     let scheme = if tls_enabled { "https" } else { "http" };
 
     // Write pid.yaml for future instance detection
-    let run_info = RunInfo {
+    timings.phase("setup");
+    let mut run_info = RunInfo {
         pid: std::process::id(),
         port: chosen_port,
         auth_token: auth_token.clone(),
         tls: tls_enabled,
+        tls_cert: effective_cfg
+            .tls_cert
+            .as_deref()
+            .filter(|_| tls_enabled)
+            .and_then(|cert| config_path(&config_dir, cert).ok())
+            .and_then(|cert| fs::canonicalize(cert).ok()),
+        supervisor_pid: std::env::var("TBL_SUPERVISOR_PID")
+            .ok()
+            .and_then(|pid| pid.parse().ok()),
+        restarts,
+        base_path: base_path.clone(),
+        timings: Some(timings.report()),
     };
     if let Err(e) = save_run_info(&run_dir, &run_info) {
         eprintln!("Failed to write pid.yaml: {e}");
     }
-
-    let public_url = format!(
-        "{}://127.0.0.1:{}/bootstrap?token={}",
-        scheme, chosen_port, auth_token
+    emit_event(
+        &state,
+        "server.started",
+        serde_json::json!({ "port": chosen_port, "tls": tls_enabled, "restarts": restarts }),
     );
+    if let Some(cert) = run_info.tls_cert.clone() {
+        tokio::spawn(watch_certificate(state.clone(), cert));
+    }
+    if effective_cfg.announce.unwrap_or(false) {
+        if addr.ip().is_loopback() {
+            tracing::warn!(
+                "announce is set, but other machines can't reach {addr}; bind e.g. 0.0.0.0"
+            );
+        } else {
+            tokio::spawn(announce(state.clone(), chosen_port, tls_enabled));
+        }
+    }
+
+    let public_url = login_url(&run_info);
+    // Detached, this output goes to the log file; keep the token out of it
+    let shown_url = if io::stdout().is_terminal() {
+        public_url.clone()
+    } else {
+        run_info.local_url(&format!("/bootstrap?token={auth_token}"))
+    };
 
     // Verbose startup output
     println!();
     println!("  Starting tbl server...");
     println!("  ───────────────────────────────────────");
-    println!("  Address: {}://{}", scheme, addr);
-    println!("  TLS:     {}", if tls_enabled { "enabled" } else { "disabled" });
+    let port_note = match port_spec {
+        _ if socket_activated => " (socket from the service manager)",
+        PortSpec::Number(0) => " (port picked by the OS)",
+        PortSpec::Number(_) => "",
+        PortSpec::Random => " (random port)",
+    };
+    println!("  Address: {}://{}{}", scheme, addr, port_note);
+    println!(
+        "  TLS:     {}",
+        if tls_enabled { "enabled" } else { "disabled" }
+    );
+    match effective_cfg.access.unwrap_or_default() {
+        Access::Public => {
+            println!("  Access:  public, no sign-in at {scheme}://{addr}{base_path}/web/")
+        }
+        access => println!("  Access:  {}", access.shown()),
+    }
+    if effective_cfg.sandbox == Some(Sandbox::Strict) {
+        println!("  Sandbox: strict (Landlock and seccomp)");
+    }
     println!("  PID:     {}", std::process::id());
     println!();
-    print_url_box(&public_url);
+    print_url_box(&shown_url);
 
-    if !cli.no_browser {
+    if !no_browser && restarts == 0 && !socket_activated {
         println!("\n  Opening browser...");
         if let Err(e) = webbrowser::open(&public_url) {
             eprintln!("  Failed to open browser: {e}");
             eprintln!("  Open the URL above manually to authenticate.");
         }
+        timings.phase("browser open");
     } else {
         println!("\n  Open the URL above to authenticate.");
     }
     println!();
 
+    // Startup is over once the browser is open; `--timings` waits for this
+    let report = timings.finish();
+    run_info.timings = Some(report.clone());
+    if let Err(e) = save_run_info(&run_dir, &run_info) {
+        eprintln!("Failed to write pid.yaml: {e}");
+    }
+    if cli.timings {
+        report.print("other");
+    }
+
     // Store run_dir for cleanup on shutdown
     let run_dir_clone = run_dir.clone();
 
-    if tls_enabled {
-        let cert = effective_cfg.tls_cert.clone().unwrap();
-        let key = effective_cfg.tls_key.clone().unwrap();
-        let tls_config = RustlsConfig::from_pem_file(cert, key)
-            .await
-            .context("failed to load TLS cert/key")?;
-
-        let server = axum_server::bind_rustls(addr, tls_config)
-            .serve(app.into_make_service());
-
-        tokio::select! {
-            result = server => {
-                if let Err(e) = result {
-                    eprintln!("Server error: {e}");
-                }
-            }
-            _ = shutdown_rx => {
-                println!("  Shutdown signal received, stopping server...");
-            }
-        }
+    let handle = axum_server::Handle::new();
+    let stop_reason = if let Some(tls_config) = tls_config {
+        let mut server = axum_server::from_tcp_rustls(listener, tls_config)
+            .map(|tls| tls.acceptor(connections::Acceptor::new(connections, true)))
+            .handle(handle.clone());
+        tune_http(&mut server, &effective_cfg);
+        let server = server.serve(connections::MakeService::new(app));
+        serve_until_shutdown(server, handle, shutdown_rx).await
     } else {
-        let listener = TcpListener::bind(addr).await?;
-        let server = axum::serve(listener, app);
+        let mut server = axum_server::from_tcp(listener)
+            .acceptor(connections::Acceptor::new(connections, false))
+            .handle(handle.clone());
+        tune_http(&mut server, &effective_cfg);
+        let server = server.serve(connections::MakeService::new(app));
+        serve_until_shutdown(server, handle, shutdown_rx).await
+    };
+    if let Err(e) = uptime::stop(&state.db, state.run, now_unix(), stop_reason) {
+        tracing::warn!("Failed to record the stop: {e:#}");
+    }
 
-        tokio::select! {
-            result = server.into_future() => {
-                if let Err(e) = result {
-                    eprintln!("Server error: {e}");
-                }
-            }
-            _ = shutdown_rx => {
-                println!("  Shutdown signal received, stopping server...");
-            }
+    // A clone or fetch still running in a handler would keep the runtime
+    // from shutting down
+    cancel_git_operations();
+
+    // Cleanup pid.yaml on shutdown; the login link dies with the server
+    if let Some(info) = load_run_info(&run_dir_clone) {
+        if let Err(e) = state.tokens.revoke_token(&info.auth_token) {
+            tracing::warn!("Failed to revoke the login token: {e:#}");
         }
     }
-
-    // Cleanup pid.yaml on shutdown
     clear_run_info(&run_dir_clone);
+
+    // Give notifiers a moment to report the shutdown
+    emit_event(&state, "server.stopped", serde_json::json!({}));
+    if let Some(notifier) = notifier {
+        let _ = tokio::time::timeout(NOTIFY_SHUTDOWN_GRACE, notifier).await;
+    }
     println!("  tbl server stopped.");
 
     Ok(())
@@ -384,7 +1829,12 @@ async fn main() -> Result<()> {
 // Configuration Helpers
 // =============================================================================
 
-fn get_config_dir() -> Result<PathBuf> {
+/// Root config dir shared by all profiles: `~/.config/tbl`, or
+/// `/var/lib/tbl` in the system scope
+fn base_config_dir() -> Result<PathBuf> {
+    if is_system() {
+        return Ok(PathBuf::from(SYSTEM_STATE_DIR));
+    }
     if let Some(base) = dirs::config_dir() {
         Ok(base.join("tbl"))
     } else {
@@ -393,7 +1843,73 @@ fn get_config_dir() -> Result<PathBuf> {
     }
 }
 
+/// Config dir of the active profile; named profiles live in `profiles/<name>/`.
+fn get_config_dir() -> Result<PathBuf> {
+    let base = base_config_dir()?;
+    Ok(match PROFILE.get().and_then(Option::as_deref) {
+        Some(name) => base.join("profiles").join(name),
+        None => base,
+    })
+}
+
+/// Dir of the files an administrator writes for the profile in
+/// `config_dir`: the config file, templates, and what its paths point at.
+/// In the system scope that is below `/etc/tbl` rather than the state dir.
+fn settings_dir(config_dir: &Path) -> PathBuf {
+    if !is_system() {
+        return config_dir.to_path_buf();
+    }
+    let profile = config_dir
+        .strip_prefix(SYSTEM_STATE_DIR)
+        .unwrap_or(Path::new(""));
+    Path::new(SYSTEM_CONFIG_DIR).join(profile)
+}
+
+/// Settings below the environment and flags: the config file, or in a
+/// container, which has none, the address from `PORT`.
+fn file_config(config_dir: &Path, container: bool) -> Result<TblConfig> {
+    if container {
+        return Ok(TblConfig {
+            addr: Some(container::default_addr()?),
+            ..TblConfig::default()
+        });
+    }
+    Ok(load_config(config_dir).unwrap_or_default())
+}
+
 fn load_config(config_dir: &Path) -> Option<TblConfig> {
+    if vault::is_encrypted(config_dir) {
+        return match vault::load(config_dir) {
+            Ok(json) => serde_json::from_slice(&json).ok(),
+            Err(e) => {
+                static WARNED: std::sync::Once = std::sync::Once::new();
+                WARNED.call_once(|| eprintln!("  {e:#}"));
+                None
+            }
+        };
+    }
+
+    if !is_system() {
+        return read_config_file(config_dir).and_then(|value| serde_json::from_value(value).ok());
+    }
+    // What tbl records itself (the workspace, its history, ...) goes on top
+    // of the administrator's config
+    let admin = read_config_file(&settings_dir(config_dir));
+    let recorded = fs::read(config_dir.join("config.json"))
+        .ok()
+        .and_then(|json| serde_json::from_slice::<serde_json::Value>(&json).ok());
+    let merged = match (admin, recorded) {
+        (Some(serde_json::Value::Object(mut admin)), Some(serde_json::Value::Object(recorded))) => {
+            admin.extend(recorded.into_iter().filter(|(_, value)| !value.is_null()));
+            serde_json::Value::Object(admin)
+        }
+        (admin, recorded) => recorded.or(admin)?,
+    };
+    serde_json::from_value(merged).ok()
+}
+
+/// The first config file in `dir` that holds a valid config, as JSON.
+fn read_config_file(dir: &Path) -> Option<serde_json::Value> {
     let candidates = [
         ("config.json", "json"),
         ("config.yaml", "yaml"),
@@ -402,19 +1918,22 @@ fn load_config(config_dir: &Path) -> Option<TblConfig> {
     ];
 
     for (file, kind) in candidates {
-        let path = config_dir.join(file);
+        let path = dir.join(file);
         if !path.exists() {
             continue;
         }
         if let Ok(content) = fs::read_to_string(&path) {
             let parsed = match kind {
-                "json" => serde_json::from_str::<TblConfig>(&content).ok(),
-                "yaml" => serde_yaml::from_str::<TblConfig>(&content).ok(),
-                "toml" => toml::from_str::<TblConfig>(&content).ok(),
+                "json" => serde_json::from_str::<serde_json::Value>(&content).ok(),
+                "yaml" => serde_yaml::from_str::<serde_json::Value>(&content).ok(),
+                "toml" => toml::from_str::<serde_json::Value>(&content).ok(),
                 _ => None,
             };
-            if let Some(cfg) = parsed {
-                return Some(cfg);
+            let valid = parsed
+                .as_ref()
+                .is_some_and(|value| TblConfig::deserialize(value).is_ok());
+            if valid {
+                return parsed;
             }
         }
     }
@@ -422,855 +1941,9956 @@ fn load_config(config_dir: &Path) -> Option<TblConfig> {
 }
 
 fn save_config(config_dir: &Path, cfg: &TblConfig) -> Result<()> {
-    let path = config_dir.join("config.json");
-    let json = serde_json::to_vec_pretty(cfg)?;
-    fs::write(path, json)?;
+    // A password given on the command line or via env stays out of the file
+    let mut cfg = cfg.clone();
+    if cfg.basic_pass.as_ref().is_some_and(Secret::is_transient) {
+        cfg.basic_pass = load_config(config_dir).and_then(|file| file.basic_pass);
+    }
+    if cfg.peer_token.as_ref().is_some_and(Secret::is_transient) {
+        cfg.peer_token = load_config(config_dir).and_then(|file| file.peer_token);
+    }
+    if vault::is_encrypted(config_dir) {
+        return vault::save(config_dir, &serde_json::to_vec_pretty(&cfg)?);
+    }
+    let mut value = serde_json::to_value(&cfg)?;
+    // Only what differs from the administrator's config is recorded, so
+    // later edits of it still apply
+    if let (true, serde_json::Value::Object(recorded)) = (is_system(), &mut value) {
+        let admin = read_config_file(&settings_dir(config_dir)).unwrap_or_default();
+        recorded.retain(|key, value| !value.is_null() && admin.get(key) != Some(value));
+    }
+    fs::write(
+        config_dir.join("config.json"),
+        serde_json::to_vec_pretty(&value)?,
+    )?;
     Ok(())
 }
 
-// =============================================================================
-// Runtime Info Helpers
-// =============================================================================
-
-fn load_run_info(run_dir: &Path) -> Option<RunInfo> {
-    let path = run_dir.join("pid.yaml");
-    let content = fs::read_to_string(path).ok()?;
-    serde_yaml::from_str::<RunInfo>(&content).ok()
+/// Expand a leading `~` and `$VAR` / `${VAR}` references in a path-valued
+/// config setting. An unset variable is an error rather than an empty string.
+fn expand_path(value: &str) -> Result<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    if rest == "~" || rest.starts_with("~/") || (cfg!(windows) && rest.starts_with("~\\")) {
+        let home = dirs::home_dir().context("cannot expand ~: home directory unknown")?;
+        out.push_str(&home.display().to_string());
+        rest = &rest[1..];
+    }
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let (name, tail) = match after.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced.find('}').context("unclosed ${")?;
+                (&braced[..end], &braced[end + 1..])
+            }
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        if name.is_empty() {
+            // A lone `$` is part of the path
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let value = std::env::var(name)
+            .map_err(|_| anyhow::anyhow!("environment variable {name} is not set"))?;
+        out.push_str(&value);
+        rest = tail;
+    }
+    out.push_str(rest);
+    Ok(out)
 }
 
-fn save_run_info(run_dir: &Path, info: &RunInfo) -> Result<()> {
-    fs::create_dir_all(run_dir)?;
-    let yaml = serde_yaml::to_string(info)?;
-    fs::write(run_dir.join("pid.yaml"), yaml)?;
-    Ok(())
+/// A path-valued config setting, expanded and resolved against the
+/// profile's [`settings_dir`].
+fn config_path(config_dir: &Path, value: &str) -> Result<PathBuf> {
+    Ok(settings_dir(config_dir).join(expand_path(value)?))
 }
 
-fn clear_run_info(run_dir: &Path) {
-    let _ = fs::remove_file(run_dir.join("pid.yaml"));
+/// Check that every path-valued setting expands, so a typo in a variable
+/// name fails at startup instead of when the path is first used.
+fn validate_config_paths(config_dir: &Path, cfg: &TblConfig) -> Result<()> {
+    for (key, value) in config_file_settings(cfg) {
+        config_path(config_dir, value).with_context(|| format!("Invalid {key} {value:?}"))?;
+    }
+    Ok(())
 }
 
-// =============================================================================
-// Port Detection
-// =============================================================================
-
-fn port_is_open(port: u16) -> bool {
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    TcpStream::connect_timeout(&addr, Duration::from_millis(200)).is_ok()
+/// Settings that name a file, with their values.
+fn config_file_settings(cfg: &TblConfig) -> Vec<(&'static str, &String)> {
+    let logo = cfg
+        .branding
+        .as_ref()
+        .and_then(|branding| branding.logo.as_ref())
+        .filter(|logo| !logo.starts_with("data:"));
+    let sftp = cfg.sftp.as_ref();
+    [
+        ("tls_cert", cfg.tls_cert.as_ref()),
+        ("tls_key", cfg.tls_key.as_ref()),
+        ("branding.logo", logo),
+        (
+            "sftp.identity_file",
+            sftp.and_then(|sftp| sftp.identity_file.as_ref()),
+        ),
+        (
+            "sftp.known_hosts_file",
+            sftp.and_then(|sftp| sftp.known_hosts_file.as_ref()),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((key, value?)))
+    .collect()
 }
 
-fn split_host_port(addr: &str) -> Result<(String, u16)> {
-    if let Some(pos) = addr.rfind(':') {
-        let (host, port_str) = addr.split_at(pos);
-        let host = host.to_string();
-        let port_str = &port_str[1..];
-        let port: u16 = port_str
-            .parse()
-            .with_context(|| format!("invalid port in addr: {addr}"))?;
-        Ok((host, port))
+/// `path` made absolute against `base`.
+fn absolute_path(base: &Path, path: String) -> String {
+    if Path::new(&path).is_absolute() {
+        path
     } else {
-        anyhow::bail!("addr must be in host:port form, got: {addr}");
+        base.join(path).display().to_string()
     }
 }
 
-fn find_available_port(host: &str, base_port: u16) -> u16 {
-    let mut port = base_port;
-    for _ in 0..100 {
-        let addr_str = format!("{host}:{port}");
-        if let Ok(sock_addr) = addr_str.parse::<SocketAddr>() {
-            if TcpStream::connect_timeout(&sock_addr, Duration::from_millis(150)).is_err() {
-                return port;
-            }
-        }
-        port = port.saturating_add(1);
+/// A git URL that is a relative path to a local repository, made absolute
+/// against `base` so the daemon and later runs read it the same way.
+fn local_git_url(base: &Path, url: String) -> String {
+    let path = base.join(&url);
+    if url.contains(':') || Path::new(&url).is_absolute() || !path.exists() {
+        return url;
     }
-    base_port
+    fs::canonicalize(path)
+        .map(|path| path.display().to_string())
+        .unwrap_or(url)
 }
 
-// =============================================================================
-// Git Integration
-// =============================================================================
+/// Canonical identity of a repository URL, equal for the spellings of one
+/// repository: `git@github.com:you/app.git`, `https://github.com/you/app`,
+/// and `ssh://git@github.com:22/you/app/` are all `github.com/you/app`.
+/// Credentials, default ports, and a trailing `.git` are dropped and the host
+/// is lowercased; local paths are made canonical.
+fn repo_identity(url: &str) -> String {
+    let url = url.trim();
+    let (host, path) = match url.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("file") => {
+            return local_repo_identity(rest)
+        }
+        Some((_, rest)) => rest.split_once('/').unwrap_or((rest, "")),
+        // scp-like `[user@]host:path`; a single letter is a Windows drive
+        None => match url.split_once(':') {
+            Some((host, path)) if !host.contains('/') && host.len() > 1 => (host, path),
+            _ => return local_repo_identity(url),
+        },
+    };
+    let host = host.rsplit('@').next().unwrap_or(host);
+    let host = match host.rsplit_once(':') {
+        Some((name, "22" | "80" | "443" | "9418")) => name,
+        _ => host,
+    };
+    let path = path.trim_matches('/');
+    let path = path
+        .strip_suffix(".git")
+        .unwrap_or(path)
+        .trim_end_matches('/');
+    format!("{}/{path}", host.to_ascii_lowercase())
+}
 
-fn ensure_git_available() -> Result<()> {
-    let status = Command::new("git")
-        .arg("--version")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status();
+/// Identity of a repository on this machine: its canonical path, without a
+/// trailing `.git` directory.
+fn local_repo_identity(path: &str) -> String {
+    let path = Path::new(path);
+    let path = path
+        .strip_prefix("~")
+        .ok()
+        .zip(dirs::home_dir())
+        .map_or_else(|| path.to_path_buf(), |(rest, home)| home.join(rest));
+    let path = fs::canonicalize(&path).unwrap_or(path);
+    let path = match path.file_name() {
+        Some(name) if name == ".git" => path.parent().map(Path::to_path_buf).unwrap_or(path),
+        _ => path,
+    };
+    path.display().to_string()
+}
 
-    match status {
-        Ok(s) if s.success() => Ok(()),
-        _ => {
-            eprintln!("`git` was not found or is not working.");
-
-            let os = std::env::consts::OS;
-            match os {
-                "macos" => {
-                    eprintln!("Install git on macOS:");
-                    eprintln!("  xcode-select --install");
-                    eprintln!("or using Homebrew:");
-                    eprintln!("  brew install git");
-                }
-                "windows" => {
-                    eprintln!("Install Git for Windows from:");
-                    eprintln!("  https://git-scm.com/download/win");
-                    eprintln!("or via winget:");
-                    eprintln!("  winget install --id Git.Git -e");
-                }
-                "linux" => {
-                    eprintln!("Install git on Linux:");
-                    eprintln!("  Debian/Ubuntu: sudo apt-get install git");
-                    eprintln!("  Fedora:        sudo dnf install git");
-                    eprintln!("  Arch Linux:    sudo pacman -S git");
-                }
-                _ => {
-                    eprintln!("Please install git from https://git-scm.com/downloads");
-                }
-            }
+/// Move `url` to the front of the history, stamping it with the current time.
+/// Other spellings of the same repository are dropped.
+fn record_history(cfg: &mut TblConfig, url: &str) {
+    let id = repo_identity(url);
+    cfg.history
+        .retain(|entry| repo_identity(&entry.git_url) != id);
+    cfg.history.insert(
+        0,
+        HistoryEntry {
+            git_url: url.to_string(),
+            last_used: now_unix(),
+        },
+    );
+    cfg.history.truncate(MAX_HISTORY);
+}
 
-            anyhow::bail!("git not available on PATH");
-        }
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Human-friendly size, e.g. "1.5 MB".
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{bytes} B"),
+        1024..=1_048_575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        1_048_576..=1_073_741_823 => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+        _ => format!("{:.1} GB", bytes as f64 / 1_073_741_824.0),
     }
 }
 
-fn ensure_repo(config_dir: &Path, url: &str) -> Result<()> {
-    let web_dir = config_dir.join("web");
-    let git_dir = web_dir.join(".git");
+// =============================================================================
+// Effective Configuration
+// =============================================================================
 
-    if web_dir.exists() && git_dir.exists() {
-        // Update existing repo
-        let status_fetch = Command::new("git")
-            .arg("-C")
-            .arg(&web_dir)
-            .arg("fetch")
-            .arg("--depth")
-            .arg("1")
-            .arg("origin")
-            .status()
-            .with_context(|| "failed to execute git fetch")?;
-
-        if !status_fetch.success() {
-            eprintln!("git fetch failed, keeping existing checkout");
-            return Ok(());
+const DEFAULT_ADDR: &str = "127.0.0.1:1234";
+
+/// Merge command-line flags, `TBL_*` environment variables, and the config
+/// file, in that order of precedence, into the settings the server runs with.
+///
+/// Relative paths from the command line or environment are relative to `cwd`,
+/// the directory tbl was started in (which the daemon inherits); paths from
+/// the config file are resolved against the config dir where they are used.
+fn effective_config(cli: &Cli, file_cfg: TblConfig, cwd: &Path) -> (TblConfig, SettingOrigins) {
+    let env = |name: &str| std::env::var(name).ok();
+    let env_flag =
+        |name: &str| env(name).map(|value| matches!(value.as_str(), "1" | "true" | "yes" | "on"));
+    let cli_path = |path: String| absolute_path(cwd, path);
+    let cli_url = |url: String| local_git_url(cwd, url);
+
+    // `--port` replaces the port of whichever addr applies
+    let cli_addr = match cli.port.filter(|_| !cli.stop) {
+        Some(port) => {
+            let addr = cli
+                .addr
+                .clone()
+                .or_else(|| env("TBL_ADDR"))
+                .or_else(|| file_cfg.addr.clone())
+                .unwrap_or_else(|| DEFAULT_ADDR.to_string());
+            let host = addr
+                .rsplit_once(':')
+                .map_or(addr.as_str(), |(host, _)| host);
+            Some(format!("{host}:{port}"))
         }
+        None => cli.addr.clone(),
+    };
 
-        let status_reset = Command::new("git")
-            .arg("-C")
-            .arg(&web_dir)
-            .arg("reset")
-            .arg("--hard")
-            .arg("origin/HEAD")
-            .status()
-            .with_context(|| "failed to execute git reset")?;
+    let mut origins = SettingOrigins::default();
+    let cfg = TblConfig {
+        git_url: origins.pick(
+            "git_url",
+            cli.git_url.clone().map(cli_url),
+            env("TBL_GIT_URL").map(cli_url),
+            file_cfg.git_url,
+        ),
+        addr: Some(origins.pick_or(
+            "addr",
+            cli_addr,
+            env("TBL_ADDR"),
+            file_cfg.addr,
+            DEFAULT_ADDR.to_string(),
+        )),
+        port_range: origins.pick(
+            "port_range",
+            cli.port_range.clone(),
+            env("TBL_PORT_RANGE"),
+            file_cfg.port_range,
+        ),
+        bind_retries: origins.pick(
+            "bind_retries",
+            cli.bind_retries,
+            env("TBL_BIND_RETRIES").and_then(|value| value.parse().ok()),
+            file_cfg.bind_retries,
+        ),
+        max_connections: origins.pick(
+            "max_connections",
+            cli.max_connections,
+            env("TBL_MAX_CONNECTIONS").and_then(|value| value.parse().ok()),
+            file_cfg.max_connections,
+        ),
+        max_connections_per_ip: origins.pick(
+            "max_connections_per_ip",
+            cli.max_connections_per_ip,
+            env("TBL_MAX_CONNECTIONS_PER_IP").and_then(|value| value.parse().ok()),
+            file_cfg.max_connections_per_ip,
+        ),
+        worker_threads: origins.pick(
+            "worker_threads",
+            None,
+            env("TBL_WORKER_THREADS").and_then(|value| value.parse().ok()),
+            file_cfg.worker_threads,
+        ),
+        max_blocking_threads: origins.pick(
+            "max_blocking_threads",
+            None,
+            env("TBL_MAX_BLOCKING_THREADS").and_then(|value| value.parse().ok()),
+            file_cfg.max_blocking_threads,
+        ),
+        http1_pipeline_flush: origins.pick(
+            "http1_pipeline_flush",
+            None,
+            env_flag("TBL_HTTP1_PIPELINE_FLUSH"),
+            file_cfg.http1_pipeline_flush,
+        ),
+        http1_max_buf_size: origins.pick(
+            "http1_max_buf_size",
+            None,
+            env("TBL_HTTP1_MAX_BUF_SIZE").and_then(|value| value.parse().ok()),
+            file_cfg.http1_max_buf_size,
+        ),
+        base_path: origins.pick(
+            "base_path",
+            cli.base_path.clone(),
+            env("TBL_BASE_PATH"),
+            file_cfg.base_path,
+        ),
+        trusted_proxies: origins.pick(
+            "trusted_proxies",
+            cli.trusted_proxies.clone(),
+            env("TBL_TRUSTED_PROXIES").map(|value| {
+                value
+                    .split(',')
+                    .map(|cidr| cidr.trim().to_string())
+                    .filter(|cidr| !cidr.is_empty())
+                    .collect()
+            }),
+            file_cfg.trusted_proxies,
+        ),
+        tls_cert: origins.pick(
+            "tls_cert",
+            cli.tls_cert.clone().map(cli_path),
+            env("TBL_TLS_CERT").map(cli_path),
+            file_cfg.tls_cert,
+        ),
+        tls_key: origins.pick(
+            "tls_key",
+            cli.tls_key.clone().map(cli_path),
+            env("TBL_TLS_KEY").map(cli_path),
+            file_cfg.tls_key,
+        ),
+        basic_user: origins.pick(
+            "basic_user",
+            cli.basic_user.clone(),
+            env("TBL_BASIC_USER"),
+            file_cfg.basic_user,
+        ),
+        basic_pass: origins.pick(
+            "basic_pass",
+            cli.basic_pass.clone(),
+            env("TBL_BASIC_PASS").map(Secret::transient),
+            file_cfg.basic_pass,
+        ),
+        peer_token: origins.pick(
+            "peer_token",
+            cli.peer_token.clone(),
+            env("TBL_PEER_TOKEN").map(Secret::transient),
+            file_cfg.peer_token,
+        ),
+        announce: origins.pick(
+            "announce",
+            cli.announce.then_some(true),
+            env_flag("TBL_ANNOUNCE"),
+            file_cfg.announce,
+        ),
+        catalog_url: origins.pick(
+            "catalog_url",
+            cli.catalog_url.clone(),
+            env("TBL_CATALOG_URL"),
+            file_cfg.catalog_url,
+        ),
+        catalog_app: file_cfg.catalog_app,
+        repo_id: file_cfg.repo_id,
+        default_branch: file_cfg.default_branch,
+        history: file_cfg.history,
+        branding: file_cfg.branding,
+        description: file_cfg.description,
+        // `--watchdog` and `TBL_WATCHDOG` only apply to this run (see
+        // `watchdog_enabled`), so just the file value is kept
+        watchdog: file_cfg.watchdog,
+        idle_timeout: origins.pick(
+            "idle_timeout",
+            cli.idle_timeout.clone(),
+            env("TBL_IDLE_TIMEOUT"),
+            file_cfg.idle_timeout,
+        ),
+        serve_hours: origins.pick(
+            "serve_hours",
+            cli.serve_hours.clone(),
+            env("TBL_SERVE_HOURS"),
+            file_cfg.serve_hours,
+        ),
+        outside_hours: origins.pick(
+            "outside_hours",
+            cli.outside_hours,
+            env("TBL_OUTSIDE_HOURS").and_then(|value| OutsideHours::from_str(&value, true).ok()),
+            file_cfg.outside_hours,
+        ),
+        sync_schedule: origins.pick(
+            "sync_schedule",
+            cli.sync_schedule.clone(),
+            env("TBL_SYNC_SCHEDULE"),
+            file_cfg.sync_schedule,
+        ),
+        lazy_sync: origins.pick(
+            "lazy_sync",
+            cli.lazy_sync.then_some(true),
+            env_flag("TBL_LAZY_SYNC"),
+            file_cfg.lazy_sync,
+        ),
+        metered: origins.pick(
+            "metered",
+            cli.metered,
+            env("TBL_METERED").and_then(|value| Metered::from_str(&value, true).ok()),
+            file_cfg.metered,
+        ),
+        git_timeout: origins.pick(
+            "git_timeout",
+            cli.git_timeout.clone(),
+            env("TBL_GIT_TIMEOUT"),
+            file_cfg.git_timeout,
+        ),
+        git_proxy: origins.pick(
+            "git_proxy",
+            cli.git_proxy.clone(),
+            env("TBL_GIT_PROXY"),
+            file_cfg.git_proxy,
+        ),
+        clone_depth: origins.pick(
+            "clone_depth",
+            cli.clone_depth,
+            env("TBL_CLONE_DEPTH").and_then(|value| value.parse().ok()),
+            file_cfg.clone_depth,
+        ),
+        git_cache: origins.pick(
+            "git_cache",
+            cli.git_cache.then_some(true),
+            env_flag("TBL_GIT_CACHE"),
+            file_cfg.git_cache,
+        ),
+        keep_releases: origins.pick(
+            "keep_releases",
+            cli.keep_releases,
+            env("TBL_KEEP_RELEASES").and_then(|value| value.parse().ok()),
+            file_cfg.keep_releases,
+        ),
+        previews: origins.pick(
+            "previews",
+            cli.previews.then_some(true),
+            env_flag("TBL_PREVIEWS"),
+            file_cfg.previews,
+        ),
+        git_mirrors: file_cfg.git_mirrors,
+        s3: file_cfg.s3,
+        sftp: file_cfg.sftp,
+        hooks: file_cfg.hooks,
+        memory: file_cfg.memory,
+        quota: file_cfg.quota,
+        backup: file_cfg.backup,
+        providers: file_cfg.providers,
+        sync_jobs: origins.pick("sync_jobs", None, None, file_cfg.sync_jobs),
+        sync_timeout: origins.pick("sync_timeout", None, None, file_cfg.sync_timeout),
+        notifications: file_cfg.notifications,
+        access: origins.pick(
+            "access",
+            cli.access.or(cli.public_web.then_some(Access::Public)),
+            env("TBL_ACCESS")
+                .and_then(|value| Access::from_str(&value, true).ok())
+                .or(env_flag("TBL_PUBLIC_WEB")
+                    .filter(|public| *public)
+                    .map(|_| Access::Public)),
+            file_cfg.access,
+        ),
+        remote_auth: origins.pick(
+            "remote_auth",
+            cli.remote_auth,
+            env("TBL_REMOTE_AUTH").and_then(|value| RemoteAuth::from_str(&value, true).ok()),
+            file_cfg.remote_auth,
+        ),
+        disable: origins
+            .pick(
+                "disable",
+                cli.disable.clone(),
+                env("TBL_DISABLE").map(|value| {
+                    value
+                        .split(',')
+                        .filter_map(|name| Capability::from_str(name.trim(), true).ok())
+                        .collect()
+                }),
+                (!file_cfg.disable.is_empty()).then_some(file_cfg.disable),
+            )
+            .unwrap_or_default(),
+        user: origins.pick("user", cli.user.clone(), env("TBL_USER"), file_cfg.user),
+        sandbox: origins.pick(
+            "sandbox",
+            cli.sandbox,
+            env("TBL_SANDBOX").and_then(|value| Sandbox::from_str(&value, true).ok()),
+            file_cfg.sandbox,
+        ),
+    };
+    origins.pick(
+        "watchdog",
+        cli.watchdog.then_some(true),
+        env_flag("TBL_WATCHDOG"),
+        cfg.watchdog,
+    );
 
-        if !status_reset.success() {
-            eprintln!("git reset failed, keeping existing checkout");
-        }
+    // Defaults applied where the setting is used
+    origins.default_is("port_range", DEFAULT_PORT_RANGE.to_string());
+    origins.default_is("bind_retries", DEFAULT_BIND_RETRIES.shown());
+    origins.default_is(
+        "max_connections",
+        connections::DEFAULT_MAX_CONNECTIONS.shown(),
+    );
+    origins.default_is(
+        "max_connections_per_ip",
+        connections::DEFAULT_MAX_CONNECTIONS_PER_IP.shown(),
+    );
+    origins.default_is(
+        "worker_threads",
+        std::thread::available_parallelism()
+            .map_or(1, |cores| cores.get())
+            .shown(),
+    );
+    origins.default_is("max_blocking_threads", DEFAULT_MAX_BLOCKING_THREADS.shown());
+    origins.default_is("http1_pipeline_flush", false.shown());
+    origins.default_is("http1_max_buf_size", DEFAULT_HTTP1_MAX_BUF_SIZE.shown());
+    origins.default_is("trusted_proxies", proxy::DEFAULT_TRUSTED_PROXIES.join(", "));
+    origins.default_is("outside_hours", OutsideHours::default().shown());
+    origins.default_is("lazy_sync", false.shown());
+    origins.default_is("announce", false.shown());
+    origins.default_is("metered", Metered::default().shown());
+    origins.default_is(
+        "git_timeout",
+        format!("{}m", DEFAULT_GIT_TIMEOUT.as_secs() / 60),
+    );
+    origins.default_is("clone_depth", source::DEFAULT_CLONE_DEPTH.shown());
+    origins.default_is("git_cache", false.shown());
+    origins.default_is("keep_releases", 0usize.shown());
+    origins.default_is("previews", false.shown());
+    origins.default_is("sync_jobs", DEFAULT_SYNC_JOBS.shown());
+    origins.default_is(
+        "sync_timeout",
+        format!("{}m", DEFAULT_SYNC_TIMEOUT.as_secs() / 60),
+    );
+    origins.default_is("watchdog", false.shown());
+    origins.default_is("access", Access::default().shown());
+    origins.default_is("remote_auth", RemoteAuth::default().shown());
 
-        return Ok(());
-    }
+    (cfg, origins)
+}
+
+/// Where an effective setting came from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SettingOrigin {
+    Flag,
+    Env,
+    File,
+    Default,
+    Unset,
+}
+
+/// A setting value as `tbl config explain` prints it.
+trait ShownValue {
+    fn shown(&self) -> String;
+}
 
-    // Fresh clone
-    if web_dir.exists() {
-        fs::remove_dir_all(&web_dir)?;
+impl ShownValue for String {
+    fn shown(&self) -> String {
+        redact_credentials(self)
     }
+}
 
-    let status = Command::new("git")
-        .arg("clone")
-        .arg("--depth")
-        .arg("1")
-        .arg(url)
-        .arg(&web_dir)
-        .status()
-        .with_context(|| "failed to execute git clone")?;
+impl ShownValue for Secret {
+    fn shown(&self) -> String {
+        self.to_string()
+    }
+}
 
-    if !status.success() {
-        anyhow::bail!("git clone failed with status {status}");
+impl ShownValue for bool {
+    fn shown(&self) -> String {
+        self.to_string()
     }
+}
 
-    Ok(())
+impl ShownValue for Vec<String> {
+    fn shown(&self) -> String {
+        self.join(", ")
+    }
 }
 
-// =============================================================================
-// Authentication Helpers
-// =============================================================================
+impl ShownValue for Vec<Capability> {
+    fn shown(&self) -> String {
+        self.iter()
+            .map(|capability| capability.name())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
 
-fn generate_token() -> String {
-    let mut buf = [0u8; 32];
-    rand::rngs::OsRng.fill_bytes(&mut buf);
-    hex::encode(buf)
+impl ShownValue for usize {
+    fn shown(&self) -> String {
+        self.to_string()
+    }
 }
 
-fn extract_token_from_cookie(headers: &HeaderMap) -> Option<String> {
-    let cookie_header = headers.get(header::COOKIE)?.to_str().ok()?;
+impl ShownValue for u32 {
+    fn shown(&self) -> String {
+        self.to_string()
+    }
+}
 
-    for part in cookie_header.split(';') {
-        let trimmed = part.trim();
-        let mut kv = trimmed.splitn(2, '=');
-        if let (Some(name), Some(value)) = (kv.next(), kv.next()) {
-            if name == "tbl_token" {
-                return Some(value.to_string());
-            }
-        }
+impl ShownValue for OutsideHours {
+    fn shown(&self) -> String {
+        job_label(self)
     }
+}
 
-    None
+impl ShownValue for Metered {
+    fn shown(&self) -> String {
+        job_label(self)
+    }
 }
 
-fn check_basic_auth(headers: &HeaderMap, user: &str, pass: &str) -> bool {
-    let header_val = match headers
-        .get(header::AUTHORIZATION)
-        .and_then(|v| v.to_str().ok())
-    {
-        Some(v) => v,
-        None => return false,
-    };
+impl ShownValue for Access {
+    fn shown(&self) -> String {
+        job_label(self)
+    }
+}
 
-    if !header_val.starts_with("Basic ") {
-        return false;
+impl ShownValue for RemoteAuth {
+    fn shown(&self) -> String {
+        job_label(self)
     }
+}
 
-    let b64 = &header_val[6..];
-    let decoded = match BASE64.decode(b64) {
-        Ok(d) => d,
-        Err(_) => return false,
-    };
+impl ShownValue for Sandbox {
+    fn shown(&self) -> String {
+        job_label(self)
+    }
+}
 
-    let creds = match String::from_utf8(decoded) {
-        Ok(s) => s,
-        Err(_) => return false,
-    };
+/// Effective settings in merge order, each with its printed value (if set)
+/// and origin.
+#[derive(Default)]
+struct SettingOrigins(Vec<(&'static str, Option<String>, SettingOrigin)>);
+
+impl SettingOrigins {
+    /// The first of the CLI, environment, and config file values that is set.
+    fn pick<T: ShownValue>(
+        &mut self,
+        key: &'static str,
+        cli: Option<T>,
+        env: Option<T>,
+        file: Option<T>,
+    ) -> Option<T> {
+        let (value, origin) = match (cli, env, file) {
+            (Some(value), _, _) => (Some(value), SettingOrigin::Flag),
+            (None, Some(value), _) => (Some(value), SettingOrigin::Env),
+            (None, None, Some(value)) => (Some(value), SettingOrigin::File),
+            (None, None, None) => (None, SettingOrigin::Unset),
+        };
+        self.0
+            .push((key, value.as_ref().map(ShownValue::shown), origin));
+        value
+    }
 
-    let mut parts = creds.splitn(2, ':');
-    let u = parts.next().unwrap_or("");
-    let p = parts.next().unwrap_or("");
+    /// Like [`Self::pick`], falling back to `default`.
+    fn pick_or<T: ShownValue>(
+        &mut self,
+        key: &'static str,
+        cli: Option<T>,
+        env: Option<T>,
+        file: Option<T>,
+        default: T,
+    ) -> T {
+        let value = self.pick(key, cli, env, file);
+        value.unwrap_or_else(|| {
+            self.default_is(key, default.shown());
+            default
+        })
+    }
 
-    u == user && p == pass
+    /// Record the default an unset setting falls back to where it is used.
+    fn default_is(&mut self, key: &str, shown: String) {
+        if let Some(entry) = self
+            .0
+            .iter_mut()
+            .find(|(name, _, origin)| *name == key && *origin == SettingOrigin::Unset)
+        {
+            entry.1 = Some(shown);
+            entry.2 = SettingOrigin::Default;
+        }
+    }
 }
 
 // =============================================================================
-// HTTP Handlers
+// Profiles
 // =============================================================================
 
-/// Root handler: redirect to /web/ if content exists, otherwise show setup page
-async fn index_handler(State(state): State<Arc<AppState>>) -> Response {
-    let index = state.web_root.join("index.html");
-    if index.exists() {
-        Redirect::temporary("/web/").into_response()
-    } else {
-        Html(setup_page_html()).into_response()
+/// Profile name used for the base config dir.
+const DEFAULT_PROFILE: &str = "default";
+
+/// Active profile, set once at startup from `--profile` or `TBL_PROFILE`.
+static PROFILE: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+
+fn init_profile(cli_profile: Option<String>) -> Result<()> {
+    let profile = cli_profile
+        .or_else(|| std::env::var("TBL_PROFILE").ok())
+        .filter(|name| !name.is_empty() && name != DEFAULT_PROFILE);
+    if let Some(name) = &profile {
+        if !valid_namespace(name) {
+            anyhow::bail!("Invalid profile name {name:?}: use letters, digits, '-', '_', and '.'");
+        }
     }
+    let _ = PROFILE.set(profile);
+    Ok(())
 }
 
-/// Bootstrap handler: validate token and set authentication cookie
-async fn bootstrap_handler(
-    State(state): State<Arc<AppState>>,
-    Query(q): Query<BootstrapQuery>,
-) -> Response {
-    let Some(token) = q.token else {
-        return (StatusCode::BAD_REQUEST, "missing token in query").into_response();
-    };
-
-    if token != state.auth_token {
-        return (StatusCode::FORBIDDEN, "invalid bootstrap token").into_response();
+/// Where the system scope keeps the config an administrator writes.
+const SYSTEM_CONFIG_DIR: &str = "/etc/tbl";
+/// Where the system scope keeps what tbl writes: the checkout, app data, ...
+const SYSTEM_STATE_DIR: &str = "/var/lib/tbl";
+/// Run state of the system scope, cleared on reboot.
+const SYSTEM_RUNTIME_DIR: &str = "/run/tbl";
+
+/// Whether the system-wide install is used, set once at startup from
+/// `--system` or `TBL_SYSTEM`.
+static SYSTEM: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+
+fn init_scope(cli_system: bool) -> Result<()> {
+    let system = cli_system
+        || std::env::var("TBL_SYSTEM")
+            .is_ok_and(|value| matches!(value.as_str(), "1" | "true" | "yes" | "on"));
+    if system && !cfg!(unix) {
+        anyhow::bail!("--system is only supported on Linux and other Unix systems");
     }
+    let _ = SYSTEM.set(system);
+    Ok(())
+}
 
-    Html(bootstrap_page_html(&token)).into_response()
+/// Whether tbl runs in the system scope rather than the user's.
+fn is_system() -> bool {
+    SYSTEM.get().copied().unwrap_or(false)
 }
 
-/// Setup handler: clone git repository and save config
-async fn setup_handler(
-    State(state): State<Arc<AppState>>,
-    Form(form): Form<SetupForm>,
-) -> Response {
-    let url = form.git_url.trim().to_string();
-    if url.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Html("<h1>Missing git URL</h1>".to_string()),
-        )
-            .into_response();
+/// The settings the daemon is about to serve with, read before it starts.
+fn serving_config(cli: &Cli) -> Result<TblConfig> {
+    let config_dir = get_config_dir()?;
+    let container = container::enabled(cli.container);
+    if vault::is_encrypted(&config_dir) && !container {
+        vault::load(&config_dir)?;
     }
+    let cwd = std::env::current_dir().context("Failed to get current directory")?;
+    let (cfg, _) = effective_config(cli, file_config(&config_dir, container)?, &cwd);
+    Ok(cfg)
+}
 
-    if let Err(e) = ensure_git_available() {
-        let body = format!(
-            r#"<!doctype html><html><body>
-            <h1>Git is required</h1>
-            <pre>{}</pre>
-            <p>Please install git and try again.</p>
-            </body></html>"#,
-            e
-        );
-        return (StatusCode::INTERNAL_SERVER_ERROR, Html(body)).into_response();
+/// Size the daemon's runtime as `worker_threads` and `max_blocking_threads`
+/// say.
+fn tune_runtime(runtime: &mut tokio::runtime::Builder, cfg: &TblConfig) -> Result<()> {
+    if let Some(threads) = cfg.worker_threads {
+        if threads == 0 {
+            anyhow::bail!("worker_threads must be at least 1");
+        }
+        runtime.worker_threads(threads);
     }
-
-    if let Err(e) = ensure_repo(&state.config_dir, &url) {
-        let body = format!(
-            r#"<!doctype html><html><body>
-            <h1>Failed to clone repository</h1>
-            <pre>{}</pre>
-            <p><a href="/">Back</a></p>
-            </body></html>"#,
-            e
-        );
-        return (StatusCode::INTERNAL_SERVER_ERROR, Html(body)).into_response();
+    if let Some(threads) = cfg.max_blocking_threads {
+        if threads == 0 {
+            anyhow::bail!("max_blocking_threads must be at least 1");
+        }
+        runtime.max_blocking_threads(threads);
     }
+    Ok(())
+}
 
-    // Persist config with new git_url
-    let mut cfg = state.config.clone();
-    cfg.git_url = Some(url);
-
-    if let Err(e) = save_config(&state.config_dir, &cfg) {
-        eprintln!("Failed to save config: {e}");
+/// Confine the daemon as `sandbox` says, before it does anything else.
+fn confine_daemon(cfg: &TblConfig) -> Result<()> {
+    if cfg.sandbox.unwrap_or_default() == Sandbox::Off {
+        return Ok(());
     }
+    let config_dir = get_config_dir()?;
 
-    Redirect::to("/").into_response()
+    // Directories only get rules if they exist
+    let mut rules = sandbox::Rules::default();
+    let writable = [
+        Some(base_config_dir()?),
+        base_runtime_dir(),
+        mirror::cache_dir(),
+        Some(std::env::temp_dir()),
+    ];
+    for dir in writable.into_iter().flatten() {
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        rules.read_write.push(dir);
+    }
+    rules.read_only.push(settings_dir(&config_dir));
+    rules.read_only.extend(std::env::current_exe().ok());
+    if let Some(paths) = std::env::var_os("PATH") {
+        rules.read_only.extend(std::env::split_paths(&paths));
+    }
+    // Settings of git and ssh
+    if let Some(home) = dirs::home_dir() {
+        rules.read_only.push(home.join(".gitconfig"));
+        rules.read_only.push(home.join(".ssh"));
+    }
+    rules
+        .read_only
+        .extend(dirs::config_dir().map(|dir| dir.join("git")));
+    for (_, value) in config_file_settings(cfg) {
+        rules.read_only.extend(config_path(&config_dir, value).ok());
+    }
+    // Local sources, including those in the history to switch back to
+    let urls = cfg
+        .git_url
+        .iter()
+        .chain(cfg.history.iter().map(|entry| &entry.git_url));
+    for url in urls {
+        let path = Path::new(url.strip_prefix("file://").unwrap_or(url));
+        if path.is_absolute() {
+            rules.read_only.push(path.to_path_buf());
+        }
+    }
+    sandbox::confine(&rules).context("Failed to confine the daemon")
 }
 
-/// Ping handler: authenticated health check endpoint
-async fn ping_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
-    // Optional basic auth
-    if let (Some(ref user), Some(ref pass)) = (&state.config.basic_user, &state.config.basic_pass) {
-        if !check_basic_auth(&headers, user, pass) {
-            return (
-                StatusCode::UNAUTHORIZED,
-                [(header::WWW_AUTHENTICATE, "Basic realm=\"tbl\"")],
-                "basic auth required",
-            )
-                .into_response();
+/// The account to serve as once the port is bound: `user`, when started as
+/// root in the system scope. Serving as root is refused otherwise.
+fn serving_user(cfg: &TblConfig) -> Result<Option<privileges::Account>> {
+    let Some(user) = cfg.user.as_deref() else {
+        if privileges::is_root() {
+            anyhow::bail!(
+                "Refusing to serve as root; start tbl as a regular user, or with --system --user <NAME> to serve as that user once the port is bound"
+            );
         }
+        return Ok(None);
+    };
+    if !is_system() {
+        anyhow::bail!("user only applies to the system-wide install; add --system");
     }
-
-    // Cookie-based auth
-    let token = extract_token_from_cookie(&headers);
-    if token.as_deref() != Some(&state.auth_token) {
-        return (StatusCode::UNAUTHORIZED, "missing or invalid auth cookie").into_response();
+    if !privileges::is_root() {
+        // Already unprivileged, e.g. started by systemd with `User=`
+        return Ok(None);
     }
+    let account = privileges::lookup(user)?;
+    if account.uid == 0 {
+        anyhow::bail!("user {user:?} is root; name an unprivileged user");
+    }
+    Ok(Some(account))
+}
 
-    let payload = PingResponse { status: "ok" };
-    let json = serde_json::to_string(&payload).unwrap_or_else(|_| r#"{"status":"ok"}"#.to_string());
-
-    (
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/json")],
-        json,
-    )
-        .into_response()
+/// Name of the active profile, for display.
+fn profile_label() -> &'static str {
+    PROFILE
+        .get()
+        .and_then(Option::as_deref)
+        .unwrap_or(DEFAULT_PROFILE)
 }
 
-/// Shutdown handler: authenticated endpoint to stop the server
-async fn shutdown_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
-    // Optional basic auth
-    if let (Some(ref user), Some(ref pass)) = (&state.config.basic_user, &state.config.basic_pass) {
-        if !check_basic_auth(&headers, user, pass) {
-            return (
-                StatusCode::UNAUTHORIZED,
-                [(header::WWW_AUTHENTICATE, "Basic realm=\"tbl\"")],
-                "basic auth required",
-            )
-                .into_response();
+/// Every profile with its config dir, the default profile first.
+fn list_profiles() -> Result<Vec<(String, PathBuf)>> {
+    let base = base_config_dir()?;
+    let mut profiles = vec![(DEFAULT_PROFILE.to_string(), base.clone())];
+    // System profiles may be configured in /etc/tbl before tbl first ran them
+    let mut named = BTreeMap::new();
+    for dir in [base.clone(), settings_dir(&base)] {
+        let Ok(entries) = fs::read_dir(dir.join("profiles")) else {
+            continue;
+        };
+        for entry in entries.filter_map(|e| e.ok()).filter(|e| e.path().is_dir()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let config_dir = base.join("profiles").join(&name);
+            named.entry(name).or_insert(config_dir);
         }
     }
+    profiles.extend(named);
+    Ok(profiles)
+}
 
-    // Cookie-based auth
-    let token = extract_token_from_cookie(&headers);
-    if token.as_deref() != Some(&state.auth_token) {
-        return (StatusCode::UNAUTHORIZED, "missing or invalid auth cookie").into_response();
-    }
+/// Which server instances `--stop` and `status` act on.
+enum InstanceSelector {
+    /// The active profile's server
+    Current,
+    /// Whichever profile's server listens on this port
+    Port(u16),
+    /// The servers of all profiles
+    All,
+}
 
-    // Trigger shutdown
-    let mut tx_guard = state.shutdown_tx.lock().await;
-    if let Some(tx) = tx_guard.take() {
-        let _ = tx.send(());
+impl InstanceSelector {
+    fn new(port: Option<u16>, all: bool) -> Self {
+        match (port, all) {
+            (_, true) => Self::All,
+            (Some(port), false) => Self::Port(port),
+            (None, false) => Self::Current,
+        }
     }
+}
 
-    let payload = ShutdownResponse { status: "shutting_down" };
-    let json = serde_json::to_string(&payload).unwrap_or_else(|_| r#"{"status":"shutting_down"}"#.to_string());
+/// A profile together with its recorded run state.
+struct Instance {
+    profile: String,
+    config_dir: PathBuf,
+    info: RunInfo,
+}
 
-    (
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/json")],
-        json,
-    )
-        .into_response()
+/// Instances with a `pid.yaml` that match `selector` (stale ones included).
+fn select_instances(selector: &InstanceSelector) -> Result<Vec<Instance>> {
+    let profiles = match selector {
+        InstanceSelector::Current => vec![(profile_label().to_string(), get_config_dir()?)],
+        InstanceSelector::Port(_) | InstanceSelector::All => list_profiles()?,
+    };
+    Ok(profiles
+        .into_iter()
+        .filter_map(|(profile, config_dir)| {
+            let info = load_run_info(&run_dir(&config_dir))?;
+            Some(Instance {
+                profile,
+                config_dir,
+                info,
+            })
+        })
+        .filter(|instance| match selector {
+            InstanceSelector::Port(port) => instance.info.port == *port,
+            _ => true,
+        })
+        .collect())
 }
 
-/// JavaScript SDK handler
-async fn tbl_js_handler() -> Response {
-    let js = r#"// tbl.js – tiny helper for tbl's local API
-(function () {
-  const apiBase = '/api/v1';
+/// Parse a duration such as `90s`, `30m`, `2h`, or `1d`; a bare number is seconds.
+fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("expected a duration like 30m, got {value:?}"))?;
+    let secs = match unit.trim() {
+        "" | "s" => number,
+        "m" => number * 60,
+        "h" => number * 3600,
+        "d" => number * 86400,
+        other => anyhow::bail!("unknown duration unit {other:?} in {value:?} (use s, m, h, or d)"),
+    };
+    Ok(Duration::from_secs(secs))
+}
 
-  async function request(path, opts) {
-    const url = apiBase + path;
-    const init = Object.assign(
-      {
-        credentials: 'include',
-        headers: {
-          'Content-Type': 'application/json',
-        },
-      },
-      opts || {}
-    );
+// =============================================================================
+// Runtime Info Helpers
+// =============================================================================
 
-    const res = await fetch(url, init);
-    if (!res.ok) {
-      const text = await res.text().catch(() => '');
-      throw new Error('API ' + res.status + ' ' + res.statusText + ': ' + text);
+/// Root for run state shared by all profiles: `$XDG_RUNTIME_DIR/tbl` on
+/// Linux, the per-user temp dir on macOS, or `None` where there is neither;
+/// `/run/tbl` in the system scope.
+fn base_runtime_dir() -> Option<PathBuf> {
+    if is_system() {
+        return Some(PathBuf::from(SYSTEM_RUNTIME_DIR));
     }
-
-    const ct = res.headers.get('content-type') || '';
-    if (ct.includes('application/json')) {
-      return res.json();
+    if let Some(dir) = dirs::runtime_dir() {
+        return Some(dir.join("tbl"));
     }
-    return res.text();
-  }
+    cfg!(target_os = "macos").then(|| std::env::temp_dir().join("tbl"))
+}
 
-  async function ping() {
-    return request('/ping');
-  }
+/// Run state (`pid.yaml`, job records) of the profile in `config_dir`. It
+/// lives in the runtime dir, so it does not survive a reboot or end up in
+/// config backups; without one it stays in `<config_dir>/run`. State left in
+/// `<config_dir>/run` by older versions is moved over on first use.
+fn run_dir(config_dir: &Path) -> PathBuf {
+    let legacy = config_dir.join("run");
+    let Some(dir) = base_runtime_dir().and_then(|runtime| {
+        let base = base_config_dir().ok()?;
+        Some(runtime.join(config_dir.strip_prefix(base).ok()?))
+    }) else {
+        return legacy;
+    };
+    if legacy.is_dir() {
+        if let Err(e) = move_dir(&legacy, &dir) {
+            eprintln!(
+                "  Failed to move run state from {} to {}: {e}",
+                legacy.display(),
+                dir.display()
+            );
+        }
+    }
+    dir
+}
 
-  window.tblApi = {
-    request,
-    ping,
-  };
-})();"#;
+/// Move the contents of `from` into `to` (copying where a rename cannot cross
+/// filesystems) and remove `from`. Entries already in `to` are kept.
+fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if target.exists() || fs::rename(entry.path(), &target).is_ok() {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            move_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    fs::remove_dir_all(from)
+}
 
-    (
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/javascript")],
-        js,
-    )
-        .into_response()
+fn load_run_info(run_dir: &Path) -> Option<RunInfo> {
+    let path = run_dir.join("pid.yaml");
+    let content = fs::read_to_string(path).ok()?;
+    serde_yaml::from_str::<RunInfo>(&content).ok()
+}
+
+fn save_run_info(run_dir: &Path, info: &RunInfo) -> Result<()> {
+    fs::create_dir_all(run_dir)?;
+    let yaml = serde_yaml::to_string(info)?;
+    fs::write(run_dir.join("pid.yaml"), yaml)?;
+    Ok(())
+}
+
+fn clear_run_info(run_dir: &Path) {
+    let _ = fs::remove_file(run_dir.join("pid.yaml"));
 }
 
 // =============================================================================
-// Embedded HTML Pages
+// Port Detection
 // =============================================================================
 
-fn bootstrap_page_html(token: &str) -> String {
-    format!(
-        r#"<!doctype html>
-<html lang="en">
-<head>
-  <meta charset="utf-8" />
-  <title>tbl – bootstrapping…</title>
-  <meta name="viewport" content="width=device-width, initial-scale=1" />
-  <style>
-    :root {{
-      color-scheme: light dark;
-      --bg: #0b1020;
-      --fg: #f5f5f7;
-      --accent: #4f46e5;
-      --accent-soft: rgba(79,70,229,0.16);
-      --border-subtle: rgba(148,163,184,0.35);
-    }}
-    * {{
-      box-sizing: border-box;
-      font-family: system-ui, -apple-system, BlinkMacSystemFont, "SF Pro Text",
-                   "Segoe UI", sans-serif;
-    }}
-    body {{
-      margin: 0;
-      min-height: 100vh;
-      display: flex;
-      align-items: center;
-      justify-content: center;
-      background: radial-gradient(circle at top, #1e293b, #020617 55%);
-      color: var(--fg);
-    }}
-    .card {{
-      background: rgba(15,23,42,0.95);
-      border-radius: 18px;
-      padding: 24px 28px;
-      box-shadow: 0 18px 40px rgba(15,23,42,0.85);
-      max-width: 420px;
-      width: 100%;
-      border: 1px solid var(--border-subtle);
-      backdrop-filter: blur(18px);
-    }}
-    .badge {{
-      display: inline-flex;
-      align-items: center;
-      gap: 8px;
-      font-size: 11px;
-      text-transform: uppercase;
-      letter-spacing: 0.12em;
-      padding: 4px 10px;
-      border-radius: 999px;
-      background: var(--accent-soft);
-      color: #c7d2fe;
-    }}
-    .pill-dot {{
-      width: 6px;
-      height: 6px;
-      border-radius: 999px;
-      background: #22c55e;
-      box-shadow: 0 0 0 4px rgba(34,197,94,0.35);
-    }}
-    h1 {{
-      margin: 14px 0 6px;
-      font-size: 22px;
-      font-weight: 600;
-    }}
-    p {{
-      margin: 6px 0 0;
-      font-size: 13px;
-      opacity: 0.8;
-    }}
-    .spinner {{
-      margin-top: 18px;
-      width: 26px;
-      height: 26px;
-      border-radius: 999px;
-      border: 3px solid rgba(148,163,184,0.4);
-      border-top-color: var(--accent);
-      animation: spin 0.8s linear infinite;
-    }}
-    @keyframes spin {{
-      to {{ transform: rotate(360deg); }}
-    }}
-  </style>
-</head>
-<body>
-  <div class="card">
-    <div class="badge">
-      <span class="pill-dot"></span>
-      <span>LOCAL SESSION</span>
-    </div>
-    <h1>Bootstrapping tbl</h1>
-    <p>We're securing your local API and loading your workspace.</p>
-    <div class="spinner"></div>
-  </div>
-  <script>
-    (function() {{
-      const token = "{token}";
-      document.cookie = "tbl_token=" + token + "; SameSite=Lax; Path=/";
-      setTimeout(function() {{
-        window.location.replace("/");
-      }}, 400);
-    }})();
-  </script>
-</body>
-</html>"#
+fn port_is_open(port: u16) -> bool {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    TcpStream::connect_timeout(&addr, Duration::from_millis(200)).is_ok()
+}
+
+/// Ports a `random` port is picked from unless `port_range` says otherwise.
+const DEFAULT_PORT_RANGE: &str = "49152-65535";
+
+/// Random ports tried before giving up.
+const RANDOM_PORT_ATTEMPTS: usize = 50;
+
+/// Times a busy port is tried again before moving on, unless `bind_retries`
+/// says otherwise.
+const DEFAULT_BIND_RETRIES: usize = 4;
+const BIND_RETRY_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const BIND_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// Tokio's limit on blocking threads, unless `max_blocking_threads` says
+/// otherwise.
+const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+
+/// hyper's largest HTTP/1 buffer, unless `http1_max_buf_size` says otherwise.
+const DEFAULT_HTTP1_MAX_BUF_SIZE: usize = 8192 + 4096 * 100;
+
+/// The smallest `http1_max_buf_size` hyper accepts.
+const MIN_HTTP1_MAX_BUF_SIZE: usize = 8192;
+
+/// Apply the `http1_*` settings to the server's connections.
+fn tune_http<A>(server: &mut axum_server::Server<A>, cfg: &TblConfig) {
+    let mut http1 = server.http_builder().http1();
+    if let Some(flush) = cfg.http1_pipeline_flush {
+        http1.pipeline_flush(flush);
+    }
+    if let Some(size) = cfg.http1_max_buf_size {
+        http1.max_buf_size(size);
+    }
+}
+
+/// Port part of `addr` and `--port`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PortSpec {
+    /// Base port for auto-detection; 0 lets the OS pick a free port
+    Number(u16),
+    /// A random free port from `port_range`
+    Random,
+}
+
+impl FromStr for PortSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("random") {
+            return Ok(Self::Random);
+        }
+        s.parse()
+            .map(Self::Number)
+            .map_err(|_| anyhow::anyhow!("expected a port number, 0, or \"random\", got: {s}"))
+    }
+}
+
+impl fmt::Display for PortSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(port) => write!(f, "{port}"),
+            Self::Random => f.write_str("random"),
+        }
+    }
+}
+
+fn split_host_port(addr: &str) -> Result<(String, PortSpec)> {
+    if let Some(pos) = addr.rfind(':') {
+        let (host, port_str) = addr.split_at(pos);
+        let host = host.to_string();
+        let port_str = &port_str[1..];
+        let port: PortSpec = port_str
+            .parse()
+            .with_context(|| format!("invalid port in addr: {addr}"))?;
+        Ok((host, port))
+    } else {
+        anyhow::bail!("addr must be in host:port form, got: {addr}");
+    }
+}
+
+/// Parse a `port_range` such as "49152-65535".
+fn parse_port_range(range: &str) -> Result<RangeInclusive<u16>> {
+    let (first, last) = range
+        .split_once('-')
+        .with_context(|| format!("expected FIRST-LAST, got: {range}"))?;
+    let first: u16 = first
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid port: {first}"))?;
+    let last: u16 = last
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid port: {last}"))?;
+    if first == 0 || first > last {
+        anyhow::bail!("expected FIRST-LAST with 0 < FIRST <= LAST, got: {range}");
+    }
+    Ok(first..=last)
+}
+
+fn bind_port(host: &str, port: u16) -> Result<std::net::TcpListener> {
+    let addr = socket_addr(host, port)?;
+    bind_socket(addr).map_err(|e| bind_error(addr, e))
+}
+
+fn bind_error(addr: SocketAddr, e: io::Error) -> anyhow::Error {
+    let hint = (e.kind() == io::ErrorKind::PermissionDenied)
+        .then(|| format!("; {}", privileges::bind_hint(addr.port())));
+    anyhow::Error::new(e).context(format!("Failed to bind {addr}{}", hint.unwrap_or_default()))
+}
+
+/// Bind `host:port` unless something already uses it (`None`).
+fn try_bind_port(host: &str, port: u16) -> Result<Option<std::net::TcpListener>> {
+    let addr = socket_addr(host, port)?;
+    // A listener on the wildcard address may not stop a bind to a specific
+    // one, so check that nothing answers first
+    if TcpStream::connect_timeout(&addr, Duration::from_millis(150)).is_ok() {
+        return Ok(None);
+    }
+    match bind_socket(addr) {
+        Ok(listener) => Ok(Some(listener)),
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => Ok(None),
+        Err(e) => Err(bind_error(addr, e)),
+    }
+}
+
+fn socket_addr(host: &str, port: u16) -> Result<SocketAddr> {
+    format!("{host}:{port}")
+        .parse()
+        .with_context(|| format!("Invalid addr: {host}:{port}"))
+}
+
+/// Listening socket with `SO_REUSEADDR` on Unix, so connections of a
+/// previous server still in `TIME_WAIT` don't block a restart. Windows
+/// lets those through anyway, and there the option would allow two
+/// servers on one port.
+fn bind_socket(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    };
+    if cfg!(unix) {
+        socket.set_reuseaddr(true)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)?.into_std()
+}
+
+/// Bind a random port from `range`, trying others while they are taken.
+fn bind_random_port(host: &str, range: RangeInclusive<u16>) -> Result<std::net::TcpListener> {
+    let mut rng = rand::thread_rng();
+    for _ in 0..RANDOM_PORT_ATTEMPTS {
+        if let Some(listener) = try_bind_port(host, rng.gen_range(range.clone()))? {
+            return Ok(listener);
+        }
+    }
+    anyhow::bail!(
+        "No free port found in {}-{} after {RANDOM_PORT_ATTEMPTS} tries",
+        range.start(),
+        range.end()
     )
 }
 
-fn setup_page_html() -> String {
-    r#"<!doctype html>
-<html lang="en">
-<head>
-  <meta charset="utf-8" />
-  <title>tbl – first-time setup</title>
-  <meta name="viewport" content="width=device-width, initial-scale=1" />
-  <style>
-    :root {
-      color-scheme: light dark;
-      --bg: #020617;
-      --card: rgba(15,23,42,0.96);
-      --fg: #f9fafb;
-      --muted: #9ca3af;
-      --accent: #6366f1;
-      --accent-soft: rgba(99,102,241,0.12);
-      --border-subtle: rgba(148,163,184,0.45);
-      --input-bg: rgba(15,23,42,0.9);
+/// Ports assigned to profiles, in the base config dir.
+const PORTS_FILE: &str = "ports.yaml";
+
+/// A profile's port, kept across restarts so bookmarks and OAuth redirect
+/// URIs stay valid.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct PortReservation {
+    port: u16,
+    /// Port of `addr` when the reservation was made; changing `addr` drops it
+    base: u16,
+}
+
+fn load_port_reservations() -> BTreeMap<String, PortReservation> {
+    base_config_dir()
+        .ok()
+        .and_then(|base| fs::read_to_string(base.join(PORTS_FILE)).ok())
+        .and_then(|yaml| serde_yaml::from_str(&yaml).ok())
+        .unwrap_or_default()
+}
+
+fn save_port_reservations(reservations: &BTreeMap<String, PortReservation>) -> Result<()> {
+    let base = base_config_dir()?;
+    fs::create_dir_all(&base)?;
+    let tmp = base.join(format!("{PORTS_FILE}.tmp"));
+    fs::write(&tmp, serde_yaml::to_string(reservations)?)?;
+    fs::rename(&tmp, base.join(PORTS_FILE))?;
+    Ok(())
+}
+
+/// Listener on the active profile's port: its reserved port, or else the
+/// first free port from `base_port` that no other profile has reserved,
+/// which is then reserved for it. A busy first choice is retried `retries`
+/// times with backoff, since a server that was just stopped may still hold
+/// it; after that a reserved port taken by another program is skipped for
+/// this run only.
+fn bind_reserved_port(host: &str, base_port: u16, retries: usize) -> Result<std::net::TcpListener> {
+    let profile = profile_label();
+    let mut reservations = load_port_reservations();
+    let before = reservations.clone();
+    // Forget profiles that have been deleted
+    if let Ok(profiles) = list_profiles() {
+        reservations.retain(|name, _| profiles.iter().any(|(p, _)| p == name));
+    }
+
+    let reserved = reservations
+        .get(profile)
+        .filter(|reservation| reservation.base == base_port)
+        .map(|reservation| reservation.port);
+    let others: Vec<u16> = reservations
+        .iter()
+        .filter(|(name, _)| name.as_str() != profile)
+        .map(|(_, reservation)| reservation.port)
+        .collect();
+    let preferred = reserved.unwrap_or(base_port);
+    let listener = if others.contains(&preferred) {
+        None
+    } else {
+        bind_with_retry(host, preferred, retries)?
+    };
+    let listener = match listener {
+        Some(listener) => listener,
+        None => find_available_port(host, preferred, &others)?,
+    };
+    let port = listener.local_addr()?.port();
+
+    match reserved {
+        Some(reserved) if reserved != port => {
+            eprintln!(
+                "  Port {reserved} (reserved for this profile) is in use; using {port} this time"
+            );
+        }
+        Some(_) => {}
+        None => {
+            reservations.insert(
+                profile.to_string(),
+                PortReservation {
+                    port,
+                    base: base_port,
+                },
+            );
+        }
+    }
+    if reservations != before {
+        if let Err(e) = save_port_reservations(&reservations) {
+            eprintln!("Failed to save {PORTS_FILE}: {e}");
+        }
+    }
+    Ok(listener)
+}
+
+/// Bind `port`, retrying with backoff while it is in use; `None` if it
+/// stays busy.
+fn bind_with_retry(host: &str, port: u16, retries: usize) -> Result<Option<std::net::TcpListener>> {
+    let mut backoff = BIND_RETRY_MIN_BACKOFF;
+    for attempt in 0..=retries {
+        if let Some(listener) = try_bind_port(host, port)? {
+            return Ok(Some(listener));
+        }
+        if attempt < retries {
+            if attempt == 0 {
+                eprintln!("  Port {port} is busy; waiting for it to be released...");
+            }
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(BIND_RETRY_MAX_BACKOFF);
+        }
+    }
+    Ok(None)
+}
+
+/// First port from `base_port` that binds, leaving out `skip`.
+fn find_available_port(host: &str, base_port: u16, skip: &[u16]) -> Result<std::net::TcpListener> {
+    let mut port = base_port;
+    for _ in 0..100 {
+        if !skip.contains(&port) {
+            if let Some(listener) = try_bind_port(host, port)? {
+                return Ok(listener);
+            }
+        }
+        port = port.checked_add(1).context("No free port left")?;
+    }
+    anyhow::bail!("No free port found from {base_port} on")
+}
+
+// =============================================================================
+// Git Integration
+// =============================================================================
+
+/// Time limit for a single git command unless `git_timeout` says otherwise.
+const DEFAULT_GIT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How often a running git command is checked for exit, timeout, or cancellation.
+const GIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Effective `git_timeout`, set once at startup; `None` means no limit.
+static GIT_TIMEOUT: std::sync::OnceLock<Option<Duration>> = std::sync::OnceLock::new();
+
+/// Set on shutdown so running git commands are killed instead of waited for,
+/// and archive downloads stop.
+static GIT_CANCELLED: AtomicBool = AtomicBool::new(false);
+
+fn init_git_timeout(value: Option<&str>) -> Result<()> {
+    let timeout = match value {
+        Some(value) => Some(parse_duration(value).context("Invalid git_timeout")?)
+            .filter(|timeout| !timeout.is_zero()),
+        None => Some(DEFAULT_GIT_TIMEOUT),
+    };
+    let _ = GIT_TIMEOUT.set(timeout);
+    Ok(())
+}
+
+fn git_timeout() -> Option<Duration> {
+    *GIT_TIMEOUT.get().unwrap_or(&Some(DEFAULT_GIT_TIMEOUT))
+}
+
+/// Effective `git_proxy`, set once at startup.
+static GIT_PROXY: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+
+fn init_git_proxy(value: Option<String>) {
+    let _ = GIT_PROXY.set(value.filter(|proxy| !proxy.is_empty()));
+}
+
+/// Give a git command `key = value` as `-c` would, through the
+/// `GIT_CONFIG_COUNT` environment, after the settings already given that way.
+fn git_config_env<'c>(cmd: &'c mut Command, key: &str, value: &str) -> &'c mut Command {
+    let count = cmd
+        .get_envs()
+        .find(|(name, _)| *name == "GIT_CONFIG_COUNT")
+        .and_then(|(_, value)| value?.to_str()?.parse::<usize>().ok())
+        .unwrap_or(0);
+    cmd.env("GIT_CONFIG_COUNT", (count + 1).to_string())
+        .env(format!("GIT_CONFIG_KEY_{count}"), key)
+        .env(format!("GIT_CONFIG_VALUE_{count}"), value)
+}
+
+/// Kill running git commands and refuse to wait for new ones.
+fn cancel_git_operations() {
+    GIT_CANCELLED.store(true, Ordering::Relaxed);
+}
+
+/// Run a git command, killing it together with its helpers (e.g.
+/// `git-remote-https`) when it outlives `git_timeout()` or tbl shuts down.
+/// A piped stdout is collected into the returned output.
+fn run_git(cmd: &mut Command, action: &str) -> Result<std::process::Output> {
+    if let Some(proxy) = GIT_PROXY.get().and_then(Option::as_deref) {
+        git_config_env(cmd, "http.proxy", proxy);
+    }
+    run_command(cmd, &format!("git {action}"))
+}
+
+/// `run_git` for other tools that fetch content (e.g. `rsync`), bound by the
+/// same timeout; `what` names the command in errors.
+fn run_command(cmd: &mut Command, what: &str) -> Result<std::process::Output> {
+    run_command_within(cmd, what, git_timeout())
+}
+
+/// `run_command` with a time limit of its own.
+fn run_command_within(
+    cmd: &mut Command,
+    what: &str,
+    timeout: Option<Duration>,
+) -> Result<std::process::Output> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Own process group, so helpers (ssh, git-remote-https) are killed along with it
+        cmd.process_group(0);
+    }
+
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("failed to execute {what}"))?;
+    let reader = child.stdout.take().map(|mut stdout| {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stdout.read_to_end(&mut buf);
+            buf
+        })
+    });
+
+    let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        let error = if GIT_CANCELLED.load(Ordering::Relaxed) {
+            format!("{what} cancelled: tbl is shutting down")
+        } else if deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+            format!(
+                "{what} timed out after {}s",
+                timeout.unwrap_or_default().as_secs()
+            )
+        } else {
+            std::thread::sleep(GIT_POLL_INTERVAL);
+            continue;
+        };
+        kill_process_tree(child.id());
+        let _ = child.wait();
+        anyhow::bail!(error);
+    };
+
+    let stdout = reader
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+    Ok(std::process::Output {
+        status,
+        stdout,
+        stderr: Vec::new(),
+    })
+}
+
+fn git_available() -> bool {
+    run_git(
+        Command::new("git")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+        "--version",
+    )
+    .is_ok_and(|output| output.status.success())
+}
+
+fn ensure_git_available() -> Result<()> {
+    if git_available() {
+        return Ok(());
+    }
+
+    eprintln!("`git` was not found or is not working.");
+
+    let os = std::env::consts::OS;
+    match os {
+        "macos" => {
+            eprintln!("Install git on macOS:");
+            eprintln!("  xcode-select --install");
+            eprintln!("or using Homebrew:");
+            eprintln!("  brew install git");
+        }
+        "windows" => {
+            eprintln!("Install Git for Windows from:");
+            eprintln!("  https://git-scm.com/download/win");
+            eprintln!("or via winget:");
+            eprintln!("  winget install --id Git.Git -e");
+        }
+        "linux" => {
+            eprintln!("Install git on Linux:");
+            eprintln!("  Debian/Ubuntu: sudo apt-get install git");
+            eprintln!("  Fedora:        sudo dnf install git");
+            eprintln!("  Arch Linux:    sudo pacman -S git");
+        }
+        _ => {
+            eprintln!("Please install git from https://git-scm.com/downloads");
+        }
+    }
+
+    anyhow::bail!("git not available on PATH");
+}
+
+/// Whether `url` is fetched with git, as opposed to an archive, S3, SFTP, or
+/// local directory source.
+fn is_git_source(url: &str) -> bool {
+    source::provider(url).uses_git()
+}
+
+/// Fill the workspace from `url` with the provider for it: clone or fetch a
+/// repository, download an archive, sync a bucket prefix, and so on. With a
+/// `job`, progress and output go to the job.
+///
+/// A failed update is an error but leaves the existing content in place, so
+/// callers may keep serving it. The new content is then hashed to report what
+/// the sync changed, and saved as a release when `keep_releases` asks for it;
+/// failing either is only worth a warning. The `hooks` run around it all.
+fn ensure_repo(
+    config_dir: &Path,
+    url: &str,
+    settings: source::Settings,
+    job: Option<&JobHandle>,
+) -> Result<()> {
+    let ctx = source::Context::new(config_dir, &settings, job);
+    let before = current_commit(&ctx.web_dir);
+    hooks::pre_update(&ctx, url, before.as_deref())?;
+    source::sync(&ctx, url)?;
+    releases::synced(config_dir);
+    metered::clear(config_dir);
+
+    ctx.progress("verifying");
+    let version = source::installed_version(&ctx.web_dir);
+    let mirror = ctx.mirror_used().map(|mirror| redact_credentials(&mirror));
+    let files = match manifest::record(config_dir, url, mirror, before.clone(), version.clone()) {
+        Ok((files, report)) => {
+            ctx.log(&format!(
+                "{} added, {} removed, {} changed",
+                report.added.len(),
+                report.removed.len(),
+                report.changed.len()
+            ));
+            Some(files)
+        }
+        Err(e) => {
+            ctx.warn(&format!("Failed to hash the workspace: {e:#}"));
+            None
+        }
+    };
+
+    if let Some(files) = &files {
+        if settings.keep_releases > 0 {
+            ctx.progress("saving release");
+        }
+        if let Err(e) = releases::record(
+            config_dir,
+            url,
+            version.as_deref(),
+            files,
+            settings.keep_releases,
+        ) {
+            ctx.warn(&format!("Failed to save the release: {e:#}"));
+        }
+    }
+
+    let report = files.map(|_| manifest::report_path(config_dir));
+    if let Err(e) = hooks::post_update(
+        &ctx,
+        url,
+        before.as_deref(),
+        version.as_deref(),
+        report.as_deref(),
+    ) {
+        ctx.warn(&format!("{e:#}"));
+    }
+    Ok(())
+}
+
+/// Serve `release` in place of the synced content until the next sync, and
+/// report what that changed like a sync.
+fn roll_back(config_dir: &Path, release: &releases::Release, job: &JobHandle) -> Result<()> {
+    let before = current_commit(&config_dir.join("web"));
+    job.progress("restoring");
+    releases::restore(config_dir, release)?;
+
+    job.progress("verifying");
+    let version = Some(release.version.clone());
+    if let Err(e) = manifest::record(config_dir, &release.url, None, before, version) {
+        tracing::warn!("Failed to hash the workspace: {e:#}");
+        job.log(&format!("Failed to hash the workspace: {e:#}"));
+    }
+    Ok(())
+}
+
+/// Report `step` as the job's progress and send the command's output to its log.
+fn job_step<'a>(
+    cmd: &'a mut Command,
+    job: Option<&JobHandle>,
+    step: &str,
+) -> Result<&'a mut Command> {
+    if let Some(job) = job {
+        job.progress(step);
+        cmd.stdout(job.output()?).stderr(job.output()?);
+    }
+    Ok(cmd)
+}
+
+/// Whether `web_dir` is a checkout of `url`, in any spelling, that can be
+/// updated in place; a different repository means the workspace is being
+/// repointed.
+fn checkout_matches(web_dir: &Path, url: &str) -> bool {
+    source::provider(url).matches(web_dir, url)
+}
+
+/// Lazy sync: fetch the checkout that is already being served and announce
+/// the result, with `changed` telling clients whether to reload.
+async fn sync_in_background(state: Arc<AppState>, url: String) {
+    emit_event(
+        &state,
+        "update.started",
+        serde_json::json!({ "git_url": url }),
+    );
+
+    let before = workspace_commit(&state).await;
+    match ensure_repo_job(&state, JobKind::Sync, &url).await {
+        Ok(()) => {
+            let commit = workspace_commit(&state).await;
+            let changed = commit != before;
+            tracing::info!(git_url = %redact_credentials(&url), changed, "background sync finished");
+            update_finished(&state, &url, commit, changed);
+        }
+        Err(e) => {
+            tracing::warn!(git_url = %redact_credentials(&url), "background sync failed: {e:#}");
+            update_failed(&state, &url, &e);
+        }
+    }
+}
+
+/// How often the wall clock is compared with the next scheduled sync. The
+/// timer stops while the machine sleeps, so a sync missed then starts at most
+/// this long after waking.
+const SYNC_SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Sync at the times of `schedule`. Runs missed while the machine slept, or
+/// while an earlier sync was still going, are caught up with one sync.
+async fn watch_sync_schedule(state: Arc<AppState>, schedule: CronSchedule) {
+    let Some(mut due) = schedule.next_after(Local::now()) else {
+        tracing::warn!("sync_schedule {schedule} never fires");
+        return;
+    };
+    tracing::info!(
+        "Next scheduled sync at {}",
+        timefmt::human(&due, timefmt::Style::Full)
+    );
+    loop {
+        let wait = (due - Local::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait.min(SYNC_SCHEDULE_CHECK_INTERVAL)).await;
+        let now = Local::now();
+        if now < due {
+            continue;
+        }
+
+        if now - due > chrono::Duration::minutes(1) {
+            tracing::info!(
+                "Catching up on the sync scheduled for {}",
+                timefmt::human(&due, timefmt::Style::Weekday)
+            );
+        }
+        let url = state.config.read().unwrap().git_url.clone();
+        match url {
+            _ if pause::current(&state.config_dir).is_some() => {
+                tracing::info!("Scheduled sync skipped: automatic syncs are paused");
+            }
+            Some(_) if is_metered(&state) => match metered::defer(&state.config_dir) {
+                Ok(_) => tracing::info!("Scheduled sync deferred: the connection is metered"),
+                Err(e) => tracing::warn!("Failed to defer the scheduled sync: {e:#}"),
+            },
+            Some(url) => sync_in_background(state.clone(), url).await,
+            None => tracing::debug!("scheduled sync skipped: no git URL configured"),
+        }
+        let Some(next) = schedule.next_after(Local::now()) else {
+            return;
+        };
+        due = next;
+    }
+}
+
+/// Back up the profile at the times of `schedule`, keeping the newest
+/// `backup.keep` scheduled backups. A time missed while the machine slept is
+/// caught up once.
+async fn watch_backup_schedule(
+    state: Arc<AppState>,
+    schedule: CronSchedule,
+    cfg: backup::BackupConfig,
+) {
+    let Some(mut due) = schedule.next_after(Local::now()) else {
+        tracing::warn!("backup.schedule {schedule} never fires");
+        return;
+    };
+    loop {
+        let wait = (due - Local::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait.min(SYNC_SCHEDULE_CHECK_INTERVAL)).await;
+        if Local::now() < due {
+            continue;
+        }
+
+        let config_dir = state.config_dir.clone();
+        let web = cfg.web.unwrap_or(false);
+        let keep = cfg.keep();
+        let result = blocking(move || -> Result<(PathBuf, backup::Created, usize)> {
+            let path = backup::default_path(&config_dir, backup::AUTO_PREFIX);
+            let created = backup::create(
+                &config_dir,
+                &run_dir(&config_dir),
+                profile_label(),
+                &path,
+                web,
+            )?;
+            Ok((
+                path,
+                created,
+                backup::rotate(&config_dir, backup::AUTO_PREFIX, keep),
+            ))
+        })
+        .await;
+        match result {
+            Ok((path, created, removed)) => {
+                for link in &created.skipped_links {
+                    tracing::warn!("Left symlink {:?} out of the backup", link);
+                }
+                tracing::info!("Backed up to {:?}, removed {removed} old backups", path)
+            }
+            Err(e) => tracing::warn!("Scheduled backup failed: {e:#}"),
+        }
+        let Some(next) = schedule.next_after(Local::now()) else {
+            return;
+        };
+        due = next;
+    }
+}
+
+/// Record that this run is still up, every `uptime::HEARTBEAT`.
+async fn record_heartbeats(state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(uptime::HEARTBEAT).await;
+        let beating = state.clone();
+        let recorded = blocking(move || uptime::heartbeat(&beating.db, beating.run, now_unix()));
+        if let Err(e) = recorded.await {
+            tracing::warn!("Failed to record the heartbeat: {e:#}");
+        }
+    }
+}
+
+/// Remove the KV keys and stored files whose TTL ran out, every
+/// `quota::SWEEP_INTERVAL`.
+async fn sweep_expired(state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(quota::SWEEP_INTERVAL).await;
+        let sweeping = state.clone();
+        if let Err(e) = blocking(move || sweep_expired_now(&sweeping)).await {
+            tracing::warn!("Failed to remove expired data: {e:#}");
+        }
+    }
+}
+
+fn sweep_expired_now(state: &AppState) -> Result<()> {
+    let now = now_unix();
+    for (namespace, key) in state.kv.sweep(now)? {
+        emit_event(
+            state,
+            "kv.changed",
+            serde_json::json!({ "namespace": namespace, "key": key }),
+        );
+    }
+    for path in state.db.expired_files(now)? {
+        if let Some(target) = state.files.path(&path) {
+            if let Err(e) = remove_stored_file(state, &target) {
+                tracing::warn!("Failed to remove expired file {path:?}: {e:#}");
+                continue;
+            }
+        }
+        state.db.forget_file_expiry(&path)?;
+        emit_event(state, "files.changed", serde_json::json!({ "path": path }));
+    }
+    Ok(())
+}
+
+/// Remove the file or directory tree `target` from the files area and take
+/// it off the tally; `false` if there was nothing to remove.
+fn remove_stored_file(state: &AppState, target: &Path) -> Result<bool> {
+    state.files_usage.change(state.files.root(), |usage| {
+        let meta = match fs::symlink_metadata(target) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let (bytes, files) = if meta.is_dir() {
+            let removed = quota::dir_usage(target);
+            fs::remove_dir_all(target)?;
+            removed
+        } else {
+            fs::remove_file(target)?;
+            if meta.is_file() {
+                (meta.len(), 1)
+            } else {
+                (0, 0)
+            }
+        };
+        *usage = (usage.0.saturating_sub(bytes), usage.1.saturating_sub(files));
+        Ok(true)
+    })
+}
+
+/// How often a deferred sync checks whether the connection is unmetered yet.
+const DEFERRED_SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether automatic syncs should wait, per the `metered` setting.
+fn is_metered(state: &AppState) -> bool {
+    let metered = state.config.read().unwrap().metered.unwrap_or_default();
+    metered.is_metered()
+}
+
+/// Run the sync deferred on a metered connection once it is unmetered.
+async fn watch_deferred_sync(state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(DEFERRED_SYNC_CHECK_INTERVAL).await;
+        if metered::deferred(&state.config_dir).is_none()
+            || pause::current(&state.config_dir).is_some()
+        {
+            continue;
+        }
+        if is_metered(&state) {
+            continue;
+        }
+        let url = state.config.read().unwrap().git_url.clone();
+        match url {
+            Some(url) => {
+                tracing::info!("The connection is unmetered; running the deferred sync");
+                sync_in_background(state.clone(), url).await;
+            }
+            None => metered::clear(&state.config_dir),
+        }
+    }
+}
+
+/// Answer mDNS queries for tbl instances with the app being served.
+async fn announce(state: Arc<AppState>, port: u16, tls: bool) {
+    let instance = discovery::instance_name(profile_label(), DEFAULT_PROFILE);
+    tracing::info!("Announcing {instance:?} on the local network");
+    let describe = || {
+        let meta = state.app_meta.read().unwrap().clone();
+        let git_url = state.config.read().unwrap().git_url.clone();
+        discovery::Announcement {
+            instance: instance.clone(),
+            port,
+            app: meta
+                .as_ref()
+                .and_then(|meta| meta.title.clone())
+                .or_else(|| git_url.as_deref().and_then(repo_name)),
+            version: meta.map(|meta| meta.commit),
+            base_path: state.base_path.clone(),
+            tls,
+        }
+    };
+    if let Err(e) = discovery::announce(describe).await {
+        tracing::warn!("Stopped announcing on the local network: {e:#}");
+    }
+}
+
+/// Failed updates in a row after which `update.failing` is emitted.
+const UPDATE_FAILING_THRESHOLD: u32 = 3;
+
+fn update_finished(state: &AppState, url: &str, commit: Option<String>, changed: bool) {
+    state.update_failures.store(0, Ordering::Relaxed);
+    emit_event(
+        state,
+        "update.finished",
+        serde_json::json!({ "git_url": url, "commit": commit, "changed": changed }),
+    );
+}
+
+/// Announce a failed update. The third failure in a row also emits
+/// `update.failing`, for notifiers that only care about lasting breakage.
+fn update_failed(state: &AppState, url: &str, error: &anyhow::Error) {
+    let failures = state.update_failures.fetch_add(1, Ordering::Relaxed) + 1;
+    let data =
+        serde_json::json!({ "git_url": url, "error": format!("{error:#}"), "failures": failures });
+    emit_event(state, "update.failed", data.clone());
+    if failures == UPDATE_FAILING_THRESHOLD {
+        emit_event(state, "update.failing", data);
+    }
+}
+
+/// Run blocking work (git, config files) on tokio's blocking pool, so a slow
+/// clone doesn't stall other requests. Panics propagate to the caller.
+async fn blocking<T: Send + 'static>(job: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(job).await {
+        Ok(value) => value,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// `ensure_git_available` for async callers about to fetch `url`; other
+/// kinds of source are fetched without git.
+async fn git_ready(url: &str) -> Result<()> {
+    if !is_git_source(url) {
+        return Ok(());
+    }
+    blocking(ensure_git_available).await
+}
+
+/// `ensure_repo` for the running server, recorded as a job. Jobs queue up
+/// behind each other, so a switch never runs against the same checkout as an
+/// update.
+async fn ensure_repo_job(state: &AppState, kind: JobKind, url: &str) -> Result<()> {
+    let config_dir = state.config_dir.clone();
+    let url = url.to_string();
+    workspace_job(state, kind, &url.clone(), move |settings, job| {
+        ensure_repo(&config_dir, &url, settings, Some(job))
+    })
+    .await
+}
+
+/// Run `work` on the workspace as a job about `target`, once the jobs queued
+/// before it are done, with the settings current at that point. The app's
+/// metadata and search index are refreshed when it succeeds.
+async fn workspace_job(
+    state: &AppState,
+    kind: JobKind,
+    target: &str,
+    work: impl FnOnce(source::Settings, &JobHandle) -> Result<()> + Send + 'static,
+) -> Result<()> {
+    let record = state.jobs.create(kind, Some(target.to_string()))?;
+    job_changed(state, Some(record.clone()));
+    let job = JobHandle::new(state.jobs.clone(), record.id);
+
+    let _turn = state.workspace_jobs.lock().await;
+    job_changed(state, job.start());
+
+    let settings = source::Settings::of(&state.config.read().unwrap());
+    let handle = job.clone();
+    let result = blocking(move || work(settings, &handle)).await;
+    if result.is_ok() {
+        refresh_app_meta(state).await;
+        check_requirements(state).await;
+        reindex(state).await;
+    }
+
+    let outcome = match &result {
+        Ok(()) => Ok(serde_json::json!({ "commit": workspace_commit(state).await })),
+        Err(e) => Err(anyhow::anyhow!("{e:#}")),
+    };
+    job_changed(state, job.finish(&outcome));
+    result
+}
+
+/// Clone or fetch `branch` of `url` for `/preview/` as a `preview` job. A
+/// checkout that is not yet stale is left alone unless `force` is set.
+async fn preview_job(
+    state: &AppState,
+    url: &str,
+    branch: &str,
+    force: bool,
+) -> Result<preview::Preview> {
+    let _turn = state.preview_jobs.lock().await;
+    let existing = {
+        let config_dir = state.config_dir.clone();
+        let branch = branch.to_string();
+        blocking(move || preview::find(&config_dir, &branch)).await
+    };
+    if let Some(preview) = existing.filter(|preview| !force && !preview::is_stale(preview)) {
+        return Ok(preview);
+    }
+
+    let record = state
+        .jobs
+        .create(JobKind::Preview, Some(branch.to_string()))?;
+    job_changed(state, Some(record.clone()));
+    let job = JobHandle::new(state.jobs.clone(), record.id);
+    job_changed(state, job.start());
+
+    let settings = source::Settings::of(&state.config.read().unwrap());
+    let config_dir = state.config_dir.clone();
+    let (url, branch) = (url.to_string(), branch.to_string());
+    let handle = job.clone();
+    let result =
+        blocking(move || preview::sync(&config_dir, &settings, Some(&handle), &url, &branch)).await;
+    let outcome = match &result {
+        Ok(preview) => Ok(serde_json::json!({ "commit": preview.commit })),
+        Err(e) => Err(anyhow::anyhow!("{e:#}")),
+    };
+    job_changed(state, job.finish(&outcome));
+    result
+}
+
+/// Rebuild the search index from the checkout.
+async fn reindex(state: &AppState) {
+    let web_root = state.web.root().to_path_buf();
+    let max_bytes = state.memory.search_index;
+    let index = blocking(move || SearchIndex::build(&web_root, max_bytes)).await;
+    tracing::debug!(documents = index.document_count(), "search index rebuilt");
+    *state.search.write().unwrap() = index;
+}
+
+/// Read the app's title, description, and icon from the checkout.
+async fn refresh_app_meta(state: &AppState) {
+    let config_dir = state.config_dir.clone();
+    let meta = blocking(move || load_app_meta(&config_dir)).await;
+    *state.app_meta.write().unwrap() = meta;
+}
+
+/// Check the checkout's `tbl.requires.yaml` against this instance.
+/// Problems are logged when they first appear.
+async fn check_requirements(state: &AppState) {
+    let web_root = state.web.root().to_path_buf();
+    let disabled = state.config.read().unwrap().disable.clone();
+    let unmet = blocking(move || requires::unmet(&web_root, &disabled)).await;
+    let mut current = state.unmet_requirements.write().unwrap();
+    for problem in unmet.iter().filter(|problem| !current.contains(problem)) {
+        tracing::warn!("{problem}");
+    }
+    *current = unmet;
+}
+
+/// Metadata of the app checked out in `config_dir`, from the cache unless
+/// the commit changed.
+fn load_app_meta(config_dir: &Path) -> Option<AppMeta> {
+    let web_dir = config_dir.join("web");
+    let commit = current_commit(&web_dir)?;
+    let cache = run_dir(config_dir).join(appmeta::CACHE_FILE);
+    Some(AppMeta::cached(&cache, &web_dir, &commit))
+}
+
+/// Announce a job's new state on the events stream.
+fn job_changed(state: &AppState, record: Option<JobRecord>) {
+    if let Some(record) = record {
+        emit_event(
+            state,
+            "job.updated",
+            serde_json::to_value(record).unwrap_or_default(),
+        );
+    }
+}
+
+/// Commit currently checked out in the served workspace.
+async fn workspace_commit(state: &AppState) -> Option<String> {
+    let web_root = state.web.root().to_path_buf();
+    blocking(move || current_commit(&web_root)).await
+}
+
+/// Branch the remote's HEAD points at, e.g. `main`.
+fn default_branch(web_dir: &Path) -> Option<String> {
+    let output = run_git(
+        Command::new("git")
+            .arg("-C")
+            .arg(web_dir)
+            .arg("symbolic-ref")
+            .arg("--short")
+            .arg("refs/remotes/origin/HEAD")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+        "symbolic-ref",
+    )
+    .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let head = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    head.strip_prefix("origin/").map(str::to_string)
+}
+
+/// Record `url`, whose checkout has `branch` as default branch, as the
+/// workspace's repository.
+fn remember_repo(cfg: &mut TblConfig, url: &str, branch: Option<String>) {
+    cfg.repo_id = Some(repo_identity(url));
+    cfg.default_branch = branch;
+}
+
+/// Unix timestamp of the checked-out commit.
+fn commit_time(web_dir: &Path) -> Option<u64> {
+    let output = run_git(
+        Command::new("git")
+            .arg("-C")
+            .arg(web_dir)
+            .arg("log")
+            .arg("-1")
+            .arg("--format=%ct")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+        "log",
+    )
+    .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// Commit checked out in `web_dir`, or what stands in for it with content
+/// from another kind of source or a rolled back release.
+fn current_commit(web_dir: &Path) -> Option<String> {
+    web_dir
+        .parent()
+        .and_then(releases::restored_version)
+        .or_else(|| source::installed_version(web_dir))
+}
+
+// =============================================================================
+// Authentication Helpers
+// =============================================================================
+
+fn extract_token_from_cookie(headers: &HeaderMap) -> Option<String> {
+    extract_cookie(headers, "tbl_token")
+}
+
+fn extract_cookie(headers: &HeaderMap, cookie_name: &str) -> Option<String> {
+    let cookie_header = headers.get(header::COOKIE)?.to_str().ok()?;
+
+    for part in cookie_header.split(';') {
+        let trimmed = part.trim();
+        let mut kv = trimmed.splitn(2, '=');
+        if let (Some(name), Some(value)) = (kv.next(), kv.next()) {
+            if name == cookie_name {
+                return Some(value.to_string());
+            }
+        }
+    }
+
+    None
+}
+
+/// Why a request failed [`check_auth`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AuthError {
+    /// Basic auth is configured and the request did not supply valid credentials
+    BasicAuthRequired,
+    /// The `tbl_token` cookie is missing, or not a valid token of this server
+    InvalidToken,
+}
+
+/// Identity of an authenticated request.
+///
+/// As an extractor it checks optional basic auth and the session cookie. The
+/// API route group runs it as a layer before every handler, so a handler only
+/// takes it to find out who is calling.
+#[derive(Clone, Debug, Default)]
+struct AuthSession {
+    /// Basic auth user, when basic auth is configured
+    user: Option<String>,
+    /// `tbl_session` cookie of the browser, if it has one
+    session: Option<String>,
+}
+
+#[axum::async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthSession {
+    type Rejection = api::error::ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(auth) = parts.extensions.get::<AuthSession>() {
+            return Ok(auth.clone());
+        }
+        let client = client_ip(state, &parts.headers, &parts.extensions);
+        let auth = check_auth(state, &parts.headers, client)?;
+        parts.extensions.insert(auth.clone());
+        Ok(auth)
+    }
+}
+
+/// Check optional basic auth and the session cookie of a request from `client`.
+fn check_auth(
+    state: &AppState,
+    headers: &HeaderMap,
+    client: Option<IpAddr>,
+) -> Result<AuthSession, AuthError> {
+    let user = check_basic(state, headers, client)?;
+
+    let token = extract_token_from_cookie(headers);
+    if token
+        .and_then(|token| state.tokens.verify(&token))
+        .is_none()
+    {
+        return Err(AuthError::InvalidToken);
+    }
+
+    let session = extract_cookie(headers, "tbl_session");
+    if let Some(id) = &session {
+        touch_session(state, id);
+    }
+
+    Ok(AuthSession { user, session })
+}
+
+/// Check basic auth, if credentials are configured and `remote_auth` asks
+/// for it from `client`, returning the user.
+fn check_basic(
+    state: &AppState,
+    headers: &HeaderMap,
+    client: Option<IpAddr>,
+) -> Result<Option<String>, AuthError> {
+    let cfg = state.config.read().unwrap();
+    let (Some(user), Some(pass)) = (&cfg.basic_user, &cfg.basic_pass) else {
+        return Ok(None);
+    };
+    let loopback = client.is_some_and(|ip| ip.is_loopback());
+    if cfg.remote_auth == Some(RemoteAuth::Basic) && loopback {
+        return Ok(None);
+    }
+    let header = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    let mut verified = state.basic_auth_verified.lock().unwrap();
+    if header.is_none() || verified.as_deref() != header {
+        if !check_basic_auth(headers, user, pass.expose()) {
+            return Err(AuthError::BasicAuthRequired);
+        }
+        *verified = header.map(str::to_string);
+    }
+    Ok(Some(user.clone()))
+}
+
+/// Whether a request carries the `peer_token` as a bearer token, as other
+/// tbl instances syncing from this one do.
+fn is_peer(state: &AppState, headers: &HeaderMap) -> bool {
+    let cfg = state.config.read().unwrap();
+    let Some(token) = &cfg.peer_token else {
+        return false;
+    };
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|given| token::same_secret(given, token.expose()))
+}
+
+fn check_basic_auth(headers: &HeaderMap, user: &str, pass: &str) -> bool {
+    let header_val = match headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(v) => v,
+        None => return false,
+    };
+
+    if !header_val.starts_with("Basic ") {
+        return false;
+    }
+
+    let b64 = &header_val[6..];
+    let decoded = match BASE64.decode(b64) {
+        Ok(d) => d,
+        Err(_) => return false,
+    };
+
+    let creds = match String::from_utf8(decoded) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+
+    let mut parts = creds.splitn(2, ':');
+    let u = parts.next().unwrap_or("");
+    let p = parts.next().unwrap_or("");
+
+    u == user && verify_password(pass, p)
+}
+
+/// Whether `basic_pass` holds an argon2 hash rather than the password itself.
+fn is_password_hash(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+/// Check a password against the configured `basic_pass`, hashed or not.
+fn verify_password(stored: &str, password: &str) -> bool {
+    if !is_password_hash(stored) {
+        return stored == password;
+    }
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            tracing::warn!("Invalid basic_pass hash: {e}");
+            false
+        }
+    }
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("failed to hash password: {e}"))?;
+    Ok(hash.to_string())
+}
+
+// =============================================================================
+// Access Control
+// =============================================================================
+
+/// Where browsers without a session are sent.
+const SIGNIN_PATH: &str = "/signin";
+
+/// Apply the app's `access` policy to the `/web` route group and `/`.
+async fn require_web_access(
+    State(state): State<Arc<AppState>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    // Nothing below answers OPTIONS beyond listing its methods
+    if request.method() == Method::OPTIONS {
+        return next.run(request).await;
+    }
+    match check_web_access(&state, &request) {
+        Ok(auth) => {
+            if let Some(auth) = auth {
+                request.extensions_mut().insert(auth);
+            }
+            next.run(request).await
+        }
+        Err(e) => {
+            let url = PublicUrl::of(&state, request.headers(), peer_ip(request.extensions()));
+            access_denied(&state, &url, e)
+        }
+    }
+}
+
+/// Require a session for the launcher pages that change the workspace.
+async fn require_session(
+    State(state): State<Arc<AppState>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let client = client_ip(&state, request.headers(), request.extensions());
+    match check_auth(&state, request.headers(), client) {
+        Ok(auth) => {
+            request.extensions_mut().insert(auth);
+            next.run(request).await
+        }
+        Err(e) => {
+            let url = PublicUrl::of(&state, request.headers(), peer_ip(request.extensions()));
+            access_denied(&state, &url, e)
+        }
+    }
+}
+
+/// Apply the app's `access` policy to a request, returning the session when
+/// the policy asked for one.
+fn check_web_access(
+    state: &AppState,
+    request: &axum::extract::Request,
+) -> Result<Option<AuthSession>, AuthError> {
+    let access = state.config.read().unwrap().access.unwrap_or_default();
+    let client = client_ip(state, request.headers(), request.extensions());
+    match access {
+        Access::Public => Ok(None),
+        Access::Lan if client.is_some_and(is_local_network) => Ok(None),
+        Access::Basic => check_basic(state, request.headers(), client).map(|_| None),
+        Access::Token | Access::Lan => check_auth(state, request.headers(), client).map(Some),
+    }
+}
+
+/// Address a request came from, which is the proxy's when there is one.
+fn peer_ip(extensions: &axum::http::Extensions) -> Option<IpAddr> {
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+}
+
+/// Address of the client that sent a request, looking through any
+/// `trusted_proxies`. Use this, not [`peer_ip`], to decide about or log a
+/// client.
+fn client_ip(
+    state: &AppState,
+    headers: &HeaderMap,
+    extensions: &axum::http::Extensions,
+) -> Option<IpAddr> {
+    peer_ip(extensions).map(|peer| state.trusted_proxies.client_ip(peer, headers))
+}
+
+/// Send a browser without a session to the sign-in page, or ask it for basic
+/// auth credentials.
+fn access_denied(state: &AppState, url: &PublicUrl, e: AuthError) -> Response {
+    match e {
+        AuthError::InvalidToken => Redirect::to(&url.path(SIGNIN_PATH)).into_response(),
+        AuthError::BasicAuthRequired => {
+            let body = error_page_html(
+                "Sign-in required",
+                "This app asks for a user name and password.",
+                &page_branding(state),
+                url,
+            );
+            let mut response = (StatusCode::UNAUTHORIZED, Html(body)).into_response();
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Basic realm=\"tbl\""),
+            );
+            response
+        }
+    }
+}
+
+/// Loopback, private, and link-local addresses.
+fn is_local_network(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local_network(IpAddr::V4(ip)),
+            // fc00::/7 (unique local) and fe80::/10 (link-local)
+            None => {
+                ip.is_loopback()
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+// =============================================================================
+// Base Path & Reverse Proxies
+// =============================================================================
+
+/// Check and tidy a `base_path`: `/tbl/` becomes `/tbl`, `/` becomes empty.
+fn normalize_base_path(path: &str) -> Result<String> {
+    let trimmed = path.trim().trim_end_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    let path = if trimmed.starts_with('/') {
+        trimmed.to_string()
+    } else {
+        format!("/{trimmed}")
+    };
+    if !is_safe_url_prefix(&path) {
+        anyhow::bail!("base_path must be a plain path such as /tbl, got: {path}");
+    }
+    Ok(path)
+}
+
+/// Path segments of letters, digits, and `-._~` only, so a prefix can go
+/// into URLs, cookies, and scripts without escaping.
+fn is_safe_url_prefix(path: &str) -> bool {
+    path.strip_prefix('/').is_some_and(|rest| {
+        rest.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+        })
+    })
+}
+
+/// How the browser reached tbl: scheme, host, and the path prefix of tbl's
+/// URLs, taking `X-Forwarded-Proto`, `X-Forwarded-Host`, and
+/// `X-Forwarded-Prefix` from one of the `trusted_proxies` into account.
+///
+/// As an extractor it never fails.
+#[derive(Clone, Debug)]
+struct PublicUrl {
+    scheme: String,
+    /// Host with optional port, as in the `Host` header
+    host: String,
+    /// What the proxy stripped followed by `base_path`, e.g. `/tbl`; empty
+    /// when tbl is served at the root
+    prefix: String,
+}
+
+impl PublicUrl {
+    fn of(state: &AppState, headers: &HeaderMap, peer: Option<IpAddr>) -> Self {
+        let forwarded = |name: &str| {
+            peer.is_some_and(|ip| state.trusted_proxies.contains(ip))
+                .then(|| headers.get(name)?.to_str().ok())
+                .flatten()
+                // Proxies in a chain append their values
+                .and_then(|value| value.split(',').next())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+        let scheme = forwarded("x-forwarded-proto")
+            .filter(|proto| matches!(*proto, "http" | "https"))
+            .unwrap_or(if state.tls { "https" } else { "http" });
+        let host = forwarded("x-forwarded-host")
+            .or_else(|| headers.get(header::HOST)?.to_str().ok())
+            .filter(|host| host.parse::<axum::http::uri::Authority>().is_ok())
+            .unwrap_or("127.0.0.1");
+        let proxy_prefix = forwarded("x-forwarded-prefix")
+            .map(|prefix| prefix.trim_end_matches('/'))
+            .filter(|prefix| is_safe_url_prefix(prefix))
+            .unwrap_or_default();
+        PublicUrl {
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+            prefix: format!("{proxy_prefix}{}", state.base_path),
+        }
+    }
+
+    /// `path` (starting with `/`) as the browser addresses it.
+    fn path(&self, path: &str) -> String {
+        format!("{}{path}", self.prefix)
+    }
+
+    /// Absolute URL of tbl's root, e.g. `https://example.com/tbl`.
+    fn base_url(&self) -> String {
+        format!("{}://{}{}", self.scheme, self.host, self.prefix)
+    }
+
+    /// Host name without the port.
+    fn host_name(&self) -> String {
+        self.host
+            .parse::<axum::http::uri::Authority>()
+            .map(|authority| authority.host().to_string())
+            .unwrap_or_else(|_| self.host.clone())
+    }
+
+    /// `Path` attribute for cookies that cover all of tbl's URLs.
+    fn cookie_path(&self) -> &str {
+        if self.prefix.is_empty() {
+            "/"
+        } else {
+            &self.prefix
+        }
+    }
+}
+
+#[axum::async_trait]
+impl FromRequestParts<Arc<AppState>> for PublicUrl {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(PublicUrl::of(
+            state,
+            &parts.headers,
+            peer_ip(&parts.extensions),
+        ))
+    }
+}
+
+/// Give redirects from the static file service under `/web` the full path:
+/// it only sees the path below `/web`.
+async fn fix_web_redirects(
+    State(state): State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let url = PublicUrl::of(&state, request.headers(), peer_ip(request.extensions()));
+    let below_web = request.uri().path().starts_with("/web/");
+    let mut response = next.run(request).await;
+    if !below_web || !response.status().is_redirection() {
+        return response;
+    }
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|location| location.starts_with('/') && !location.starts_with("//"))
+        .map(|location| url.path(&format!("/web{location}")));
+    if let Some(value) = location.and_then(|l| header::HeaderValue::from_str(&l).ok()) {
+        response.headers_mut().insert(header::LOCATION, value);
+    }
+    response
+}
+
+/// Files at least this big are sent with `sendfile` where the connection
+/// allows it, and otherwise read in [`LARGE_FILE_CHUNK`] pieces.
+const LARGE_FILE: u64 = 1024 * 1024;
+
+/// Read and write size for large files `ServeDir` serves. It reads 64 KiB at
+/// a time, which for media means a read, a body frame, and a socket write per
+/// 64 KiB; small files keep that, so idle connections don't hold big buffers.
+const LARGE_FILE_CHUNK: usize = 1024 * 1024;
+
+/// A file of at least [`LARGE_FILE`] bytes, opened.
+struct LargeFile {
+    file: std::fs::File,
+    path: PathBuf,
+    len: u64,
+    modified: Option<std::time::SystemTime>,
+}
+
+/// The large file at the URL path `rest` in `jail`, if it is one. Looking it
+/// up blocks, so it runs off the async workers.
+async fn large_file(jail: &Jail, rest: &str) -> Option<LargeFile> {
+    let (jail, rest) = (jail.clone(), rest.to_string());
+    tokio::task::spawn_blocking(move || {
+        let path = jail.path(jail::decode_url_path(&rest)?)?;
+        let file = std::fs::File::open(&path).ok()?;
+        let meta = file.metadata().ok()?;
+        (meta.is_file() && meta.len() >= LARGE_FILE).then(|| LargeFile {
+            file,
+            path,
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Serve the file at the URL path `rest` in `jail`: large ones with
+/// `sendfile` when the request and its connection allow it, everything else
+/// with `ServeDir`.
+async fn serve_static(jail: &Jail, rest: &str, request: axum::extract::Request) -> Response {
+    let serve = ServeDir::new(jail.root());
+    let serve = match large_file(jail, rest).await {
+        Some(large) => {
+            let sent = sendfile_handle(&request)
+                .and_then(|handle| send_large_file(&handle, large, request.headers()));
+            if let Some(response) = sent {
+                return response;
+            }
+            serve.with_buf_chunk_size(LARGE_FILE_CHUNK)
+        }
+        None => serve,
+    };
+    let Ok(response) = serve.oneshot(request).await;
+    response.into_response()
+}
+
+/// The connection's `sendfile` handle, if it has one and the request is a
+/// plain HTTP/1 `GET`; conditional requests are left to `ServeDir`.
+fn sendfile_handle(request: &axum::extract::Request) -> Option<sendfile::Handle> {
+    let conditional = [
+        header::IF_MATCH,
+        header::IF_NONE_MATCH,
+        header::IF_MODIFIED_SINCE,
+        header::IF_UNMODIFIED_SINCE,
+        header::IF_RANGE,
+    ]
+    .iter()
+    .any(|name| request.headers().contains_key(name));
+    let http1 = matches!(
+        request.version(),
+        axum::http::Version::HTTP_10 | axum::http::Version::HTTP_11
+    );
+    if request.method() != Method::GET || !http1 || conditional {
+        return None;
+    }
+    request.extensions().get::<sendfile::Handle>().cloned()
+}
+
+/// The response sending `large`, or the range of it `headers` ask for, with
+/// `sendfile`; `None` for ranges other than one satisfiable one.
+fn send_large_file(
+    handle: &sendfile::Handle,
+    large: LargeFile,
+    headers: &HeaderMap,
+) -> Option<Response> {
+    let (status, start, end) = match headers.get(header::RANGE) {
+        Some(range) => {
+            let (start, end) = single_range(range.to_str().ok()?, large.len)?;
+            (StatusCode::PARTIAL_CONTENT, start, end)
+        }
+        None => (StatusCode::OK, 0, large.len - 1),
+    };
+    let length = end - start + 1;
+    let content_type = mime_guess::from_path(&large.path)
+        .first_raw()
+        .unwrap_or("application/octet-stream");
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, length)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(modified) = large.modified {
+        response = response.header(header::LAST_MODIFIED, timefmt::http_date(modified));
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(
+            header::CONTENT_RANGE,
+            format!("bytes {start}-{end}/{}", large.len),
+        );
+    }
+    response.body(handle.send(large.file, start, length)).ok()
+}
+
+/// First and last byte of a `Range` header asking for one range of a file of
+/// `len` bytes, if it can be served.
+fn single_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = range.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') || len == 0 {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (start, end) = if first.is_empty() {
+        let suffix: u64 = last.parse().ok().filter(|&n| n > 0)?;
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = first.parse().ok()?;
+        let end = match last {
+            "" => len - 1,
+            last => last.parse::<u64>().ok()?.min(len - 1),
+        };
+        (start, end)
+    };
+    (start <= end && start < len).then_some((start, end))
+}
+
+/// Serve the app's files below `/web/`.
+async fn web_files_handler(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+    original: OriginalUri,
+    request: axum::extract::Request,
+) -> Response {
+    let rest = request.uri().path().trim_start_matches('/').to_string();
+    let response = serve_static(&state.web, &rest, request).await;
+    if response.status() == StatusCode::NOT_FOUND {
+        return not_found_handler(State(state), url, original).await;
+    }
+    response
+}
+
+/// Answer `404` for `/web/` paths that symlinks lead out of the checkout,
+/// which `ServeDir` would follow.
+async fn jail_web(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+    original: OriginalUri,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let Some(rest) = request.uri().path().strip_prefix("/web/") else {
+        return next.run(request).await;
+    };
+    if serves_inside(&state.web, rest) {
+        next.run(request).await
+    } else {
+        not_found_handler(State(state), url, original).await
+    }
+}
+
+/// Whether the URL path `rest` names a file inside `jail`, or a directory
+/// whose `index.html` is.
+fn serves_inside(jail: &Jail, rest: &str) -> bool {
+    let Some(rel) = jail::decode_url_path(rest) else {
+        return false;
+    };
+    match jail.path(&rel) {
+        Some(path) if path.is_dir() => jail.path(Path::new(&rel).join("index.html")).is_some(),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Answer the app's pages with what its `tbl.requires.yaml` asks for but
+/// this instance lacks, rather than let the app fail in the browser.
+async fn enforce_requirements(
+    State(state): State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let unmet = state.unmet_requirements.read().unwrap().clone();
+    if unmet.is_empty() || !request.uri().path().starts_with("/web/") {
+        return next.run(request).await;
+    }
+    let url = PublicUrl::of(&state, request.headers(), peer_ip(request.extensions()));
+    let body = render_template_override(
+        &state,
+        &url,
+        "requirements.html",
+        context! { problems => &unmet },
+    )
+    .unwrap_or_else(|| {
+        let detail = format!("{}.", unmet.join(". "));
+        error_page_html(
+            "This app can't run here",
+            &detail,
+            &page_branding(&state),
+            &url,
+        )
+    });
+    (StatusCode::SERVICE_UNAVAILABLE, Html(body)).into_response()
+}
+
+// =============================================================================
+// HTTP Handlers
+// =============================================================================
+
+/// Answer `OPTIONS` with the methods the route takes.
+///
+/// No route has an `OPTIONS` handler, so the request comes back from the
+/// router with the `Allow` header axum fills in; that becomes a 204. Unknown
+/// paths stay 404. tbl is same-origin only, so no `Access-Control-Allow-*`
+/// headers are sent and cross-origin preflights fail.
+async fn answer_options(request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    if request.method() != Method::OPTIONS {
+        return next.run(request).await;
+    }
+    let response = next.run(request).await;
+    let Some(allow) = response
+        .headers()
+        .get(header::ALLOW)
+        .and_then(|allow| allow.to_str().ok())
+    else {
+        return response;
+    };
+    let mut answer = (
+        StatusCode::NO_CONTENT,
+        [(header::ALLOW, format!("{allow},OPTIONS"))],
+    )
+        .into_response();
+    if let Some(id) = response.headers().get("x-request-id") {
+        answer.headers_mut().insert("x-request-id", id.clone());
+    }
+    answer
+}
+
+/// Root handler: redirect to /web/ if content exists, otherwise show setup page
+async fn index_handler(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+    auth: Option<AuthSession>,
+) -> Response {
+    let apps = {
+        let config_dir = state.config_dir.clone();
+        let url = url.clone();
+        blocking(move || list_apps(&config_dir, &url)).await
+    };
+    if apps.len() > 1 {
+        let body =
+            render_template_override(&state, &url, "directory.html", context! { apps => &apps })
+                .unwrap_or_else(|| directory_page_html(&apps, &page_branding(&state)));
+        return Html(body).into_response();
+    }
+
+    if state
+        .web
+        .path("index.html")
+        .is_some_and(|index| index.exists())
+    {
+        Redirect::temporary(&url.path("/web/")).into_response()
+    } else if auth.is_none() {
+        // `access` let a visitor in, but the setup form needs a session
+        Redirect::to(&url.path(SIGNIN_PATH)).into_response()
+    } else {
+        Html(render_setup_page(&state, &url)).into_response()
+    }
+}
+
+/// Setup page handler: always show the setup form so the workspace can be switched
+async fn setup_page_handler(State(state): State<Arc<AppState>>, url: PublicUrl) -> Response {
+    Html(render_setup_page(&state, &url)).into_response()
+}
+
+/// Render the setup page, preferring a user template override.
+fn render_setup_page(state: &AppState, url: &PublicUrl) -> String {
+    render_template_override(state, url, "setup.html", context! {}).unwrap_or_else(|| {
+        let (history, providers, catalog) = {
+            let cfg = state.config.read().unwrap();
+            let providers = cfg
+                .providers
+                .as_ref()
+                .map(forge::ProvidersConfig::configured)
+                .unwrap_or_default();
+            (cfg.history.clone(), providers, cfg.catalog_url.is_some())
+        };
+        let files = state.files_usage.current().unwrap_or_default();
+        let storage = quota::usage(&state.db, files, &state.quotas)
+            .inspect_err(|e| tracing::warn!("Failed to measure stored data: {e:#}"))
+            .ok();
+        let clients: Vec<ConnectedClient> =
+            state.clients.lock().unwrap().values().cloned().collect();
+        let uptime = uptime::stats(&state.db, true, now_unix())
+            .inspect_err(|e| tracing::warn!("Failed to read the run history: {e:#}"))
+            .ok();
+        let instance = InstanceOverview {
+            storage: storage.as_ref(),
+            clients: &clients,
+            uptime: uptime.as_ref().map(|stats| (stats, state.started_at)),
+        };
+        setup_page_html(
+            &history,
+            &providers,
+            catalog,
+            &instance,
+            &page_branding(state),
+            url,
+        )
+    })
+}
+
+/// Sign-in page: explains how to get a session when a page needs one
+async fn signin_handler(State(state): State<Arc<AppState>>, url: PublicUrl) -> Response {
+    let body =
+        render_template_override(&state, &url, "signin.html", context! {}).unwrap_or_else(|| {
+            error_page_html(
+                "Sign-in required",
+                "This page opens only in a browser signed in with the login link tbl printed \
+                 when it started. Run `tbl status --reveal` on the machine running tbl to see \
+                 the link again.",
+                &page_branding(&state),
+                &url,
+            )
+        });
+    Html(body).into_response()
+}
+
+/// Fallback handler: branded 404 page for unknown routes and missing static files
+async fn not_found_handler(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+    OriginalUri(uri): OriginalUri,
+) -> Response {
+    let path = uri.path().to_string();
+    let body = render_template_override(&state, &url, "404.html", context! { path => path })
+        .unwrap_or_else(|| {
+            let detail = format!("No page exists at {path}.");
+            error_page_html("Page not found", &detail, &page_branding(&state), &url)
+        });
+    (StatusCode::NOT_FOUND, Html(body)).into_response()
+}
+
+/// Preview handler: serve a file of a branch's checkout, cloning the branch
+/// on first use and fetching it in the background once it is stale
+async fn preview_handler(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+    original: OriginalUri,
+    UrlPath(params): UrlPath<HashMap<String, String>>,
+    request: axum::extract::Request,
+) -> Response {
+    let (Some(git_url), Some(branch)) = (previews_source(&state), params.get("branch").cloned())
+    else {
+        return not_found_handler(State(state), url, original).await;
+    };
+    let prefix = format!("/preview/{}", preview::encode(&branch));
+    let rest = request
+        .uri()
+        .path()
+        .strip_prefix("/preview/")
+        .and_then(|path| path.split_once('/'))
+        .map(|(_, rest)| rest.to_string());
+    let Some(rest) = rest else {
+        // Relative links of the site only resolve below the slash
+        return Redirect::temporary(&url.path(&format!("{prefix}/"))).into_response();
+    };
+
+    let existing = {
+        let config_dir = state.config_dir.clone();
+        let branch = branch.clone();
+        blocking(move || preview::find(&config_dir, &branch)).await
+    };
+    match existing {
+        Some(preview) => {
+            if preview::is_stale(&preview) {
+                let state = state.clone();
+                let branch = branch.clone();
+                tokio::spawn(async move {
+                    let _ = preview_job(&state, &git_url, &branch, false).await;
+                });
+            }
+        }
+        None => {
+            let branches = {
+                let settings = source::Settings::of(&state.config.read().unwrap());
+                let git_url = git_url.clone();
+                blocking(move || preview::branches(&settings, &git_url)).await
+            };
+            let result = match branches {
+                Ok(branches) if !branches.contains(&branch) => {
+                    return not_found_handler(State(state), url, original).await;
+                }
+                Ok(_) => preview_job(&state, &git_url, &branch, false)
+                    .await
+                    .map(drop),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                let detail = redact_credentials(&format!("Could not check out {branch}: {e:#}"));
+                let body =
+                    error_page_html("Preview unavailable", &detail, &page_branding(&state), &url);
+                return (StatusCode::BAD_GATEWAY, Html(body)).into_response();
+            }
+        }
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let query = parts
+        .uri
+        .query()
+        .map(|q| format!("?{q}"))
+        .unwrap_or_default();
+    match format!("/{rest}{query}").parse() {
+        Ok(uri) => parts.uri = uri,
+        Err(_) => return not_found_handler(State(state), url, original).await,
+    }
+    let jail = Jail::new(preview::dir(&state.config_dir, &branch));
+    if !serves_inside(&jail, &rest) {
+        return not_found_handler(State(state), url, original).await;
+    }
+    let mut response = serve_static(
+        &jail,
+        &rest,
+        axum::extract::Request::from_parts(parts, body),
+    )
+    .await;
+    if response.status() == StatusCode::NOT_FOUND {
+        return not_found_handler(State(state), url, original).await;
+    }
+    // ServeDir redirects directories to their slash relative to the checkout
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|location| location.starts_with('/') && !location.starts_with("//"))
+        .map(|location| url.path(&format!("{prefix}{location}")));
+    if let Some(value) = location.and_then(|l| header::HeaderValue::from_str(&l).ok()) {
+        response.headers_mut().insert(header::LOCATION, value);
+    }
+    response.map(axum::body::Body::new).into_response()
+}
+
+/// The git URL whose branches `/preview/` serves, if previews are on.
+fn previews_source(state: &AppState) -> Option<String> {
+    let cfg = state.config.read().unwrap();
+    if !cfg.previews.unwrap_or(false) {
+        return None;
+    }
+    cfg.git_url
+        .clone()
+        .filter(|url| source::provider(url).uses_git())
+}
+
+/// Failed `/bootstrap` attempts from one client within
+/// [`BOOTSTRAP_LOCKOUT`] after which it has to wait.
+const BOOTSTRAP_MAX_FAILURES: usize = 5;
+
+/// Window over which failed `/bootstrap` attempts count.
+const BOOTSTRAP_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Bootstrap handler: validate token and set authentication cookie
+async fn bootstrap_handler(
+    State(state): State<Arc<AppState>>,
+    url: PublicUrl,
+    parts: Parts,
+    Query(q): Query<BootstrapQuery>,
+) -> Response {
+    let client = client_ip(&state, &parts.headers, &parts.extensions)
+        .map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    let now = now_unix();
+    let failures = bootstrap_failures(&state, &client, now);
+    if failures.len() >= BOOTSTRAP_MAX_FAILURES {
+        // Even a valid token waits, so guessing gets nowhere
+        let retry_after = failures[BOOTSTRAP_MAX_FAILURES - 1] + BOOTSTRAP_LOCKOUT.as_secs() - now;
+        return bootstrap_failed_page(&state, &url, 0, retry_after.max(1));
+    }
+
+    let Some(token) = q.token else {
+        return (StatusCode::BAD_REQUEST, "missing token in query").into_response();
+    };
+
+    if state.tokens.verify(&token).is_none() {
+        if let Err(e) = state.db.record_bootstrap_failure(&client, now) {
+            tracing::warn!("Failed to record a failed sign-in: {e:#}");
+        }
+        let failed = failures.len() + 1;
+        if failed < BOOTSTRAP_MAX_FAILURES {
+            tracing::info!(client, failed, "invalid login link");
+            return bootstrap_failed_page(&state, &url, BOOTSTRAP_MAX_FAILURES - failed, 0);
+        }
+        let retry_after = BOOTSTRAP_LOCKOUT.as_secs();
+        tracing::warn!(client, failed, "repeated invalid login links; locked out");
+        emit_event(
+            &state,
+            "auth.lockout",
+            serde_json::json!({ "client": client, "failures": failed, "until": now + retry_after }),
+        );
+        return bootstrap_failed_page(&state, &url, 0, retry_after);
+    }
+    if !failures.is_empty() {
+        if let Err(e) = state.db.clear_bootstrap_failures(&client) {
+            tracing::warn!("Failed to clear failed sign-ins: {e:#}");
+        }
+    }
+
+    // The browser gets a token of its own, revoked with its session
+    let session_id = create_session(&state, &parts.headers);
+    let Some(token) = state.tokens.issue_for(&session_id) else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "failed to issue a token").into_response();
+    };
+    let token = token.expose().to_string();
+    let body = render_template_override(
+        &state,
+        &url,
+        "bootstrap.html",
+        context! { token => token, session_id => session_id },
+    )
+    .unwrap_or_else(|| bootstrap_page_html(&token, &session_id, &page_branding(&state), &url));
+    Html(body).into_response()
+}
+
+/// When `client` failed `/bootstrap` within [`BOOTSTRAP_LOCKOUT`], newest
+/// first.
+fn bootstrap_failures(state: &AppState, client: &str, now: u64) -> Vec<u64> {
+    state
+        .db
+        .bootstrap_failures(client, now.saturating_sub(BOOTSTRAP_LOCKOUT.as_secs()))
+        .inspect_err(|e| tracing::warn!("Failed to read failed sign-ins: {e:#}"))
+        .unwrap_or_default()
+}
+
+/// Page for a login link that didn't work: `403` with the tries left, or
+/// `429` once locked out for `retry_after` seconds.
+fn bootstrap_failed_page(
+    state: &AppState,
+    url: &PublicUrl,
+    tries_left: usize,
+    retry_after: u64,
+) -> Response {
+    let body = render_template_override(
+        state,
+        url,
+        "bootstrap-failed.html",
+        context! { tries_left => tries_left, retry_after => retry_after },
+    )
+    .unwrap_or_else(|| {
+        let (heading, detail) = if retry_after > 0 {
+            let wait = match retry_after {
+                0..=59 => format!("{retry_after} seconds"),
+                60..=119 => "a minute".to_string(),
+                _ => format!("{} minutes", retry_after.div_ceil(60)),
+            };
+            (
+                "Too many attempts",
+                format!(
+                    "This browser tried too many login links that don't work. Try again in \
+                     {wait}. Run `tbl status --reveal` on the machine running tbl to see the \
+                     current link."
+                ),
+            )
+        } else {
+            let tries = match tries_left {
+                1 => "1 more try".to_string(),
+                n => format!("{n} more tries"),
+            };
+            (
+                "This login link doesn't work",
+                format!(
+                    "It may be mistyped, or from before tbl restarted. Run `tbl status --reveal` \
+                     on the machine running tbl to see the current link. {tries} before a pause."
+                ),
+            )
+        };
+        error_page_html(heading, &detail, &page_branding(state), url)
+    });
+    let status = if retry_after > 0 {
+        StatusCode::TOO_MANY_REQUESTS
+    } else {
+        StatusCode::FORBIDDEN
+    };
+    let mut response = (status, Html(body)).into_response();
+    if retry_after > 0 {
+        if let Ok(value) = retry_after.to_string().parse() {
+            response.headers_mut().insert(header::RETRY_AFTER, value);
+        }
+    }
+    response
+}
+
+/// Setup handler: clone git repository and save config
+async fn setup_handler(
+    State(state): State<Arc<AppState>>,
+    public_url: PublicUrl,
+    Form(form): Form<SetupForm>,
+) -> Response {
+    let branding = page_branding(&state);
+    let url = form.git_url.trim().to_string();
+    if url.is_empty() {
+        let body = error_page_html(
+            "Missing git URL",
+            "Enter a repository URL.",
+            &branding,
+            &public_url,
+        );
+        return (StatusCode::BAD_REQUEST, Html(body)).into_response();
+    }
+
+    if let Err(e) = git_ready(&url).await {
+        let detail = format!("{e}\n\nPlease install git and try again.");
+        let body = error_page_html("Git is required", &detail, &branding, &public_url);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(body)).into_response();
+    }
+
+    if let Err(e) = ensure_repo_job(&state, JobKind::Switch, &url).await {
+        let body = error_page_html(
+            "Failed to clone repository",
+            &e.to_string(),
+            &branding,
+            &public_url,
+        );
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(body)).into_response();
+    }
+
+    // Persist config with new git_url
+    let pin = form.catalog_app.map(|name| CatalogPin {
+        name,
+        version: None,
+        channel: None,
+        installed: form.catalog_version.filter(|version| !version.is_empty()),
+    });
+    commit_workspace(&state, &url, pin).await;
+
+    Redirect::to(&public_url.path("/")).into_response()
+}
+
+/// Persist `url` as the active workspace and record it in the history,
+/// with the catalog app it is a release of, if any.
+async fn commit_workspace(state: &AppState, url: &str, pin: Option<CatalogPin>) {
+    let web_root = state.web.root().to_path_buf();
+    let branch = blocking(move || default_branch(&web_root)).await;
+    let cfg = {
+        let mut cfg = state.config.write().unwrap();
+        cfg.git_url = Some(url.to_string());
+        cfg.catalog_app = pin;
+        record_history(&mut cfg, url);
+        remember_repo(&mut cfg, url, branch);
+        cfg.clone()
+    };
+
+    let config_dir = state.config_dir.clone();
+    if let Err(e) = blocking(move || save_config(&config_dir, &cfg)).await {
+        tracing::error!("Failed to save config: {e:#}");
+    }
+}
+
+/// Persist `clone_depth: 0`; later fetches with a depth would make the
+/// checkout shallow again.
+async fn keep_full_history(state: &AppState) {
+    let cfg = {
+        let mut cfg = state.config.write().unwrap();
+        cfg.clone_depth = Some(0);
+        cfg.clone()
+    };
+
+    let config_dir = state.config_dir.clone();
+    if let Err(e) = blocking(move || save_config(&config_dir, &cfg)).await {
+        tracing::error!("Failed to save config: {e:#}");
+    }
+}
+
+/// JavaScript SDK handler; the API base follows the page's URL prefix
+async fn tbl_js_handler(url: PublicUrl) -> Response {
+    let js = TBL_JS
+        .replace("__TBL_VERSION__", env!("CARGO_PKG_VERSION"))
+        .replace("__TBL_PREFIX__", &url.prefix);
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/javascript")],
+        js,
+    )
+        .into_response()
+}
+
+/// TypeScript declarations for the JavaScript SDK
+async fn tbl_dts_handler() -> Response {
+    let dts = TBL_DTS.replace("__TBL_VERSION__", env!("CARGO_PKG_VERSION"));
+
+    (
+        StatusCode::OK,
+        [(
+            header::CONTENT_TYPE,
+            "application/typescript; charset=utf-8",
+        )],
+        dts,
+    )
+        .into_response()
+}
+
+// =============================================================================
+// Logging
+// =============================================================================
+
+/// Default log filter; override with `TBL_LOG` (e.g. `TBL_LOG=debug`).
+const DEFAULT_LOG_FILTER: &str = "info";
+
+/// Log to stderr, which the daemon redirects to its log file, or to stdout
+/// in a container, where the runtime collects it.
+fn init_logging(to_stdout: bool) {
+    let filter = tracing_subscriber::EnvFilter::try_from_env("TBL_LOG")
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(DEFAULT_LOG_FILTER));
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_timer(timefmt::LogTime);
+    if to_stdout {
+        logger
+            .with_writer(io::stdout)
+            .with_ansi(io::stdout().is_terminal())
+            .init();
+    } else {
+        logger
+            .with_writer(io::stderr)
+            .with_ansi(io::stderr().is_terminal())
+            .init();
+    }
+}
+
+/// Span for one HTTP request, tagged with its `x-request-id` and client.
+///
+/// Only the path is recorded: query strings can carry the bootstrap token.
+fn request_span<B>(state: &AppState, request: &axum::http::Request<B>) -> tracing::Span {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or("-");
+    let client = client_ip(state, request.headers(), request.extensions());
+    tracing::info_span!(
+        "request",
+        id = %request_id,
+        client = %client.map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+        method = %request.method(),
+        path = %request.uri().path(),
+    )
+}
+
+// =============================================================================
+// Crash Reports
+// =============================================================================
+
+/// Crash reports kept in `crash/`; older ones are pruned on each new crash.
+const MAX_CRASH_REPORTS: usize = 20;
+
+/// Write a report to `<config_dir>/crash/` whenever the daemon panics.
+///
+/// The default hook still runs afterwards, so the panic also reaches stderr.
+fn install_crash_handler(config_dir: &Path) {
+    let config_dir = config_dir.to_path_buf();
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        match write_crash_report(&config_dir, info) {
+            Ok(path) => eprintln!("tbl crashed; report written to {:?}", path),
+            Err(e) => eprintln!("tbl crashed; failed to write crash report: {e:#}"),
+        }
+        default_hook(info);
+    }));
+}
+
+fn write_crash_report(config_dir: &Path, info: &std::panic::PanicHookInfo) -> Result<PathBuf> {
+    let crash_dir = config_dir.join("crash");
+    fs::create_dir_all(&crash_dir)?;
+
+    let message = info
+        .payload()
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "<non-string panic payload>".to_string());
+    let location = info
+        .location()
+        .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+        .unwrap_or_else(|| "<unknown>".to_string());
+    let thread = std::thread::current()
+        .name()
+        .unwrap_or("<unnamed>")
+        .to_string();
+    // Read the persisted config rather than sharing state with the server
+    let config = load_config(config_dir)
+        .map(|cfg| redacted_config(&cfg))
+        .and_then(|cfg| serde_yaml::to_string(&cfg).ok())
+        .unwrap_or_else(|| "<unavailable>\n".to_string());
+    let backtrace = std::backtrace::Backtrace::force_capture();
+
+    let report = format!(
+        "tbl crash report\n\
+         \n\
+         version:  {}\n\
+         time:     {}\n\
+         pid:      {}\n\
+         thread:   {}\n\
+         platform: {}/{}\n\
+         \n\
+         panic: {}\n\
+         at:    {}\n\
+         \n\
+         config (secrets redacted):\n\
+         {}\n\
+         backtrace:\n\
+         {}\n",
+        env!("CARGO_PKG_VERSION"),
+        timefmt::rfc3339(&chrono::Utc::now()),
+        std::process::id(),
+        thread,
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        message,
+        location,
+        config,
+        backtrace
+    );
+
+    let path = crash_dir.join(format!("crash-{}-{}.txt", now_unix(), std::process::id()));
+    fs::write(&path, report)?;
+    prune_crash_reports(&crash_dir);
+    Ok(path)
+}
+
+/// Copy of `cfg` that is safe to write into reports.
+fn redacted_config(cfg: &TblConfig) -> TblConfig {
+    let mut cfg = cfg.clone();
+    if cfg.basic_pass.is_some() {
+        cfg.basic_pass = Some(Secret::new("<redacted>"));
+    }
+    if cfg.peer_token.is_some() {
+        cfg.peer_token = Some(Secret::new("<redacted>"));
+    }
+    cfg.git_url = cfg.git_url.as_deref().map(redact_credentials);
+    cfg.git_proxy = cfg.git_proxy.as_deref().map(redact_credentials);
+    cfg.catalog_url = cfg.catalog_url.as_deref().map(redact_credentials);
+    if let Some(mirrors) = &mut cfg.git_mirrors {
+        *mirrors = mirrors
+            .iter()
+            .map(|(url, mirrors)| {
+                let mirrors = mirrors.iter().map(|mirror| redact_credentials(mirror));
+                (redact_credentials(url), mirrors.collect())
+            })
+            .collect();
+    }
+    for entry in &mut cfg.history {
+        entry.git_url = redact_credentials(&entry.git_url);
+    }
+    for notifier in &mut cfg.notifications {
+        notifier.redact_secrets();
+    }
+    if let Some(s3) = &mut cfg.s3 {
+        s3.redact_secrets();
+    }
+    if let Some(providers) = &mut cfg.providers {
+        providers.redact_secrets();
+    }
+    cfg
+}
+
+/// Crash reports, newest first.
+fn crash_reports(config_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(config_dir.join("crash")) else {
+        return Vec::new();
+    };
+    let mut reports: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("crash-") && n.ends_with(".txt"))
+        })
+        .collect();
+    reports.sort_by_key(|p| std::cmp::Reverse(crash_report_time(p)));
+    reports
+}
+
+fn prune_crash_reports(crash_dir: &Path) {
+    let Some(config_dir) = crash_dir.parent() else {
+        return;
+    };
+    for old in crash_reports(config_dir)
+        .into_iter()
+        .skip(MAX_CRASH_REPORTS)
+    {
+        let _ = fs::remove_file(old);
+    }
+}
+
+/// Unix time encoded in a `crash-<time>-<pid>.txt` file name.
+fn crash_report_time(path: &Path) -> u64 {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_prefix("crash-"))
+        .and_then(|n| n.split('-').next())
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The `panic:` line of a crash report.
+fn crash_report_summary(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok()?.lines().find_map(|line| {
+        line.strip_prefix("panic: ")
+            .map(|msg| msg.trim().to_string())
+    })
+}
+
+// =============================================================================
+// Daemon Detachment
+// =============================================================================
+
+/// The daemon log is rotated to `tbl.log.1` once it grows past this size.
+const MAX_LOG_BYTES: u64 = 5 * 1024 * 1024;
+
+/// How long the launching process waits for the daemon to start serving.
+/// Generous because the first start clones the repository.
+const STARTUP_WAIT: Duration = Duration::from_secs(120);
+
+fn daemon_log_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("log").join("tbl.log")
+}
+
+fn open_daemon_log(path: &Path) -> Result<fs::File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create log dir {:?}", dir))?;
+    }
+    if fs::metadata(path).is_ok_and(|m| m.len() > MAX_LOG_BYTES) {
+        let _ = fs::rename(path, path.with_extension("log.1"));
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file {:?}", path))
+}
+
+/// Start the daemon in its own session (Unix) or without a console (Windows)
+/// so closing the terminal does not take it down.
+#[cfg(unix)]
+fn detach_process(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    // SAFETY: setsid is async-signal-safe and touches no parent state
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(windows)]
+fn detach_process(cmd: &mut Command) {
+    use std::os::windows::process::CommandExt;
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+    cmd.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP | CREATE_NO_WINDOW);
+}
+
+#[cfg(not(any(unix, windows)))]
+fn detach_process(_cmd: &mut Command) {}
+
+/// Wait until the spawned daemon writes `pid.yaml` and accepts connections,
+/// then print where it is listening.
+fn wait_for_daemon(
+    mut child: std::process::Child,
+    run_dir: &Path,
+    log_path: &Path,
+    no_browser: bool,
+    timings: Option<Timings>,
+) -> Result<()> {
+    let child_pid = child.id();
+    println!(
+        "  Starting tbl in the background (log: {})",
+        log_path.display()
+    );
+
+    let deadline = std::time::Instant::now() + STARTUP_WAIT;
+    while std::time::Instant::now() < deadline {
+        if let Some(info) = load_run_info(run_dir)
+            .filter(|info| info.pid == child_pid || info.supervisor_pid == Some(child_pid))
+            .filter(|info| port_is_open(info.port))
+        {
+            let public_url = login_url(&info);
+            println!();
+            println!("  tbl server started");
+            println!("  ───────────────────────────────────────");
+            println!("  PID:    {}", info.pid);
+            println!("  Port:   {}", info.port);
+            println!(
+                "  TLS:    {}",
+                if info.tls { "enabled" } else { "disabled" }
+            );
+            println!();
+            print_url_box(&public_url);
+            if no_browser {
+                println!("\n  Open the URL above to authenticate.");
+            } else {
+                println!("\n  Opening browser...");
+            }
+            println!();
+            if let Some(timings) = timings {
+                print_startup_timings(timings, run_dir, child_pid, deadline);
+            }
+            return Ok(());
+        }
+
+        if let Some(status) = child.try_wait()? {
+            eprintln!();
+            eprintln!("  tbl exited during startup ({status}).");
+            eprintln!("  See {} for details.", log_path.display());
+            eprintln!();
+            anyhow::bail!("daemon failed to start");
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    println!();
+    println!(
+        "  tbl is still starting; check `tbl status` or {}.",
+        log_path.display()
+    );
+    println!();
+    Ok(())
+}
+
+/// Print the daemon's startup phases once it has recorded them all, after
+/// `launcher`, the time this process took before starting it.
+fn print_startup_timings(
+    mut timings: Timings,
+    run_dir: &Path,
+    child_pid: u32,
+    deadline: std::time::Instant,
+) {
+    let recorded = loop {
+        let report = load_run_info(run_dir)
+            .filter(|info| info.pid == child_pid || info.supervisor_pid == Some(child_pid))
+            .and_then(|info| info.timings);
+        match report {
+            Some(report) if report.total_ms.is_some() => break Some(report),
+            _ if std::time::Instant::now() >= deadline => break report,
+            _ => std::thread::sleep(Duration::from_millis(100)),
+        }
+    };
+    let mut report = timings.finish();
+    if let Some(recorded) = recorded {
+        report.phases.extend(recorded.phases);
+    }
+    report.print("other");
+}
+
+// =============================================================================
+// Watchdog
+// =============================================================================
+
+/// First restart delay; doubles after each short-lived run.
+const WATCHDOG_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const WATCHDOG_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A daemon that ran at least this long resets the backoff.
+const WATCHDOG_STABLE_RUN: Duration = Duration::from_secs(60);
+/// Consecutive short-lived runs before the watchdog gives up.
+const WATCHDOG_MAX_RAPID_RESTARTS: u32 = 10;
+
+/// Watchdog mode: `--watchdog` > `TBL_WATCHDOG` > `watchdog` in the config file.
+fn watchdog_enabled(cli: &Cli) -> bool {
+    if cli.watchdog {
+        return true;
+    }
+    if let Ok(value) = std::env::var("TBL_WATCHDOG") {
+        return matches!(value.as_str(), "1" | "true" | "yes" | "on");
+    }
+    get_config_dir()
+        .ok()
+        .and_then(|dir| load_config(&dir))
+        .and_then(|cfg| cfg.watchdog)
+        .unwrap_or(false)
+}
+
+/// Restart number passed down by the watchdog (0 for the first run).
+fn watchdog_restart_count() -> u32 {
+    std::env::var("TBL_RESTART_COUNT")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Run the daemon as a child process, restarting it with backoff whenever it
+/// exits abnormally. A clean exit (e.g. `tbl --stop`) ends the watchdog too.
+fn run_watchdog() -> Result<()> {
+    let exe = std::env::current_exe().context("cannot get current exe")?;
+    let mut restarts: u32 = 0;
+    let mut rapid_restarts: u32 = 0;
+    let mut backoff = WATCHDOG_MIN_BACKOFF;
+
+    loop {
+        let started = std::time::Instant::now();
+        let mut cmd = Command::new(&exe);
+        cmd.args(std::env::args().skip(1))
+            .env_remove("TBL_SUPERVISOR")
+            .env("TBL_DAEMONIZED", "1")
+            .env("TBL_SUPERVISOR_PID", std::process::id().to_string())
+            .env("TBL_RESTART_COUNT", restarts.to_string())
+            .stdin(Stdio::null());
+        let status = vault::spawn_with_passphrase(&mut cmd, vault::stdin_passphrase())
+            .and_then(|mut child| child.wait())
+            .context("failed to spawn tbl daemon")?;
+
+        if status.success() {
+            return Ok(());
+        }
+
+        if started.elapsed() >= WATCHDOG_STABLE_RUN {
+            backoff = WATCHDOG_MIN_BACKOFF;
+            rapid_restarts = 0;
+        } else {
+            rapid_restarts += 1;
+        }
+        if rapid_restarts >= WATCHDOG_MAX_RAPID_RESTARTS {
+            eprintln!(
+                "tbl watchdog: daemon failed {} times in a row ({status}); giving up",
+                rapid_restarts
+            );
+            anyhow::bail!("daemon keeps exiting abnormally");
+        }
+
+        eprintln!(
+            "tbl watchdog: daemon exited ({status}); restarting in {}s",
+            backoff.as_secs()
+        );
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(WATCHDOG_MAX_BACKOFF);
+        restarts += 1;
+    }
+}
+
+// =============================================================================
+// Idle Shutdown
+// =============================================================================
+
+/// Requests that do not count as activity for the idle timeout.
+const IDLE_EXEMPT_PATHS: &[&str] = &["/api/v1/ping"];
+
+/// Middleware recording the time of the last non-health-check request.
+async fn track_activity(
+    State(state): State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if !IDLE_EXEMPT_PATHS.contains(&request.uri().path()) {
+        state.last_activity.store(now_unix(), Ordering::Relaxed);
+    }
+    next.run(request).await
+}
+
+/// Shut the server down once nothing has happened for `timeout`.
+async fn watch_idle(state: Arc<AppState>, timeout: Duration) {
+    let check_every = (timeout / 4).clamp(Duration::from_secs(1), Duration::from_secs(30));
+    loop {
+        tokio::time::sleep(check_every).await;
+        if !state.clients.lock().unwrap().is_empty() {
+            state.last_activity.store(now_unix(), Ordering::Relaxed);
+            continue;
+        }
+        let idle = now_unix().saturating_sub(state.last_activity.load(Ordering::Relaxed));
+        if idle >= timeout.as_secs() {
+            tracing::info!("No requests for {idle}s; shutting down (idle timeout)");
+            request_shutdown(&state, "idle_timeout", ShutdownMode::Graceful).await;
+            return;
+        }
+    }
+}
+
+/// How long a graceful shutdown waits for open requests before closing
+/// their connections.
+const SHUTDOWN_DRAIN: Duration = Duration::from_secs(10);
+
+/// How the server stops.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum ShutdownMode {
+    /// Stop accepting connections and give open requests up to
+    /// `SHUTDOWN_DRAIN` to finish
+    #[default]
+    Graceful,
+    /// Close every connection at once
+    Now,
+}
+
+/// A shutdown asked for later, as `/api/v1/info` reports it.
+#[derive(Serialize, Debug, Clone, Copy, ToSchema)]
+struct ScheduledShutdown {
+    /// Unix timestamp (seconds) when the server stops
+    at: u64,
+    mode: ShutdownMode,
+}
+
+/// Serve until `server` fails or a shutdown is requested, then let open
+/// requests finish or drop them as the request asked. Returns the reason
+/// the server stopped for.
+async fn serve_until_shutdown(
+    server: impl std::future::Future<Output = std::io::Result<()>>,
+    handle: axum_server::Handle,
+    shutdown_rx: oneshot::Receiver<(&'static str, ShutdownMode)>,
+) -> &'static str {
+    tokio::pin!(server);
+    let reason = tokio::select! {
+        result = &mut server => {
+            if let Err(e) = result {
+                tracing::error!("Server error: {e}");
+            }
+            return "server_error";
+        }
+        request = shutdown_rx => {
+            println!("  Shutdown signal received, stopping server...");
+            match request {
+                Ok((reason, ShutdownMode::Graceful)) => reason,
+                Ok((reason, ShutdownMode::Now)) => return reason,
+                Err(_) => return "shutdown_request",
+            }
+        }
+    };
+    handle.graceful_shutdown(Some(SHUTDOWN_DRAIN));
+    if let Err(e) = server.await {
+        tracing::error!("Server error: {e}");
+    }
+    reason
+}
+
+/// Emit `server.stopping` with `reason` and ask the server to shut down;
+/// later calls are no-ops.
+async fn request_shutdown(state: &AppState, reason: &'static str, mode: ShutdownMode) {
+    if let Some(tx) = state.shutdown_tx.lock().await.take() {
+        emit_event(
+            state,
+            "server.stopping",
+            serde_json::json!({ "reason": reason, "mode": mode }),
+        );
+        let _ = tx.send((reason, mode));
+    }
+}
+
+/// A scheduled shutdown and the task waiting for it.
+struct PendingShutdown {
+    scheduled: ScheduledShutdown,
+    /// Tells this schedule from the ones replacing it
+    id: u64,
+    task: tokio::task::AbortHandle,
+}
+
+/// Shut down after `delay`, replacing an earlier schedule.
+fn schedule_shutdown(
+    state: &Arc<AppState>,
+    delay: Duration,
+    mode: ShutdownMode,
+) -> ScheduledShutdown {
+    let scheduled = ScheduledShutdown {
+        at: now_unix().saturating_add(delay.as_secs()),
+        mode,
+    };
+    let id = state.next_shutdown_id.fetch_add(1, Ordering::Relaxed);
+    let mut current = state.scheduled_shutdown.lock().unwrap();
+    let task = tokio::spawn({
+        let state = state.clone();
+        async move {
+            tokio::time::sleep(delay).await;
+            // Aborting misses a task already past its sleep, so it checks
+            // that it wasn't replaced or cancelled meanwhile
+            {
+                let mut current = state.scheduled_shutdown.lock().unwrap();
+                if current.as_ref().is_none_or(|pending| pending.id != id) {
+                    return;
+                }
+                current.take();
+            }
+            tracing::info!("Scheduled shutdown is due; shutting down");
+            request_shutdown(&state, "shutdown_request", mode).await;
+        }
+    });
+    let pending = PendingShutdown {
+        scheduled,
+        id,
+        task: task.abort_handle(),
+    };
+    if let Some(previous) = current.replace(pending) {
+        previous.task.abort();
+    }
+    scheduled
+}
+
+/// Call off a scheduled shutdown, returning it; `None` if there was none.
+fn cancel_shutdown(state: &AppState) -> Option<ScheduledShutdown> {
+    let pending = state.scheduled_shutdown.lock().unwrap().take()?;
+    pending.task.abort();
+    Some(pending.scheduled)
+}
+
+/// The scheduled shutdown, if any.
+fn scheduled_shutdown(state: &AppState) -> Option<ScheduledShutdown> {
+    state
+        .scheduled_shutdown
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|pending| pending.scheduled)
+}
+
+// =============================================================================
+// Serving Hours
+// =============================================================================
+
+/// Requests answered even while sleeping, so `tbl --stop` and monitoring work.
+const SCHEDULE_EXEMPT_PATHS: &[&str] = &[
+    "/api/v1/ping",
+    "/api/v1/info",
+    "/api/v1/shutdown",
+    "/api/v1/shutdown/cancel",
+    "/api/v1/openapi.json",
+];
+
+/// Middleware answering with a "sleeping" response outside serving hours.
+async fn enforce_schedule(
+    State(state): State<Arc<AppState>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let Some(schedule) = &state.schedule else {
+        return next.run(request).await;
+    };
+    let now = Local::now();
+    let path = request.uri().path();
+    if schedule.is_open(now) || SCHEDULE_EXEMPT_PATHS.contains(&path) {
+        return next.run(request).await;
+    }
+
+    let next_open = schedule.next_open(now);
+    let retry_after = next_open
+        .map(|at| (at - now).num_seconds().max(1))
+        .unwrap_or(3600);
+    let back_at = next_open.map(|at| timefmt::human(&at, timefmt::Style::Weekday));
+
+    let mut response = if path.starts_with("/api/") {
+        api::error::ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "sleeping",
+            format!("outside serving hours ({schedule})"),
+        )
+        .with_details(serde_json::json!({
+            "serve_hours": schedule.to_string(),
+            "next_open": next_open.map(|at| timefmt::rfc3339(&at)),
+        }))
+        .into_response()
+    } else {
+        let url = PublicUrl::of(&state, request.headers(), peer_ip(request.extensions()));
+        let body = render_template_override(
+            &state,
+            &url,
+            "sleeping.html",
+            context! { serve_hours => schedule.to_string(), back_at => back_at },
+        )
+        .unwrap_or_else(|| {
+            let detail = match &back_at {
+                Some(at) => format!("Available {schedule}. Back {at}."),
+                None => format!("Available {schedule}."),
+            };
+            error_page_html("Sleeping", &detail, &page_branding(&state), &url)
+        });
+        (StatusCode::SERVICE_UNAVAILABLE, Html(body)).into_response()
+    };
+    if let Ok(value) = retry_after.to_string().parse() {
+        response.headers_mut().insert(header::RETRY_AFTER, value);
+    }
+    response
+}
+
+/// With `outside_hours: stop`, shut down when the current window closes.
+async fn watch_schedule(state: Arc<AppState>) {
+    let Some(schedule) = state.schedule.clone() else {
+        return;
+    };
+    loop {
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        if !schedule.is_open(Local::now()) {
+            tracing::info!("Serving window closed ({schedule}); shutting down");
+            request_shutdown(&state, "schedule", ShutdownMode::Graceful).await;
+            return;
+        }
+    }
+}
+
+// =============================================================================
+// Certificate Expiry
+// =============================================================================
+
+/// Warn this long before the TLS certificate expires (`tls.expiring`).
+const TLS_EXPIRY_WARNING: Duration = Duration::from_secs(14 * 24 * 3600);
+const TLS_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// Expiry of the first certificate in a PEM file, as a Unix timestamp.
+fn certificate_expiry(path: &Path) -> Result<i64> {
+    let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&data)
+        .map_err(|e| anyhow::anyhow!("invalid PEM in {}: {e}", path.display()))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|e| anyhow::anyhow!("invalid certificate in {}: {e}", path.display()))?;
+    Ok(cert.validity().not_after.timestamp())
+}
+
+/// Emit `tls.expiring` once a day while the certificate is about to expire
+/// (or already has), so notifiers can warn before browsers start refusing it.
+async fn watch_certificate(state: Arc<AppState>, cert: PathBuf) {
+    loop {
+        let path = cert.clone();
+        match blocking(move || certificate_expiry(&path)).await {
+            Ok(not_after) => {
+                let left = not_after - now_unix() as i64;
+                if left < TLS_EXPIRY_WARNING.as_secs() as i64 {
+                    let days_left = left.max(0) / 86400;
+                    tracing::warn!(cert = %cert.display(), days_left, "TLS certificate expires soon");
+                    emit_event(
+                        &state,
+                        "tls.expiring",
+                        serde_json::json!({
+                            "cert": cert,
+                            "not_after": not_after,
+                            "days_left": days_left,
+                            "expired": left <= 0,
+                        }),
+                    );
+                }
+            }
+            Err(e) => tracing::warn!("Cannot check TLS certificate expiry: {e:#}"),
+        }
+        tokio::time::sleep(TLS_EXPIRY_CHECK_INTERVAL).await;
+    }
+}
+
+// =============================================================================
+// Events & Sessions
+// =============================================================================
+
+/// Publish an event to all `/api/v1/events` subscribers.
+fn emit_event(state: &AppState, kind: &str, data: serde_json::Value) {
+    // Held while sending, so a new subscriber sees each event either in the
+    // recent ones or on its receiver
+    let mut recent = state.recent_events.lock().unwrap();
+    let event = Event {
+        id: state.next_event_id.fetch_add(1, Ordering::Relaxed),
+        kind: kind.to_string(),
+        time: now_unix(),
+        data,
+    };
+    if recent.len() == EVENT_BUFFER {
+        recent.pop_front();
+    }
+    recent.push_back(event.clone());
+    // Sending only fails when nobody is subscribed
+    let _ = state.events.send(event);
+}
+
+/// Subscribe to events, with the recent ones after the event ID `after` to
+/// deliver first. An ID this server hasn't reached is from before a restart,
+/// so every recent event is new to that client.
+fn subscribe_events(
+    state: &AppState,
+    after: Option<u64>,
+) -> (broadcast::Receiver<Event>, Vec<Event>) {
+    let recent = state.recent_events.lock().unwrap();
+    let rx = state.events.subscribe();
+    let missed = match after {
+        Some(after) if after < state.next_event_id.load(Ordering::Relaxed) => recent
+            .iter()
+            .filter(|event| event.id > after)
+            .cloned()
+            .collect(),
+        Some(_) => recent.iter().cloned().collect(),
+        None => Vec::new(),
+    };
+    (rx, missed)
+}
+
+fn create_session(state: &AppState, headers: &HeaderMap) -> String {
+    let mut buf = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut buf);
+    let id = hex::encode(buf);
+
+    let now = now_unix();
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let session = SessionInfo {
+        id: id.clone(),
+        created_at: now,
+        last_seen: now,
+        user_agent,
+    };
+    if let Err(e) = state.db.create_session(&session) {
+        tracing::warn!("Failed to record a session: {e:#}");
+    }
+
+    id
+}
+
+fn touch_session(state: &AppState, id: &str) {
+    if let Err(e) = state.db.touch_session(id, now_unix()) {
+        tracing::warn!("Failed to record session activity: {e:#}");
+    }
+}
+
+// =============================================================================
+// Key-Value Store
+// =============================================================================
+
+/// JSON key-value store in the `kv` table of the state database.
+///
+/// Namespaces are loaded lazily and written through on every change. Once
+/// the loaded ones exceed `max_bytes`, those used least recently are
+/// unloaded again. Writes that would take a namespace over its quota fail.
+struct KvStore {
+    db: Arc<StateDb>,
+    max_bytes: Option<u64>,
+    quotas: quota::Quotas,
+    namespaces: Mutex<KvNamespaces>,
+}
+
+#[derive(Default)]
+struct KvNamespaces {
+    loaded: HashMap<String, KvNamespace>,
+    /// Counts accesses, to tell which namespace was used least recently
+    clock: u64,
+}
+
+struct KvNamespace {
+    data: BTreeMap<String, serde_json::Value>,
+    /// Size of the keys and their JSON values, as an estimate of its memory
+    bytes: u64,
+    last_used: u64,
+}
+
+impl KvStore {
+    fn new(db: Arc<StateDb>, max_bytes: Option<u64>, quotas: quota::Quotas) -> Self {
+        Self {
+            db,
+            max_bytes,
+            quotas,
+            namespaces: Mutex::default(),
+        }
+    }
+
+    /// Estimate of the memory the loaded namespaces hold, in bytes.
+    fn memory_usage(&self) -> u64 {
+        let namespaces = self.namespaces.lock().unwrap();
+        namespaces.loaded.values().map(|ns| ns.bytes).sum()
+    }
+
+    /// Every namespace that holds a key.
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let conn = self.db.conn();
+        let mut stmt = conn.prepare("SELECT DISTINCT namespace FROM kv ORDER BY namespace")?;
+        let namespaces = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(namespaces)
+    }
+
+    /// The keys and values of `namespaces`, or of every namespace when none
+    /// are given.
+    fn export(&self, namespaces: &[String]) -> Result<KvData> {
+        let namespaces = if namespaces.is_empty() {
+            self.namespaces()?
+        } else {
+            namespaces.to_vec()
+        };
+        let mut data = BTreeMap::new();
+        for namespace in namespaces {
+            let values = self.read(&namespace, |values| values.clone())?;
+            data.insert(namespace, values);
+        }
+        Ok(KvData { namespaces: data })
+    }
+
+    /// Store the keys of `data` in one transaction, first removing the
+    /// other keys of its namespaces when `replace` is set. Returns the
+    /// number of keys written. Imported keys don't expire.
+    fn import(&self, data: &KvData, replace: bool) -> Result<usize> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        for (namespace, values) in &data.namespaces {
+            let loaded = self.load(&mut namespaces, namespace)?;
+            let mut after = if replace { 0 } else { loaded.bytes };
+            for (key, value) in values {
+                if !replace {
+                    after -= loaded
+                        .data
+                        .get(key)
+                        .map_or(0, |old| kv_entry_bytes(key, old));
+                }
+                after += kv_entry_bytes(key, value);
+            }
+            quota::check(
+                &format!("kv:{namespace}"),
+                self.quotas.kv(namespace),
+                loaded.bytes,
+                after,
+            )?;
+        }
+        let mut written = 0;
+        {
+            let mut conn = self.db.conn();
+            let tx = conn.transaction()?;
+            for (namespace, values) in &data.namespaces {
+                if replace {
+                    tx.execute(
+                        "DELETE FROM kv WHERE namespace = ?1",
+                        rusqlite::params![namespace],
+                    )?;
+                }
+                for (key, value) in values {
+                    tx.execute(
+                        "INSERT INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (namespace, key)
+                         DO UPDATE SET value = excluded.value, expires_at = NULL",
+                        rusqlite::params![namespace, key, value.to_string()],
+                    )?;
+                    written += 1;
+                }
+            }
+            tx.commit()?;
+        }
+        // Read again from the database when next used
+        for namespace in data.namespaces.keys() {
+            namespaces.loaded.remove(namespace);
+        }
+        Ok(written)
+    }
+
+    /// Remove every key of `namespace`, returning how many there were.
+    fn clear(&self, namespace: &str) -> Result<usize> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let removed = self.db.conn().execute(
+            "DELETE FROM kv WHERE namespace = ?1",
+            rusqlite::params![namespace],
+        )?;
+        namespaces.loaded.remove(namespace);
+        Ok(removed)
+    }
+
+    /// Remove the keys that expired by `now`, returning their namespaces
+    /// and keys.
+    fn sweep(&self, now: u64) -> Result<Vec<(String, String)>> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let expired: Vec<(String, String)> = {
+            let conn = self.db.conn();
+            let mut stmt =
+                conn.prepare("DELETE FROM kv WHERE expires_at <= ?1 RETURNING namespace, key")?;
+            let expired = stmt
+                .query_map([now], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            expired
+        };
+        for (namespace, key) in &expired {
+            if let Some(loaded) = namespaces.loaded.get_mut(namespace) {
+                if let Some(value) = loaded.data.remove(key) {
+                    loaded.bytes -= kv_entry_bytes(key, &value);
+                }
+            }
+        }
+        Ok(expired)
+    }
+
+    fn keys(&self, namespace: &str) -> Result<Vec<String>> {
+        self.read(namespace, |data| data.keys().cloned().collect())
+    }
+
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<serde_json::Value>> {
+        self.read(namespace, |data| data.get(key).cloned())
+    }
+
+    /// Store `value` under `key`, removed by the sweeper after `expires_at`
+    /// when given.
+    fn set(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: serde_json::Value,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let json = value.to_string();
+        self.modify(
+            namespace,
+            |loaded, conn| {
+                let old = loaded
+                    .data
+                    .get(key)
+                    .map_or(0, |old| kv_entry_bytes(key, old));
+                quota::check(
+                    &format!("kv:{namespace}"),
+                    self.quotas.kv(namespace),
+                    loaded.bytes,
+                    loaded.bytes - old + (key.len() + json.len()) as u64,
+                )?;
+                conn.execute(
+                    "INSERT INTO kv (namespace, key, value, expires_at) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (namespace, key)
+                     DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+                    rusqlite::params![namespace, key, json, expires_at],
+                )?;
+                Ok(())
+            },
+            |data| {
+                data.insert(key.to_string(), value);
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> Result<bool> {
+        let deleted = self.modify(
+            namespace,
+            |_, conn| {
+                Ok(conn.execute(
+                    "DELETE FROM kv WHERE namespace = ?1 AND key = ?2",
+                    rusqlite::params![namespace, key],
+                )?)
+            },
+            |data| {
+                data.remove(key);
+            },
+        )?;
+        Ok(deleted > 0)
+    }
+
+    fn read<R>(
+        &self,
+        namespace: &str,
+        f: impl FnOnce(&BTreeMap<String, serde_json::Value>) -> R,
+    ) -> Result<R> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let result = f(&self.load(&mut namespaces, namespace)?.data);
+        self.evict(&mut namespaces, namespace);
+        Ok(result)
+    }
+
+    /// Write a change to the database and apply it to the loaded namespace
+    /// while holding the lock, so concurrent writers can't leave the two
+    /// disagreeing. `write` sees the namespace as it was, to check quotas.
+    fn modify<R>(
+        &self,
+        namespace: &str,
+        write: impl FnOnce(&KvNamespace, &rusqlite::Connection) -> Result<R>,
+        apply: impl FnOnce(&mut BTreeMap<String, serde_json::Value>),
+    ) -> Result<R> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let loaded = self.load(&mut namespaces, namespace)?;
+        let result = write(loaded, &self.db.conn())?;
+        apply(&mut loaded.data);
+        loaded.bytes = kv_bytes(&loaded.data);
+        self.evict(&mut namespaces, namespace);
+        Ok(result)
+    }
+
+    fn load<'a>(
+        &self,
+        namespaces: &'a mut KvNamespaces,
+        namespace: &str,
+    ) -> Result<&'a mut KvNamespace> {
+        namespaces.clock += 1;
+        let now = namespaces.clock;
+        if !namespaces.loaded.contains_key(namespace) {
+            let conn = self.db.conn();
+            let mut stmt = conn.prepare("SELECT key, value FROM kv WHERE namespace = ?1")?;
+            let mut data = BTreeMap::new();
+            let rows = stmt.query_map([namespace], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?;
+            for row in rows {
+                let (key, value) = row?;
+                let value = serde_json::from_str(&value).with_context(|| {
+                    format!("corrupt value of {key:?} in kv namespace {namespace:?}")
+                })?;
+                data.insert(key, value);
+            }
+            namespaces.loaded.insert(
+                namespace.to_string(),
+                KvNamespace {
+                    bytes: kv_bytes(&data),
+                    data,
+                    last_used: now,
+                },
+            );
+        }
+        let loaded = namespaces.loaded.get_mut(namespace).unwrap();
+        loaded.last_used = now;
+        Ok(loaded)
+    }
+
+    /// Unload the namespaces used least recently until the rest fit in
+    /// `max_bytes`, keeping `current` loaded; they are read again when used.
+    fn evict(&self, namespaces: &mut KvNamespaces, current: &str) {
+        let Some(max) = self.max_bytes else {
+            return;
+        };
+        let mut total: u64 = namespaces.loaded.values().map(|ns| ns.bytes).sum();
+        while total > max {
+            let Some((oldest, bytes)) = namespaces
+                .loaded
+                .iter()
+                .filter(|(name, _)| name.as_str() != current)
+                .min_by_key(|(_, ns)| ns.last_used)
+                .map(|(name, ns)| (name.clone(), ns.bytes))
+            else {
+                break;
+            };
+            namespaces.loaded.remove(&oldest);
+            total -= bytes;
+        }
+    }
+}
+
+/// KV namespaces with their keys and values, as exported and imported.
+#[derive(Serialize, Deserialize, Debug, Default, ToSchema)]
+struct KvData {
+    /// Values by key, by namespace
+    namespaces: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
+
+fn kv_bytes(data: &BTreeMap<String, serde_json::Value>) -> u64 {
+    data.iter()
+        .map(|(key, value)| kv_entry_bytes(key, value))
+        .sum()
+}
+
+/// Size of a key and its JSON value, as stored.
+fn kv_entry_bytes(key: &str, value: &serde_json::Value) -> u64 {
+    (key.len() + value.to_string().len()) as u64
+}
+
+/// Names under `/api/v1/kv/` that are routes rather than namespaces.
+const RESERVED_KV_NAMESPACES: &[&str] = &["export", "import"];
+
+/// Whether apps may store keys in `namespace`.
+fn valid_kv_namespace(namespace: &str) -> bool {
+    valid_namespace(namespace) && !RESERVED_KV_NAMESPACES.contains(&namespace)
+}
+
+/// Namespaces appear in URLs, so keep them to a safe character set.
+fn valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && namespace.len() <= 64
+        && !namespace.starts_with('.')
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// =============================================================================
+// Branding
+// =============================================================================
+
+impl Branding {
+    /// Inline a logo file as a data URI so pages stay self-contained.
+    fn resolve(&self, config_dir: &Path) -> Branding {
+        let mut resolved = self.clone();
+        resolved.logo = self.logo.as_deref().and_then(|logo| {
+            if logo.starts_with("data:") {
+                return Some(logo.to_string());
+            }
+            let path = match config_path(config_dir, logo) {
+                Ok(path) => path,
+                Err(e) => {
+                    tracing::warn!("Invalid logo path {logo:?}: {e:#}");
+                    return None;
+                }
+            };
+            match fs::read(&path) {
+                Ok(bytes) => Some(format!(
+                    "data:{};base64,{}",
+                    image_mime(&path),
+                    BASE64.encode(bytes)
+                )),
+                Err(e) => {
+                    tracing::warn!("Failed to read logo {:?}: {e}", path);
+                    None
+                }
+            }
+        });
+        resolved
+    }
+
+    fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or("tbl")
+    }
+
+    /// Extra `<style>` block overriding the accent color, if a valid one is set.
+    fn style_html(&self) -> String {
+        match self
+            .accent_color
+            .as_deref()
+            .filter(|c| is_safe_css_color(c))
+        {
+            Some(color) => format!(
+                "<style>:root {{ --accent: {color}; --accent-strong: {color}; \
+                 --accent-soft: color-mix(in srgb, {color} 16%, transparent); }}</style>"
+            ),
+            None => String::new(),
+        }
+    }
+
+    fn logo_html(&self, class: &str) -> Option<String> {
+        self.logo.as_ref().map(|src| {
+            format!(
+                r#"<img class="{class}" src="{}" alt="" />"#,
+                html_escape(src)
+            )
+        })
+    }
+
+    fn footer_html(&self) -> String {
+        self.footer
+            .as_deref()
+            .map(|text| format!(r#"<div class="footer">{}</div>"#, html_escape(text)))
+            .unwrap_or_default()
+    }
+}
+
+/// Branding from the current config with the logo resolved for embedding.
+fn page_branding(state: &AppState) -> Branding {
+    let cfg = state.config.read().unwrap();
+    cfg.branding
+        .clone()
+        .unwrap_or_default()
+        .resolve(&state.config_dir)
+}
+
+/// Accept plain color values only, so config can't inject arbitrary CSS.
+fn is_safe_css_color(color: &str) -> bool {
+    !color.is_empty()
+        && color.len() <= 64
+        && color
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "#(),.% ".contains(c))
+}
+
+fn image_mime(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    match ext.as_str() {
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+// =============================================================================
+// Template Overrides
+// =============================================================================
+
+/// Render `~/.config/tbl/templates/<name>` if the user dropped one in.
+///
+/// Templates are re-read on every request so edits show up without a restart.
+/// Returns `None` (falling back to the embedded page) when the file is missing
+/// or fails to render.
+fn render_template_override(
+    state: &AppState,
+    url: &PublicUrl,
+    name: &str,
+    extra: minijinja::Value,
+) -> Option<String> {
+    let templates = Jail::new(settings_dir(&state.config_dir).join("templates"));
+    let source = templates.read_to_string(name).ok()?;
+
+    let cfg = state.config.read().unwrap().clone();
+    let ctx = context! {
+        app_name => cfg.git_url.as_deref().and_then(repo_name),
+        git_url => cfg.git_url,
+        history => cfg.history,
+        branding => page_branding(state),
+        app => state.app_meta.read().unwrap().clone(),
+        base_path => &url.prefix,
+        version => env!("CARGO_PKG_VERSION"),
+        ..extra
+    };
+
+    let mut env = Environment::new();
+    env.set_auto_escape_callback(|_| AutoEscape::Html);
+    match env.render_str(&source, ctx) {
+        Ok(html) => Some(html),
+        Err(e) => {
+            tracing::warn!("Failed to render template {name}: {e:#}");
+            None
+        }
+    }
+}
+
+/// Short repository name from a git URL, e.g. "web-ui" for `.../you/web-ui.git`.
+fn repo_name(url: &str) -> Option<String> {
+    let name = url
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()?
+        .trim_end_matches(".git");
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+// =============================================================================
+// App Directory
+// =============================================================================
+
+/// An app (a profile with a `git_url`) as listed on the directory page.
+#[derive(Serialize, Debug)]
+struct AppListing {
+    name: String,
+    profile: String,
+    description: Option<String>,
+    /// The app's icon as a `data:` URI
+    icon: Option<String>,
+    /// `/web/` for this server's app, the other server's address for a
+    /// running one, none for a stopped one
+    url: Option<String>,
+    /// Unix timestamp of the checked-out commit
+    updated: Option<u64>,
+    health: AppHealth,
+    /// Served by this server
+    current: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AppHealth {
+    Running,
+    Stopped,
+    NotCloned,
+}
+
+/// The apps of all profiles, the default profile first. Profiles whose
+/// `config.enc` this process has not unlocked are left out.
+fn list_apps(own_config_dir: &Path, url: &PublicUrl) -> Vec<AppListing> {
+    let host = url.host_name();
+    let Ok(profiles) = list_profiles() else {
+        return Vec::new();
+    };
+    profiles
+        .into_iter()
+        .filter_map(|(profile, config_dir)| {
+            let current = config_dir == own_config_dir;
+            if !current
+                && vault::is_encrypted(&config_dir)
+                && vault::passphrase(&config_dir).is_none()
+            {
+                return None;
+            }
+            let cfg = load_config(&config_dir)?;
+            let git_url = cfg.git_url?;
+            let web_dir = config_dir.join("web");
+            let meta = load_app_meta(&config_dir).unwrap_or_default();
+            let running =
+                load_run_info(&run_dir(&config_dir)).filter(|info| port_is_open(info.port));
+
+            let link = if current {
+                Some(url.path("/web/"))
+            } else {
+                running.as_ref().map(|info| {
+                    let scheme = if info.tls { "https" } else { "http" };
+                    format!("{scheme}://{host}:{}{}/", info.port, info.base_path)
+                })
+            };
+            let health = if !web_dir.join("index.html").exists() {
+                AppHealth::NotCloned
+            } else if current || running.is_some() {
+                AppHealth::Running
+            } else {
+                AppHealth::Stopped
+            };
+
+            Some(AppListing {
+                name: meta
+                    .title
+                    .or_else(|| repo_name(&git_url))
+                    .unwrap_or_else(|| profile.clone()),
+                description: cfg
+                    .description
+                    .or(meta.description)
+                    .or_else(|| repo_description(&web_dir)),
+                icon: meta.icon,
+                url: link,
+                updated: commit_time(&web_dir),
+                health,
+                current,
+                profile,
+            })
+        })
+        .collect()
+}
+
+/// README descriptions are cut off after this many characters.
+const MAX_DESCRIPTION_CHARS: usize = 200;
+
+/// Description from the checkout when its `index.html` and manifest have
+/// none: `package.json`, else the first paragraph of the README.
+fn repo_description(web_dir: &Path) -> Option<String> {
+    let from_package = fs::read_to_string(web_dir.join("package.json"))
+        .ok()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+        .and_then(|package| package.get("description")?.as_str().map(str::to_string))
+        .map(|description| description.trim().to_string())
+        .filter(|description| !description.is_empty());
+    if from_package.is_some() {
+        return from_package;
+    }
+
+    let readme = ["README.md", "readme.md", "README"]
+        .iter()
+        .find_map(|name| fs::read_to_string(web_dir.join(name)).ok())?;
+    // The first block of plain text, past headings, badges and blank lines
+    let paragraph: Vec<&str> = readme
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.starts_with(char::is_alphanumeric))
+        .take_while(|line| !line.is_empty())
+        .collect();
+    let text = paragraph.join(" ");
+    if text.is_empty() {
+        return None;
+    }
+    Some(match text.char_indices().nth(MAX_DESCRIPTION_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    })
+}
+
+// =============================================================================
+// Embedded SDK
+// =============================================================================
+
+/// Browser SDK served at `/tbl.js`; `__TBL_VERSION__` and `__TBL_PREFIX__`
+/// are replaced at runtime.
+const TBL_JS: &str = r#"// tbl.js __TBL_VERSION__ – SDK for tbl's local API (v1)
+(function () {
+  'use strict';
+
+  const VERSION = '__TBL_VERSION__';
+  const API_VERSION = 'v1';
+  const apiBase = '__TBL_PREFIX__/api/' + API_VERSION;
+  const RETRY_STATUSES = [502, 503, 504];
+  const IDEMPOTENT = ['GET', 'HEAD', 'PUT', 'DELETE'];
+  // Identifies this page to the server, so its own broadcasts aren't echoed
+  const clientId =
+    typeof crypto !== 'undefined' && crypto.randomUUID
+      ? crypto.randomUUID()
+      : Date.now().toString(36) + Math.random().toString(36).slice(2);
+
+  class TblError extends Error {
+    constructor(status, code, message, details) {
+      super(message);
+      this.name = 'TblError';
+      this.status = status;
+      this.code = code;
+      this.details = details;
+    }
+  }
+
+  function sleep(ms) {
+    return new Promise((resolve) => setTimeout(resolve, ms));
+  }
+
+  async function toError(res) {
+    const text = await res.text().catch(() => '');
+    let body = null;
+    try {
+      body = JSON.parse(text);
+    } catch (_) {
+      // plain-text error body
+    }
+    if (body && typeof body === 'object' && (body.code || body.message)) {
+      return new TblError(
+        res.status,
+        body.code || 'http_' + res.status,
+        body.message || res.statusText,
+        body.details
+      );
+    }
+    return new TblError(res.status, 'http_' + res.status, text || res.statusText, null);
+  }
+
+  // Generic API call. Idempotent requests are retried with backoff on
+  // network errors and 502/503/504; pass `retries` to override, and
+  // `raw: true` to get the Response object instead of a parsed body.
+  async function request(path, opts) {
+    opts = opts || {};
+    const method = (opts.method || 'GET').toUpperCase();
+    const retries = opts.retries != null ? opts.retries : IDEMPOTENT.includes(method) ? 2 : 0;
+    const init = Object.assign(
+      {
+        credentials: 'include',
+        headers: {
+          'Content-Type': 'application/json',
+        },
+      },
+      opts
+    );
+    delete init.retries;
+    delete init.raw;
+
+    for (let attempt = 0; ; attempt++) {
+      let res;
+      try {
+        res = await fetch(apiBase + path, init);
+      } catch (err) {
+        if (attempt < retries) {
+          await sleep(200 * 2 ** attempt);
+          continue;
+        }
+        throw new TblError(0, 'network_error', err.message || String(err), null);
+      }
+
+      if (!res.ok) {
+        if (attempt < retries && RETRY_STATUSES.includes(res.status)) {
+          await sleep(200 * 2 ** attempt);
+          continue;
+        }
+        throw await toError(res);
+      }
+
+      if (opts.raw) {
+        return res;
+      }
+      const ct = res.headers.get('content-type') || '';
+      if (ct.includes('application/json')) {
+        return res.json();
+      }
+      return res.text();
+    }
+  }
+
+  function send(method, path, body) {
+    const opts = { method };
+    if (body !== undefined) {
+      opts.body = JSON.stringify(body);
+    }
+    return request(path, opts);
+  }
+
+  function orNull(promise) {
+    return promise.catch((err) => {
+      if (err instanceof TblError && err.status === 404) {
+        return null;
+      }
+      throw err;
+    });
+  }
+
+  function enc(s) {
+    return encodeURIComponent(s);
+  }
+
+  function ttlQuery(opts) {
+    return opts && opts.ttl != null ? '?ttl=' + enc(opts.ttl) : '';
+  }
+
+  function filesPath(path) {
+    const rel = String(path || '')
+      .split('/')
+      .filter(Boolean)
+      .map(enc)
+      .join('/');
+    return rel ? '/files/' + rel : '/files';
+  }
+
+  const kv = {
+    list: (ns) => request('/kv/' + enc(ns)).then((r) => r.keys),
+    get: (ns, key) => orNull(request('/kv/' + enc(ns) + '/' + enc(key))),
+    set: (ns, key, value, opts) =>
+      send('PUT', '/kv/' + enc(ns) + '/' + enc(key) + ttlQuery(opts), value),
+    delete: (ns, key) => send('DELETE', '/kv/' + enc(ns) + '/' + enc(key)),
+    export: (namespaces) => {
+      const list = [].concat(namespaces || []);
+      return request('/kv/export' + (list.length ? '?namespace=' + list.map(enc).join(',') : ''));
+    },
+    import: (data, opts) =>
+      send('POST', '/kv/import' + (opts && opts.replace ? '?replace=true' : ''), data),
+    reset: (ns) => send('DELETE', '/kv/' + enc(ns)),
+  };
+
+  const files = {
+    list: (dir) => request(filesPath(dir)),
+    read: (path) => orNull(request(filesPath(path), { raw: true }).then((r) => r.text())),
+    readBlob: (path) => orNull(request(filesPath(path), { raw: true }).then((r) => r.blob())),
+    write: (path, data, opts) =>
+      request(filesPath(path) + ttlQuery(opts), {
+        method: 'PUT',
+        body: data,
+        headers: { 'Content-Type': 'application/octet-stream' },
+      }),
+    delete: (path) => send('DELETE', filesPath(path)),
+  };
+
+  const events = {
+    // Subscribe to server events over a WebSocket, or Server-Sent Events
+    // where WebSockets are missing or keep failing to connect (e.g. behind a
+    // proxy that drops upgrades). Reconnects automatically with backoff,
+    // resuming after the last event seen. Returns a function that
+    // unsubscribes.
+    subscribe(handler, opts) {
+      const types = opts && opts.types;
+      const transport = (opts && opts.transport) || 'auto';
+      const hasSse = typeof EventSource !== 'undefined';
+      let useSse = transport === 'sse' || (transport === 'auto' && typeof WebSocket === 'undefined');
+      let source = null;
+      let closed = false;
+      let delay = 500;
+      let lastId = null;
+      // WebSocket connections in a row that closed before opening
+      let failures = 0;
+
+      function deliver(data) {
+        let event;
+        try {
+          event = JSON.parse(data);
+        } catch (_) {
+          return;
+        }
+        lastId = event.id;
+        if (!types || types.includes(event.type)) {
+          handler(event);
+        }
+      }
+
+      function retry() {
+        if (!closed) {
+          setTimeout(connect, delay);
+          delay = Math.min(delay * 2, 10000);
+        }
+      }
+
+      function streamQuery() {
+        const query = '?client=' + enc(clientId) + '&page=' + enc(location.pathname);
+        return query + (lastId != null ? '&last_event_id=' + lastId : '');
+      }
+
+      function connectWebSocket() {
+        const proto = location.protocol === 'https:' ? 'wss:' : 'ws:';
+        const ws = new WebSocket(proto + '//' + location.host + apiBase + '/events' + streamQuery());
+        let opened = false;
+        source = ws;
+        ws.onopen = () => {
+          opened = true;
+          failures = 0;
+          delay = 500;
+        };
+        ws.onmessage = (msg) => deliver(msg.data);
+        ws.onclose = () => {
+          if (!opened && transport === 'auto' && hasSse && ++failures >= 2) {
+            useSse = true;
+          }
+          retry();
+        };
+      }
+
+      function connectEventSource() {
+        const es = new EventSource(apiBase + '/events/sse' + streamQuery());
+        source = es;
+        es.onopen = () => {
+          delay = 500;
+        };
+        es.onmessage = (msg) => deliver(msg.data);
+        es.onerror = () => {
+          // EventSource reconnects by itself with Last-Event-ID, except
+          // after an error response
+          if (es.readyState === EventSource.CLOSED) {
+            retry();
+          }
+        };
+      }
+
+      function connect() {
+        if (closed) {
+          return;
+        }
+        if (useSse) {
+          connectEventSource();
+        } else {
+          connectWebSocket();
+        }
+      }
+
+      connect();
+      return function unsubscribe() {
+        closed = true;
+        if (source) {
+          source.close();
+        }
+      };
+    },
+  };
+
+  const broadcast = {
+    // Relay a small JSON message to the app's other tabs and devices
+    send: (message, opts) =>
+      send('POST', '/broadcast', {
+        channel: (opts && opts.channel) || null,
+        message,
+        client: clientId,
+        page: location.pathname,
+      }),
+    // Receive what other clients send, on one channel if given. Returns a
+    // function that unsubscribes.
+    subscribe(handler, opts) {
+      const channel = opts && opts.channel;
+      return events.subscribe(
+        (event) => {
+          if (!channel || event.data.channel === channel) {
+            handler(event.data.message, event.data);
+          }
+        },
+        { types: ['broadcast'], transport: opts && opts.transport }
+      );
+    },
+  };
+
+  const sessions = {
+    list: () => request('/sessions').then((r) => r.sessions),
+    clients: () => request('/clients').then((r) => r.clients),
+    current: () =>
+      request('/sessions').then((r) => r.sessions.find((s) => s.id === r.current) || null),
+  };
+
+  const audit = {
+    list: (opts) => {
+      const params = new URLSearchParams();
+      ['before', 'since', 'path', 'limit'].forEach((key) => {
+        if (opts && opts[key] != null) params.set(key, opts[key]);
+      });
+      const query = params.toString() ? '?' + params : '';
+      return request('/audit' + query).then((r) => r.records);
+    },
+  };
+
+  const jobs = {
+    list: () => request('/jobs').then((r) => r.jobs),
+    get: (id) => orNull(request('/jobs/' + enc(id))),
+    log: (id) => orNull(request('/jobs/' + enc(id) + '/log', { raw: true }).then((r) => r.text())),
+  };
+
+  const providers = {
+    list: () => request('/providers').then((r) => r.providers),
+    repos: (name, opts) => {
+      const params = new URLSearchParams();
+      ['org', 'page', 'per_page'].forEach((key) => {
+        if (opts && opts[key] != null) params.set(key, opts[key]);
+      });
+      const query = params.toString() ? '?' + params : '';
+      return request('/providers/' + enc(name) + '/repos' + query);
+    },
+    orgs: (name) => request('/providers/' + enc(name) + '/orgs').then((r) => r.orgs),
+  };
+
+  // Which optional subsystems this instance offers, fetched once per page
+  let capabilitiesCache = null;
+  function capabilities() {
+    if (!capabilitiesCache) {
+      capabilitiesCache = request('/capabilities').catch((err) => {
+        capabilitiesCache = null;
+        throw err;
+      });
+    }
+    return capabilitiesCache;
+  }
+
+  window.tblApi = {
+    version: VERSION,
+    apiVersion: API_VERSION,
+    TblError,
+    request,
+    ping: () => request('/ping'),
+    info: () => request('/info'),
+    uptime: (opts) => {
+      const limit = opts && opts.limit != null ? '?limit=' + enc(opts.limit) : '';
+      return request('/uptime' + limit);
+    },
+    capabilities,
+    supports: (name) => capabilities().then((caps) => caps[name] === true),
+    update: () => send('POST', '/update'),
+    unshallow: () => send('POST', '/unshallow'),
+    lastSync: () => orNull(request('/sync/last')),
+    pauseSync: (opts) => send('POST', '/sync/pause', opts || {}),
+    resumeSync: () => send('POST', '/sync/resume'),
+    releases: () => request('/releases'),
+    rollback: (id) => send('POST', '/releases/' + enc(id) + '/rollback'),
+    previews: () => request('/previews').then((r) => r.previews),
+    syncPreview: (branch) => send('POST', '/previews/' + enc(branch) + '/sync'),
+    removePreview: (branch) => send('DELETE', '/previews/' + enc(branch)),
+    shutdown: (opts) => send('POST', '/shutdown', opts || {}),
+    cancelShutdown: () => send('POST', '/shutdown/cancel'),
+    search: (query, opts) => {
+      const limit = opts && opts.limit != null ? '&limit=' + enc(opts.limit) : '';
+      return request('/search?q=' + enc(query) + limit).then((r) => r.hits);
+    },
+    storage: () => request('/storage'),
+    clientId,
+    events,
+    broadcast,
+    kv,
+    files,
+    sessions,
+    audit,
+    jobs,
+    providers,
+  };
+})();"#;
+
+/// TypeScript declarations for `TBL_JS`, served at `/tbl.d.ts`.
+const TBL_DTS: &str = r#"// Type definitions for tbl.js __TBL_VERSION__ (API v1)
+// Usage: save next to your sources and reference it, e.g.
+//   /// <reference path="./tbl.d.ts" />
+
+declare namespace Tbl {
+  type JsonValue = string | number | boolean | null | JsonValue[] | { [key: string]: JsonValue };
+
+  interface TblError extends Error {
+    readonly name: 'TblError';
+    /** HTTP status, or 0 for network failures */
+    readonly status: number;
+    readonly code: string;
+    readonly details: unknown;
+  }
+
+  interface RequestOptions extends RequestInit {
+    /** Retry attempts on network errors and 502/503/504 (default: 2 for idempotent methods) */
+    retries?: number;
+    /** Resolve with the raw Response instead of a parsed body */
+    raw?: boolean;
+  }
+
+  interface Info {
+    version: string;
+    api_version: string;
+    pid: number;
+    tls: boolean;
+    started_at: number;
+    uptime_secs: number;
+    app_name: string | null;
+    git_url: string | null;
+    commit: string | null;
+    /** Set while automatic syncs are paused */
+    sync_paused: SyncPause | null;
+    /** Set while an automatic sync waits for an unmetered connection */
+    sync_deferred: SyncDeferral | null;
+    /** What the app's tbl.requires.yaml asks for but this instance lacks */
+    unmet_requirements: string[];
+    /** Set while a shutdown is scheduled */
+    shutdown: ScheduledShutdown | null;
+    /** Restarts and uptime since the first start */
+    uptime: UptimeStats;
+  }
+
+  interface UptimeStats {
+    /** When the server first started */
+    first_started_at: number;
+    /** Starts after the first */
+    restarts: number;
+    /** Runs that ended without stopping cleanly */
+    unclean_stops: number;
+    total_uptime_secs: number;
+    /** Share of the time since the first start that the server was up, 0 to 1 */
+    availability: number;
+  }
+
+  interface Run {
+    started_at: number;
+    /** Null for the current run */
+    ended_at: number | null;
+    /** reason of its server.stopping event; null if it never stopped cleanly */
+    stop_reason: string | null;
+    /** Ended with a crash, a kill, or a power loss */
+    unclean: boolean;
+    uptime_secs: number;
+    version: string;
+  }
+
+  type ShutdownMode = 'graceful' | 'now';
+
+  interface ScheduledShutdown {
+    /** When the server stops */
+    at: number;
+    mode: ShutdownMode;
+  }
+
+  interface ShutdownOptions {
+    /** 'graceful' (default) lets open requests finish; 'now' closes every connection */
+    mode?: ShutdownMode;
+    /** Stop this many seconds from now instead, replacing an earlier schedule */
+    delay_secs?: number;
+  }
+
+  type Capability = 'kv' | 'files' | 'events' | 'search' | 'commands' | 'proxy';
+
+  /** Capabilities by name; ones this version of tbl does not know are absent */
+  type Capabilities = { [name: string]: boolean };
+
+  interface SyncDeferral {
+    /** When the first sync was deferred */
+    since: number;
+    /** Syncs deferred since then */
+    count: number;
+  }
+
+  interface SyncPause {
+    since: number;
+    /** When the pause ends by itself */
+    until?: number;
+    reason?: string;
+  }
+
+  interface SyncPauseOptions {
+    /** Shown in status output */
+    reason?: string;
+    /** Resume by itself after this long, e.g. "2h" */
+    for?: string;
+  }
+
+  interface UpdateResult {
+    status: 'updated' | 'unshallowed' | 'rolled_back';
+    commit: string | null;
+  }
+
+  interface FileChange {
+    /** Path relative to the workspace */
+    path: string;
+    /** Size after the sync; absent for removed files */
+    size?: number;
+    /** Size before the sync; absent for added files */
+    previous_size?: number;
+  }
+
+  interface SyncReport {
+    url: string;
+    finished_at: number;
+    previous_version: string | null;
+    version: string | null;
+    files: number;
+    size: number;
+    added: FileChange[];
+    removed: FileChange[];
+    /** Files whose content changed */
+    changed: FileChange[];
+  }
+
+  interface Release {
+    /** Creation time as YYYYMMDD-HHMMSS, local time */
+    id: string;
+    /** Commit or source version of the content */
+    version: string;
+    url: string;
+    created: number;
+    files: number;
+    size: number;
+    /** Whether the workspace serves this release */
+    current: boolean;
+  }
+
+  interface Releases {
+    /** Newest first */
+    releases: Release[];
+    keep: number;
+    /** Bytes all releases take on disk together */
+    disk_size: number;
+  }
+
+  interface Preview {
+    branch: string;
+    /** Where the branch is served, e.g. "/preview/feature%2Flogin/" */
+    url: string;
+    /** Commit checked out; null until the preview is first opened */
+    commit: string | null;
+    synced_at: number | null;
+  }
+
+  interface ProviderRepo {
+    /** Owner and name, e.g. "acme/site" */
+    name: string;
+    description: string | null;
+    /** HTTPS URL to clone */
+    clone_url: string;
+    ssh_url: string | null;
+    web_url: string;
+    private: boolean;
+    default_branch: string | null;
+    updated_at: string | null;
+  }
+
+  interface ProviderRepos {
+    /** Most recently updated first */
+    repos: ProviderRepo[];
+    page: number;
+    /** Page to ask for next; null on the last */
+    next_page: number | null;
+  }
+
+  interface ProviderReposOptions {
+    /** Organization (GitLab group) to list */
+    org?: string;
+    /** From 1 */
+    page?: number;
+    /** Default 30, at most 100 */
+    per_page?: number;
+  }
+
+  interface Event<T = JsonValue> {
+    id: number;
+    type: string;
+    time: number;
+    data: T;
+  }
+
+  interface SubscribeOptions {
+    /** Only deliver events of these types */
+    types?: string[];
+    /**
+     * "auto" (the default) uses a WebSocket and switches to Server-Sent
+     * Events when WebSockets are missing or keep failing to connect
+     */
+    transport?: 'auto' | 'websocket' | 'sse';
+  }
+
+  interface BroadcastOptions {
+    /** Only messages sent on this channel, or send on it */
+    channel?: string;
+    transport?: SubscribeOptions['transport'];
+  }
+
+  interface Broadcast {
+    channel: string | null;
+    message: JsonValue;
+    /** `clientId` of the sender */
+    client: string | null;
+    /** App the sender has open: `web` or `preview/<branch>` */
+    app: string | null;
+  }
+
+  interface FileEntry {
+    name: string;
+    dir: boolean;
+    size: number;
+    modified: number;
+  }
+
+  interface WriteOptions {
+    /** Remove the data after this long, e.g. "30m" or "7d" */
+    ttl?: string;
+  }
+
+  /** Sizes in bytes; `quota` is null where there is none */
+  interface StorageUsage {
+    kv: { namespace: string; bytes: number; keys: number; expiring: number; quota: number | null }[];
+    files: { bytes: number; files: number; expiring: number; quota: number | null };
+  }
+
+  /** Values by key, by namespace, as `tbl kv export` writes them */
+  interface KvData {
+    namespaces: { [namespace: string]: { [key: string]: JsonValue } };
+  }
+
+  interface Session {
+    id: string;
+    created_at: number;
+    last_seen: number;
+    user_agent: string | null;
+  }
+
+  /** A tab or other client with an events stream open */
+  interface ConnectedClient {
+    id: number;
+    ip: string | null;
+    user_agent: string | null;
+    /** "web", or "preview/<branch>" for a branch preview */
+    app: string | null;
+    page: string | null;
+    /** `clientId` of a tbl.js page */
+    client: string | null;
+    session: string | null;
+    user: string | null;
+    transport: 'websocket' | 'sse';
+    connected_at: number;
+  }
+
+  /** A request that changed something through the API */
+  interface AuditRecord {
+    id: number;
+    time: number;
+    method: string;
+    path: string;
+    status: number;
+    user: string | null;
+    session: string | null;
+    client: string | null;
+  }
+
+  interface AuditOptions {
+    /** Only records with a smaller ID, to page back */
+    before?: number;
+    /** Only records at or after this Unix timestamp */
+    since?: number;
+    /** Only requests to paths starting with this, e.g. "/api/v1/kv" */
+    path?: string;
+    /** Default 100, at most 1000 */
+    limit?: number;
+  }
+
+  interface SearchHit {
+    /** Path below /web/, e.g. "guide/setup.html" */
+    path: string;
+    title: string;
+    /** Text around the first match */
+    snippet: string;
+    score: number;
+  }
+
+  interface SearchOptions {
+    /** Maximum number of hits (default 20, at most 100) */
+    limit?: number;
+  }
+
+  interface Job {
+    /** Sorts in creation order */
+    id: string;
+    kind: 'sync' | 'switch' | 'unshallow' | 'rollback' | 'preview';
+    state: 'queued' | 'running' | 'succeeded' | 'failed';
+    target?: string;
+    created_at: number;
+    started_at?: number;
+    finished_at?: number;
+    /** Current step while running, e.g. "fetching" */
+    progress?: string;
+    result?: JsonValue;
+    error?: string;
+  }
+
+  interface Api {
+    readonly version: string;
+    readonly apiVersion: 'v1';
+    readonly TblError: new (status: number, code: string, message: string, details?: unknown) => TblError;
+
+    request<T = unknown>(path: string, opts?: RequestOptions): Promise<T>;
+    ping(): Promise<{ status: 'ok' }>;
+    info(): Promise<Info>;
+    /** Uptime totals and the most recent runs, newest first (default 20) */
+    uptime(opts?: { limit?: number }): Promise<{ stats: UptimeStats; runs: Run[] }>;
+    /** Whether each optional subsystem is enabled here; fetched once per page */
+    capabilities(): Promise<Capabilities>;
+    /** Whether the capability is enabled here, e.g. `supports('kv')` */
+    supports(name: Capability | string): Promise<boolean>;
+    update(): Promise<UpdateResult>;
+    /** Fetch the full history of the checkout and keep it from now on */
+    unshallow(): Promise<UpdateResult>;
+    /** Files the last sync added, removed, or changed; null before the first sync */
+    lastSync(): Promise<SyncReport | null>;
+    /** Stop scheduled syncs and syncs at startup until resumeSync() */
+    pauseSync(opts?: SyncPauseOptions): Promise<{ paused: SyncPause }>;
+    resumeSync(): Promise<{ paused: null }>;
+    /** Releases kept for rollback (see keep_releases) */
+    releases(): Promise<Releases>;
+    /** Serve a kept release, by ID or start of its version, until the next sync */
+    rollback(id: string): Promise<UpdateResult>;
+    /** Branches served under /preview/ (see previews) */
+    previews(): Promise<Preview[]>;
+    /** Fetch a branch's preview now rather than when it is next opened */
+    syncPreview(branch: string): Promise<Preview>;
+    /** Remove a branch's checkout; opening it clones the branch again */
+    removePreview(branch: string): Promise<void>;
+    /** Stop the server, now or after a delay */
+    shutdown(
+      opts?: ShutdownOptions
+    ): Promise<{ status: 'shutting_down' | 'scheduled'; mode: ShutdownMode; at?: number }>;
+    /** Call off a scheduled shutdown */
+    cancelShutdown(): Promise<{ cancelled: ScheduledShutdown | null }>;
+    /** Full-text search over the app's pages; the last word also matches as a prefix */
+    search(query: string, opts?: SearchOptions): Promise<SearchHit[]>;
+    /** What the KV namespaces and files area hold, against their quotas */
+    storage(): Promise<StorageUsage>;
+
+    events: {
+      subscribe(handler: (event: Event) => void, opts?: SubscribeOptions): () => void;
+    };
+
+    /** Random ID of this page, sent with its broadcasts */
+    readonly clientId: string;
+
+    /** Messages between the app's open tabs and devices; a sender doesn't get its own */
+    broadcast: {
+      /** At most 16 KiB */
+      send(message: JsonValue, opts?: BroadcastOptions): Promise<void>;
+      subscribe(
+        handler: (message: JsonValue, broadcast: Broadcast) => void,
+        opts?: BroadcastOptions
+      ): () => void;
+    };
+
+    kv: {
+      list(namespace: string): Promise<string[]>;
+      get<T = JsonValue>(namespace: string, key: string): Promise<T | null>;
+      set(namespace: string, key: string, value: JsonValue, opts?: WriteOptions): Promise<void>;
+      delete(namespace: string, key: string): Promise<void>;
+      /** All namespaces when none are given */
+      export(namespaces?: string | string[]): Promise<KvData>;
+      /** `replace` removes the other keys of the imported namespaces first */
+      import(
+        data: KvData,
+        opts?: { replace?: boolean }
+      ): Promise<{ namespaces: string[]; keys: number }>;
+      /** Remove every key of a namespace */
+      reset(namespace: string): Promise<void>;
+    };
+
+    files: {
+      list(dir?: string): Promise<FileEntry[]>;
+      read(path: string): Promise<string | null>;
+      readBlob(path: string): Promise<Blob | null>;
+      write(path: string, data: BodyInit, opts?: WriteOptions): Promise<void>;
+      delete(path: string): Promise<void>;
+    };
+
+    sessions: {
+      list(): Promise<Session[]>;
+      current(): Promise<Session | null>;
+      /** Who has the app open right now, as far as they listen for events */
+      clients(): Promise<ConnectedClient[]>;
+    };
+
+    /** Newest first */
+    audit: {
+      list(opts?: AuditOptions): Promise<AuditRecord[]>;
+    };
+
+    jobs: {
+      list(): Promise<Job[]>;
+      get(id: string): Promise<Job | null>;
+      log(id: string): Promise<string | null>;
+    };
+
+    /** Repositories on GitHub, GitLab, and Gitea, with a token in the providers config section */
+    providers: {
+      list(): Promise<{ name: 'github' | 'gitlab' | 'gitea'; url: string }[]>;
+      repos(name: string, opts?: ProviderReposOptions): Promise<ProviderRepos>;
+      orgs(name: string): Promise<{ name: string; description: string | null }[]>;
+    };
+  }
+}
+
+declare const tblApi: Tbl.Api;
+
+interface Window {
+  tblApi: Tbl.Api;
+}"#;
+
+// =============================================================================
+// Embedded HTML Pages
+// =============================================================================
+
+fn bootstrap_page_html(
+    token: &str,
+    session_id: &str,
+    branding: &Branding,
+    url: &PublicUrl,
+) -> String {
+    let cookie_path = url.cookie_path();
+    let home = url.path("/");
+    let title = html_escape(branding.title());
+    let logo = branding.logo_html("brand-logo").unwrap_or_default();
+    let footer = branding.footer_html();
+    let branding_style = branding.style_html();
+    format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>{title} – bootstrapping…</title>
+  <meta name="viewport" content="width=device-width, initial-scale=1" />
+  <style>
+    :root {{
+      color-scheme: light dark;
+      --bg: #0b1020;
+      --fg: #f5f5f7;
+      --accent: #4f46e5;
+      --accent-soft: rgba(79,70,229,0.16);
+      --border-subtle: rgba(148,163,184,0.35);
+    }}
+    * {{
+      box-sizing: border-box;
+      font-family: system-ui, -apple-system, BlinkMacSystemFont, "SF Pro Text",
+                   "Segoe UI", sans-serif;
+    }}
+    body {{
+      margin: 0;
+      min-height: 100vh;
+      display: flex;
+      align-items: center;
+      justify-content: center;
+      background: radial-gradient(circle at top, #1e293b, #020617 55%);
+      color: var(--fg);
+    }}
+    .card {{
+      background: rgba(15,23,42,0.95);
+      border-radius: 18px;
+      padding: 24px 28px;
+      box-shadow: 0 18px 40px rgba(15,23,42,0.85);
+      max-width: 420px;
+      width: 100%;
+      border: 1px solid var(--border-subtle);
+      backdrop-filter: blur(18px);
+    }}
+    .badge {{
+      display: inline-flex;
+      align-items: center;
+      gap: 8px;
+      font-size: 11px;
+      text-transform: uppercase;
+      letter-spacing: 0.12em;
+      padding: 4px 10px;
+      border-radius: 999px;
+      background: var(--accent-soft);
+      color: #c7d2fe;
+    }}
+    .pill-dot {{
+      width: 6px;
+      height: 6px;
+      border-radius: 999px;
+      background: #22c55e;
+      box-shadow: 0 0 0 4px rgba(34,197,94,0.35);
+    }}
+    h1 {{
+      margin: 14px 0 6px;
+      font-size: 22px;
+      font-weight: 600;
+    }}
+    p {{
+      margin: 6px 0 0;
+      font-size: 13px;
+      opacity: 0.8;
+    }}
+    .spinner {{
+      margin-top: 18px;
+      width: 26px;
+      height: 26px;
+      border-radius: 999px;
+      border: 3px solid rgba(148,163,184,0.4);
+      border-top-color: var(--accent);
+      animation: spin 0.8s linear infinite;
+    }}
+    @keyframes spin {{
+      to {{ transform: rotate(360deg); }}
+    }}
+    .brand-logo {{
+      display: block;
+      max-height: 36px;
+      max-width: 160px;
+      margin-bottom: 14px;
+    }}
+    .footer {{
+      margin-top: 18px;
+      font-size: 11px;
+      opacity: 0.6;
+    }}
+  </style>
+  {branding_style}
+</head>
+<body>
+  <div class="card">
+    {logo}
+    <div class="badge">
+      <span class="pill-dot"></span>
+      <span>LOCAL SESSION</span>
+    </div>
+    <h1>Bootstrapping {title}</h1>
+    <p>We're securing your local API and loading your workspace.</p>
+    <div class="spinner"></div>
+    {footer}
+  </div>
+  <script>
+    (function() {{
+      const token = "{token}";
+      document.cookie = "tbl_token=" + token + "; SameSite=Lax; Path={cookie_path}";
+      document.cookie = "tbl_session={session_id}; SameSite=Lax; Path={cookie_path}";
+      setTimeout(function() {{
+        window.location.replace("{home}");
+      }}, 400);
+    }})();
+  </script>
+</body>
+</html>"#
+    )
+}
+
+/// What the setup page shows about the running instance.
+struct InstanceOverview<'a> {
+    storage: Option<&'a quota::StorageUsage>,
+    clients: &'a [ConnectedClient],
+    /// Uptime totals, with this run's start
+    uptime: Option<(&'a uptime::UptimeStats, u64)>,
+}
+
+fn setup_page_html(
+    history: &[HistoryEntry],
+    providers: &[forge::Provider],
+    catalog: bool,
+    instance: &InstanceOverview,
+    branding: &Branding,
+    url: &PublicUrl,
+) -> String {
+    let title = html_escape(branding.title());
+    let logo = branding
+        .logo_html("logo-mark logo-img")
+        .unwrap_or_else(|| r#"<div class="logo-mark">t</div>"#.to_string());
+
+    r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title><!--TITLE--> – first-time setup</title>
+  <meta name="viewport" content="width=device-width, initial-scale=1" />
+  <style>
+    :root {
+      color-scheme: light dark;
+      --bg: #020617;
+      --card: rgba(15,23,42,0.96);
+      --fg: #f9fafb;
+      --muted: #9ca3af;
+      --accent: #6366f1;
+      --accent-strong: #4f46e5;
+      --accent-soft: rgba(99,102,241,0.12);
+      --border-subtle: rgba(148,163,184,0.45);
+      --input-bg: rgba(15,23,42,0.9);
+    }
+    * {
+      box-sizing: border-box;
+      font-family: system-ui, -apple-system, BlinkMacSystemFont, "SF Pro Text",
+                   "Segoe UI", sans-serif;
+    }
+    body {
+      margin: 0;
+      min-height: 100vh;
+      background:
+        radial-gradient(circle at top, #1e293b, transparent 60%),
+        radial-gradient(circle at bottom, #020617, #000);
+      color: var(--fg);
+      display: flex;
+      align-items: center;
+      justify-content: center;
+      padding: 24px;
+    }
+    .shell {
+      max-width: 460px;
+      width: 100%;
+    }
+    .logo {
+      display: flex;
+      align-items: center;
+      gap: 10px;
+      margin-bottom: 12px;
+    }
+    .logo-mark {
+      width: 26px;
+      height: 26px;
+      border-radius: 9px;
+      background: radial-gradient(circle at 20% 0%, #a5b4fc, #4f46e5);
+      box-shadow: 0 8px 22px rgba(79,70,229,0.7);
+      display: flex;
+      align-items: center;
+      justify-content: center;
+      font-size: 14px;
+      font-weight: 700;
+      color: #e5e7eb;
+    }
+    .logo-img {
+      background: none;
+      box-shadow: none;
+      object-fit: contain;
+      width: auto;
+      max-width: 120px;
+    }
+    .logo-text {
+      font-weight: 600;
+      letter-spacing: 0.06em;
+      font-size: 12px;
+      text-transform: uppercase;
+      color: var(--muted);
+    }
+    .card {
+      background: var(--card);
+      border-radius: 20px;
+      padding: 22px 22px 20px;
+      border: 1px solid var(--border-subtle);
+      box-shadow:
+        0 22px 50px rgba(15,23,42,0.95),
+        0 0 0 1px rgba(15,23,42,0.8);
+      backdrop-filter: blur(20px);
+    }
+    h1 {
+      margin: 0 0 6px;
+      font-size: 22px;
+      font-weight: 600;
+    }
+    p {
+      margin: 0 0 14px;
+      font-size: 13px;
+      color: var(--muted);
+    }
+    .field-label {
+      font-size: 12px;
+      margin-bottom: 6px;
+      color: #e5e7eb;
+    }
+    input[type="text"] {
+      width: 100%;
+      padding: 10px 11px;
+      border-radius: 11px;
+      border: 1px solid rgba(148,163,184,0.7);
+      background: var(--input-bg);
+      color: var(--fg);
+      font-size: 13px;
+      outline: none;
+      transition: border-color 0.15s ease, box-shadow 0.15s ease,
+                  background 0.15s ease;
+    }
+    input[type="text"]::placeholder {
+      color: rgba(148,163,184,0.9);
+    }
+    input[type="text"]:focus {
+      border-color: var(--accent);
+      box-shadow: 0 0 0 1px rgba(99,102,241,0.7);
+      background: rgba(15,23,42,1);
+    }
+    .hint {
+      font-size: 11px;
+      margin-top: 6px;
+      color: rgba(148,163,184,0.95);
+    }
+    button {
+      margin-top: 16px;
+      width: 100%;
+      border-radius: 999px;
+      border: none;
+      padding: 9px 14px;
+      font-size: 13px;
+      font-weight: 500;
+      background: linear-gradient(135deg, var(--accent-strong), var(--accent));
+      color: white;
+      cursor: pointer;
+      display: inline-flex;
+      align-items: center;
+      justify-content: center;
+      gap: 8px;
+      box-shadow: 0 14px 32px rgba(79,70,229,0.6);
+      transition: transform 0.07s ease, box-shadow 0.07s ease,
+                  filter 0.07s ease;
+    }
+    button:hover {
+      transform: translateY(-1px);
+      box-shadow: 0 18px 40px rgba(79,70,229,0.7);
+      filter: brightness(1.03);
+    }
+    button:active {
+      transform: translateY(0);
+      box-shadow: 0 10px 22px rgba(79,70,229,0.65);
+    }
+    .btn-icon {
+      font-size: 15px;
+    }
+    .meta {
+      margin-top: 10px;
+      font-size: 11px;
+      color: var(--muted);
+      display: flex;
+      justify-content: space-between;
+      gap: 12px;
+      flex-wrap: wrap;
+    }
+    .pill {
+      padding: 3px 8px;
+      border-radius: 999px;
+      font-size: 10px;
+      border: 1px dashed rgba(148,163,184,0.5);
+      background: rgba(15,23,42,0.8);
+    }
+    .recent {
+      margin-top: 18px;
+      padding-top: 14px;
+      border-top: 1px solid var(--border-subtle);
+    }
+    .recent form {
+      margin: 6px 0 0;
+    }
+    .recent button {
+      margin-top: 0;
+      padding: 7px 12px;
+      justify-content: space-between;
+      background: var(--accent-soft);
+      box-shadow: none;
+      font-size: 12px;
+      text-align: left;
+    }
+    .recent button:hover {
+      box-shadow: none;
+      background: rgba(99,102,241,0.24);
+    }
+    .recent .url {
+      overflow: hidden;
+      text-overflow: ellipsis;
+      white-space: nowrap;
+    }
+    .recent .age {
+      flex-shrink: 0;
+      color: var(--muted);
+      font-size: 11px;
+    }
+    .picker-bar {
+      display: flex;
+      gap: 8px;
+    }
+    .picker-bar select {
+      flex: 1;
+      min-width: 0;
+      padding: 7px 10px;
+      border-radius: 10px;
+      border: 1px solid var(--border-subtle);
+      background: var(--input-bg);
+      color: var(--fg);
+      font-size: 12px;
+    }
+    .picker-status {
+      margin-top: 8px;
+      font-size: 11px;
+      color: var(--muted);
+    }
+    .usage {
+      display: flex;
+      justify-content: space-between;
+      gap: 8px;
+      margin-top: 6px;
+      font-size: 12px;
+    }
+    .usage .amount {
+      flex-shrink: 0;
+      color: var(--muted);
+      font-size: 11px;
+    }
+    .usage .over {
+      color: #f87171;
+    }
+    .footer {
+      margin-top: 14px;
+      text-align: center;
+      font-size: 11px;
+      color: var(--muted);
+    }
+  </style>
+  <!--BRANDING-->
+</head>
+<body>
+  <div class="shell">
+    <div class="logo">
+      <!--LOGO-->
+      <div class="logo-text"><!--TITLE--> bootstrap</div>
+    </div>
+    <div class="card">
+      <h1>Connect your workspace</h1>
+      <p>Point <strong>tbl</strong> at a Git repo that contains your web UI. We'll shallow-clone it into your local config and serve it securely.</p>
+      <form method="post" action="<!--SETUP_URL-->">
+        <label class="field-label" for="git_url">Git repository URL</label>
+        <input
+          id="git_url"
+          type="text"
+          name="git_url"
+          placeholder="https://github.com/you/your-tbl-web.git"
+          required
+        />
+        <div class="hint">
+          We clone into <code>~/.config/tbl/web/</code>, keeping <code>clone_depth</code> commits of history (default 1).
+        </div>
+        <button type="submit">
+          <span class="btn-icon">⏎</span>
+          <span>Clone &amp; launch</span>
+        </button>
+      </form>
+      <!--CATALOG-->
+      <!--PICKER-->
+      <!--HISTORY-->
+      <!--STORAGE-->
+      <!--CLIENTS-->
+      <!--UPTIME-->
+      <div class="meta">
+        <div>CLI &amp; ENV override: <code>--git-url</code>, <code>TBL_GIT_URL</code></div>
+        <div class="pill">Single static binary • local only</div>
+      </div>
+    </div>
+    <!--FOOTER-->
+  </div>
+</body>
+</html>"#
+        .replace("<!--TITLE-->", &title)
+        .replace("<!--LOGO-->", &logo)
+        .replace("<!--BRANDING-->", &branding.style_html())
+        .replace("<!--FOOTER-->", &branding.footer_html())
+        .replace("<!--CATALOG-->", &catalog_picker_html(catalog, url))
+        .replace("<!--PICKER-->", &repo_picker_html(providers, url))
+        .replace("<!--HISTORY-->", &recent_repos_html(history, url))
+        .replace("<!--STORAGE-->", &storage_usage_html(instance.storage))
+        .replace("<!--CLIENTS-->", &connected_clients_html(instance.clients))
+        .replace("<!--UPTIME-->", &uptime_html(instance.uptime))
+        .replace("<!--SETUP_URL-->", &url.path("/setup"))
+}
+
+/// Generic error page sharing the bootstrap page's look and branding.
+fn error_page_html(heading: &str, detail: &str, branding: &Branding, url: &PublicUrl) -> String {
+    let title = html_escape(branding.title());
+    let setup_url = url.path("/setup");
+    let heading = html_escape(heading);
+    let detail = html_escape(detail);
+    let logo = branding.logo_html("brand-logo").unwrap_or_default();
+    let footer = branding.footer_html();
+    let branding_style = branding.style_html();
+    format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>{title} – {heading}</title>
+  <meta name="viewport" content="width=device-width, initial-scale=1" />
+  <style>
+    :root {{
+      color-scheme: light dark;
+      --fg: #f5f5f7;
+      --accent: #6366f1;
+      --border-subtle: rgba(148,163,184,0.35);
+    }}
+    * {{
+      box-sizing: border-box;
+      font-family: system-ui, -apple-system, BlinkMacSystemFont, "SF Pro Text",
+                   "Segoe UI", sans-serif;
+    }}
+    body {{
+      margin: 0;
+      min-height: 100vh;
+      display: flex;
+      align-items: center;
+      justify-content: center;
+      padding: 24px;
+      background: radial-gradient(circle at top, #1e293b, #020617 55%);
+      color: var(--fg);
+    }}
+    .card {{
+      background: rgba(15,23,42,0.95);
+      border-radius: 18px;
+      padding: 24px 28px;
+      box-shadow: 0 18px 40px rgba(15,23,42,0.85);
+      max-width: 560px;
+      width: 100%;
+      border: 1px solid var(--border-subtle);
+    }}
+    h1 {{
+      margin: 0 0 10px;
+      font-size: 22px;
+      font-weight: 600;
+    }}
+    pre {{
+      margin: 0;
+      padding: 12px;
+      border-radius: 10px;
+      background: rgba(2,6,23,0.8);
+      font-size: 12px;
+      white-space: pre-wrap;
+      word-break: break-word;
+    }}
+    a {{
+      display: inline-block;
+      margin-top: 16px;
+      color: var(--accent);
+      font-size: 13px;
+    }}
+    .brand-logo {{
+      display: block;
+      max-height: 36px;
+      max-width: 160px;
+      margin-bottom: 14px;
+    }}
+    .footer {{
+      margin-top: 18px;
+      font-size: 11px;
+      opacity: 0.6;
+    }}
+  </style>
+  {branding_style}
+</head>
+<body>
+  <div class="card">
+    {logo}
+    <h1>{heading}</h1>
+    <pre>{detail}</pre>
+    <a href="{setup_url}">Back to setup</a>
+    {footer}
+  </div>
+</body>
+</html>"#
+    )
+}
+
+/// Directory page listing the apps of all profiles.
+fn directory_page_html(apps: &[AppListing], branding: &Branding) -> String {
+    let title = html_escape(branding.title());
+    let logo = branding.logo_html("brand-logo").unwrap_or_default();
+    let footer = branding.footer_html();
+    let branding_style = branding.style_html();
+
+    let mut items = String::new();
+    for app in apps {
+        let name = html_escape(&app.name);
+        let name = match &app.url {
+            Some(url) => format!(r#"<a href="{}">{name}</a>"#, html_escape(url)),
+            None => name,
+        };
+        let icon = app
+            .icon
+            .as_deref()
+            .map(|src| format!(r#"<img class="icon" src="{}" alt="" />"#, html_escape(src)))
+            .unwrap_or_default();
+        let current = if app.current {
+            r#" <span class="pill">this server</span>"#
+        } else {
+            ""
+        };
+        let description = app
+            .description
+            .as_deref()
+            .map(|d| format!("<p>{}</p>", html_escape(d)))
+            .unwrap_or_default();
+        let health = match app.health {
+            AppHealth::Running => "running",
+            AppHealth::Stopped => "stopped",
+            AppHealth::NotCloned => "not cloned",
+        };
+        let updated = app
+            .updated
+            .map(|at| format!(" · updated {}", timefmt::age(at)))
+            .unwrap_or_default();
+        items.push_str(&format!(
+            r#"
+      <li>
+        <div class="name">{icon}{name}{current}</div>
+        {description}
+        <div class="meta"><span class="health {class}">{health}</span>{updated} · profile {profile}</div>
+      </li>"#,
+            class = job_label(app.health),
+            profile = html_escape(&app.profile),
+        ));
+    }
+
+    format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>{title} – Apps</title>
+  <meta name="viewport" content="width=device-width, initial-scale=1" />
+  <style>
+    :root {{
+      color-scheme: light dark;
+      --fg: #f5f5f7;
+      --muted: #9ca3af;
+      --accent: #6366f1;
+      --border-subtle: rgba(148,163,184,0.35);
+    }}
+    * {{
+      box-sizing: border-box;
+      font-family: system-ui, -apple-system, BlinkMacSystemFont, "SF Pro Text",
+                   "Segoe UI", sans-serif;
+    }}
+    body {{
+      margin: 0;
+      min-height: 100vh;
+      display: flex;
+      align-items: center;
+      justify-content: center;
+      padding: 24px;
+      background: radial-gradient(circle at top, #1e293b, #020617 55%);
+      color: var(--fg);
+    }}
+    .card {{
+      background: rgba(15,23,42,0.95);
+      border-radius: 18px;
+      padding: 24px 28px;
+      box-shadow: 0 18px 40px rgba(15,23,42,0.85);
+      max-width: 640px;
+      width: 100%;
+      border: 1px solid var(--border-subtle);
+    }}
+    h1 {{
+      margin: 0 0 14px;
+      font-size: 22px;
+      font-weight: 600;
+    }}
+    ul {{
+      list-style: none;
+      margin: 0;
+      padding: 0;
+    }}
+    li {{
+      padding: 12px 0;
+      border-top: 1px solid var(--border-subtle);
+    }}
+    .name {{
+      font-size: 15px;
+      font-weight: 600;
+    }}
+    .name a {{
+      color: var(--accent);
+      text-decoration: none;
+    }}
+    .icon {{
+      width: 20px;
+      height: 20px;
+      margin-right: 8px;
+      vertical-align: -4px;
+      border-radius: 4px;
+    }}
+    p {{
+      margin: 4px 0 0;
+      font-size: 13px;
+      color: var(--muted);
+    }}
+    .meta {{
+      margin-top: 6px;
+      font-size: 11px;
+      color: var(--muted);
+    }}
+    .pill {{
+      margin-left: 6px;
+      padding: 2px 8px;
+      border-radius: 999px;
+      font-size: 10px;
+      font-weight: 400;
+      border: 1px dashed rgba(148,163,184,0.5);
+    }}
+    .health::before {{
+      content: "●";
+      margin-right: 4px;
+    }}
+    .running::before {{
+      color: #22c55e;
+    }}
+    .stopped::before {{
+      color: var(--muted);
+    }}
+    .not_cloned::before {{
+      color: #f59e0b;
+    }}
+    .brand-logo {{
+      display: block;
+      max-height: 36px;
+      max-width: 160px;
+      margin-bottom: 14px;
+    }}
+    .footer {{
+      margin-top: 18px;
+      font-size: 11px;
+      opacity: 0.6;
+    }}
+  </style>
+  {branding_style}
+</head>
+<body>
+  <div class="card">
+    {logo}
+    <h1>Apps</h1>
+    <ul>{items}
+    </ul>
+    {footer}
+  </div>
+</body>
+</html>"#
+    )
+}
+
+/// Quick-switch buttons for previously used repositories.
+fn recent_repos_html(history: &[HistoryEntry], url: &PublicUrl) -> String {
+    if history.is_empty() {
+        return String::new();
+    }
+
+    let mut html = String::from(
+        r#"<div class="recent">
+        <label class="field-label">Recently used</label>"#,
+    );
+    for entry in history {
+        let git_url = html_escape(&entry.git_url);
+        html.push_str(&format!(
+            r#"
+        <form method="post" action="{setup_url}">
+          <input type="hidden" name="git_url" value="{git_url}" />
+          <button type="submit" title="{git_url}">
+            <span class="url">{git_url}</span>
+            <span class="age">{age}</span>
+          </button>
+        </form>"#,
+            setup_url = url.path("/setup"),
+            age = timefmt::age(entry.last_used),
+        ));
+    }
+    html.push_str("\n      </div>");
+    html
+}
+
+/// Tabs and devices with the app open, as far as they listen for events.
+fn connected_clients_html(clients: &[ConnectedClient]) -> String {
+    if clients.is_empty() {
+        return String::new();
+    }
+
+    let mut html = String::from(
+        r#"<div class="recent">
+        <label class="field-label">Viewing now</label>"#,
+    );
+    for client in clients {
+        let who = client.ip.as_deref().unwrap_or("unknown address");
+        let app = client.app.as_deref().unwrap_or("app");
+        html.push_str(&format!(
+            r#"
+        <div class="usage"><span class="url" title="{agent}">{who} · <code>{app}</code></span><span class="amount">joined {age}</span></div>"#,
+            agent = html_escape(client.user_agent.as_deref().unwrap_or_default()),
+            who = html_escape(who),
+            app = html_escape(app),
+            age = timefmt::age(client.connected_at),
+        ));
+    }
+    html.push_str("\n      </div>");
+    html
+}
+
+/// How long this run has been up, and the uptime and restarts since the
+/// first start, from the stats and this run's start time.
+fn uptime_html(uptime: Option<(&uptime::UptimeStats, u64)>) -> String {
+    let Some((stats, started_at)) = uptime else {
+        return String::new();
+    };
+    format!(
+        r#"<div class="recent">
+        <label class="field-label">Uptime</label>
+        <div class="usage"><span class="url">Up {current} · {total} in all since {first} ({availability:.1}%)</span><span class="amount">{restarts} restart{s}, {unclean} unclean</span></div>
+      </div>"#,
+        current = timefmt::duration(now_unix().saturating_sub(started_at)),
+        total = timefmt::duration(stats.total_uptime_secs),
+        first = timefmt::human_unix(stats.first_started_at, timefmt::Style::Date),
+        availability = stats.availability * 100.0,
+        restarts = stats.restarts,
+        s = if stats.restarts == 1 { "" } else { "s" },
+        unclean = stats.unclean_stops,
+    )
+}
+
+/// What apps have stored in the KV store and files area, against their
+/// quotas; nothing until they store something.
+fn storage_usage_html(storage: Option<&quota::StorageUsage>) -> String {
+    let Some(storage) = storage.filter(|s| !s.kv.is_empty() || s.files.files > 0) else {
+        return String::new();
+    };
+
+    let row = |name: String, bytes: u64, count: String, quota: Option<u64>| {
+        let (amount, class) = match quota {
+            Some(quota) => (
+                format!("{} of {}", format_bytes(bytes), format_bytes(quota)),
+                if bytes > quota {
+                    "amount over"
+                } else {
+                    "amount"
+                },
+            ),
+            None => (format_bytes(bytes), "amount"),
+        };
+        format!(
+            r#"
+        <div class="usage"><span class="url">{name}</span><span class="{class}">{amount} · {count}</span></div>"#
+        )
+    };
+    let mut html = String::from(
+        r#"<div class="recent">
+        <label class="field-label">Stored data</label>"#,
+    );
+    for ns in &storage.kv {
+        html.push_str(&row(
+            format!("<code>{}</code>", html_escape(&ns.namespace)),
+            ns.bytes,
+            format!("{} keys", ns.keys),
+            ns.quota,
+        ));
+    }
+    if storage.files.files > 0 {
+        html.push_str(&row(
+            "Files".to_string(),
+            storage.files.bytes,
+            format!("{} files", storage.files.files),
+            storage.files.quota,
+        ));
+    }
+    html.push_str("\n      </div>");
+    html
+}
+
+/// Apps of the configured catalog to pick from, loaded by the page from
+/// `/api/v1/catalog`.
+fn catalog_picker_html(catalog: bool, url: &PublicUrl) -> String {
+    if !catalog {
+        return String::new();
+    }
+    format!(
+        r#"<div class="recent" id="catalog">
+        <label class="field-label">App catalog</label>
+        <div id="catalog-apps"></div>
+        <div class="picker-status" id="catalog-status">Loading…</div>
+      </div>
+      <script>
+        (function() {{
+          const setup = "{setup_url}";
+          const $ = (id) => document.getElementById(id);
+          function add(app) {{
+            const form = document.createElement('form');
+            form.method = 'post';
+            form.action = setup;
+            const input = document.createElement('input');
+            input.type = 'hidden';
+            input.name = 'git_url';
+            input.value = app.source;
+            const name = document.createElement('input');
+            name.type = 'hidden';
+            name.name = 'catalog_app';
+            name.value = app.name;
+            const version = document.createElement('input');
+            version.type = 'hidden';
+            version.name = 'catalog_version';
+            version.value = app.version || '';
+            const button = document.createElement('button');
+            button.type = 'submit';
+            button.title = app.description || app.source;
+            const label = document.createElement('span');
+            label.className = 'url';
+            label.textContent = app.title || app.name;
+            const tag = document.createElement('span');
+            tag.className = 'age';
+            tag.textContent = app.version || '';
+            button.append(label, tag);
+            form.append(input, name, version, button);
+            $('catalog-apps').append(form);
+          }}
+          fetch("{api}/catalog", {{ credentials: 'same-origin' }}).then((r) =>
+            r.json().then((body) => (r.ok ? body : Promise.reject(new Error(body.message)))))
+            .then((catalog) => {{
+              catalog.apps.forEach(add);
+              $('catalog-status').textContent = catalog.apps.length ? '' : 'No apps';
+            }}, (e) => {{ $('catalog-status').textContent = e.message; }});
+        }})();
+      </script>"#,
+        api = url.path("/api/v1"),
+        setup_url = url.path("/setup"),
+    )
+}
+
+/// Repositories of the configured providers to pick from, loaded by the
+/// page from `/api/v1/providers/<name>/repos` a page at a time.
+fn repo_picker_html(providers: &[forge::Provider], url: &PublicUrl) -> String {
+    if providers.is_empty() {
+        return String::new();
+    }
+    let options: String = providers
+        .iter()
+        .map(|provider| format!(r#"<option value="{0}">{0}</option>"#, provider.name()))
+        .collect();
+    format!(
+        r#"<div class="recent" id="picker">
+        <label class="field-label">Your repositories</label>
+        <div class="picker-bar">
+          <select id="picker-provider" aria-label="Provider">{options}</select>
+          <select id="picker-org" aria-label="Organization"><option value="">All repositories</option></select>
+        </div>
+        <div id="picker-repos"></div>
+        <div class="picker-status" id="picker-status"></div>
+        <form id="picker-more" hidden><button type="submit"><span class="url">Load more</span></button></form>
+      </div>
+      <script>
+        (function() {{
+          const api = "{api}";
+          const setup = "{setup_url}";
+          const $ = (id) => document.getElementById(id);
+          let next = 1;
+          const get = (path) => fetch(api + path, {{ credentials: 'same-origin' }}).then((r) =>
+            r.json().then((body) => (r.ok ? body : Promise.reject(new Error(body.message)))));
+          function add(repo) {{
+            const form = document.createElement('form');
+            form.method = 'post';
+            form.action = setup;
+            const input = document.createElement('input');
+            input.type = 'hidden';
+            input.name = 'git_url';
+            input.value = repo.clone_url;
+            const button = document.createElement('button');
+            button.type = 'submit';
+            button.title = repo.description || repo.clone_url;
+            const name = document.createElement('span');
+            name.className = 'url';
+            name.textContent = repo.name;
+            const tag = document.createElement('span');
+            tag.className = 'age';
+            tag.textContent = repo.private ? 'private' : '';
+            button.append(name, tag);
+            form.append(input, button);
+            $('picker-repos').append(form);
+          }}
+          function load() {{
+            const provider = encodeURIComponent($('picker-provider').value);
+            const org = $('picker-org').value;
+            const query = '?page=' + next + (org ? '&org=' + encodeURIComponent(org) : '');
+            $('picker-status').textContent = 'Loading…';
+            get('/providers/' + provider + '/repos' + query).then((page) => {{
+              page.repos.forEach(add);
+              next = page.next_page;
+              $('picker-more').hidden = !next;
+              $('picker-status').textContent = $('picker-repos').children.length ? '' : 'No repositories';
+            }}, (e) => {{ $('picker-status').textContent = e.message; }});
+          }}
+          function reset() {{
+            $('picker-repos').replaceChildren();
+            next = 1;
+            load();
+          }}
+          function loadOrgs() {{
+            const select = $('picker-org');
+            select.replaceChildren(new Option('All repositories', ''));
+            get('/providers/' + encodeURIComponent($('picker-provider').value) + '/orgs').then((r) =>
+              r.orgs.forEach((org) => select.append(new Option(org.name, org.name))), () => {{}});
+          }}
+          $('picker-provider').addEventListener('change', () => {{ loadOrgs(); reset(); }});
+          $('picker-org').addEventListener('change', reset);
+          $('picker-more').addEventListener('submit', (e) => {{ e.preventDefault(); load(); }});
+          loadOrgs();
+          load();
+        }})();
+      </script>"#,
+        api = url.path("/api/v1"),
+        setup_url = url.path("/setup"),
+    )
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// =============================================================================
+// Verbose Output Helpers
+// =============================================================================
+
+/// Print the details of an already-running server and open a new browser
+/// context for it.
+fn attach_to_running(info: &RunInfo, no_browser: bool) {
+    let public_url = login_url(info);
+
+    println!();
+    println!("  tbl is already running");
+    println!("  ───────────────────────────────────────");
+    println!("  PID:    {}", info.pid);
+    println!("  Port:   {}", info.port);
+    println!(
+        "  TLS:    {}",
+        if info.tls { "enabled" } else { "disabled" }
+    );
+    println!();
+    print_url_box(&public_url);
+
+    if !no_browser {
+        println!("\n  Opening browser...");
+        if let Err(e) = webbrowser::open(&public_url) {
+            eprintln!("  Failed to open browser: {e}");
+            eprintln!("  Open the URL above manually to authenticate.");
+        }
+    } else {
+        println!("\n  Open the URL above to authenticate.");
+    }
+    println!();
+}
+
+/// What to do about a running server that serves another repository.
+enum RunningChoice {
+    Switch,
+    SecondProfile(String),
+    Keep,
+}
+
+/// Attach to the running server, unless `--git-url` (or `TBL_GIT_URL`) names
+/// another repository than it serves: then switch it over with `--take-over`,
+/// or ask whether to switch it or run the new one as a second profile.
+fn attach_or_take_over(cli: &Cli, info: &RunInfo) -> Result<()> {
+    let cwd = std::env::current_dir().context("Failed to get current directory")?;
+    let requested = cli
+        .git_url
+        .clone()
+        .or_else(|| std::env::var("TBL_GIT_URL").ok())
+        .filter(|url| !url.is_empty())
+        .map(|url| local_git_url(&cwd, url));
+    let Some(requested) = requested else {
+        attach_to_running(info, cli.no_browser);
+        return Ok(());
+    };
+    let serving = send_api_request(info, "GET", "/api/v1/info", None)
+        .ok()
+        .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok())
+        .and_then(|details| details["git_url"].as_str().map(str::to_string));
+    if serving.as_deref().map(repo_identity) == Some(repo_identity(&requested)) {
+        attach_to_running(info, cli.no_browser);
+        return Ok(());
+    }
+
+    let choice = if cli.take_over {
+        RunningChoice::Switch
+    } else {
+        ask_about_running(serving.as_deref(), &requested, cli.profile.is_none())?
+    };
+    match choice {
+        RunningChoice::Switch => {
+            println!();
+            println!(
+                "  Switching running tbl server to {}...",
+                redact_credentials(&requested)
+            );
+            let body = serde_json::json!({ "git_url": requested }).to_string();
+            send_api_request(info, "POST", "/api/v1/switch", Some(&body))?;
+            println!("  Workspace switched.");
+            attach_to_running(info, cli.no_browser);
+        }
+        RunningChoice::SecondProfile(profile) => {
+            println!();
+            println!("  Starting profile {profile} on another port...");
+            let exe = std::env::current_exe().context("cannot get current exe")?;
+            let status = Command::new(exe)
+                .args(std::env::args().skip(1))
+                .arg("--profile")
+                .arg(&profile)
+                .status()
+                .context("failed to start tbl")?;
+            if !status.success() {
+                anyhow::bail!("tbl failed to start ({status})");
+            }
+        }
+        RunningChoice::Keep => attach_to_running(info, cli.no_browser),
+    }
+    Ok(())
+}
+
+/// Ask what to do about a running server that serves `serving` when
+/// `requested` was asked for; without a terminal, keep it and say how to
+/// switch.
+fn ask_about_running(
+    serving: Option<&str>,
+    requested: &str,
+    offer_profile: bool,
+) -> Result<RunningChoice> {
+    let serving = serving.map_or_else(|| "no repository".to_string(), redact_credentials);
+    let profile = repo_name(requested)
+        .filter(|name| valid_namespace(name) && name != profile_label())
+        .filter(|_| offer_profile);
+
+    println!();
+    println!("  tbl is already running with {serving}");
+    println!("  You asked for {}.", redact_credentials(requested));
+    if !io::stdin().is_terminal() {
+        println!("  Pass --take-over to switch it, or --profile NAME to run a second server.");
+        return Ok(RunningChoice::Keep);
+    }
+    println!();
+    println!("  [s] Switch the running server to it");
+    if let Some(profile) = &profile {
+        println!("  [p] Start it as profile {profile} on another port");
+    }
+    println!("  [o] Open the running server as it is (default)");
+    print!("  Choice: ");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(match (line.trim().to_ascii_lowercase().as_str(), profile) {
+        ("s", _) => RunningChoice::Switch,
+        ("p", Some(profile)) => RunningChoice::SecondProfile(profile),
+        _ => RunningChoice::Keep,
+    })
+}
+
+fn print_banner() {
+    let version = env!("CARGO_PKG_VERSION");
+    println!();
+    println!("  ╭─────────────────────────────────────────╮");
+    println!("  │                                         │");
+    println!("  │   tbl v{:<32} │", version);
+    println!("  │   Tiny self-bootstrapping web launcher  │");
+    println!("  │                                         │");
+    println!("  ╰─────────────────────────────────────────╯");
+}
+
+fn print_url_box(url: &str) {
+    let padding = 4;
+    let url_len = url.len();
+    let box_width = url_len + padding * 2;
+
+    let top_bottom = "─".repeat(box_width);
+    let spaces = " ".repeat(padding);
+
+    println!("  ╭{}╮", top_bottom);
+    println!("  │{}{}{}│", spaces, url, spaces);
+    println!("  ╰{}╯", top_bottom);
+}
+
+// =============================================================================
+// Stop Command
+// =============================================================================
+
+/// What `tbl --stop` asks of each server.
+#[derive(Clone, Copy)]
+enum StopAction {
+    /// Stop now, killing the process with `force` if asking fails
+    Stop { force: bool, mode: ShutdownMode },
+    /// Stop after `delay` (`--after`, `--at`)
+    Schedule { delay: Duration, mode: ShutdownMode },
+    /// Call off a scheduled stop (`--cancel`)
+    Cancel,
+}
+
+fn handle_stop_command(selector: InstanceSelector, action: StopAction) -> Result<()> {
+    let instances = select_instances(&selector)?;
+    if instances.is_empty() {
+        println!();
+        match selector {
+            InstanceSelector::Current => println!("  No tbl server is currently running."),
+            InstanceSelector::Port(port) => println!("  No tbl server found on port {port}."),
+            InstanceSelector::All => println!("  No tbl servers are running."),
+        }
+        println!();
+        return Ok(());
+    }
+
+    for instance in instances {
+        match action {
+            StopAction::Stop { force, mode } => stop_instance(instance, force, mode),
+            StopAction::Schedule { delay, mode } => schedule_stop(&instance, delay, mode),
+            StopAction::Cancel => cancel_stop(&instance),
+        }
+    }
+    Ok(())
+}
+
+fn schedule_stop(instance: &Instance, delay: Duration, mode: ShutdownMode) {
+    println!();
+    if !port_is_open(instance.info.port) {
+        println!(
+            "  No tbl server is running for profile {}.",
+            instance.profile
+        );
+        println!();
+        return;
+    }
+    let body = serde_json::json!({ "mode": mode, "delay_secs": delay.as_secs() }).to_string();
+    match send_api_request(&instance.info, "POST", "/api/v1/shutdown", Some(&body)) {
+        Ok(response) => {
+            let response: serde_json::Value = serde_json::from_str(&response).unwrap_or_default();
+            let at = response["at"].as_u64().unwrap_or_default();
+            println!(
+                "  Profile {} stops {} ({}).",
+                instance.profile,
+                describe_stop_time(at),
+                response["mode"].as_str().unwrap_or("graceful")
+            );
+            println!("  Use `tbl --stop --cancel` to call it off.");
+        }
+        Err(e) => eprintln!("  Failed to schedule the stop: {e:#}"),
+    }
+    println!();
+}
+
+fn cancel_stop(instance: &Instance) {
+    println!();
+    if !port_is_open(instance.info.port) {
+        println!(
+            "  No tbl server is running for profile {}.",
+            instance.profile
+        );
+        println!();
+        return;
+    }
+    match send_api_request(&instance.info, "POST", "/api/v1/shutdown/cancel", None) {
+        Ok(response) => {
+            let response: serde_json::Value = serde_json::from_str(&response).unwrap_or_default();
+            match response["cancelled"]["at"].as_u64() {
+                Some(at) => println!(
+                    "  Called off the stop of profile {} {}.",
+                    instance.profile,
+                    describe_stop_time(at)
+                ),
+                None => println!("  No stop is scheduled for profile {}.", instance.profile),
+            }
+        }
+        Err(e) => eprintln!("  Failed to cancel the stop: {e:#}"),
+    }
+    println!();
+}
+
+/// "at Thu 2026-10-15 18:00" for a Unix timestamp.
+fn describe_stop_time(at: u64) -> String {
+    format!("at {}", timefmt::human_unix(at, timefmt::Style::Full))
+}
+
+fn stop_instance(instance: Instance, force: bool, mode: ShutdownMode) {
+    let Instance {
+        profile,
+        config_dir,
+        info,
+    } = instance;
+    let run_dir = run_dir(&config_dir);
+
+    if !port_is_open(info.port) {
+        if force && is_tbl_process(info.pid) {
+            // Not serving but still alive, e.g. hung during startup
+            println!();
+            force_stop(&info);
+            revoke_login_token(&config_dir, &info);
+            clear_run_info(&run_dir);
+            println!();
+            return;
+        }
+        // Stale pid.yaml
+        revoke_login_token(&config_dir, &info);
+        clear_run_info(&run_dir);
+        println!();
+        println!("  No tbl server is running for profile {profile} (stale pid file cleaned up).");
+        println!();
+        return;
+    }
+
+    println!();
+    println!("  Stopping tbl server...");
+    println!("  ───────────────────────────────────────");
+    println!("  Profile: {}", profile);
+    println!("  PID:     {}", info.pid);
+    println!("  Port:    {}", info.port);
+    println!();
+
+    // Send authenticated shutdown request
+    match send_shutdown_request(&info, mode) {
+        Ok(_) => {
+            // Wait for server to stop (up to 5 seconds)
+            if wait_for_port_closed(info.port, Duration::from_secs(5)) {
+                println!("  Server stopped successfully.");
+                println!();
+                return;
+            }
+            if !force {
+                println!("  Server may still be shutting down.");
+                println!("  Use `tbl --stop --force` to kill it.");
+                println!();
+                return;
+            }
+            println!("  Server did not stop in time.");
+        }
+        Err(e) => {
+            eprintln!("  Failed to send shutdown request: {e}");
+            if !force {
+                eprintln!(
+                    "  Use `tbl --stop --force` to kill the process (PID: {}).",
+                    info.pid
+                );
+                println!();
+                return;
+            }
+        }
+    }
+
+    force_stop(&info);
+    revoke_login_token(&config_dir, &info);
+    clear_run_info(&run_dir);
+    println!();
+}
+
+/// Revoke the login link of a server that stopped without doing so itself.
+fn revoke_login_token(config_dir: &Path, info: &RunInfo) {
+    let revoked = StateDb::open(config_dir, &run_dir(config_dir))
+        .and_then(token::Tokens::load)
+        .and_then(|tokens| tokens.revoke_token(&info.auth_token));
+    if let Err(e) = revoked {
+        tracing::warn!("Failed to revoke the login token: {e:#}");
+    }
+}
+
+fn wait_for_port_closed(port: u16, timeout: Duration) -> bool {
+    let deadline = std::time::Instant::now() + timeout;
+    while std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(100));
+        if !port_is_open(port) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Terminate the daemon (and its watchdog first, so it is not restarted),
+/// escalating from a graceful signal to a hard kill.
+fn force_stop(info: &RunInfo) {
+    let mut targets = Vec::new();
+    if let Some(supervisor) = info.supervisor_pid {
+        targets.push(("watchdog", supervisor));
+    }
+    targets.push(("server", info.pid));
+
+    for (label, pid) in targets {
+        if !is_tbl_process(pid) {
+            println!("  PID {pid} is no longer a tbl process; skipping {label}.");
+            continue;
+        }
+        println!("  Terminating {label} (PID {pid})...");
+        terminate_process(pid, false);
+        if wait_for_exit(pid, Duration::from_secs(5)) {
+            println!("  {} stopped.", capitalize(label));
+            continue;
+        }
+        println!("  Still running; killing {label} (PID {pid})...");
+        terminate_process(pid, true);
+        if wait_for_exit(pid, Duration::from_secs(2)) {
+            println!("  {} killed.", capitalize(label));
+        } else {
+            eprintln!("  Failed to kill PID {pid}.");
+        }
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .map(|c| c.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+fn wait_for_exit(pid: u32, timeout: Duration) -> bool {
+    let deadline = std::time::Instant::now() + timeout;
+    while std::time::Instant::now() < deadline {
+        if !process_alive(pid) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    !process_alive(pid)
+}
+
+fn send_shutdown_request(info: &RunInfo, mode: ShutdownMode) -> Result<()> {
+    let body = serde_json::json!({ "mode": mode }).to_string();
+    send_api_request(info, "POST", "/api/v1/shutdown", Some(&body)).map(|_| ())
+}
+
+/// Send an authenticated request to a running daemon and return the response body.
+///
+/// HTTPS daemons are reached over TLS with their certificate pinned from
+/// `pid.yaml`, so self-signed certificates work without a CA.
+fn send_api_request(
+    info: &RunInfo,
+    method: &str,
+    path: &str,
+    body: Option<&str>,
+) -> Result<String> {
+    let addr = format!("127.0.0.1:{}", info.port);
+    let tcp = TcpStream::connect_timeout(&addr.parse::<SocketAddr>()?, Duration::from_secs(5))?;
+
+    // Repo switches clone before responding, so allow a generous read timeout
+    tcp.set_read_timeout(Some(Duration::from_secs(120)))?;
+    tcp.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut stream: Box<dyn ReadWrite> = if info.tls {
+        let cert = info.tls_cert.as_deref().context(
+            "pid.yaml has no certificate path for this HTTPS server; restart tbl to refresh it",
+        )?;
+        Box::new(pinned_tls_stream(tcp, cert)?)
+    } else {
+        Box::new(tcp)
+    };
+
+    let body = body.unwrap_or("");
+    let request = format!(
+        "{} {}{} HTTP/1.1\r\n\
+         Host: 127.0.0.1:{}\r\n\
+         Cookie: tbl_token={}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        method,
+        info.base_path,
+        path,
+        info.port,
+        info.auth_token.expose(),
+        body.len(),
+        body
+    );
+
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut raw = Vec::new();
+    match stream.read_to_end(&mut raw) {
+        Ok(_) => {}
+        // Some servers close TLS without close_notify once the body is sent
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !raw.is_empty() => {}
+        Err(e) => return Err(e.into()),
+    }
+    let response = String::from_utf8_lossy(&raw);
+
+    let status_line = response.lines().next().unwrap_or("");
+    let success = status_line
+        .split_whitespace()
+        .nth(1)
+        .is_some_and(|code| code.starts_with('2'));
+    let response_body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, b)| b.to_string())
+        .unwrap_or_default();
+
+    if success {
+        Ok(response_body)
+    } else {
+        anyhow::bail!(
+            "Unexpected response: {} {}",
+            status_line,
+            response_body.trim()
+        )
+    }
+}
+
+// =============================================================================
+// TLS Client
+// =============================================================================
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+/// TLS connection to the local daemon that only accepts the exact certificate
+/// at `cert_path`.
+fn pinned_tls_stream(
+    tcp: TcpStream,
+    cert_path: &Path,
+) -> Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>> {
+    let config = pinned_tls_config(cert_path)?;
+    let server_name = rustls::pki_types::ServerName::try_from("127.0.0.1")?;
+    let conn = rustls::ClientConnection::new(Arc::new(config), server_name)?;
+    Ok(rustls::StreamOwned::new(conn, tcp))
+}
+
+/// TLS client settings that only accept the exact certificate at `cert_path`.
+fn pinned_tls_config(cert_path: &Path) -> Result<rustls::ClientConfig> {
+    use rustls::pki_types::{pem::PemObject, CertificateDer};
+
+    let cert = CertificateDer::from_pem_file(cert_path)
+        .with_context(|| format!("Failed to read TLS certificate {:?}", cert_path))?;
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let verifier = PinnedCertVerifier {
+        cert,
+        algorithms: provider.signature_verification_algorithms,
+    };
+    Ok(rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// Trusts exactly one certificate, regardless of issuer, name, or expiry.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    cert: rustls::pki_types::CertificateDer<'static>,
+    algorithms: rustls::crypto::WebPkiSupportedAlgorithms,
+}
+
+impl rustls::client::danger::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate does not match the one tbl was started with".into(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+// =============================================================================
+// Process Control
+// =============================================================================
+
+/// Whether `pid` is alive and runs the same executable as this process, so a
+/// recycled PID is never killed by mistake.
+fn is_tbl_process(pid: u32) -> bool {
+    let Some(name) = process_name(pid) else {
+        return false;
+    };
+    let Some(own) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.file_stem().map(|s| s.to_string_lossy().into_owned()))
+    else {
+        return false;
+    };
+    let name = Path::new(name.trim())
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // Linux truncates command names to 15 characters
+    !name.is_empty() && (name == own || (name.len() >= 15 && own.starts_with(&name)))
+}
+
+#[cfg(unix)]
+fn process_name(pid: u32) -> Option<String> {
+    let output = Command::new("ps")
+        .args(["-p", &pid.to_string(), "-o", "comm="])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !name.is_empty()).then_some(name)
+}
+
+#[cfg(windows)]
+fn process_name(pid: u32) -> Option<String> {
+    let output = Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {pid}"), "/FO", "CSV", "/NH"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    // "tbl.exe","1234","Console","1","5,000 K"
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let name = stdout.lines().next()?.split(',').next()?.trim_matches('"');
+    (!name.is_empty() && !name.starts_with("INFO:")).then(|| name.to_string())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn process_name(_pid: u32) -> Option<String> {
+    None
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // Signal 0 only checks that the process exists
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(not(unix))]
+fn process_alive(pid: u32) -> bool {
+    process_name(pid).is_some()
+}
+
+/// Ask `pid` to exit (SIGTERM), or kill it outright (SIGKILL) when `hard`.
+#[cfg(unix)]
+fn terminate_process(pid: u32, hard: bool) {
+    let signal = if hard { libc::SIGKILL } else { libc::SIGTERM };
+    unsafe {
+        libc::kill(pid as libc::pid_t, signal);
+    }
+}
+
+#[cfg(windows)]
+fn terminate_process(pid: u32, hard: bool) {
+    let mut cmd = Command::new("taskkill");
+    cmd.args(["/PID", &pid.to_string()]);
+    if hard {
+        cmd.arg("/F");
+    }
+    let _ = cmd.stdout(Stdio::null()).stderr(Stdio::null()).status();
+}
+
+#[cfg(not(any(unix, windows)))]
+fn terminate_process(_pid: u32, _hard: bool) {}
+
+/// Kill `pid` and everything it started; on Unix `pid` must lead its own
+/// process group.
+#[cfg(unix)]
+fn kill_process_tree(pid: u32) {
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+fn kill_process_tree(pid: u32) {
+    let _ = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+#[cfg(not(any(unix, windows)))]
+fn kill_process_tree(_pid: u32) {}
+
+// =============================================================================
+// Switch Command
+// =============================================================================
+
+fn handle_switch_command(target: Option<&str>) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let mut cfg = load_config(&config_dir).unwrap_or_default();
+
+    let Some(target) = target else {
+        println!();
+        if cfg.history.is_empty() {
+            println!("  No repositories in history yet.");
+            println!();
+            return Ok(());
+        }
+        println!("  Recently used repositories");
+        println!("  ───────────────────────────────────────");
+        for (i, entry) in cfg.history.iter().enumerate() {
+            let current = cfg.git_url.as_deref().map(repo_identity);
+            let marker = if current == Some(repo_identity(&entry.git_url)) {
+                "*"
+            } else {
+                " "
+            };
+            println!(
+                "  {} {:>2}. {}  ({})",
+                marker,
+                i + 1,
+                redact_credentials(&entry.git_url),
+                timefmt::age(entry.last_used)
+            );
+        }
+        println!();
+        println!("  Run `tbl switch <N>` to switch workspace.");
+        println!();
+        return Ok(());
+    };
+
+    let url = match target.parse::<usize>() {
+        Ok(n) => cfg
+            .history
+            .get(n.wrapping_sub(1))
+            .map(|entry| entry.git_url.clone())
+            .with_context(|| format!("no history entry #{n}"))?,
+        Err(_) => local_git_url(&std::env::current_dir()?, target.to_string()),
+    };
+
+    println!();
+    let run_dir = run_dir(&config_dir);
+    if let Some(info) = load_run_info(&run_dir).filter(|info| port_is_open(info.port)) {
+        // Let the running daemon clone and persist the switch itself
+        println!(
+            "  Switching running tbl server to {}...",
+            redact_credentials(&url)
+        );
+        let body = serde_json::json!({ "git_url": url }).to_string();
+        send_api_request(&info, "POST", "/api/v1/switch", Some(&body))?;
+        println!("  Workspace switched.");
+    } else {
+        cfg.git_url = Some(url.clone());
+        record_history(&mut cfg, &url);
+        remember_repo(&mut cfg, &url, None);
+        save_config(&config_dir, &cfg)?;
+        println!("  Workspace set to {}.", redact_credentials(&url));
+        println!("  It will be cloned the next time tbl starts.");
+    }
+    println!();
+
+    Ok(())
+}
+
+/// List the tbl instances announcing themselves on the local network, or
+/// switch the workspace to one of them as a `tbl://` source and sync it.
+async fn handle_clone_from_peer_command(
+    name: Option<&str>,
+    token: Option<&Secret>,
+    wait: &str,
+) -> Result<()> {
+    let wait = parse_duration(wait).context("Invalid --wait")?;
+    println!();
+    println!("  Looking for tbl instances on the local network...");
+    let found = discovery::browse(wait).await?;
+
+    let Some(name) = name else {
+        println!();
+        if found.is_empty() {
+            println!("  None answered. Instances show up here when they run with `announce`");
+            println!("  and listen on an address other machines reach, e.g. 0.0.0.0.");
+            println!();
+            return Ok(());
+        }
+        println!(
+            "  {:>3}  {:<24} {:<20} {:<8}  ADDRESS",
+            "", "NAME", "APP", "VERSION"
+        );
+        println!("  ───────────────────────────────────────────────────────────────");
+        for (i, peer) in found.iter().enumerate() {
+            let version = peer.version.as_deref().unwrap_or("-");
+            println!(
+                "  {:>2}.  {:<24} {:<20} {:<8}  {}",
+                i + 1,
+                peer.instance,
+                peer.app.as_deref().unwrap_or("-"),
+                version.chars().take(7).collect::<String>(),
+                peer.url(None).unwrap_or_default()
+            );
+        }
+        println!();
+        println!("  Run `tbl clone-from-peer <N>` to serve one of them here.");
+        println!();
+        return Ok(());
+    };
+
+    let peer = match name.parse::<usize>() {
+        Ok(n) => found.get(n.wrapping_sub(1)),
+        Err(_) => found
+            .iter()
+            .find(|peer| peer.instance.eq_ignore_ascii_case(name)),
+    }
+    .with_context(|| format!("no instance {name:?} answered"))?;
+    let url = peer
+        .url(token.map(Secret::expose))
+        .context("the instance sent no address")?;
+    let app = peer.app.as_deref().unwrap_or("the app");
+    switch_workspace(&url, &format!("{app} from {}", peer.instance), None).await?;
+    println!();
+    Ok(())
+}
+
+// =============================================================================
+// Bundle Commands
+// =============================================================================
+
+fn handle_bundle_command(output: Option<&Path>) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let web_dir = config_dir.join("web");
+    if !web_dir.is_dir() {
+        anyhow::bail!("Nothing to bundle yet; run `tbl` to sync an app first");
+    }
+    let cfg = load_config(&config_dir).unwrap_or_default();
+    let name = cfg
+        .git_url
+        .as_deref()
+        .and_then(repo_name)
+        .map(|name| {
+            name.trim_end_matches(&format!(".{}", bundle::EXTENSION))
+                .to_string()
+        })
+        .unwrap_or_else(|| "app".to_string());
+    let mut info = bundle::BundleInfo::new(name);
+    if let Some(meta) = load_app_meta(&config_dir) {
+        info.title = meta.title;
+        info.description = meta.description;
+    }
+    info.version = current_commit(&web_dir);
+    let output = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from(format!("{}.{}", info.name, bundle::EXTENSION)));
+
+    let (key, created) = bundle::signing_key(&base_config_dir()?)?;
+    println!();
+    if created {
+        println!("  Created a signing key for bundles.");
+    }
+    let (files, size) = bundle::create(&web_dir, &info, &key, &output)?;
+    println!(
+        "  Bundled {} ({files} files, {}) into {}",
+        info.label(),
+        format_bytes(size),
+        output.display()
+    );
+    println!("  Signed with {}", bundle::public_key(&key));
+    println!();
+    Ok(())
+}
+
+async fn handle_install_command(file: &str, trust: bool) -> Result<()> {
+    let url = if bundle::is_remote(file) {
+        file.to_string()
+    } else {
+        let path = fs::canonicalize(file).with_context(|| format!("No such file: {file}"))?;
+        path.to_string_lossy().into_owned()
+    };
+    if !bundle::is_bundle(&url) {
+        anyhow::bail!(
+            "{file} is not a bundle: expected a .{} file",
+            bundle::EXTENSION
+        );
+    }
+    println!();
+    let app = check_bundle(&url, trust, &format!("tbl install --trust {file}"))?;
+    switch_workspace(&url, &app, None).await?;
+    println!();
+    Ok(())
+}
+
+/// Verify the bundle at `url` before installing it, trusting its key with
+/// `trust`, and print what it holds. Returns the app's title or name.
+fn check_bundle(url: &str, trust: bool, retry: &str) -> Result<String> {
+    let base = base_config_dir()?;
+    let run_dir = run_dir(&get_config_dir()?);
+    fs::create_dir_all(&run_dir)?;
+    let download = run_dir.join(format!("bundle-check.{}", bundle::EXTENSION));
+    let check = run_dir.join("bundle-check.tar.gz");
+    let verified = match bundle::bundle_path(url) {
+        Some(path) => bundle::verify(&path, &base, &check),
+        None => {
+            bundle::download(url, &download).and_then(|()| bundle::verify(&download, &base, &check))
+        }
+    };
+    let _ = fs::remove_file(&download);
+    let _ = fs::remove_file(&check);
+    let verified = verified?;
+    let app = verified.info.label().to_string();
+
+    println!(
+        "  {app} {} ({} files, {})",
+        verified
+            .info
+            .version
+            .as_deref()
+            .map_or_else(|| "-".to_string(), |v| v.chars().take(7).collect()),
+        verified.files(),
+        format_bytes(verified.size())
+    );
+    println!("  Signed with {}", verified.key);
+    if !verified.trusted {
+        if !trust {
+            println!();
+            println!("  This key is not trusted yet. If you know it is the publisher's, run");
+            println!("  `{retry}`.");
+            println!();
+            anyhow::bail!("bundle signed by an untrusted key");
+        }
+        bundle::trust(&base, &verified.key, &verified.info.name)?;
+        println!("  Key trusted from now on.");
+    }
+    Ok(app)
+}
+
+/// Point the workspace of the current profile at `url`: through the running
+/// server if there is one, else by syncing here and saving the config. `pin`
+/// is the catalog app `url` is a release of, if it is one.
+async fn switch_workspace(url: &str, app: &str, pin: Option<&CatalogPin>) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let run_dir = run_dir(&config_dir);
+    if let Some(info) = load_run_info(&run_dir).filter(|info| port_is_open(info.port)) {
+        // Let the running daemon sync and persist the switch itself
+        println!("  Switching running tbl server to {app}...");
+        let body = serde_json::json!({ "git_url": url, "catalog_app": pin }).to_string();
+        send_api_request(&info, "POST", "/api/v1/switch", Some(&body))?;
+        println!("  Workspace switched.");
+    } else {
+        println!("  Syncing {app}...");
+        let dir = config_dir.clone();
+        let source = url.to_string();
+        blocking(move || sync_locally(&dir, JobKind::Switch, &source)).await?;
+
+        let mut cfg = load_config(&config_dir).unwrap_or_default();
+        cfg.git_url = Some(url.to_string());
+        cfg.catalog_app = pin.cloned();
+        record_history(&mut cfg, url);
+        remember_repo(&mut cfg, url, None);
+        save_config(&config_dir, &cfg)?;
+        println!("  Workspace set to {app}; run `tbl` to open it.");
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Catalog Command
+// =============================================================================
+
+/// The apps of the configured catalog.
+async fn load_catalog(cfg: &TblConfig) -> Result<catalog::Catalog> {
+    let url = std::env::var("TBL_CATALOG_URL")
+        .ok()
+        .or(cfg.catalog_url.clone())
+        .context("No catalog configured; set catalog_url or TBL_CATALOG_URL")?;
+    blocking(move || catalog::fetch(&url)).await
+}
+
+async fn handle_catalog_command(command: &CatalogCommands) -> Result<()> {
+    let cfg = load_config(&get_config_dir()?).unwrap_or_default();
+    let catalog = load_catalog(&cfg).await?;
+
+    match command {
+        CatalogCommands::List => {
+            println!();
+            if catalog.apps.is_empty() {
+                println!("  The catalog lists no apps.");
+                println!();
+                return Ok(());
+            }
+            println!("  {:<20} {:<10} DESCRIPTION", "NAME", "VERSION");
+            println!("  ───────────────────────────────────────────────────────────────");
+            for app in &catalog.apps {
+                let about = app
+                    .title
+                    .iter()
+                    .chain(&app.description)
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(": ");
+                println!(
+                    "  {:<20} {:<10} {}",
+                    app.name,
+                    app.version.as_deref().unwrap_or("-"),
+                    about
+                );
+            }
+            println!();
+            println!("  Run `tbl catalog install <NAME>` to serve one of them here.");
+            println!();
+        }
+        CatalogCommands::Install {
+            name,
+            version,
+            channel,
+            trust,
+        } => {
+            let app = catalog.find(name).with_context(|| {
+                format!("The catalog lists no app {name:?}; see `tbl catalog list`")
+            })?;
+            let pin = CatalogPin {
+                name: app.name.clone(),
+                version: version.clone(),
+                channel: *channel,
+                installed: None,
+            };
+            // Without a pin, the release the catalog installs by default
+            let (installed, source) = if version.is_some() || channel.is_some() {
+                let (version, source) = select_release(app, &pin)?;
+                (Some(version.to_string()), source)
+            } else {
+                (app.version.clone(), app.source.as_str())
+            };
+            let retry = format!("tbl catalog install --trust {}", app.name);
+            let pin = CatalogPin { installed, ..pin };
+            println!();
+            install_release(app, source, &pin, *trust, &retry).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn handle_upgrade_command(check: bool, trust: bool) -> Result<()> {
+    let cfg = load_config(&get_config_dir()?).unwrap_or_default();
+    let pin = cfg.catalog_app.clone().context(
+        "The workspace is not an app of the catalog; install one with `tbl catalog install <NAME>`",
+    )?;
+    let catalog = load_catalog(&cfg).await?;
+    let app = catalog
+        .find(&pin.name)
+        .with_context(|| format!("The catalog no longer lists {}", pin.name))?;
+    let label = app.title.as_deref().unwrap_or(&app.name);
+
+    println!();
+    if app.version.is_none() && app.releases.is_empty() {
+        println!(
+            "  The catalog lists no versions of {label}; `tbl sync` fetches its latest content."
+        );
+        println!();
+        return Ok(());
+    }
+    let (latest, source) = select_release(app, &pin)?;
+    let installed = pin.installed.as_deref().and_then(|v| v.parse().ok());
+    if installed.is_some_and(|installed: semver::Version| installed >= latest) {
+        println!("  {label} {latest} is up to date ({}).", describe_pin(&pin));
+        println!();
+        return Ok(());
+    }
+    println!(
+        "  {label} {} -> {latest} ({})",
+        pin.installed.as_deref().unwrap_or("?"),
+        describe_pin(&pin)
+    );
+    if check {
+        println!("  Run `tbl upgrade` to install it.");
+        println!();
+        return Ok(());
+    }
+    let pin = CatalogPin {
+        installed: Some(latest.to_string()),
+        ..pin.clone()
+    };
+    install_release(app, source, &pin, trust, "tbl upgrade --trust").await
+}
+
+/// The newest release of `app` that `pin` allows.
+fn select_release<'a>(
+    app: &'a catalog::CatalogApp,
+    pin: &CatalogPin,
+) -> Result<(semver::Version, &'a str)> {
+    app.select(pin.requirement()?.as_ref(), pin.channel.unwrap_or_default())
+        .with_context(|| format!("No release of {} matches {}", app.name, describe_pin(pin)))
+}
+
+/// `pin`'s constraint and channel, for messages.
+fn describe_pin(pin: &CatalogPin) -> String {
+    format!(
+        "{}, {} channel",
+        pin.version.as_deref().unwrap_or("any version"),
+        job_label(pin.channel.unwrap_or_default())
+    )
+}
+
+/// Switch the workspace to `source`, a release of the catalog app `app`,
+/// checking it first if it is a bundle.
+async fn install_release(
+    app: &catalog::CatalogApp,
+    source: &str,
+    pin: &CatalogPin,
+    trust: bool,
+    retry: &str,
+) -> Result<()> {
+    let label = if bundle::is_bundle(source) {
+        check_bundle(source, trust, retry)?
+    } else {
+        app.title.clone().unwrap_or_else(|| app.name.clone())
+    };
+    switch_workspace(source, &label, Some(pin)).await?;
+    println!();
+    Ok(())
+}
+
+// =============================================================================
+// Sync Command
+// =============================================================================
+
+/// Apps synced at the same time unless `--jobs` or `sync_jobs` says otherwise.
+const DEFAULT_SYNC_JOBS: usize = 4;
+
+/// Per-app limit unless `--timeout` or `sync_timeout` says otherwise.
+const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What happened to one app during `tbl sync`.
+enum SyncResult {
+    Updated(Option<String>),
+    Unchanged(Option<String>),
+    Failed(String),
+    TimedOut,
+}
+
+struct SyncOutcome {
+    profile: String,
+    git_url: String,
+    result: SyncResult,
+    /// What the app's `tbl.requires.yaml` asks for but tbl lacks
+    unmet: Vec<String>,
+    elapsed: Duration,
+}
+
+async fn handle_sync_command(all: bool, jobs: Option<usize>, timeout: Option<&str>) -> Result<()> {
+    let settings = load_config(&get_config_dir()?).unwrap_or_default();
+    init_git_timeout(
+        std::env::var("TBL_GIT_TIMEOUT")
+            .ok()
+            .or(settings.git_timeout)
+            .as_deref(),
+    )?;
+    init_git_proxy(std::env::var("TBL_GIT_PROXY").ok().or(settings.git_proxy));
+    let jobs = jobs
+        .or(settings.sync_jobs)
+        .unwrap_or(DEFAULT_SYNC_JOBS)
+        .max(1);
+    let timeout = match timeout.or(settings.sync_timeout.as_deref()) {
+        Some(value) => parse_duration(value).context("Invalid sync timeout")?,
+        None => DEFAULT_SYNC_TIMEOUT,
+    };
+
+    let profiles = if all {
+        list_profiles()?
+    } else {
+        vec![(profile_label().to_string(), get_config_dir()?)]
+    };
+    let apps: Vec<(String, PathBuf, String)> = profiles
+        .into_iter()
+        .filter_map(|(profile, config_dir)| {
+            let url = load_config(&config_dir)?.git_url?;
+            Some((profile, config_dir, url))
+        })
+        .collect();
+
+    println!();
+    if apps.is_empty() {
+        println!("  Nothing to sync: no profile has a git URL configured.");
+        println!();
+        return Ok(());
+    }
+    if apps.iter().any(|(_, _, url)| is_git_source(url)) {
+        ensure_git_available()?;
+    }
+
+    println!(
+        "  Syncing {} app{} ({} at a time, {}s timeout each)...",
+        apps.len(),
+        if apps.len() == 1 { "" } else { "s" },
+        jobs,
+        timeout.as_secs()
+    );
+
+    let limit = Arc::new(tokio::sync::Semaphore::new(jobs));
+    let tasks: Vec<_> = apps
+        .into_iter()
+        .map(|(profile, config_dir, git_url)| {
+            let limit = limit.clone();
+            tokio::spawn(async move {
+                let _permit = limit.acquire_owned().await;
+                let started = std::time::Instant::now();
+                let url = git_url.clone();
+                let web_dir = config_dir.join("web");
+                let disabled = load_config(&config_dir)
+                    .map(|cfg| cfg.disable)
+                    .unwrap_or_default();
+                let job = tokio::task::spawn_blocking(move || sync_app(&config_dir, &url));
+                let result = match tokio::time::timeout(timeout, job).await {
+                    Ok(Ok(result)) => result,
+                    Ok(Err(e)) => SyncResult::Failed(e.to_string()),
+                    Err(_) => SyncResult::TimedOut,
+                };
+                let elapsed = started.elapsed();
+                let unmet = match result {
+                    SyncResult::Updated(_) | SyncResult::Unchanged(_) => {
+                        blocking(move || requires::unmet(&web_dir, &disabled)).await
+                    }
+                    _ => Vec::new(),
+                };
+                SyncOutcome {
+                    profile,
+                    git_url,
+                    result,
+                    unmet,
+                    elapsed,
+                }
+            })
+        })
+        .collect();
+
+    let mut outcomes = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        outcomes.push(task.await?);
+    }
+
+    // Stop git commands still running for apps that timed out
+    cancel_git_operations();
+
+    let failures = print_sync_report(&outcomes);
+    if failures > 0 {
+        anyhow::bail!("{failures} of {} apps failed to sync", outcomes.len());
+    }
+    Ok(())
+}
+
+/// `tbl sync --once`: sync the profile's workspace in this process alone,
+/// with the settings a server would use (flags, `TBL_*` variables, and the
+/// config file, which container mode skips), never through a running server.
+///
+/// Made for containers that share the workspace volume with a separate
+/// `tbl --container`: as an init container with `--exit`, or as a sidecar
+/// that syncs again at every `sync_schedule` time until stopped.
+async fn handle_sync_once_command(cli: &Cli, timeout: Option<&str>, exit: bool) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    fs::create_dir_all(&config_dir)
+        .with_context(|| format!("Failed to create config dir {:?}", config_dir))?;
+    let container = container::enabled(cli.container);
+    if vault::is_encrypted(&config_dir) && !container {
+        vault::load(&config_dir)?;
+    }
+    let cwd = std::env::current_dir().context("Failed to get current directory")?;
+    let (mut cfg, _) = effective_config(cli, file_config(&config_dir, container)?, &cwd);
+    let providers = cfg.providers.take().unwrap_or_default();
+    cfg.providers = Some(providers.resolve(&config_dir)?).filter(|providers| !providers.is_empty());
+    validate_config_paths(&config_dir, &cfg)?;
+    init_git_timeout(cfg.git_timeout.as_deref())?;
+    init_git_proxy(cfg.git_proxy.clone());
+    let timeout = match timeout.or(cfg.sync_timeout.as_deref()) {
+        Some(value) => parse_duration(value).context("Invalid sync timeout")?,
+        None => DEFAULT_SYNC_TIMEOUT,
+    };
+    let schedule = match cfg.sync_schedule.as_deref().filter(|_| !exit) {
+        Some(spec) => Some(CronSchedule::parse(spec).context("Invalid sync_schedule")?),
+        None => None,
+    };
+    let Some(git_url) = cfg.git_url.clone() else {
+        anyhow::bail!("No git URL configured; set --git-url or TBL_GIT_URL");
+    };
+    if is_git_source(&git_url) {
+        ensure_git_available()?;
+    }
+
+    loop {
+        println!();
+        println!("  Syncing {}...", redact_credentials(&git_url));
+        let started = std::time::Instant::now();
+        let job = {
+            let (config_dir, url) = (config_dir.clone(), git_url.clone());
+            let settings = source::Settings::of(&cfg);
+            tokio::task::spawn_blocking(move || {
+                let before = current_commit(&config_dir.join("web"));
+                let result = run_as_job(
+                    &config_dir,
+                    JobKind::Sync,
+                    &url,
+                    settings,
+                    |settings, job| ensure_repo(&config_dir, &url, settings, Some(job)),
+                );
+                let after = current_commit(&config_dir.join("web"));
+                match result {
+                    Err(e) => SyncResult::Failed(format!("{e:#}")),
+                    Ok(()) if after == before => SyncResult::Unchanged(after),
+                    Ok(()) => SyncResult::Updated(after),
+                }
+            })
+        };
+        let result = match tokio::time::timeout(timeout, job).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => SyncResult::Failed(e.to_string()),
+            Err(_) => {
+                cancel_git_operations();
+                SyncResult::TimedOut
+            }
+        };
+        let unmet = match result {
+            SyncResult::Updated(_) | SyncResult::Unchanged(_) => {
+                let (web_dir, disabled) = (config_dir.join("web"), cfg.disable.clone());
+                blocking(move || requires::unmet(&web_dir, &disabled)).await
+            }
+            _ => Vec::new(),
+        };
+        let failed = print_sync_report(&[SyncOutcome {
+            profile: profile_label().to_string(),
+            git_url: git_url.clone(),
+            result,
+            unmet,
+            elapsed: started.elapsed(),
+        }]) > 0;
+
+        // A sidecar keeps going after a failed sync; the next one may work
+        let Some(due) = schedule.as_ref().and_then(|s| s.next_after(Local::now())) else {
+            if failed {
+                anyhow::bail!("The sync failed");
+            }
+            return Ok(());
+        };
+        println!(
+            "  Next sync at {}.",
+            timefmt::human(&due, timefmt::Style::Full)
+        );
+        let wait = (due - Local::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = container::stop_signal() => return Ok(()),
+        }
+    }
+}
+
+/// Update one app, through its running server when there is one so that
+/// connected pages receive the usual update events.
+fn sync_app(config_dir: &Path, url: &str) -> SyncResult {
+    let web_dir = config_dir.join("web");
+    let before = current_commit(&web_dir);
+
+    let result = match load_run_info(&run_dir(config_dir)).filter(|info| port_is_open(info.port)) {
+        Some(info) => send_api_request(&info, "POST", "/api/v1/update", None).map(|_| ()),
+        None => sync_locally(config_dir, JobKind::Sync, url),
+    };
+    if let Err(e) = result {
+        return SyncResult::Failed(format!("{e:#}"));
+    }
+
+    let after = current_commit(&web_dir);
+    if after == before {
+        SyncResult::Unchanged(after)
+    } else {
+        SyncResult::Updated(after)
+    }
+}
+
+/// `ensure_repo` for a profile whose server is not running, recorded in the
+/// profile's job list like a sync done by the server.
+fn sync_locally(config_dir: &Path, kind: JobKind, url: &str) -> Result<()> {
+    run_locally(config_dir, kind, url, |settings, job| {
+        ensure_repo(config_dir, url, settings, Some(job))
+    })
+}
+
+/// Run `work` on the workspace as a job about `target`, without a server, so
+/// it still shows in `tbl jobs`.
+fn run_locally(
+    config_dir: &Path,
+    kind: JobKind,
+    target: &str,
+    work: impl FnOnce(source::Settings, &JobHandle) -> Result<()>,
+) -> Result<()> {
+    let mut settings = source::Settings::of(&load_config(config_dir).unwrap_or_default());
+    settings.providers = settings.providers.resolve(config_dir)?;
+    run_as_job(config_dir, kind, target, settings, work)
+}
+
+/// Run `work` with `settings` as a job about `target` in the profile's job
+/// list.
+fn run_as_job(
+    config_dir: &Path,
+    kind: JobKind,
+    target: &str,
+    settings: source::Settings,
+    work: impl FnOnce(source::Settings, &JobHandle) -> Result<()>,
+) -> Result<()> {
+    let run_dir = run_dir(config_dir);
+    let db = StateDb::open(config_dir, &run_dir)?;
+    let jobs = Arc::new(JobStore::open(db, run_dir.join("jobs"))?);
+    let record = jobs.create(kind, Some(target.to_string()))?;
+    let job = JobHandle::new(jobs, record.id);
+
+    job.start();
+    let result = work(settings, &job);
+    let outcome = match &result {
+        Ok(()) => Ok(serde_json::json!({
+            "commit": current_commit(&config_dir.join("web"))
+        })),
+        Err(e) => Err(anyhow::anyhow!("{e:#}")),
+    };
+    job.finish(&outcome);
+    result
+}
+
+/// Print one line per app plus a summary; returns the number of failures.
+fn print_sync_report(outcomes: &[SyncOutcome]) -> usize {
+    let short = |commit: &Option<String>| {
+        commit
+            .as_deref()
+            .map(|c| c.chars().take(7).collect::<String>())
+            .unwrap_or_else(|| "-".to_string())
+    };
+
+    println!();
+    println!(
+        "  {:<16} {:<10} {:<8} {:>6}  URL",
+        "PROFILE", "RESULT", "COMMIT", "TIME"
+    );
+    println!("  ───────────────────────────────────────────────────────────────");
+    let (mut updated, mut failed) = (0, 0);
+    for outcome in outcomes {
+        let (label, commit) = match &outcome.result {
+            SyncResult::Updated(commit) => {
+                updated += 1;
+                ("updated", short(commit))
+            }
+            SyncResult::Unchanged(commit) => ("unchanged", short(commit)),
+            SyncResult::Failed(_) => {
+                failed += 1;
+                ("failed", "-".to_string())
+            }
+            SyncResult::TimedOut => {
+                failed += 1;
+                ("timed out", "-".to_string())
+            }
+        };
+        println!(
+            "  {:<16} {:<10} {:<8} {:>5.1}s  {}",
+            outcome.profile,
+            label,
+            commit,
+            outcome.elapsed.as_secs_f64(),
+            redact_credentials(&outcome.git_url)
+        );
+        if let SyncResult::Failed(error) = &outcome.result {
+            println!("    {}", redact_credentials(error));
+        }
+        for problem in &outcome.unmet {
+            println!("    {problem}");
+        }
+    }
+    println!();
+    println!(
+        "  {} synced: {} updated, {} unchanged, {} failed.",
+        outcomes.len(),
+        updated,
+        outcomes.len() - updated - failed,
+        failed
+    );
+    println!();
+    failed
+}
+
+// =============================================================================
+// Bench Command
+// =============================================================================
+
+/// Connections `tbl bench` opens unless `--concurrency` says otherwise.
+const DEFAULT_BENCH_CONCURRENCY: usize = 16;
+
+/// How long `tbl bench` runs unless `--duration` says otherwise.
+const DEFAULT_BENCH_DURATION: Duration = Duration::from_secs(10);
+
+/// `tbl bench`: load the profile's server with requests for `paths` below
+/// `/web/`, or for every file of the app. A server that isn't running is
+/// started for the run and stopped after it.
+async fn handle_bench_command(
+    concurrency: Option<usize>,
+    duration: Option<&str>,
+    paths: &[String],
+) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let concurrency = concurrency.unwrap_or(DEFAULT_BENCH_CONCURRENCY).max(1);
+    let duration = match duration {
+        Some(value) => parse_duration(value).context("Invalid duration")?,
+        None => DEFAULT_BENCH_DURATION,
+    };
+    if duration.is_zero() {
+        anyhow::bail!("--duration must be longer than 0");
+    }
+    let paths = if paths.is_empty() {
+        bench_paths(&config_dir)?
+    } else {
+        paths.to_vec()
+    };
+
+    let was_running =
+        load_run_info(&run_dir(&config_dir)).is_some_and(|info| port_is_open(info.port));
+    let info = {
+        let config_dir = config_dir.clone();
+        blocking(move || start_server(&config_dir)).await?
+    };
+    let urls = paths
+        .iter()
+        .map(|path| {
+            let mut url = reqwest::Url::parse(&info.local_url("/web/"))?;
+            if let Ok(mut segments) = url.path_segments_mut() {
+                segments
+                    .pop_if_empty()
+                    .extend(path.split('/').filter(|segment| !segment.is_empty()));
+            }
+            Ok(url.to_string())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .default_headers(
+            [(
+                header::COOKIE,
+                header::HeaderValue::from_str(&format!("tbl_token={}", info.auth_token.expose()))?,
+            )]
+            .into_iter()
+            .collect(),
+        );
+    if info.tls {
+        let cert = info.tls_cert.as_deref().context(
+            "pid.yaml has no certificate path for this HTTPS server; restart tbl to refresh it",
+        )?;
+        client = client.tls_backend_preconfigured(pinned_tls_config(cert)?);
+    }
+    let client = client.build().context("Failed to set up the HTTP client")?;
+
+    println!();
+    println!(
+        "  Benchmarking {} ({} path{}, {} connection{}, {}s)...",
+        info.local_url("/web/"),
+        urls.len(),
+        if urls.len() == 1 { "" } else { "s" },
+        concurrency,
+        if concurrency == 1 { "" } else { "s" },
+        duration.as_secs_f64()
+    );
+    let report = bench::run(client, urls, concurrency, duration).await;
+    if !was_running {
+        let stopped = blocking(move || send_shutdown_request(&info, ShutdownMode::Graceful)).await;
+        if let Err(e) = stopped {
+            eprintln!("  Failed to stop the server started for the benchmark: {e:#}");
+        }
+    }
+    report.print();
+    if report.requests > 0 && report.errors == report.requests {
+        anyhow::bail!("Every request failed; check the paths and `tbl status`");
+    }
+    Ok(())
+}
+
+/// Every file of the app: those the last sync recorded, or what the
+/// workspace holds.
+fn bench_paths(config_dir: &Path) -> Result<Vec<String>> {
+    let mut files = manifest::load(config_dir);
+    if files.is_empty() {
+        files = manifest::scan(&config_dir.join("web")).unwrap_or_default();
+    }
+    if files.is_empty() {
+        anyhow::bail!("No files to request: sync the app first, or pass --path");
+    }
+    Ok(files.into_keys().collect())
+}
+
+// =============================================================================
+// Unshallow Command
+// =============================================================================
+
+/// Fetch the full history of the checkout and set `clone_depth` to 0 so later
+/// syncs keep it, through the running server when there is one.
+fn handle_unshallow_command() -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let mut cfg = load_config(&config_dir).unwrap_or_default();
+    let Some(url) = cfg.git_url.clone() else {
+        anyhow::bail!("No git URL configured");
+    };
+    let provider = source::provider(&url);
+    if !provider.uses_git() {
+        anyhow::bail!(
+            "The {} source is not a git repository and has no history",
+            provider.name()
+        );
+    }
+
+    println!();
+    println!(
+        "  Fetching the full history of {}...",
+        redact_credentials(&url)
+    );
+    let run_dir = run_dir(&config_dir);
+    if let Some(info) = load_run_info(&run_dir).filter(|info| port_is_open(info.port)) {
+        send_api_request(&info, "POST", "/api/v1/unshallow", None)?;
+    } else {
+        init_git_timeout(
+            std::env::var("TBL_GIT_TIMEOUT")
+                .ok()
+                .or(cfg.git_timeout.clone())
+                .as_deref(),
+        )?;
+        init_git_proxy(
+            std::env::var("TBL_GIT_PROXY")
+                .ok()
+                .or(cfg.git_proxy.clone()),
+        );
+        ensure_git_available()?;
+        cfg.clone_depth = Some(0);
+        save_config(&config_dir, &cfg)?;
+        sync_locally(&config_dir, JobKind::Unshallow, &url)?;
+    }
+    println!("  Checkout has the full history; later syncs keep it.");
+    println!();
+
+    Ok(())
+}
+
+// =============================================================================
+// Pause Commands
+// =============================================================================
+
+fn handle_pause_command(reason: Option<String>, duration: Option<&str>) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let duration = duration
+        .map(parse_duration)
+        .transpose()
+        .context("Invalid --for")?
+        .filter(|duration| !duration.is_zero());
+
+    let run_dir = run_dir(&config_dir);
+    if let Some(info) = load_run_info(&run_dir).filter(|info| port_is_open(info.port)) {
+        let mut body = serde_json::json!({ "reason": reason });
+        if let Some(duration) = duration {
+            body["for"] = format!("{}s", duration.as_secs()).into();
+        }
+        send_api_request(&info, "POST", "/api/v1/sync/pause", Some(&body.to_string()))?;
+    } else {
+        pause::pause(&config_dir, reason, duration.map(|d| d.as_secs()))?;
+    }
+
+    println!();
+    match pause::current(&config_dir) {
+        Some(paused) => println!("  Automatic syncs {}.", describe_pause(&paused)),
+        None => println!("  Automatic syncs paused."),
+    }
+    println!("  `tbl sync` still updates; `tbl resume` ends the pause.");
+    println!();
+    Ok(())
+}
+
+fn handle_resume_command() -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let was_paused = pause::current(&config_dir).is_some();
+
+    let run_dir = run_dir(&config_dir);
+    if let Some(info) = load_run_info(&run_dir).filter(|info| port_is_open(info.port)) {
+        send_api_request(&info, "POST", "/api/v1/sync/resume", None)?;
+    } else {
+        pause::resume(&config_dir)?;
+    }
+
+    println!();
+    if was_paused {
+        println!("  Automatic syncs resumed.");
+    } else {
+        println!("  Automatic syncs were not paused.");
+    }
+    println!();
+    Ok(())
+}
+
+/// "paused 2h ago until Fri 18:00 (demo)", for status output.
+fn describe_pause(paused: &pause::SyncPause) -> String {
+    let mut text = format!("paused {}", timefmt::age(paused.since));
+    if let Some(until) = paused.until {
+        text.push_str(&format!(
+            " until {}",
+            timefmt::human_unix(until, timefmt::Style::Weekday)
+        ));
+    }
+    if let Some(reason) = &paused.reason {
+        text.push_str(&format!(" ({reason})"));
+    }
+    text
+}
+
+// =============================================================================
+// Releases Commands
+// =============================================================================
+
+fn handle_releases_command() -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let cfg = load_config(&config_dir).unwrap_or_default();
+    let list = releases::list(&config_dir);
+
+    println!();
+    if list.is_empty() {
+        match cfg.keep_releases.unwrap_or(0) {
+            0 => println!("  No releases kept; set keep_releases to keep some for rollback."),
+            _ => println!("  No releases saved yet; the next sync saves one."),
+        }
+        println!();
+        return Ok(());
+    }
+
+    let mut current = current_commit(&config_dir.join("web"));
+    println!(
+        "  {:<2}{:<18} {:<12} {:<9} {:>6} {:>9}",
+        "", "ID", "VERSION", "CREATED", "FILES", "SIZE"
+    );
+    println!("  ──────────────────────────────────────────────────────────────");
+    for release in &list {
+        // Only the newest release with the commit is the one served
+        let marker = match current.take_if(|commit| *commit == release.version) {
+            Some(_) => "*",
+            None => "",
+        };
+        println!(
+            "  {:<2}{:<18} {:<12} {:<9} {:>6} {:>9}",
+            marker,
+            release.id,
+            release.version.chars().take(12).collect::<String>(),
+            timefmt::age(release.created),
+            release.files,
+            format_bytes(release.size)
+        );
+    }
+    println!();
+    println!(
+        "  {} release(s), {} on disk ({} without sharing)",
+        list.len(),
+        format_bytes(releases::pool_size(&config_dir)),
+        format_bytes(list.iter().map(|release| release.size).sum())
+    );
+    println!("  Run `tbl rollback <ID>` to serve one until the next sync.");
+    println!();
+    Ok(())
+}
+
+fn handle_rollback_command(id: &str) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let release = releases::find(&config_dir, id)?;
+
+    println!();
+    println!(
+        "  Restoring release {} ({})...",
+        release.id,
+        release.version.chars().take(12).collect::<String>()
+    );
+    let run_dir = run_dir(&config_dir);
+    if let Some(info) = load_run_info(&run_dir).filter(|info| port_is_open(info.port)) {
+        let path = format!("/api/v1/releases/{}/rollback", release.id);
+        send_api_request(&info, "POST", &path, None)?;
+    } else {
+        let id = release.id.clone();
+        run_locally(&config_dir, JobKind::Rollback, &id, |_, job| {
+            roll_back(&config_dir, &release, job)
+        })?;
+    }
+    println!("  Serving the release until the next sync.");
+    println!();
+
+    Ok(())
+}
+
+// =============================================================================
+// Jobs Command
+// =============================================================================
+
+fn handle_jobs_command(id: Option<&str>) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let run_dir = run_dir(&config_dir);
+    let db = StateDb::open(&config_dir, &run_dir)?;
+    let records = jobs::select(&db.conn())?;
+
+    println!();
+    let Some(id) = id else {
+        if records.is_empty() {
+            println!("  No jobs recorded yet.");
+            println!();
+            return Ok(());
+        }
+        println!(
+            "  {:<15} {:<9} {:<10} {:<9} TARGET",
+            "ID", "KIND", "STATE", "CREATED"
+        );
+        println!("  ─────────────────────────────────────────────────────────────────");
+        for job in &records {
+            println!(
+                "  {:<15} {:<9} {:<10} {:<9} {}",
+                job.id,
+                job_label(job.kind),
+                job_label(job.state),
+                timefmt::age(job.created_at),
+                job.target.as_deref().map_or("-".into(), redact_credentials)
+            );
+        }
+        println!();
+        println!("  Run `tbl jobs <ID>` to see a job's output.");
+        println!();
+        return Ok(());
+    };
+
+    let job = records
+        .iter()
+        .find(|job| job.id == id)
+        .with_context(|| format!("no job {id:?}"))?;
+    println!("  Job {}", job.id);
+    println!("  ───────────────────────────────────────");
+    println!("  Kind:     {}", job_label(job.kind));
+    println!("  State:    {}", job_label(job.state));
+    if let Some(target) = &job.target {
+        println!("  Target:   {}", redact_credentials(target));
+    }
+    println!(
+        "  Created:  {} ({})",
+        timefmt::human_unix(job.created_at, timefmt::Style::Full),
+        timefmt::age(job.created_at)
+    );
+    if let (Some(started), Some(finished)) = (job.started_at, job.finished_at) {
+        println!(
+            "  Duration: {}",
+            timefmt::duration(finished.saturating_sub(started))
+        );
+    }
+    if let Some(progress) = &job.progress {
+        println!("  Progress: {progress}");
+    }
+    if let Some(result) = &job.result {
+        println!("  Result:   {result}");
+    }
+    if let Some(error) = &job.error {
+        println!("  Error:    {}", redact_credentials(error));
+    }
+
+    let log = jobs::job_log(&db, &run_dir.join("jobs"), &job.id);
+    if !log.trim().is_empty() {
+        println!();
+        for line in log.lines() {
+            println!("  │ {}", redact_credentials(line));
+        }
+    }
+    println!();
+    Ok(())
+}
+
+/// Serialized (lowercase) name of a job kind or state.
+fn job_label(value: impl Serialize) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+// =============================================================================
+// Auth Command
+// =============================================================================
+
+/// `tbl auth set-password`: prompt for a password without echo and store
+/// only its argon2 hash in the config file.
+fn handle_set_password_command(user: Option<&str>) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let mut cfg = load_config(&config_dir).unwrap_or_default();
+
+    println!();
+    let user = match user.map(str::to_string).or_else(|| cfg.basic_user.clone()) {
+        Some(user) => user,
+        None => {
+            print!("  Username: ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            line.trim().to_string()
+        }
+    };
+    if user.is_empty() || user.contains(':') {
+        anyhow::bail!("Invalid username {user:?}: it must be non-empty and contain no ':'");
+    }
+
+    let password =
+        rpassword::prompt_password("  New password: ").context("failed to read password")?;
+    if password.is_empty() {
+        anyhow::bail!("Password must not be empty");
+    }
+    let confirm =
+        rpassword::prompt_password("  Repeat password: ").context("failed to read password")?;
+    if password != confirm {
+        anyhow::bail!("Passwords do not match");
+    }
+
+    cfg.basic_user = Some(user.clone());
+    cfg.basic_pass = Some(Secret::new(hash_password(&password)?));
+    save_config(&config_dir, &cfg)?;
+
+    println!();
+    println!("  Basic auth password for {user:?} saved (argon2 hash).");
+    if load_run_info(&run_dir(&config_dir)).is_some_and(|info| port_is_open(info.port)) {
+        println!("  Restart tbl for the running server to use it.");
+    }
+    if std::env::var("TBL_BASIC_PASS").is_ok() {
+        println!("  Note: TBL_BASIC_PASS is set and takes precedence over the config file.");
+    }
+    println!();
+
+    Ok(())
+}
+
+// =============================================================================
+// Config Commands
+// =============================================================================
+
+const PLAIN_CONFIG_FILES: &[&str] = &["config.json", "config.yaml", "config.yml", "config.toml"];
+
+/// The config file `load_config` reads in `config_dir`, if there is one.
+fn config_file_path(config_dir: &Path) -> Option<PathBuf> {
+    [vault::FILE_NAME]
+        .iter()
+        .chain(PLAIN_CONFIG_FILES)
+        .map(|name| config_dir.join(name))
+        .find(|path| path.exists())
+}
+
+/// `tbl config encrypt`: replace the plain config file with `config.enc`, or
+/// re-encrypt an existing `config.enc` under a new passphrase.
+/// `tbl login github`: authorize tbl on GitHub through the OAuth device
+/// flow and store the token in the OS keyring.
+async fn handle_login_command(provider: &str, client_id: Option<String>) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    match forge::Provider::parse(provider) {
+        Some(forge::Provider::GitHub) => {}
+        Some(other) => anyhow::bail!(
+            "tbl login only supports GitHub; put a token in providers.{}.token",
+            other.name()
+        ),
+        None => anyhow::bail!("Unknown provider {provider:?}; expected github"),
+    }
+    let account = load_config(&config_dir)
+        .and_then(|cfg| cfg.providers)
+        .unwrap_or_default()
+        .resolve(&config_dir)?
+        .github
+        .unwrap_or_default();
+    let client_id = client_id
+        .or_else(|| account.client_id.clone())
+        .context("No OAuth app to authorize; pass --client-id or set providers.github.client_id")?;
+
+    let code = forge::device_code(&account, &client_id).await?;
+    println!();
+    println!("  Open {} and enter the code:", code.verification_uri);
+    println!();
+    println!("      {}", code.user_code);
+    println!();
+    println!(
+        "  Waiting for GitHub (the code expires in {} minutes)...",
+        code.expires_in / 60
+    );
+    if std::io::stdout().is_terminal() {
+        let _ = webbrowser::open(&code.verification_uri);
+    }
+
+    let token = forge::device_token(&account, &client_id, &code).await?;
+    vault::token_set(&config_dir, "github", &token)?;
+    println!();
+    println!("  Signed in to GitHub; the token is stored in the OS keyring.");
+    if !account.token.expose().is_empty() {
+        println!("  providers.github.token in the config takes precedence over it.");
+    }
+    if load_run_info(&run_dir(&config_dir)).is_some_and(|info| port_is_open(info.port)) {
+        println!("  Restart tbl for the running server to use it.");
+    }
+    println!();
+    Ok(())
+}
+
+/// `tbl logout <provider>`: forget the token `tbl login` stored.
+fn handle_logout_command(provider: &str) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let provider = forge::Provider::parse(provider).with_context(|| {
+        format!("Unknown provider {provider:?}; expected github, gitlab, or gitea")
+    })?;
+
+    println!();
+    if vault::token_delete(&config_dir, provider.name()) {
+        println!(
+            "  Token for {} removed from the OS keyring.",
+            provider.name()
+        );
+        if load_run_info(&run_dir(&config_dir)).is_some_and(|info| port_is_open(info.port)) {
+            println!("  Restart tbl for the running server to stop using it.");
+        }
+    } else {
+        println!("  No token for {} in the OS keyring.", provider.name());
+    }
+    println!();
+    Ok(())
+}
+
+fn handle_config_encrypt_command(keyring: bool) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let plain_files: Vec<PathBuf> = PLAIN_CONFIG_FILES
+        .iter()
+        .map(|name| config_dir.join(name))
+        .filter(|path| path.exists())
+        .collect();
+
+    let reencrypt = vault::is_encrypted(&config_dir);
+    let cfg = if reencrypt {
+        serde_json::from_slice::<TblConfig>(&vault::load(&config_dir)?)
+            .context("config.enc does not hold a valid config")?
+    } else if plain_files.is_empty() {
+        TblConfig::default()
+    } else {
+        load_config(&config_dir).context("The config file could not be parsed; fix it first")?
+    };
+
+    println!();
+    let passphrase = match std::env::var(vault::PASSPHRASE_ENV) {
+        Ok(value) if !value.is_empty() => Secret::transient(value),
+        _ => {
+            let passphrase = vault::prompt("  New passphrase: ")?;
+            let confirm = vault::prompt("  Repeat passphrase: ")?;
+            if passphrase.expose() != confirm.expose() {
+                anyhow::bail!("Passphrases do not match");
+            }
+            passphrase
+        }
+    };
+
+    fs::create_dir_all(&config_dir)
+        .with_context(|| format!("Failed to create config dir {:?}", config_dir))?;
+    if keyring {
+        vault::keyring_set(&config_dir, &passphrase)?;
+    }
+    vault::write(&config_dir, &serde_json::to_vec_pretty(&cfg)?, &passphrase)?;
+    for path in &plain_files {
+        fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+
+    let path = config_dir.join(vault::FILE_NAME);
+    if reencrypt {
+        println!("  {} re-encrypted with the new passphrase.", path.display());
+    } else {
+        println!("  Config encrypted to {}", path.display());
+    }
+    for path in &plain_files {
+        println!("  Removed {}", path.display());
+    }
+    if keyring {
+        println!("  Passphrase stored in the OS keyring.");
+    }
+    if load_run_info(&run_dir(&config_dir)).is_some_and(|info| port_is_open(info.port)) {
+        println!("  Restart tbl for the running server to use it.");
+    }
+    println!();
+
+    Ok(())
+}
+
+/// `tbl config decrypt`: write `config.enc` back out as `config.json`.
+fn handle_config_decrypt_command() -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let path = config_dir.join(vault::FILE_NAME);
+    if !vault::is_encrypted(&config_dir) {
+        anyhow::bail!("{} does not exist", path.display());
+    }
+
+    let cfg = serde_json::from_slice::<TblConfig>(&vault::load(&config_dir)?)
+        .context("config.enc does not hold a valid config")?;
+    let json_path = config_dir.join("config.json");
+    fs::write(&json_path, serde_json::to_vec_pretty(&cfg)?)
+        .with_context(|| format!("Failed to write {}", json_path.display()))?;
+    fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+
+    println!();
+    println!("  Config decrypted to {}", json_path.display());
+    if vault::keyring_delete(&config_dir) {
+        println!("  Passphrase removed from the OS keyring.");
+    }
+    if load_run_info(&run_dir(&config_dir)).is_some_and(|info| port_is_open(info.port)) {
+        println!("  Restart tbl for the running server to use it.");
+    }
+    println!();
+
+    Ok(())
+}
+
+/// `tbl config explain`: print every effective setting with the flag,
+/// environment variable, config file, or default it comes from. Flags given
+/// before `config explain` are taken into account.
+fn handle_config_explain_command(cli: &Cli) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let file = config_file_path(&settings_dir(&config_dir));
+    let file_cfg = load_config(&config_dir);
+    let parsed = file_cfg.is_some();
+    let cwd = std::env::current_dir().context("Failed to get current directory")?;
+    let (cfg, origins) = effective_config(cli, file_cfg.unwrap_or_default(), &cwd);
+
+    let file_label = file
+        .as_deref()
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let profile_origin = if cli.profile.is_some() {
+        "--profile"
+    } else if std::env::var("TBL_PROFILE").is_ok() {
+        "TBL_PROFILE"
+    } else {
+        "default"
+    };
+    let row = |key: &str, origin: &str, value: &str| {
+        println!("  {key:<14} {origin:<18} {value}");
+    };
+
+    println!();
+    println!("  Effective configuration");
+    println!("  ───────────────────────────────────────");
+    match &file {
+        Some(path) if parsed => println!("  Config file: {}", path.display()),
+        Some(path) => println!(
+            "  Config file: {} (could not be read; ignored)",
+            path.display()
+        ),
+        None => println!("  Config file: none"),
+    }
+    let recorded = config_dir.join("config.json");
+    if is_system() && recorded.exists() {
+        println!("  Recorded:    {} (on top)", recorded.display());
+    }
+    println!();
+    row("profile", profile_origin, profile_label());
+    for (key, value, origin) in &origins.0 {
+        let origin = match origin {
+            SettingOrigin::Flag => format!("--{}", key.replace('_', "-")),
+            SettingOrigin::Env => format!("TBL_{}", key.to_uppercase()),
+            SettingOrigin::File => file_label.clone(),
+            SettingOrigin::Default => "default".to_string(),
+            SettingOrigin::Unset => "not set".to_string(),
+        };
+        row(key, &origin, value.as_deref().unwrap_or("-"));
+    }
+    if let Some(branding) = &cfg.branding {
+        let set: Vec<&str> = [
+            ("title", branding.title.is_some()),
+            ("logo", branding.logo.is_some()),
+            ("accent_color", branding.accent_color.is_some()),
+            ("footer", branding.footer.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        row("branding", &file_label, &set.join(", "));
+    }
+    if !cfg.notifications.is_empty() {
+        let labels: Vec<String> = cfg.notifications.iter().map(|n| n.label()).collect();
+        row("notifications", &file_label, &labels.join(", "));
+    }
+    if !cfg.history.is_empty() {
+        row(
+            "history",
+            &file_label,
+            &format!("{} repositories", cfg.history.len()),
+        );
+    }
+    println!();
+
+    Ok(())
+}
+
+// =============================================================================
+// Config Export & Import
+// =============================================================================
+
+/// First entry of an export bundle.
+const EXPORT_MANIFEST: &str = "tbl-export.json";
+
+/// Config dir entries left out of export bundles: runtime state, logs, the
+/// checkout and app data (re-created on the new machine), and other profiles.
+const EXPORT_SKIP_DIRS: &[&str] = &["run", "log", "crash", "web", "data", "profiles", "backups"];
+
+/// Whether a file in a profile's config dir belongs to the state database
+/// (with its `-wal` and `-shm` companions), which is app data as well.
+fn is_state_file(name: &str) -> bool {
+    name.starts_with(state::STATE_DB)
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportManifest {
+    /// tbl version that wrote the bundle
+    version: String,
+    created: u64,
+    profiles: Vec<String>,
+    /// Profiles whose certificate and key are bundled as `tls/cert.pem` and
+    /// `tls/key.pem` in their config dir
+    #[serde(default)]
+    tls: Vec<String>,
+}
+
+/// Config dir of `profile` relative to the base config dir.
+fn profile_subdir(profile: &str) -> PathBuf {
+    if profile == DEFAULT_PROFILE {
+        PathBuf::new()
+    } else {
+        Path::new("profiles").join(profile)
     }
-    * {
-      box-sizing: border-box;
-      font-family: system-ui, -apple-system, BlinkMacSystemFont, "SF Pro Text",
-                   "Segoe UI", sans-serif;
+}
+
+/// `tbl export-config`: write the config files, templates, and branding
+/// assets of every profile (and, with `--tls`, their certificates) to a
+/// gzipped tarball.
+fn handle_export_config_command(path: &Path, tls: bool) -> Result<()> {
+    let mut manifest = ExportManifest {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        created: now_unix(),
+        profiles: Vec::new(),
+        tls: Vec::new(),
+    };
+    let mut files = Vec::new();
+    let mut notes = Vec::new();
+
+    for (profile, dir) in list_profiles()? {
+        let subdir = profile_subdir(&profile);
+        let cfg = load_config(&dir).unwrap_or_default();
+        let tls_files: Vec<PathBuf> = [&cfg.tls_cert, &cfg.tls_key]
+            .into_iter()
+            .flatten()
+            .filter_map(|file| config_path(&dir, file).ok())
+            .filter_map(|file| fs::canonicalize(file).ok())
+            .collect();
+        collect_export_files(&dir, &subdir, &tls_files, true, &mut files)?;
+
+        match (&cfg.tls_cert, &cfg.tls_key) {
+            (Some(cert), Some(key)) if tls => {
+                let cert = config_path(&dir, cert).context("Invalid tls_cert")?;
+                let key = config_path(&dir, key).context("Invalid tls_key")?;
+                files.push((cert, subdir.join("tls").join("cert.pem")));
+                files.push((key, subdir.join("tls").join("key.pem")));
+                manifest.tls.push(profile.clone());
+            }
+            (Some(cert), _) => notes.push(format!(
+                "{profile}: TLS certificate {cert} not included (use --tls)"
+            )),
+            _ => {}
+        }
+        manifest.profiles.push(profile);
     }
-    body {
-      margin: 0;
-      min-height: 100vh;
-      background:
-        radial-gradient(circle at top, #1e293b, transparent 60%),
-        radial-gradient(circle at bottom, #020617, #000);
-      color: var(--fg);
-      display: flex;
-      align-items: center;
-      justify-content: center;
-      padding: 24px;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
     }
-    .shell {
-      max-width: 460px;
-      width: 100%;
+    let file = options
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+        file,
+        flate2::Compression::default(),
+    ));
+
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created);
+    header.set_cksum();
+    archive.append_data(&mut header, EXPORT_MANIFEST, manifest_json.as_slice())?;
+    for (source, name) in &files {
+        archive
+            .append_path_with_name(source, name)
+            .with_context(|| format!("Failed to add {}", source.display()))?;
     }
-    .logo {
-      display: flex;
-      align-items: center;
-      gap: 10px;
-      margin-bottom: 12px;
+    archive
+        .into_inner()
+        .and_then(|gz| gz.finish())
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    println!();
+    println!(
+        "  Exported {} profile{} ({} files) to {}",
+        manifest.profiles.len(),
+        if manifest.profiles.len() == 1 {
+            ""
+        } else {
+            "s"
+        },
+        files.len(),
+        path.display()
+    );
+    if !manifest.tls.is_empty() {
+        println!(
+            "  TLS certificates included for: {}",
+            manifest.tls.join(", ")
+        );
     }
-    .logo-mark {
-      width: 26px;
-      height: 26px;
-      border-radius: 9px;
-      background: radial-gradient(circle at 20% 0%, #a5b4fc, #4f46e5);
-      box-shadow: 0 8px 22px rgba(79,70,229,0.7);
-      display: flex;
-      align-items: center;
-      justify-content: center;
-      font-size: 14px;
-      font-weight: 700;
-      color: #e5e7eb;
+    for note in notes {
+        println!("  Note: {note}");
     }
-    .logo-text {
-      font-weight: 600;
-      letter-spacing: 0.06em;
-      font-size: 12px;
-      text-transform: uppercase;
-      color: var(--muted);
+    println!("  The bundle holds tokens and passwords from the config; keep it private.");
+    println!();
+
+    Ok(())
+}
+
+/// Files under `dir` that go into an export bundle, as (source, name in the
+/// bundle). `top` is set for a profile's config dir, whose runtime
+/// directories are skipped; `skip` lists TLS files, only bundled with `--tls`.
+fn collect_export_files(
+    dir: &Path,
+    name: &Path,
+    skip: &[PathBuf],
+    top: bool,
+    out: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    let mut entries: Vec<fs::DirEntry> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .collect();
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if top && EXPORT_SKIP_DIRS.iter().any(|skipped| file_name == *skipped) {
+                continue;
+            }
+            collect_export_files(&path, &name.join(&file_name), skip, false, out)?;
+        } else if file_type.is_file()
+            && !file_name.to_string_lossy().ends_with(".tmp")
+            && (!top || !is_state_file(&file_name.to_string_lossy()))
+            && !fs::canonicalize(&path).is_ok_and(|path| skip.contains(&path))
+        {
+            out.push((path, name.join(&file_name)));
+        }
     }
-    .card {
-      background: var(--card);
-      border-radius: 20px;
-      padding: 22px 22px 20px;
-      border: 1px solid var(--border-subtle);
-      box-shadow:
-        0 22px 50px rgba(15,23,42,0.95),
-        0 0 0 1px rgba(15,23,42,0.8);
-      backdrop-filter: blur(20px);
+    Ok(())
+}
+
+/// Whether an export bundle may contain `name`: a relative path without
+/// `..` that stays out of the directories export leaves behind.
+fn is_bundle_path(name: &Path) -> bool {
+    let parts: Option<Vec<&str>> = name
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect();
+    let Some(parts) = parts else {
+        return false;
+    };
+    let rest = match parts.as_slice() {
+        ["profiles", profile, rest @ ..] if valid_namespace(profile) => rest,
+        rest => rest,
+    };
+    match rest {
+        [file] => !EXPORT_SKIP_DIRS.contains(file) && !is_state_file(file),
+        [first, ..] => !EXPORT_SKIP_DIRS.contains(first),
+        [] => false,
     }
-    h1 {
-      margin: 0 0 6px;
-      font-size: 22px;
-      font-weight: 600;
+}
+
+/// `tbl import-config`: unpack a bundle into the base config dir, pointing
+/// bundled TLS certificates at their new location.
+fn handle_import_config_command(path: &Path, force: bool) -> Result<()> {
+    let base = base_config_dir()?;
+    let jail = Jail::new(&base);
+    let open = || -> Result<tar::Archive<flate2::read::GzDecoder<fs::File>>> {
+        let file =
+            fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(tar::Archive::new(flate2::read::GzDecoder::new(file)))
+    };
+
+    // Check the whole bundle before writing anything
+    let mut manifest = None;
+    let mut names = Vec::new();
+    for entry in open()?.entries()? {
+        let mut entry =
+            entry.with_context(|| format!("{} is not a valid bundle", path.display()))?;
+        let name = entry.path()?.into_owned();
+        if name == Path::new(EXPORT_MANIFEST) {
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            manifest = Some(
+                serde_json::from_str::<ExportManifest>(&json)
+                    .context("Invalid tbl-export.json in bundle")?,
+            );
+        } else if entry.header().entry_type().is_file()
+            && is_bundle_path(&name)
+            && jail.path(&name).is_some()
+        {
+            names.push(name);
+        } else {
+            anyhow::bail!("Refusing to import {}: unexpected entry", name.display());
+        }
     }
-    p {
-      margin: 0 0 14px;
-      font-size: 13px;
-      color: var(--muted);
+    let manifest = manifest
+        .with_context(|| format!("{} is not a tbl export-config bundle", path.display()))?;
+    if let Some(profile) = manifest
+        .tls
+        .iter()
+        .find(|profile| *profile != DEFAULT_PROFILE && !valid_namespace(profile))
+    {
+        anyhow::bail!("Invalid profile name {profile:?} in bundle");
     }
-    .field-label {
-      font-size: 12px;
-      margin-bottom: 6px;
-      color: #e5e7eb;
+
+    let existing: Vec<&PathBuf> = names
+        .iter()
+        .filter(|name| jail.path(name).is_some_and(|path| path.exists()))
+        .collect();
+    if !existing.is_empty() && !force {
+        anyhow::bail!(
+            "{} file{} already exist in {} (e.g. {}); pass --force to overwrite",
+            existing.len(),
+            if existing.len() == 1 { "" } else { "s" },
+            base.display(),
+            existing[0].display()
+        );
     }
-    input[type="text"] {
-      width: 100%;
-      padding: 10px 11px;
-      border-radius: 11px;
-      border: 1px solid rgba(148,163,184,0.7);
-      background: var(--input-bg);
-      color: var(--fg);
-      font-size: 13px;
-      outline: none;
-      transition: border-color 0.15s ease, box-shadow 0.15s ease,
-                  background 0.15s ease;
+
+    fs::create_dir_all(&base).with_context(|| format!("Failed to create config dir {:?}", base))?;
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        if entry.path()? != Path::new(EXPORT_MANIFEST) {
+            entry.unpack_in(&base)?;
+        }
     }
-    input[type="text"]::placeholder {
-      color: rgba(148,163,184,0.9);
+
+    let mut moved_tls = Vec::new();
+    for profile in &manifest.tls {
+        let dir = base.join(profile_subdir(profile));
+        let Some(mut cfg) = load_config(&dir) else {
+            continue;
+        };
+        cfg.tls_cert = Some(dir.join("tls").join("cert.pem").display().to_string());
+        cfg.tls_key = Some(dir.join("tls").join("key.pem").display().to_string());
+        save_config(&dir, &cfg)?;
+        moved_tls.push(dir.join("tls"));
     }
-    input[type="text"]:focus {
-      border-color: var(--accent);
-      box-shadow: 0 0 0 1px rgba(99,102,241,0.7);
-      background: rgba(15,23,42,1);
+
+    println!();
+    println!(
+        "  Imported {} profile{} ({} files) into {}",
+        manifest.profiles.len(),
+        if manifest.profiles.len() == 1 {
+            ""
+        } else {
+            "s"
+        },
+        names.len(),
+        base.display()
+    );
+    for dir in moved_tls {
+        println!("  TLS certificate and key installed in {}", dir.display());
     }
-    .hint {
-      font-size: 11px;
-      margin-top: 6px;
-      color: rgba(148,163,184,0.95);
+    println!("  Run `tbl sync --all` to clone the apps.");
+    if list_profiles()?
+        .iter()
+        .any(|(_, dir)| load_run_info(&run_dir(dir)).is_some_and(|info| port_is_open(info.port)))
+    {
+        println!("  Restart running servers to use the imported config.");
     }
-    button {
-      margin-top: 16px;
-      width: 100%;
-      border-radius: 999px;
-      border: none;
-      padding: 9px 14px;
-      font-size: 13px;
-      font-weight: 500;
-      background: linear-gradient(135deg, #4f46e5, #6366f1);
-      color: white;
-      cursor: pointer;
-      display: inline-flex;
-      align-items: center;
-      justify-content: center;
-      gap: 8px;
-      box-shadow: 0 14px 32px rgba(79,70,229,0.6);
-      transition: transform 0.07s ease, box-shadow 0.07s ease,
-                  filter 0.07s ease;
+    println!();
+
+    Ok(())
+}
+
+// =============================================================================
+// Backup Command
+// =============================================================================
+
+fn handle_backup_command(command: &BackupCommands) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let run_dir = run_dir(&config_dir);
+    match command {
+        BackupCommands::Create { path, web } => {
+            let path = path
+                .clone()
+                .unwrap_or_else(|| backup::default_path(&config_dir, "tbl-backup"));
+            let created = backup::create(&config_dir, &run_dir, profile_label(), &path, *web)?;
+            let size = fs::metadata(&path).map_or(0, |meta| meta.len());
+            println!();
+            println!(
+                "  Backed up profile {} ({} files, {}) to {}",
+                profile_label(),
+                created.files,
+                format_bytes(size),
+                path.display()
+            );
+            if !created.skipped_links.is_empty() {
+                println!("  Left out symlinks, which only the checkout may hold in a backup:");
+                for link in &created.skipped_links {
+                    println!("    {}", link.display());
+                }
+            }
+            println!("  The backup holds tokens and passwords from the config; keep it private.");
+            println!();
+        }
+        BackupCommands::Restore { path } => {
+            let path = if path.exists() {
+                path.clone()
+            } else {
+                backup::dir(&config_dir).join(path)
+            };
+            if load_run_info(&run_dir).is_some_and(|info| port_is_open(info.port)) {
+                anyhow::bail!("The server is running; stop it first with `tbl --stop`");
+            }
+            // The state being replaced is kept, so a restore can be undone;
+            // that includes the checkout when the backup replaces it
+            let incoming = backup::manifest(&path)?;
+            let previous = backup::default_path(&config_dir, backup::PRE_RESTORE_PREFIX);
+            backup::create(
+                &config_dir,
+                &run_dir,
+                profile_label(),
+                &previous,
+                incoming.web,
+            )?;
+            backup::rotate(
+                &config_dir,
+                backup::PRE_RESTORE_PREFIX,
+                backup::PRE_RESTORE_KEEP,
+            );
+            let manifest = backup::restore(&config_dir, &path)?;
+            println!();
+            println!(
+                "  Restored profile {} from a backup taken {}",
+                profile_label(),
+                timefmt::age(manifest.created)
+            );
+            if manifest.profile != profile_label() {
+                println!("  The backup was taken of profile {}.", manifest.profile);
+            }
+            if !manifest.web {
+                println!("  The backup has no checkout; the current one stays.");
+            }
+            println!("  The previous state is in {}", previous.display());
+            println!();
+        }
+        BackupCommands::List => {
+            let backups = backup::list(&config_dir);
+            println!();
+            if backups.is_empty() {
+                println!("  No backups in {}", backup::dir(&config_dir).display());
+            }
+            for backup in &backups {
+                println!(
+                    "  {:<40} {:>10}  {}",
+                    backup
+                        .path
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy(),
+                    format_bytes(backup.size),
+                    timefmt::age(backup.modified)
+                );
+            }
+            println!();
+        }
+    }
+    Ok(())
+}
+
+// =============================================================================
+// KV Commands
+// =============================================================================
+
+/// `tbl kv`: through the running server, so its loaded namespaces and
+/// subscribers see the change, or on the state database when it is stopped.
+fn handle_kv_command(command: &KvCommands) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let run_dir = run_dir(&config_dir);
+    let server = load_run_info(&run_dir).filter(|info| port_is_open(info.port));
+    let local = || -> Result<KvStore> {
+        let quotas = load_config(&config_dir)
+            .and_then(|cfg| cfg.quota)
+            .unwrap_or_default()
+            .quotas()?;
+        Ok(KvStore::new(
+            StateDb::open(&config_dir, &run_dir)?,
+            None,
+            quotas,
+        ))
+    };
+    let check = |namespace: &str| -> Result<()> {
+        if !valid_kv_namespace(namespace) {
+            anyhow::bail!("Invalid namespace {namespace:?}");
+        }
+        Ok(())
+    };
+
+    match command {
+        KvCommands::Export { namespaces, output } => {
+            for namespace in namespaces {
+                check(namespace)?;
+            }
+            let data = match &server {
+                Some(info) => {
+                    let mut path = "/api/v1/kv/export".to_string();
+                    if !namespaces.is_empty() {
+                        path.push_str(&format!("?namespace={}", namespaces.join(",")));
+                    }
+                    serde_json::from_str(&send_api_request(info, "GET", &path, None)?)?
+                }
+                None => local()?.export(namespaces)?,
+            };
+            let json = serde_json::to_string_pretty(&data)? + "\n";
+            match output {
+                Some(output) => {
+                    fs::write(output, json)
+                        .with_context(|| format!("Failed to write {}", output.display()))?;
+                    let keys: usize = data.namespaces.values().map(BTreeMap::len).sum();
+                    eprintln!(
+                        "Exported {} namespaces ({keys} keys) to {}",
+                        data.namespaces.len(),
+                        output.display()
+                    );
+                }
+                None => print!("{json}"),
+            }
+        }
+        KvCommands::Import { path, replace } => {
+            let json = if path == Path::new("-") {
+                io::read_to_string(io::stdin())?
+            } else {
+                fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?
+            };
+            let data: KvData = serde_json::from_str(&json)
+                .with_context(|| format!("{} is not a KV export", path.display()))?;
+            for namespace in data.namespaces.keys() {
+                check(namespace)?;
+            }
+            let keys = match &server {
+                Some(info) => {
+                    let path = format!("/api/v1/kv/import?replace={replace}");
+                    let response = send_api_request(info, "POST", &path, Some(&json))?;
+                    let response: serde_json::Value = serde_json::from_str(&response)?;
+                    response["keys"].as_u64().unwrap_or_default() as usize
+                }
+                None => local()?.import(&data, *replace)?,
+            };
+            println!();
+            println!(
+                "  Imported {keys} keys into {}",
+                data.namespaces
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            if *replace {
+                println!("  Keys of these namespaces missing from the file were removed.");
+            }
+            println!();
+        }
+        KvCommands::Reset { namespace } => {
+            check(namespace)?;
+            match &server {
+                Some(info) => {
+                    let path = format!("/api/v1/kv/{namespace}");
+                    send_api_request(info, "DELETE", &path, None)?;
+                }
+                None => {
+                    local()?.clear(namespace)?;
+                }
+            }
+            println!();
+            println!("  Removed every key of namespace {namespace}.");
+            println!();
+        }
+    }
+    Ok(())
+}
+
+fn handle_token_command(command: &TokenCommands) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let run_dir = run_dir(&config_dir);
+    let server = load_run_info(&run_dir).filter(|info| port_is_open(info.port));
+    let local = || token::Tokens::load(StateDb::open(&config_dir, &run_dir)?);
+
+    match command {
+        TokenCommands::Rotate { grace: grace_arg } => {
+            let grace = parse_duration(grace_arg).context("Invalid --grace")?;
+            let key = match &server {
+                Some(info) => {
+                    let body = serde_json::json!({ "grace": format!("{}s", grace.as_secs()) });
+                    let response = send_api_request(
+                        info,
+                        "POST",
+                        "/api/v1/tokens/rotate",
+                        Some(&body.to_string()),
+                    )?;
+                    let response: serde_json::Value = serde_json::from_str(&response)?;
+                    response["key"].as_u64().unwrap_or_default() as u32
+                }
+                None => local()?.rotate(grace)?,
+            };
+            println!();
+            println!("  Tokens are signed with key {key} from now on.");
+            if grace.is_zero() {
+                println!("  Every browser has to sign in again with the login link.");
+            } else {
+                println!(
+                    "  Browsers signed in before keep working for {grace_arg}; sign them in again by then."
+                );
+            }
+            println!();
+        }
+        TokenCommands::Revoke { session } => {
+            match &server {
+                Some(info) => {
+                    send_api_request(info, "DELETE", &format!("/api/v1/sessions/{session}"), None)?;
+                }
+                None => {
+                    let db = StateDb::open(&config_dir, &run_dir)?;
+                    if !db.delete_session(session)? {
+                        anyhow::bail!("No session {session}");
+                    }
+                    token::Tokens::load(db)?.revoke(session)?;
+                }
+            }
+            println!();
+            println!("  Session {session} is signed out.");
+            println!();
+        }
     }
-    button:hover {
-      transform: translateY(-1px);
-      box-shadow: 0 18px 40px rgba(79,70,229,0.7);
-      filter: brightness(1.03);
+    Ok(())
+}
+
+// =============================================================================
+// Open & Shortcut Commands
+// =============================================================================
+
+/// `tbl open`: open the current profile's app, starting its server first if
+/// it isn't running.
+fn handle_open_command(app_mode: bool) -> Result<()> {
+    let info = start_server(&get_config_dir()?)?;
+    open_browser(&login_url(&info), app_mode)
+}
+
+/// The profile's running server, started with its config if there is none.
+fn start_server(config_dir: &Path) -> Result<RunInfo> {
+    let run_dir = run_dir(config_dir);
+    let running = || load_run_info(&run_dir).filter(|info| port_is_open(info.port));
+    if let Some(info) = running() {
+        return Ok(info);
     }
-    button:active {
-      transform: translateY(0);
-      box-shadow: 0 10px 22px rgba(79,70,229,0.65);
+
+    let exe = std::env::current_exe().context("cannot get current exe")?;
+    let mut cmd = Command::new(exe);
+    if let Some(profile) = PROFILE.get().and_then(Option::as_deref) {
+        cmd.arg("--profile").arg(profile);
     }
-    .btn-icon {
-      font-size: 15px;
+    let status = cmd
+        .arg("--no-browser")
+        .status()
+        .context("failed to start tbl")?;
+    if !status.success() {
+        anyhow::bail!("tbl failed to start ({status})");
     }
-    .meta {
-      margin-top: 10px;
-      font-size: 11px;
-      color: var(--muted);
-      display: flex;
-      justify-content: space-between;
-      gap: 12px;
-      flex-wrap: wrap;
+    running().context("tbl is still starting; try again in a moment")
+}
+
+fn login_url(info: &RunInfo) -> String {
+    info.local_url(&format!("/bootstrap?token={}", info.auth_token.expose()))
+}
+
+/// Open `url` in the default browser or, with `app_mode`, in an app window
+/// of a Chromium-based browser, falling back to the default browser when
+/// none is installed.
+fn open_browser(url: &str, app_mode: bool) -> Result<()> {
+    if app_mode {
+        match app_window_command(url) {
+            Some(mut cmd) => {
+                cmd.stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null());
+                detach_process(&mut cmd);
+                cmd.spawn().context("Failed to open an app window")?;
+                return Ok(());
+            }
+            None => eprintln!("  No Chromium-based browser found; opening the default browser."),
+        }
     }
-    .pill {
-      padding: 3px 8px;
-      border-radius: 999px;
-      font-size: 10px;
-      border: 1px dashed rgba(148,163,184,0.5);
-      background: rgba(15,23,42,0.8);
+    webbrowser::open(url).context("Failed to open browser")
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn app_window_command(url: &str) -> Option<Command> {
+    const BROWSERS: &[&str] = &[
+        "google-chrome",
+        "google-chrome-stable",
+        "chromium",
+        "chromium-browser",
+        "microsoft-edge",
+        "microsoft-edge-stable",
+        "brave-browser",
+    ];
+    let paths = std::env::var_os("PATH")?;
+    let program = BROWSERS.iter().find_map(|name| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    })?;
+    let mut cmd = Command::new(program);
+    cmd.arg(format!("--app={url}"));
+    Some(cmd)
+}
+
+#[cfg(target_os = "macos")]
+fn app_window_command(url: &str) -> Option<Command> {
+    const BROWSERS: &[&str] = &[
+        "Google Chrome",
+        "Chromium",
+        "Microsoft Edge",
+        "Brave Browser",
+    ];
+    let app = BROWSERS.iter().find(|app| {
+        Path::new("/Applications")
+            .join(format!("{app}.app"))
+            .exists()
+    })?;
+    let mut cmd = Command::new("open");
+    cmd.args(["-na", app, "--args"]).arg(format!("--app={url}"));
+    Some(cmd)
+}
+
+/// Edge ships with Windows, so it is always there.
+#[cfg(windows)]
+fn app_window_command(url: &str) -> Option<Command> {
+    let mut cmd = Command::new("cmd");
+    cmd.args(["/C", "start", "", "msedge"])
+        .arg(format!("--app={url}"));
+    Some(cmd)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn app_window_command(_url: &str) -> Option<Command> {
+    None
+}
+
+/// `tbl shortcut create`: add a launcher named after the app (its title,
+/// else the repository name) that opens it with `tbl open --app-mode`.
+fn handle_shortcut_create_command(name: Option<&str>) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let cfg = load_config(&config_dir).unwrap_or_default();
+    let meta = load_app_meta(&config_dir).unwrap_or_default();
+    let profile = PROFILE.get().and_then(Option::as_deref);
+
+    let mut args = Vec::new();
+    if let Some(profile) = profile {
+        args.extend(["--profile".to_string(), profile.to_string()]);
     }
-  </style>
-</head>
-<body>
-  <div class="shell">
-    <div class="logo">
-      <div class="logo-mark">t</div>
-      <div class="logo-text">tbl bootstrap</div>
-    </div>
-    <div class="card">
-      <h1>Connect your workspace</h1>
-      <p>Point <strong>tbl</strong> at a Git repo that contains your web UI. We'll shallow-clone it into your local config and serve it securely.</p>
-      <form method="post" action="/setup">
-        <label class="field-label" for="git_url">Git repository URL</label>
-        <input
-          id="git_url"
-          type="text"
-          name="git_url"
-          placeholder="https://github.com/you/your-tbl-web.git"
-          required
-        />
-        <div class="hint">
-          We clone with <code>--depth 1</code> into <code>~/.config/tbl/web/</code>.
-        </div>
-        <button type="submit">
-          <span class="btn-icon">⏎</span>
-          <span>Clone &amp; launch</span>
-        </button>
-      </form>
-      <div class="meta">
-        <div>CLI &amp; ENV override: <code>--git-url</code>, <code>TBL_GIT_URL</code></div>
-        <div class="pill">Single static binary • local only</div>
-      </div>
-    </div>
-  </div>
-</body>
-</html>"#
-        .to_string()
+    args.extend(["open".to_string(), "--app-mode".to_string()]);
+    let shortcut = shortcut::Shortcut {
+        id: profile.map_or_else(|| "tbl".to_string(), |profile| format!("tbl-{profile}")),
+        name: name
+            .map(str::to_string)
+            .or(meta.title)
+            .or_else(|| cfg.git_url.as_deref().and_then(repo_name))
+            .unwrap_or_else(|| "tbl".to_string()),
+        description: cfg.description.or(meta.description),
+        program: std::env::current_exe().context("cannot get current exe")?,
+        icon: meta.icon_file.map(|file| config_dir.join("web").join(file)),
+        args,
+    };
+    let path = shortcut.create()?;
+
+    println!();
+    println!(
+        "  Shortcut \"{}\" created: {}",
+        shortcut.name,
+        path.display()
+    );
+    println!("  It runs: tbl {}", shortcut.args.join(" "));
+    println!();
+    Ok(())
 }
 
 // =============================================================================
-// Verbose Output Helpers
+// Tray Icon
 // =============================================================================
 
-fn print_banner() {
-    let version = env!("CARGO_PKG_VERSION");
-    println!();
-    println!("  ╭─────────────────────────────────────────╮");
-    println!("  │                                         │");
-    println!("  │   tbl v{:<32} │", version);
-    println!("  │   Tiny self-bootstrapping web launcher  │");
-    println!("  │                                         │");
-    println!("  ╰─────────────────────────────────────────╯");
+/// `tbl --tray`: start the server if needed and show a tray icon for it
+/// until Quit is chosen from its menu.
+#[cfg(all(feature = "tray", target_os = "linux"))]
+fn handle_tray() -> Result<()> {
+    let config_dir = get_config_dir()?;
+    start_server(&config_dir)?;
+    let title = load_app_meta(&config_dir)
+        .and_then(|meta| meta.title)
+        .or_else(|| {
+            load_config(&config_dir)?
+                .git_url
+                .as_deref()
+                .and_then(repo_name)
+        })
+        .unwrap_or_else(|| "tbl".to_string());
+    tray::run(title, move |action| tray_action(&config_dir, action))
 }
 
-fn print_url_box(url: &str) {
-    let padding = 4;
-    let url_len = url.len();
-    let box_width = url_len + padding * 2;
+#[cfg(not(all(feature = "tray", target_os = "linux")))]
+fn handle_tray() -> Result<()> {
+    anyhow::bail!(
+        "This tbl was built without tray support; rebuild it with `cargo build --features tray` (Linux only)"
+    )
+}
 
-    let top_bottom = "─".repeat(box_width);
-    let spaces = " ".repeat(padding);
+/// Run a tray menu entry against the profile's server through its API;
+/// returns the text to show as a notification.
+#[cfg(all(feature = "tray", target_os = "linux"))]
+fn tray_action(config_dir: &Path, action: tray::Action) -> Result<Option<String>> {
+    let running = load_run_info(&run_dir(config_dir)).filter(|info| port_is_open(info.port));
+    let not_running = || anyhow::anyhow!("tbl is not running");
+
+    match action {
+        tray::Action::Open => {
+            let info = match running {
+                Some(info) => info,
+                None => start_server(config_dir)?,
+            };
+            open_browser(&login_url(&info), false)?;
+            Ok(None)
+        }
+        tray::Action::Sync => {
+            let info = running.ok_or_else(not_running)?;
+            let body = send_api_request(&info, "POST", "/api/v1/update", None)?;
+            let commit = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|v| v["commit"].as_str().map(short_commit));
+            Ok(Some(match commit {
+                Some(commit) => format!("Synced, now at {commit}"),
+                None => "Synced".to_string(),
+            }))
+        }
+        tray::Action::Status => {
+            let Some(info) = running else {
+                return Ok(Some(format!("Not running (profile {})", profile_label())));
+            };
+            let body = send_api_request(&info, "GET", "/api/v1/info", None)?;
+            let details: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+            let mut lines = vec![format!(
+                "Running on port {} (profile {})",
+                info.port,
+                profile_label()
+            )];
+            if let Some(commit) = details["commit"].as_str() {
+                lines.push(format!("Commit {}", short_commit(commit)));
+            }
+            if let Some(started_at) = details["started_at"].as_u64() {
+                lines.push(format!("Started {}", timefmt::age(started_at)));
+            }
+            Ok(Some(lines.join("\n")))
+        }
+        tray::Action::Quit => {
+            if let Some(info) = running {
+                send_api_request(&info, "POST", "/api/v1/shutdown", None)?;
+            }
+            Ok(None)
+        }
+    }
+}
 
-    println!("  ╭{}╮", top_bottom);
-    println!("  │{}{}{}│", spaces, url, spaces);
-    println!("  ╰{}╯", top_bottom);
+#[cfg(all(feature = "tray", target_os = "linux"))]
+fn short_commit(commit: &str) -> String {
+    commit.chars().take(7).collect()
 }
 
 // =============================================================================
-// Stop Command
+// Status & Doctor Commands
 // =============================================================================
 
-fn handle_stop_command() -> Result<()> {
-    let config_dir = get_config_dir()?;
-    let run_dir = config_dir.join("run");
+fn handle_status_command(selector: InstanceSelector, reveal: bool, verbose: bool) -> Result<()> {
+    match selector {
+        InstanceSelector::Current => {
+            print_status(profile_label(), &get_config_dir()?, reveal, verbose)
+        }
+        InstanceSelector::Port(port) => match select_instances(&selector)?.first() {
+            Some(instance) => {
+                print_status(&instance.profile, &instance.config_dir, reveal, verbose)
+            }
+            None => {
+                println!();
+                println!("  No tbl server found on port {port}.");
+                println!();
+            }
+        },
+        InstanceSelector::All => print_all_status()?,
+    }
+    Ok(())
+}
 
-    let Some(info) = load_run_info(&run_dir) else {
-        println!();
-        println!("  No tbl server is currently running.");
-        println!();
-        return Ok(());
-    };
+fn print_status(profile: &str, config_dir: &Path, reveal: bool, verbose: bool) {
+    let run_dir = run_dir(config_dir);
 
-    if !port_is_open(info.port) {
-        // Stale pid.yaml
-        clear_run_info(&run_dir);
-        println!();
-        println!("  No tbl server is currently running (stale pid file cleaned up).");
-        println!();
-        return Ok(());
+    println!();
+    let info = load_run_info(&run_dir);
+    let running = info.as_ref().is_some_and(|info| port_is_open(info.port));
+    match info {
+        Some(info) if running => {
+            println!("  tbl is running");
+            println!("  ───────────────────────────────────────");
+            println!("  Profile:  {}", profile);
+            println!("  PID:      {}", info.pid);
+            println!("  Port:     {}", info.port);
+            println!(
+                "  TLS:      {}",
+                if info.tls { "enabled" } else { "disabled" }
+            );
+            println!("  URL:      {}", info.local_url("/"));
+            if reveal {
+                println!("  Login:    {}", login_url(&info));
+            }
+            if let Some(next) = load_config(config_dir)
+                .and_then(|cfg| cfg.sync_schedule)
+                .and_then(|spec| CronSchedule::parse(&spec).ok())
+                .and_then(|schedule| schedule.next_after(Local::now()))
+            {
+                println!(
+                    "  Next sync: {}",
+                    timefmt::human(&next, timefmt::Style::Full)
+                );
+            }
+            if let Some(at) = send_api_request(&info, "GET", "/api/v1/info", None)
+                .ok()
+                .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok())
+                .and_then(|details| details["shutdown"]["at"].as_u64())
+            {
+                println!("  Stops:    {}", describe_stop_time(at));
+            }
+            if let Some(supervisor) = info.supervisor_pid {
+                println!(
+                    "  Watchdog: PID {} ({} restart{})",
+                    supervisor,
+                    info.restarts,
+                    if info.restarts == 1 { "" } else { "s" }
+                );
+            }
+        }
+        Some(info) => {
+            println!("  tbl is not running");
+            println!("  ───────────────────────────────────────");
+            println!("  Profile:  {}", profile);
+            println!(
+                "  Stale pid file for PID {} (port {}); the server exited without cleaning up.",
+                info.pid, info.port
+            );
+        }
+        None => {
+            println!("  tbl is not running");
+            println!("  ───────────────────────────────────────");
+            println!("  Profile:  {}", profile);
+        }
     }
 
+    if let Some(paused) = pause::current(config_dir) {
+        println!("  Syncing:  {}", describe_pause(&paused));
+    } else if let Some(deferred) = metered::deferred(config_dir) {
+        println!(
+            "  Syncing:  deferred {}, until the connection is unmetered",
+            timefmt::age(deferred.since)
+        );
+    }
+    print_uptime(config_dir, running, verbose);
+    println!("  Log:      {}", daemon_log_path(config_dir).display());
+    print_last_crash(config_dir);
+    if verbose {
+        print_last_sync(config_dir);
+    }
     println!();
-    println!("  Stopping tbl server...");
-    println!("  ───────────────────────────────────────");
-    println!("  PID:  {}", info.pid);
-    println!("  Port: {}", info.port);
-    println!();
+}
 
-    // Send authenticated shutdown request
-    match send_shutdown_request(info.port, &info.auth_token, info.tls) {
-        Ok(_) => {
-            // Wait for server to stop (up to 5 seconds)
-            for _ in 0..50 {
-                std::thread::sleep(Duration::from_millis(100));
-                if !port_is_open(info.port) {
-                    println!("  Server stopped successfully.");
-                    println!();
-                    return Ok(());
-                }
-            }
-            println!("  Server may still be shutting down.");
-            println!();
-        }
+/// Runs listed by `tbl status -v`.
+const STATUS_RUNS: usize = 5;
+
+fn print_uptime(config_dir: &Path, running: bool, verbose: bool) {
+    if !config_dir.join(state::STATE_DB).exists() {
+        return;
+    }
+    let now = now_unix();
+    let recorded = StateDb::open(config_dir, &run_dir(config_dir)).and_then(|db| {
+        let runs = uptime::runs(&db, running, now, STATUS_RUNS)?;
+        Ok((uptime::stats(&db, running, now)?, runs))
+    });
+    let (stats, runs) = match recorded {
+        Ok((_, runs)) if runs.is_empty() => return,
+        Ok(recorded) => recorded,
         Err(e) => {
-            eprintln!("  Failed to send shutdown request: {e}");
-            eprintln!("  You may need to kill the process manually (PID: {}).", info.pid);
-            println!();
+            println!("  Uptime:   unknown ({e:#})");
+            return;
+        }
+    };
+    let current = runs
+        .first()
+        .filter(|run| run.ended_at.is_none())
+        .map(|run| format!("up {}; ", timefmt::duration(run.uptime_secs)))
+        .unwrap_or_default();
+    println!(
+        "  Uptime:   {current}{} in all since {}, {:.1}%",
+        timefmt::duration(stats.total_uptime_secs),
+        timefmt::human_unix(stats.first_started_at, timefmt::Style::Date),
+        stats.availability * 100.0
+    );
+    println!(
+        "  Restarts: {} ({} unclean)",
+        stats.restarts, stats.unclean_stops
+    );
+    if !verbose {
+        return;
+    }
+    for run in &runs {
+        let end = match (&run.ended_at, &run.stop_reason) {
+            (None, _) => "running".to_string(),
+            (Some(_), Some(reason)) => format!("stopped ({reason})"),
+            (Some(_), None) => "crashed or killed".to_string(),
+        };
+        println!(
+            "    {}  {:>8}  {end}",
+            timefmt::human_unix(run.started_at, timefmt::Style::Full),
+            timefmt::duration(run.uptime_secs)
+        );
+    }
+}
+
+/// Files listed by `tbl status -v`; the API has the rest.
+const SYNC_REPORT_LINES: usize = 50;
+
+fn print_last_sync(config_dir: &Path) {
+    let Some(report) = manifest::last(config_dir) else {
+        println!("  Last sync:  none");
+        return;
+    };
+    let short = |version: &Option<String>| {
+        version
+            .as_deref()
+            .map(|v| v.chars().take(7).collect::<String>())
+            .unwrap_or_else(|| "-".to_string())
+    };
+    println!(
+        "  Last sync:  {}, {} → {} ({} files, {})",
+        timefmt::age(report.finished_at),
+        short(&report.previous_version),
+        short(&report.version),
+        report.files,
+        format_bytes(report.size)
+    );
+    if let Some(mirror) = &report.mirror {
+        println!("    From the mirror {mirror}");
+    }
+    if report.is_empty() {
+        println!("    No files changed");
+        return;
+    }
+    println!(
+        "    {} added, {} removed, {} changed",
+        report.added.len(),
+        report.removed.len(),
+        report.changed.len()
+    );
+
+    let size = |size: Option<u64>| size.map(format_bytes).unwrap_or_default();
+    let lines = report
+        .added
+        .iter()
+        .map(|file| ('+', file, size(file.size)))
+        .chain(
+            report
+                .removed
+                .iter()
+                .map(|file| ('-', file, size(file.previous_size))),
+        )
+        .chain(report.changed.iter().map(|file| {
+            let sizes = format!("{} → {}", size(file.previous_size), size(file.size));
+            ('~', file, sizes)
+        }));
+    let total = report.added.len() + report.removed.len() + report.changed.len();
+    for (mark, file, sizes) in lines.take(SYNC_REPORT_LINES) {
+        println!("    {mark} {:<44} {sizes:>20}", file.path);
+    }
+    if total > SYNC_REPORT_LINES {
+        println!(
+            "    … and {} more (GET /api/v1/sync/last lists all)",
+            total - SYNC_REPORT_LINES
+        );
+    }
+}
+
+fn print_all_status() -> Result<()> {
+    println!();
+    println!(
+        "  {:<16} {:<9} {:>7} {:>6}  URL",
+        "PROFILE", "STATE", "PID", "PORT"
+    );
+    println!("  ───────────────────────────────────────────────────────────────");
+    for (profile, config_dir) in list_profiles()? {
+        match load_run_info(&run_dir(&config_dir)) {
+            Some(info) if port_is_open(info.port) => {
+                println!(
+                    "  {:<16} {:<9} {:>7} {:>6}  {}",
+                    profile,
+                    "running",
+                    info.pid,
+                    info.port,
+                    info.local_url("/")
+                );
+            }
+            Some(info) => println!(
+                "  {:<16} {:<9} {:>7} {:>6}",
+                profile, "stale", info.pid, info.port
+            ),
+            None => println!("  {:<16} {:<9} {:>7} {:>6}", profile, "stopped", "-", "-"),
         }
     }
+    println!();
+    Ok(())
+}
+
+fn print_last_crash(config_dir: &Path) {
+    let reports = crash_reports(config_dir);
+    let Some(last) = reports.first() else {
+        println!("  Last crash: none");
+        return;
+    };
+    println!(
+        "  Last crash: {} ({} report{} total)",
+        timefmt::age(crash_report_time(last)),
+        reports.len(),
+        if reports.len() == 1 { "" } else { "s" }
+    );
+    if let Some(summary) = crash_report_summary(last) {
+        println!("    {summary}");
+    }
+    println!("    {}", last.display());
+}
 
+fn handle_setcap_command(remove: bool) -> Result<()> {
+    let exe = std::env::current_exe().context("cannot get current exe")?;
+    // setcap wants the file itself, not a symlink to it
+    let exe = fs::canonicalize(&exe).unwrap_or(exe);
+    privileges::set_bind_capability(&exe, !remove)?;
+    println!();
+    if remove {
+        println!("  {} can no longer bind ports below 1024.", exe.display());
+    } else {
+        println!("  {} can now bind ports below 1024,", exe.display());
+        println!("  e.g. with `addr: 0.0.0.0:443`, without running as root.");
+        println!("  Replacing the binary drops this; run `sudo tbl setcap` again then.");
+    }
+    println!();
     Ok(())
 }
 
-fn send_shutdown_request(port: u16, token: &str, _tls: bool) -> Result<()> {
-    // For simplicity, we use plain HTTP even for TLS servers on localhost
-    // The auth token provides security
-    let addr = format!("127.0.0.1:{}", port);
-    let mut stream = TcpStream::connect_timeout(
-        &addr.parse::<SocketAddr>()?,
-        Duration::from_secs(5),
-    )?;
+fn handle_doctor_command() -> Result<()> {
+    let config_dir = get_config_dir()?;
+    let mut problems = 0;
+    let mut check = |ok: bool, label: &str, detail: String| {
+        let mark = if ok { "✓" } else { "✗" };
+        println!("  {mark} {label:<12} {detail}");
+        if !ok {
+            problems += 1;
+        }
+    };
 
-    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
-    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    println!();
+    println!("  tbl doctor");
+    println!("  ───────────────────────────────────────");
 
-    let request = format!(
-        "POST /api/v1/shutdown HTTP/1.1\r\n\
-         Host: 127.0.0.1:{}\r\n\
-         Cookie: tbl_token={}\r\n\
-         Content-Length: 0\r\n\
-         Connection: close\r\n\
-         \r\n",
-        port, token
+    let dir_ok = fs::create_dir_all(&config_dir).is_ok();
+    check(dir_ok, "Config dir", config_dir.display().to_string());
+
+    match config_file_path(&config_dir) {
+        Some(path) => check(
+            load_config(&config_dir).is_some(),
+            "Config file",
+            path.display().to_string(),
+        ),
+        None => check(true, "Config file", "none (using defaults)".to_string()),
+    }
+
+    let has_git = git_available();
+    check(
+        has_git,
+        "Git",
+        if has_git {
+            "found on PATH".to_string()
+        } else {
+            "not found; run `tbl` for install hints".to_string()
+        },
     );
 
-    stream.write_all(request.as_bytes())?;
+    let cfg = load_config(&config_dir).unwrap_or_default();
+    if let Err(e) = validate_config_paths(&config_dir, &cfg) {
+        check(false, "Paths", format!("{e:#}"));
+    }
+    let port = cfg
+        .addr
+        .as_deref()
+        .and_then(|addr| split_host_port(addr).ok())
+        .and_then(|(_, port)| match port {
+            PortSpec::Number(port) if port != 0 => Some(port),
+            _ => None,
+        });
+    if let Some(port) = port.filter(|port| *port < 1024) {
+        // A system install started as root binds before switching to `user`
+        let ok = privileges::can_bind(port) || (is_system() && cfg.user.is_some());
+        check(
+            ok,
+            "Port",
+            if ok {
+                format!("{port} can be bound")
+            } else {
+                format!("{port}: {}", privileges::bind_hint(port))
+            },
+        );
+    }
+    let web_dir = config_dir.join("web");
+    let rolled_back = releases::restored_version(&config_dir).is_some();
+    match &cfg.git_url {
+        Some(url) => check(
+            web_dir.join(".git").exists() || checkout_matches(&web_dir, url) || rolled_back,
+            "Workspace",
+            match current_commit(&web_dir) {
+                Some(commit) if rolled_back => format!(
+                    "{} @ {commit} (rolled back until the next sync)",
+                    redact_credentials(url)
+                ),
+                Some(commit) => format!("{} @ {commit}", redact_credentials(url)),
+                None => format!("{} (not cloned yet)", redact_credentials(url)),
+            },
+        ),
+        None => check(true, "Workspace", "no git URL configured".to_string()),
+    }
 
-    let mut response = String::new();
-    stream.read_to_string(&mut response)?;
+    match load_run_info(&run_dir(&config_dir)) {
+        Some(info) if port_is_open(info.port) => check(
+            true,
+            "Server",
+            format!("running (PID {}, port {})", info.pid, info.port),
+        ),
+        Some(info) => check(
+            false,
+            "Server",
+            format!(
+                "stale pid file for PID {}; run `tbl --stop` to clean up",
+                info.pid
+            ),
+        ),
+        None => check(true, "Server", "not running".to_string()),
+    }
 
-    if response.contains("200") || response.contains("shutting_down") {
-        Ok(())
+    if let (Some(user), Some(pass)) = (&cfg.basic_user, &cfg.basic_pass) {
+        check(
+            is_password_hash(pass.expose()),
+            "Basic auth",
+            if is_password_hash(pass.expose()) {
+                format!("user {user:?}, password hashed")
+            } else {
+                "password stored in clear text; run `tbl auth set-password`".to_string()
+            },
+        );
+    }
+
+    if let Some(cert) = &cfg.tls_cert {
+        match config_path(&config_dir, cert).and_then(|path| certificate_expiry(&path)) {
+            Ok(not_after) => {
+                let left = not_after - now_unix() as i64;
+                let expires = timefmt::human_unix(not_after as u64, timefmt::Style::Date);
+                let detail = if left <= 0 {
+                    format!("{cert} expired on {expires}")
+                } else {
+                    format!("{cert} expires {expires} ({} days)", left / 86400)
+                };
+                check(
+                    left >= TLS_EXPIRY_WARNING.as_secs() as i64,
+                    "TLS cert",
+                    detail,
+                );
+            }
+            Err(e) => check(false, "TLS cert", format!("{e:#}")),
+        }
+    }
+
+    let reports = crash_reports(&config_dir);
+    match reports.first() {
+        Some(last) => check(
+            false,
+            "Crashes",
+            format!(
+                "last {}: {}",
+                timefmt::age(crash_report_time(last)),
+                crash_report_summary(last).unwrap_or_default()
+            ),
+        ),
+        None => check(true, "Crashes", "none recorded".to_string()),
+    }
+
+    println!();
+    if problems == 0 {
+        println!("  No problems found.");
     } else {
-        anyhow::bail!("Unexpected response: {}", response.lines().next().unwrap_or(""))
+        println!(
+            "  {} problem{} found.",
+            problems,
+            if problems == 1 { "" } else { "s" }
+        );
+        if let Some(last) = reports.first() {
+            println!("  Latest crash report: {}", last.display());
+        }
     }
+    println!();
+
+    Ok(())
 }
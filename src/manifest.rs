@@ -0,0 +1,215 @@
+//! Checksums of the served files and what each sync changed.
+//!
+//! After every sync the workspace is hashed into a manifest, saved as
+//! `<config_dir>/sync/manifest.json`, and compared with the one the sync
+//! before left behind. The difference — files added, removed, or whose
+//! content changed — is kept in `sync/last.json` for `GET /api/v1/sync/last`
+//! and `tbl status -v`. Files are compared by SHA-256, not modification time,
+//! so a fetch that rewrites a file with the same content reports nothing.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::now_unix;
+
+/// A file of the workspace as hashed after a sync.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct Entry {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Every file of the workspace, by path relative to it with `/` separators.
+pub type Manifest = BTreeMap<String, Entry>;
+
+/// A file a sync added, removed, or changed.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct FileChange {
+    /// Path relative to the workspace
+    pub path: String,
+    /// Size after the sync; absent for removed files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Size before the sync; absent for added files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_size: Option<u64>,
+}
+
+/// What the last sync changed on disk.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct SyncReport {
+    /// Source the content was synced from
+    pub url: String,
+    /// Mirror the content came from because `url` failed (see `git_mirrors`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<String>,
+    /// Unix time the sync finished
+    pub finished_at: u64,
+    /// Commit or source version before the sync
+    pub previous_version: Option<String>,
+    /// Commit or source version after the sync
+    pub version: Option<String>,
+    /// Files in the workspace after the sync
+    pub files: usize,
+    /// Their total size in bytes
+    pub size: u64,
+    pub added: Vec<FileChange>,
+    pub removed: Vec<FileChange>,
+    /// Files whose content changed
+    pub changed: Vec<FileChange>,
+}
+
+impl SyncReport {
+    /// Whether the sync changed no file.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+fn sync_dir(config_dir: &Path) -> PathBuf {
+    config_dir.join("sync")
+}
+
+/// The manifest the last sync saved; empty before the first.
+pub fn load(config_dir: &Path) -> Manifest {
+    fs::read(sync_dir(config_dir).join("manifest.json"))
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Where the report of the last sync is saved.
+pub fn report_path(config_dir: &Path) -> PathBuf {
+    sync_dir(config_dir).join("last.json")
+}
+
+/// The report of the last sync, if there was one.
+pub fn last(config_dir: &Path) -> Option<SyncReport> {
+    let data = fs::read(report_path(config_dir)).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// After the workspace changed: hash it, compare it with the manifest of the
+/// sync before, and save both the new manifest and the report.
+pub fn record(
+    config_dir: &Path,
+    url: &str,
+    mirror: Option<String>,
+    previous_version: Option<String>,
+    version: Option<String>,
+) -> Result<(Manifest, SyncReport)> {
+    let manifest = scan(&config_dir.join("web"))?;
+    let previous = load(config_dir);
+
+    let mut report = SyncReport {
+        url: url.to_string(),
+        mirror,
+        finished_at: now_unix(),
+        previous_version,
+        version,
+        files: manifest.len(),
+        size: manifest.values().map(|entry| entry.size).sum(),
+        added: Vec::new(),
+        removed: Vec::new(),
+        changed: Vec::new(),
+    };
+    for (path, entry) in &manifest {
+        match previous.get(path) {
+            None => report.added.push(FileChange {
+                path: path.clone(),
+                size: Some(entry.size),
+                previous_size: None,
+            }),
+            Some(old) if old.sha256 != entry.sha256 => report.changed.push(FileChange {
+                path: path.clone(),
+                size: Some(entry.size),
+                previous_size: Some(old.size),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (path, old) in &previous {
+        if !manifest.contains_key(path) {
+            report.removed.push(FileChange {
+                path: path.clone(),
+                size: None,
+                previous_size: Some(old.size),
+            });
+        }
+    }
+
+    let dir = sync_dir(config_dir);
+    fs::create_dir_all(&dir)?;
+    write_json(&dir.join("manifest.json"), &manifest)?;
+    write_json(&report_path(config_dir), &report)?;
+    Ok((manifest, report))
+}
+
+/// Hash every file below `web_dir`, leaving out `.git`.
+pub fn scan(web_dir: &Path) -> Result<Manifest> {
+    let mut manifest = Manifest::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(rel) = pending.pop() {
+        let dir = web_dir.join(&rel);
+        for entry in
+            fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?
+        {
+            let entry = entry?;
+            if rel.as_os_str().is_empty() && entry.file_name() == ".git" {
+                continue;
+            }
+            let path = rel.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            } else if entry.path().is_file() {
+                let (size, sha256) = hash_file(&entry.path())?;
+                manifest.insert(rel_string(&path), Entry { size, sha256 });
+            }
+        }
+    }
+    Ok(manifest)
+}
+
+/// Size and SHA-256 of the file at `path`.
+pub fn hash_file(path: &Path) -> Result<(u64, String)> {
+    let mut file =
+        fs::File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut digest = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        digest.update(&buf[..read]);
+        size += read as u64;
+    }
+    Ok((size, hex::encode(digest.finalize())))
+}
+
+/// `rel` with `/` separators, as stored in the manifest.
+fn rel_string(rel: &Path) -> String {
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Write `value` under a temporary name and move it in place, so a reader
+/// never sees half a file.
+fn write_json(path: &Path, value: &impl Serialize) -> Result<()> {
+    let partial = path.with_extension("json.partial");
+    fs::write(&partial, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    fs::rename(&partial, path).with_context(|| format!("Failed to write {}", path.display()))
+}
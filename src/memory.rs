@@ -0,0 +1,108 @@
+//! Memory held by the server's in-memory stores (`memory` config section).
+//!
+//! The search index, the KV namespaces loaded from disk, and the job records
+//! with their logs each report an estimate of the bytes they hold, shown in
+//! `/api/v1/info`, and stay below a cap: KV namespaces that were used least
+//! recently are unloaded (they stay on disk), the oldest finished jobs are
+//! removed, and the search index stops taking documents once full.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Cap on the search index unless `memory.search_index` says otherwise.
+pub const DEFAULT_SEARCH_INDEX: u64 = 64 * 1024 * 1024;
+
+/// Cap on the loaded KV namespaces unless `memory.kv` says otherwise.
+pub const DEFAULT_KV: u64 = 16 * 1024 * 1024;
+
+/// Cap on the job records and logs unless `memory.jobs` says otherwise.
+pub const DEFAULT_JOBS: u64 = 16 * 1024 * 1024;
+
+/// `memory` config section: caps such as "32MiB"; "0" for no cap.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MemoryConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search_index: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<String>,
+}
+
+/// Caps in bytes; `None` for no cap.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub search_index: Option<u64>,
+    pub kv: Option<u64>,
+    pub jobs: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            search_index: Some(DEFAULT_SEARCH_INDEX),
+            kv: Some(DEFAULT_KV),
+            jobs: Some(DEFAULT_JOBS),
+        }
+    }
+}
+
+impl MemoryConfig {
+    pub fn limits(&self) -> Result<Limits> {
+        let limit = |key: &str, value: &Option<String>, default: u64| -> Result<Option<u64>> {
+            let bytes = match value {
+                Some(value) => {
+                    parse_size(value).with_context(|| format!("Invalid memory.{key}"))?
+                }
+                None => default,
+            };
+            Ok(Some(bytes).filter(|&bytes| bytes > 0))
+        };
+        Ok(Limits {
+            search_index: limit("search_index", &self.search_index, DEFAULT_SEARCH_INDEX)?,
+            kv: limit("kv", &self.kv, DEFAULT_KV)?,
+            jobs: limit("jobs", &self.jobs, DEFAULT_JOBS)?,
+        })
+    }
+}
+
+/// Parse a size such as "512", "64KiB", "32MB", or "1G"; the units are
+/// powers of 1024 whether written `KB` or `KiB`.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("not a size: {s:?}"))?;
+    let shift = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        _ => anyhow::bail!("unknown unit in {s:?}; use B, KiB, MiB, or GiB"),
+    };
+    number
+        .checked_mul(1 << shift)
+        .with_context(|| format!("size too large: {s:?}"))
+}
+
+/// Estimated bytes a store holds, and its cap.
+#[derive(Serialize, Debug, Clone, Copy, ToSchema)]
+pub struct Usage {
+    pub bytes: u64,
+    /// Null when there is no cap
+    pub limit: Option<u64>,
+}
+
+/// What `/api/v1/info` reports under `memory`.
+#[derive(Serialize, Debug, Clone, Copy, ToSchema)]
+pub struct MemoryUsage {
+    /// Full-text index of the checkout
+    pub search_index: Usage,
+    /// KV namespaces loaded from disk
+    pub kv: Usage,
+    /// Job records and their logs
+    pub jobs: Usage,
+}
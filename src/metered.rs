@@ -0,0 +1,137 @@
+//! Metered connections: automatic syncs wait for an unmetered one.
+//!
+//! With `metered` on `auto` the OS is asked whether the connection is metered
+//! (NetworkManager on Linux, the connection cost on Windows; other systems
+//! count as unmetered). On a metered connection, scheduled syncs and the sync
+//! when the server starts are deferred rather than run, as long as there is
+//! content to serve: the deferral is saved as `<config_dir>/sync/deferred.json`
+//! and the sync runs once the connection is unmetered again. Syncs asked for
+//! explicitly still run.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context as _, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::now_unix;
+
+/// Whether the connection counts as metered.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Metered {
+    /// Ask the OS
+    #[default]
+    Auto,
+    /// Always, e.g. on a laptop that mostly tethers to a phone
+    Yes,
+    /// Never
+    No,
+}
+
+impl Metered {
+    /// Whether the connection is metered right now.
+    pub fn is_metered(self) -> bool {
+        match self {
+            Self::Auto => detect().unwrap_or(false),
+            Self::Yes => true,
+            Self::No => false,
+        }
+    }
+}
+
+/// Whether the OS reports the connection as metered; `None` when it can't
+/// tell.
+pub fn detect() -> Option<bool> {
+    if cfg!(target_os = "linux") {
+        // NMMetered: 1 yes, 2 no, 3 guessed yes, 4 guessed no, 0 unknown
+        let output = probe(Command::new("busctl").args([
+            "--system",
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ]))?;
+        match output.trim() {
+            "u 1" | "u 3" => Some(true),
+            "u 2" | "u 4" => Some(false),
+            _ => None,
+        }
+    } else if cfg!(windows) {
+        let script = "[void][Windows.Networking.Connectivity.NetworkInformation,Windows,ContentType=WindowsRuntime]; \
+             $profile = [Windows.Networking.Connectivity.NetworkInformation]::GetInternetConnectionProfile(); \
+             if ($profile) { $profile.GetConnectionCost().NetworkCostType }";
+        let output = probe(Command::new("powershell").args(["-NoProfile", "-Command", script]))?;
+        match output.trim() {
+            "Fixed" | "Variable" => Some(true),
+            "Unrestricted" => Some(false),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+/// Standard output of `cmd`, if it succeeds.
+fn probe(cmd: &mut Command) -> Option<String> {
+    let output = cmd
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// An automatic sync put off until the connection is unmetered.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct SyncDeferral {
+    /// Unix time of the first sync deferred
+    pub since: u64,
+    /// Syncs deferred since then
+    pub count: u32,
+}
+
+fn path(config_dir: &Path) -> PathBuf {
+    config_dir.join("sync").join("deferred.json")
+}
+
+/// The deferred sync, if any.
+pub fn deferred(config_dir: &Path) -> Option<SyncDeferral> {
+    let data = fs::read(path(config_dir)).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Defer a sync; syncs deferred while one already is are counted with it.
+pub fn defer(config_dir: &Path) -> Result<SyncDeferral> {
+    let deferral = match deferred(config_dir) {
+        Some(earlier) => SyncDeferral {
+            count: earlier.count + 1,
+            ..earlier
+        },
+        None => SyncDeferral {
+            since: now_unix(),
+            count: 1,
+        },
+    };
+    let path = path(config_dir);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, serde_json::to_vec_pretty(&deferral)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(deferral)
+}
+
+/// Forget the deferred sync, once content was synced.
+pub fn clear(config_dir: &Path) {
+    let _ = fs::remove_file(path(config_dir));
+}
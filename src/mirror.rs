@@ -0,0 +1,197 @@
+//! Shared bare mirrors of the repositories workspaces are cloned from.
+//!
+//! With `git_cache` on, every remote repository is mirrored once under
+//! `~/.cache/tbl/git/<host>/<path>.git`, whichever profiles serve it, and
+//! workspaces are cloned with `--reference` to the mirror. Objects the mirror
+//! already has are then neither downloaded nor stored again, so a second
+//! profile of the same large repository costs a fetch of nothing new.
+//!
+//! Checkouts borrow objects from the mirror, so it never prunes unreachable
+//! objects: a force-pushed branch must not take commits away from a checkout
+//! that has not fetched since.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{forge::ProvidersConfig, job_step, jobs::JobHandle, repo_identity, run_git};
+
+/// Root of the mirrors: `~/.cache/tbl/git`, or `/var/cache/tbl/git` in the
+/// system scope.
+pub fn cache_dir() -> Option<PathBuf> {
+    if crate::is_system() {
+        return Some(PathBuf::from("/var/cache/tbl/git"));
+    }
+    dirs::cache_dir().map(|dir| dir.join("tbl").join("git"))
+}
+
+/// Where the mirror of `url` lives, named after its repository identity, e.g.
+/// `github.com/acme/site.git`. Repositories on this machine have none, as git
+/// already clones those with hard links.
+pub fn path(url: &str) -> Option<PathBuf> {
+    let identity = repo_identity(url);
+    if Path::new(&identity).is_absolute() {
+        return None;
+    }
+    let mut path = cache_dir()?;
+    for segment in identity.split('/').filter(|segment| !segment.is_empty()) {
+        let segment: String = segment
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        path.push(match segment.as_str() {
+            "." | ".." => "_".to_string(),
+            _ => segment,
+        });
+    }
+    path.set_extension("git");
+    Some(path)
+}
+
+/// Clone or fetch the mirror of `url` and return its path. Profiles syncing
+/// the same repository take turns.
+pub fn update(url: &str, providers: &ProvidersConfig, job: Option<&JobHandle>) -> Result<PathBuf> {
+    let mirror = path(url).context("no mirror for a local repository")?;
+    let parent = mirror.parent().context("mirror has no parent directory")?;
+    fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    let _lock = Lock::acquire(&mirror.with_extension("lock"))?;
+
+    if mirror.join("HEAD").exists() {
+        git(
+            Command::new("git")
+                .arg("--git-dir")
+                .arg(&mirror)
+                .arg("remote")
+                .arg("set-url")
+                .arg("origin")
+                .arg(url),
+            "remote set-url",
+        )?;
+        git(
+            job_step(
+                providers
+                    .authorize(&mut Command::new("git"), url)
+                    .arg("--git-dir")
+                    .arg(&mirror)
+                    .arg("fetch")
+                    .arg("--prune")
+                    .arg("--tags")
+                    .arg("origin"),
+                job,
+                "updating mirror",
+            )?,
+            "fetch",
+        )?;
+        return Ok(mirror);
+    }
+
+    // Clone next to the mirror and move it in place once complete, so an
+    // interrupted clone is never taken for a mirror
+    let partial = mirror.with_extension("partial");
+    if partial.exists() {
+        fs::remove_dir_all(&partial)?;
+    }
+    let cloned = git(
+        job_step(
+            providers
+                .authorize(&mut Command::new("git"), url)
+                .arg("clone")
+                .arg("--bare")
+                .arg(url)
+                .arg(&partial),
+            job,
+            "cloning mirror",
+        )?,
+        "clone",
+    )
+    .and_then(|()| {
+        // Branches and tags only, not e.g. GitHub's pull request refs
+        git(
+            Command::new("git")
+                .arg("--git-dir")
+                .arg(&partial)
+                .arg("config")
+                .arg("remote.origin.fetch")
+                .arg("+refs/heads/*:refs/heads/*"),
+            "config",
+        )?;
+        git(
+            Command::new("git")
+                .arg("--git-dir")
+                .arg(&partial)
+                .arg("config")
+                .arg("gc.pruneExpire")
+                .arg("never"),
+            "config",
+        )
+    })
+    .and_then(|()| fs::rename(&partial, &mirror).context("Failed to move the mirror in place"));
+    if cloned.is_err() {
+        let _ = fs::remove_dir_all(&partial);
+    }
+    cloned.map(|()| mirror)
+}
+
+/// Let the checkout in `web_dir` use objects of `mirror`, as if it had been
+/// cloned with `--reference` to it.
+pub fn link(web_dir: &Path, mirror: &Path) -> Result<()> {
+    let alternates = web_dir.join(".git/objects/info/alternates");
+    let objects = mirror.join("objects").display().to_string();
+    let mut content = fs::read_to_string(&alternates).unwrap_or_default();
+    if content.lines().any(|line| line == objects) {
+        return Ok(());
+    }
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(&objects);
+    content.push('\n');
+    if let Some(dir) = alternates.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&alternates, content)
+        .with_context(|| format!("Failed to write {}", alternates.display()))
+}
+
+/// Run a git command that must succeed.
+fn git(cmd: &mut Command, action: &str) -> Result<()> {
+    let output = run_git(cmd, action)?;
+    if !output.status.success() {
+        anyhow::bail!("git {action} failed with status {}", output.status);
+    }
+    Ok(())
+}
+
+/// Exclusive lock on a file next to the mirror, released on drop. Only
+/// advisory, and a no-op where `flock` is not available.
+struct Lock {
+    _file: fs::File,
+}
+
+impl Lock {
+    fn acquire(path: &Path) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            // SAFETY: the descriptor is valid for as long as `file` lives
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+        Ok(Self { _file: file })
+    }
+}
@@ -0,0 +1,76 @@
+//! Pausing automatic syncs, e.g. while demoing a particular version.
+//!
+//! A pause is saved as `<config_dir>/sync/paused.json`, so it outlasts
+//! restarts. While it holds, neither `sync_schedule` nor a server start
+//! updates content that is already there; syncs asked for explicitly still
+//! run. A pause may end by itself at a set time.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::now_unix;
+
+/// A pause of automatic syncs.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct SyncPause {
+    /// Unix time the pause began
+    pub since: u64,
+    /// Unix time it ends by itself; absent until resumed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<u64>,
+    /// Why syncs are paused, for status output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+fn path(config_dir: &Path) -> PathBuf {
+    config_dir.join("sync").join("paused.json")
+}
+
+/// The pause in effect, if any. One whose time is up counts as resumed.
+pub fn current(config_dir: &Path) -> Option<SyncPause> {
+    let data = fs::read(path(config_dir)).ok()?;
+    let pause: SyncPause = serde_json::from_slice(&data).ok()?;
+    pause
+        .until
+        .is_none_or(|until| until > now_unix())
+        .then_some(pause)
+}
+
+/// Pause automatic syncs, for `duration_secs` if given, replacing any
+/// earlier pause.
+pub fn pause(
+    config_dir: &Path,
+    reason: Option<String>,
+    duration_secs: Option<u64>,
+) -> Result<SyncPause> {
+    let since = now_unix();
+    let pause = SyncPause {
+        since,
+        until: duration_secs.map(|secs| since + secs),
+        reason: reason.filter(|reason| !reason.trim().is_empty()),
+    };
+    let path = path(config_dir);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, serde_json::to_vec_pretty(&pause)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(pause)
+}
+
+/// End the pause; returns whether one was in effect.
+pub fn resume(config_dir: &Path) -> Result<bool> {
+    let was_paused = current(config_dir).is_some();
+    match fs::remove_file(path(config_dir)) {
+        Ok(()) => Ok(was_paused),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).context("Failed to remove the pause"),
+    }
+}
@@ -0,0 +1,287 @@
+//! Peer sources: another tbl instance, `tbl://[token@]host:port[/base]`,
+//! served in place of its own origin.
+//!
+//! A site cloned once on a server on the LAN can be handed on to other
+//! machines without each reaching the origin. The upstream lists the files
+//! it serves with their SHA-256 (`GET /api/v1/peer/manifest`); only those
+//! that differ from the last sync are downloaded (`/api/v1/peer/files/*`)
+//! and those gone upstream are deleted. The token is the upstream's
+//! `peer_token`; `tbls://` reaches an upstream serving HTTPS, and the path is
+//! its `base_path`, if it has one.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use reqwest::{blocking::Client, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::{
+    format_bytes,
+    jail::Jail,
+    manifest::{self, Entry},
+    secret::Secret,
+    source::{self, Context, Record, SourceProvider},
+};
+
+/// A connection that sends nothing for this long is dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What an upstream serves, as listed for its peers.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct PeerManifest {
+    /// Commit or source version of the content
+    pub version: Option<String>,
+    /// Every file by path relative to the workspace, with `/` separators
+    pub files: BTreeMap<String, Entry>,
+}
+
+/// Other tbl instances.
+pub struct Peer;
+
+impl SourceProvider for Peer {
+    fn name(&self) -> &'static str {
+        "peer"
+    }
+
+    fn handles(&self, url: &str) -> bool {
+        parse_url(url).is_some()
+    }
+
+    fn sync(&self, ctx: &Context, url: &str) -> Result<()> {
+        sync(ctx, url)
+    }
+
+    fn installed_url(&self, web_dir: &Path) -> Option<String> {
+        Record::load(web_dir, self.name()).map(|record| record.url)
+    }
+
+    /// The upstream's version of the content.
+    fn version(&self, web_dir: &Path) -> Option<String> {
+        Record::load(web_dir, self.name()).map(|record| record.version)
+    }
+}
+
+/// Where `tbl[s]://[token@]host[:port][/base]` points.
+struct Upstream {
+    /// `http[s]://host[:port][/base]/api/v1/peer`
+    api: Url,
+    token: Option<Secret>,
+}
+
+fn parse_url(url: &str) -> Option<Upstream> {
+    let (scheme, rest) = url.trim().split_once("://")?;
+    let scheme = match scheme.to_ascii_lowercase().as_str() {
+        "tbl" => "http",
+        "tbls" => "https",
+        _ => return None,
+    };
+    let (authority, base) = rest.split_once('/').unwrap_or((rest, ""));
+    let (token, host) = match authority.rsplit_once('@') {
+        Some((token, host)) => (Some(Secret::new(token)), host),
+        None => (None, authority),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let base = base.trim_end_matches('/');
+    let base = if base.is_empty() {
+        String::new()
+    } else {
+        format!("/{base}")
+    };
+    Some(Upstream {
+        api: Url::parse(&format!("{scheme}://{host}{base}/api/v1/peer")).ok()?,
+        token,
+    })
+}
+
+/// Download what changed upstream since the last sync and delete what is
+/// gone. Each file is written under a temporary name and moved in place
+/// once its checksum matches, so a failed sync leaves whole files behind.
+fn sync(ctx: &Context, url: &str) -> Result<()> {
+    let upstream = parse_url(url).with_context(|| format!("{url} is not a tbl:// URL"))?;
+    let web_dir = &ctx.web_dir;
+    source::claim_workspace(web_dir, Peer.name(), url)?;
+
+    // The blocking client refuses to run on a thread of the async runtime,
+    // which the sync before the server starts is on
+    let version = std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let client = Client::builder()
+                    .user_agent(concat!("tbl/", env!("CARGO_PKG_VERSION")))
+                    .timeout(STALL_TIMEOUT)
+                    .build()?;
+                sync_files(&client, &upstream, ctx)
+            })
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })?;
+
+    Record {
+        url: url.to_string(),
+        version: match version {
+            Some(version) => version,
+            None => source::tree_digest(web_dir)?,
+        },
+    }
+    .save(web_dir, Peer.name())
+}
+
+/// Bring the workspace in line with the upstream's manifest, returning the
+/// upstream's version of the content.
+fn sync_files(client: &Client, upstream: &Upstream, ctx: &Context) -> Result<Option<String>> {
+    let web_dir = &ctx.web_dir;
+    let jail = Jail::new(web_dir);
+    ctx.progress("comparing");
+    let remote: PeerManifest = get(client, upstream, &["manifest"])?
+        .json()
+        .context("Invalid manifest from the upstream")?;
+    let local = manifest::load(ctx.config_dir);
+
+    let wanted: Vec<(&String, &Entry)> = remote
+        .files
+        .iter()
+        .filter(|(path, entry)| {
+            local.get(*path) != Some(entry)
+                || jail
+                    .path(path.as_str())
+                    .and_then(|path| fs::metadata(path).ok())
+                    .is_none_or(|meta| meta.len() != entry.size)
+        })
+        .collect();
+    let total: u64 = wanted.iter().map(|(_, entry)| entry.size).sum();
+    if !wanted.is_empty() {
+        ctx.log(&format!(
+            "Downloading {} of {} files ({})",
+            wanted.len(),
+            remote.files.len(),
+            format_bytes(total)
+        ));
+    }
+    for (i, (path, entry)) in wanted.iter().enumerate() {
+        if ctx.cancelled() {
+            anyhow::bail!("download cancelled: tbl is shutting down");
+        }
+        ctx.progress(&format!("downloading {} of {}", i + 1, wanted.len()));
+        let target = jail
+            .path(path.as_str())
+            .with_context(|| format!("Invalid path from the upstream: {path}"))?;
+        download(client, upstream, path, entry, &target)?;
+    }
+
+    ctx.progress("removing");
+    for path in local
+        .keys()
+        .filter(|path| !remote.files.contains_key(*path))
+    {
+        let Some(file) = jail.path(path.as_str()) else {
+            continue;
+        };
+        match fs::remove_file(&file) {
+            Ok(()) => source::remove_empty_parents(web_dir, &file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to remove {}", file.display()))
+            }
+        }
+    }
+    Ok(remote.version)
+}
+
+/// Download the upstream's `path` to `target`, checking it against `entry`.
+fn download(
+    client: &Client,
+    upstream: &Upstream,
+    path: &str,
+    entry: &Entry,
+    target: &Path,
+) -> Result<()> {
+    let segments: Vec<&str> = ["files"].into_iter().chain(path.split('/')).collect();
+    let mut response = get(client, upstream, &segments)?;
+    if let Some(dir) = target.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = target.with_file_name(format!(
+        "{}.tbl-copy",
+        target.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let mut file = fs::File::create(&partial)
+        .with_context(|| format!("Failed to write {}", partial.display()))?;
+    let mut digest = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = response
+            .read(&mut buf)
+            .with_context(|| format!("Failed to download {path}"))?;
+        if read == 0 {
+            break;
+        }
+        digest.update(&buf[..read]);
+        file.write_all(&buf[..read])?;
+    }
+    drop(file);
+    if hex::encode(digest.finalize()) != entry.sha256 {
+        let _ = fs::remove_file(&partial);
+        anyhow::bail!("{path} changed upstream during the sync; try again");
+    }
+    fs::rename(&partial, target).with_context(|| format!("Failed to write {}", target.display()))
+}
+
+/// `GET` an endpoint of the upstream's peer API, given as path segments
+/// that are percent-encoded one by one.
+fn get(
+    client: &Client,
+    upstream: &Upstream,
+    segments: &[&str],
+) -> Result<reqwest::blocking::Response> {
+    let mut url = upstream.api.clone();
+    if let Ok(mut path) = url.path_segments_mut() {
+        path.extend(segments);
+    }
+    let mut request = client.get(url);
+    if let Some(token) = &upstream.token {
+        request = request.bearer_auth(token.expose());
+    }
+    let response = request.send()?;
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::UNAUTHORIZED if upstream.token.is_none() => {
+            anyhow::bail!("the upstream asks for its peer_token: tbl://<token>@host:port")
+        }
+        StatusCode::UNAUTHORIZED => {
+            anyhow::bail!("the upstream refused the token; check its peer_token")
+        }
+        StatusCode::NOT_FOUND if segments == ["manifest"] => {
+            anyhow::bail!("the upstream has no content yet, or is not tbl")
+        }
+        status => anyhow::bail!("the upstream answered {status}"),
+    }
+}
+
+/// Paths and checksums of the upstream's own content, for its peers.
+pub fn manifest(config_dir: &Path) -> Result<PeerManifest> {
+    let web_dir = config_dir.join("web");
+    let mut files = manifest::load(config_dir);
+    if files.is_empty() {
+        files = manifest::scan(&web_dir)?;
+    }
+    Ok(PeerManifest {
+        version: source::installed_version(&web_dir),
+        files,
+    })
+}
+
+/// Whether files of the workspace may be handed to peers at `path`: not the
+/// checkout's git directory.
+pub fn is_shared(path: &str) -> bool {
+    path.split('/').next() != Some(".git")
+}
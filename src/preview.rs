@@ -0,0 +1,280 @@
+//! Preview environments: every branch of the git source served on its own.
+//!
+//! With `previews` on, `/preview/<branch>/` serves a checkout of that branch
+//! from `<config_dir>/previews/<name>/`, cloned the first time it is opened
+//! and fetched again once it is older than [`REFRESH`]. Branch names are
+//! percent-encoded in both the URL and the directory name, so `feature/login`
+//! is `/preview/feature%2Flogin/`. Checkouts of branches that were deleted on
+//! the remote, or of another repository, are removed when previews are
+//! listed.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    job_step, jobs::JobHandle, mirror, now_unix, repo_identity, run_git, source::Settings,
+};
+
+/// Age after which opening a preview fetches its branch again.
+pub const REFRESH: Duration = Duration::from_secs(60);
+
+/// A preview checkout, saved next to it as `<name>.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct Preview {
+    pub branch: String,
+    /// Source the branch was fetched from
+    pub url: String,
+    /// Commit checked out
+    pub commit: String,
+    /// Unix time of the last fetch
+    pub synced_at: u64,
+}
+
+fn previews_dir(config_dir: &Path) -> PathBuf {
+    config_dir.join("previews")
+}
+
+/// `branch` with everything but unreserved URL characters percent-encoded,
+/// for URLs and directory names. A leading `.` is encoded too, so no name
+/// is `.`, `..`, or hidden; git allows no branch starting with one.
+pub fn encode(branch: &str) -> String {
+    let mut encoded = String::with_capacity(branch.len());
+    for (i, byte) in branch.bytes().enumerate() {
+        match byte {
+            b'.' if i == 0 => encoded.push_str("%2E"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Checkout of `branch`.
+pub fn dir(config_dir: &Path, branch: &str) -> PathBuf {
+    previews_dir(config_dir).join(encode(branch))
+}
+
+/// Whether `path` is an entry right inside the previews dir.
+fn is_preview_entry(config_dir: &Path, path: &Path) -> bool {
+    let previews = previews_dir(config_dir);
+    path.parent() == Some(previews.as_path())
+        && path
+            .file_name()
+            .is_some_and(|name| !name.to_string_lossy().starts_with('.'))
+}
+
+fn record_path(config_dir: &Path, branch: &str) -> PathBuf {
+    previews_dir(config_dir).join(format!("{}.json", encode(branch)))
+}
+
+/// The checkout of `branch`, if there is a complete one.
+pub fn find(config_dir: &Path, branch: &str) -> Option<Preview> {
+    let data = fs::read(record_path(config_dir, branch)).ok()?;
+    serde_json::from_slice::<Preview>(&data)
+        .ok()
+        .filter(|preview| preview.branch == branch && dir(config_dir, branch).is_dir())
+}
+
+/// All checkouts, by branch.
+pub fn list(config_dir: &Path) -> Vec<Preview> {
+    let Ok(entries) = fs::read_dir(previews_dir(config_dir)) else {
+        return Vec::new();
+    };
+    let mut previews: Vec<Preview> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let data = fs::read(entry.path()).ok()?;
+            serde_json::from_slice::<Preview>(&data).ok()
+        })
+        .filter(|preview| find(config_dir, &preview.branch).is_some())
+        .collect();
+    previews.sort_by(|a, b| a.branch.cmp(&b.branch));
+    previews
+}
+
+/// Whether `preview` is due to be fetched again.
+pub fn is_stale(preview: &Preview) -> bool {
+    now_unix().saturating_sub(preview.synced_at) >= REFRESH.as_secs()
+}
+
+/// Branches of the repository at `url`, by name.
+pub fn branches(settings: &Settings, url: &str) -> Result<Vec<String>> {
+    let output = run_git(
+        settings
+            .providers
+            .authorize(&mut Command::new("git"), url)
+            .arg("ls-remote")
+            .arg("--heads")
+            .arg(url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+        "ls-remote",
+    )?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git ls-remote failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let mut branches: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter_map(|(_, name)| name.strip_prefix("refs/heads/"))
+        .map(str::to_string)
+        .collect();
+    branches.sort();
+    Ok(branches)
+}
+
+/// Remove the checkouts of branches not in `branches`, or of a repository
+/// other than `url`.
+pub fn prune(config_dir: &Path, url: &str, branches: &[String]) -> Result<()> {
+    for preview in list(config_dir) {
+        if !branches.contains(&preview.branch) || repo_identity(&preview.url) != repo_identity(url)
+        {
+            remove(config_dir, &preview.branch)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the checkout of `branch`; returns whether there was one.
+pub fn remove(config_dir: &Path, branch: &str) -> Result<bool> {
+    let dir = dir(config_dir, branch);
+    if !is_preview_entry(config_dir, &dir) {
+        anyhow::bail!("Refusing to remove {}: not a preview", dir.display());
+    }
+    let existed = dir.exists();
+    if existed {
+        fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+    }
+    let _ = fs::remove_file(record_path(config_dir, branch));
+    Ok(existed)
+}
+
+/// Clone `branch` of `url`, or fetch and reset its checkout, and return the
+/// commit checked out. Previews take the clone depth and git cache of the
+/// workspace, but no hooks, releases, or sync reports.
+pub fn sync(
+    config_dir: &Path,
+    settings: &Settings,
+    job: Option<&JobHandle>,
+    url: &str,
+    branch: &str,
+) -> Result<Preview> {
+    let dir = dir(config_dir, branch);
+    let existing = find(config_dir, branch)
+        .filter(|preview| repo_identity(&preview.url) == repo_identity(url));
+    let mirror = if settings.cache && mirror::path(url).is_some() {
+        mirror::update(url, &settings.providers, job)
+            .inspect_err(|e| tracing::warn!("{e:#}; fetching without the git cache"))
+            .ok()
+    } else {
+        None
+    };
+
+    if let Some(mut existing) = existing {
+        let mut fetch = Command::new("git");
+        settings.providers.authorize(&mut fetch, url);
+        fetch.arg("-C").arg(&dir).arg("fetch");
+        if settings.depth > 0 {
+            fetch.arg("--depth").arg(settings.depth.to_string());
+        }
+        fetch
+            .arg(url)
+            .arg(format!("+refs/heads/{branch}:refs/remotes/origin/{branch}"));
+        let updated = git(&mut fetch, job, "fetching", "fetch").and_then(|()| {
+            git(
+                Command::new("git")
+                    .arg("-C")
+                    .arg(&dir)
+                    .arg("reset")
+                    .arg("--hard")
+                    .arg(format!("origin/{branch}")),
+                job,
+                "resetting",
+                "reset",
+            )
+        });
+        if let Err(e) = updated {
+            // Keep serving the old checkout, and try again after `REFRESH`
+            // rather than on every request
+            existing.synced_at = now_unix();
+            save(config_dir, &existing)?;
+            return Err(e);
+        }
+    } else {
+        remove(config_dir, branch)?;
+        fs::create_dir_all(previews_dir(config_dir))?;
+        let mut clone = Command::new("git");
+        settings.providers.authorize(&mut clone, url);
+        clone.arg("clone").arg("--single-branch");
+        if let Some(mirror) = &mirror {
+            clone.arg("--reference").arg(mirror);
+        }
+        if settings.depth > 0 {
+            clone.arg("--depth").arg(settings.depth.to_string());
+        }
+        clone.arg("--branch").arg(branch).arg(url).arg(&dir);
+        if let Err(e) = git(&mut clone, job, "cloning", "clone") {
+            // Don't leave a half-written checkout behind
+            let _ = fs::remove_dir_all(&dir);
+            return Err(e);
+        }
+    }
+
+    let commit = head(&dir).context("Failed to read the commit of the preview")?;
+    let preview = Preview {
+        branch: branch.to_string(),
+        url: url.to_string(),
+        commit,
+        synced_at: now_unix(),
+    };
+    save(config_dir, &preview)?;
+    Ok(preview)
+}
+
+fn save(config_dir: &Path, preview: &Preview) -> Result<()> {
+    let path = record_path(config_dir, &preview.branch);
+    fs::write(&path, serde_json::to_vec_pretty(preview)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Run a git command as `step` of `job`, failing unless it succeeds.
+fn git(cmd: &mut Command, job: Option<&JobHandle>, step: &str, action: &str) -> Result<()> {
+    let output = run_git(job_step(cmd, job, step)?, action)?;
+    if !output.status.success() {
+        anyhow::bail!("git {action} failed with status {}", output.status);
+    }
+    Ok(())
+}
+
+/// Commit checked out in `dir`.
+fn head(dir: &Path) -> Option<String> {
+    let output = run_git(
+        Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .arg("rev-parse")
+            .arg("HEAD")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+        "rev-parse",
+    )
+    .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,238 @@
+//! Serving as an unprivileged user after starting as root.
+//!
+//! A system install started as root, e.g. to bind port 443, binds its socket
+//! and loads its TLS key first, then switches to the `user` it was given for
+//! good: its groups, then its group, then its user ID. Anything it does after
+//! that, including git and notifier commands, runs as that user.
+//!
+//! Without root, ports below 1024 need `CAP_NET_BIND_SERVICE` on Linux:
+//! `tbl setcap` gives it to the tbl binary, so the daemon can serve
+//! `https://hostname/` while staying unprivileged.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+/// A user account to serve as.
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+/// Whether the process runs as root.
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_root() -> bool {
+    false
+}
+
+/// Linux capability to bind ports below `ip_unprivileged_port_start`.
+#[cfg(target_os = "linux")]
+const CAP_NET_BIND_SERVICE: u32 = 10;
+
+/// Whether this process may listen on `port`.
+pub fn can_bind(port: u16) -> bool {
+    port >= unprivileged_port_start() || is_root() || has_bind_capability()
+}
+
+/// The first port anyone may bind.
+#[cfg(target_os = "linux")]
+fn unprivileged_port_start() -> u16 {
+    std::fs::read_to_string("/proc/sys/net/ipv4/ip_unprivileged_port_start")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(1024)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn unprivileged_port_start() -> u16 {
+    1024
+}
+
+#[cfg(not(unix))]
+fn unprivileged_port_start() -> u16 {
+    0
+}
+
+/// Whether the process has `CAP_NET_BIND_SERVICE`, e.g. from `tbl setcap`.
+#[cfg(target_os = "linux")]
+fn has_bind_capability() -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let effective = status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))?;
+            u64::from_str_radix(effective.trim(), 16).ok()
+        })
+        .is_some_and(|caps| caps & (1 << CAP_NET_BIND_SERVICE) != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_bind_capability() -> bool {
+    false
+}
+
+/// What to do when binding `port` was refused.
+pub fn bind_hint(port: u16) -> String {
+    if cfg!(target_os = "linux") {
+        format!(
+            "ports below {} need privileges: run `sudo tbl setcap` once, start the system install as root with --user, or use a socket unit",
+            unprivileged_port_start()
+        )
+    } else {
+        format!("port {port} needs privileges: start the system install as root with --user, or use a socket unit")
+    }
+}
+
+/// Give the tbl binary at `exe` `CAP_NET_BIND_SERVICE`, or take it away,
+/// with the `setcap` program; needs root.
+#[cfg(target_os = "linux")]
+pub fn set_bind_capability(exe: &Path, grant: bool) -> Result<()> {
+    use anyhow::Context;
+
+    if !is_root() {
+        anyhow::bail!(
+            "Setting capabilities needs root; run `sudo {} setcap`",
+            exe.display()
+        );
+    }
+    let mut cmd = std::process::Command::new("setcap");
+    if grant {
+        cmd.arg("cap_net_bind_service=+ep");
+    } else {
+        cmd.arg("-r");
+    }
+    let status = cmd.arg(exe).status().context(
+        "Failed to run setcap; install it (libcap2-bin on Debian and Ubuntu, libcap elsewhere)",
+    )?;
+    if !status.success() {
+        anyhow::bail!("setcap failed ({status})");
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_bind_capability(_exe: &Path, _grant: bool) -> Result<()> {
+    anyhow::bail!("Capabilities are Linux-only; elsewhere use a socket unit or --system --user")
+}
+
+/// The account called `name`, from the user database.
+#[cfg(unix)]
+pub fn lookup(name: &str) -> Result<Account> {
+    use std::{
+        ffi::{CStr, CString},
+        io,
+    };
+
+    let c_name = CString::new(name)?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    // SAFETY: passwd is plain data; getpwnam_r fills it in
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    loop {
+        // SAFETY: every pointer is valid for the length passed with it
+        let rc = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        match rc {
+            0 => break,
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            err => {
+                return Err(anyhow::Error::new(io::Error::from_raw_os_error(err))
+                    .context(format!("Failed to look up the user {name:?}")))
+            }
+        }
+    }
+    if found.is_null() {
+        anyhow::bail!(
+            "There is no user {name:?}; create it, e.g. with `useradd --system --no-create-home {name}`"
+        );
+    }
+    // SAFETY: on success pw_dir points into `buf`, NUL-terminated
+    let home = unsafe { CStr::from_ptr(pwd.pw_dir) };
+    Ok(Account {
+        name: name.to_string(),
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        home: PathBuf::from(home.to_string_lossy().into_owned()),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn lookup(_name: &str) -> Result<Account> {
+    anyhow::bail!("Switching users is only supported on Linux and other Unix systems")
+}
+
+/// Create `dirs` if needed and make them the account's, so it can write
+/// there once root is gone. What is already inside them is left alone.
+#[cfg(unix)]
+pub fn hand_over(account: &Account, dirs: &[&Path]) -> Result<()> {
+    use anyhow::Context;
+
+    for dir in dirs {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        std::os::unix::fs::chown(dir, Some(account.uid), Some(account.gid))
+            .with_context(|| format!("Failed to give {dir:?} to {}", account.name))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn hand_over(_account: &Account, _dirs: &[&Path]) -> Result<()> {
+    Ok(())
+}
+
+/// Switch the whole process to `account`, irrevocably, and point `HOME` at
+/// its home directory.
+#[cfg(unix)]
+pub fn drop_to(account: &Account) -> Result<()> {
+    use std::{ffi::CString, io};
+
+    let name = CString::new(account.name.as_str())?;
+    let check = |rc: libc::c_int, what: &str| -> Result<()> {
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(anyhow::Error::new(io::Error::last_os_error())
+                .context(format!("Failed to drop privileges ({what})")))
+        }
+    };
+    // SAFETY: plain system calls on valid arguments. The C library applies
+    // the ID changes to every thread of the process, not just this one.
+    unsafe {
+        check(
+            libc::initgroups(name.as_ptr(), account.gid as _),
+            "initgroups",
+        )?;
+        check(libc::setgid(account.gid), "setgid")?;
+        check(libc::setuid(account.uid), "setuid")?;
+        if account.uid != 0 && libc::setuid(0) == 0 {
+            anyhow::bail!("Failed to drop privileges: root could be regained");
+        }
+    }
+    // Git and other commands read their settings from the user's home
+    std::env::set_var("HOME", &account.home);
+    std::env::set_var("USER", &account.name);
+    std::env::set_var("LOGNAME", &account.name);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_to(_account: &Account) -> Result<()> {
+    anyhow::bail!("Switching users is only supported on Linux and other Unix systems")
+}
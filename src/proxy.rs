@@ -0,0 +1,106 @@
+//! Client addresses behind reverse proxies.
+//!
+//! Behind a proxy every request comes from the proxy's address, and the
+//! client's is in the `Forwarded` or `X-Forwarded-For` header. Anyone can send
+//! those headers, so they are only read when the connection comes from one of
+//! the `trusted_proxies`, and only as far back along the chain as each hop is
+//! trusted too.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result};
+use axum::http::{header, HeaderMap};
+use ipnet::IpNet;
+
+/// Proxies trusted when `trusted_proxies` is not set: the local machine.
+pub const DEFAULT_TRUSTED_PROXIES: &[&str] = &["127.0.0.0/8", "::1/128"];
+
+/// Networks whose forwarding headers are believed.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// Parse CIDRs such as `10.0.0.0/8`; a bare address stands for itself.
+    pub fn parse<S: AsRef<str>>(specs: &[S]) -> Result<Self> {
+        specs
+            .iter()
+            .map(|spec| {
+                let spec = spec.as_ref().trim();
+                spec.parse::<IpNet>()
+                    .or_else(|_| spec.parse::<IpAddr>().map(IpNet::from))
+                    .with_context(|| format!("not an address or CIDR: {spec}"))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The client behind `peer`: the last address of the forwarding chain
+    /// that was not added by a trusted proxy, or `peer` itself when it isn't
+    /// one. `Forwarded` wins over `X-Forwarded-For` when both are present.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut chain = forwarded_for(headers);
+        if chain.is_empty() {
+            chain = x_forwarded_for(headers);
+        }
+        let mut client = peer.to_canonical();
+        for hop in chain.iter().rev() {
+            if !self.contains(client) {
+                break;
+            }
+            // `unknown` and obfuscated names end the chain at the last proxy
+            match parse_node(hop) {
+                Some(ip) => client = ip.to_canonical(),
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// `for=` values of `Forwarded` headers (RFC 7239), first hop first.
+fn forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect()
+}
+
+/// Addresses of `X-Forwarded-For` headers, first hop first.
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().to_string())
+        .filter(|hop| !hop.is_empty())
+        .collect()
+}
+
+/// An address as proxies write it: `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1`,
+/// or `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| {
+            node.strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+                .unwrap_or(node)
+                .parse::<IpAddr>()
+        })
+        .ok()
+}
@@ -0,0 +1,247 @@
+//! Quotas and time-to-live on the data apps store (`quota` config section).
+//!
+//! Each KV namespace may hold up to a quota of keys and JSON values, and the
+//! files area up to a quota of file contents, so one app that keeps writing
+//! can't fill the disk. A write that would take a store over its quota fails
+//! with `507 quota_exceeded`; writes that shrink it always pass, so an app
+//! over a lowered quota can clean up. Keys and files written with a TTL are
+//! removed by a sweeper that runs every [`SWEEP_INTERVAL`].
+//!
+//! What the files area holds is kept as a running [`FilesTally`] rather than
+//! measured on every write: the tree is walked once, and writes and removals
+//! adjust the figures.
+
+use std::{collections::BTreeMap, fmt, fs, path::Path, sync::Mutex, time::Duration};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{memory::parse_size, state::StateDb};
+
+/// Quota on each KV namespace unless `quota.kv` says otherwise.
+pub const DEFAULT_KV: u64 = 10 * 1024 * 1024;
+
+/// Quota on the files area unless `quota.files` says otherwise.
+pub const DEFAULT_FILES: u64 = 1024 * 1024 * 1024;
+
+/// How often expired keys and files are removed; they can outlive their
+/// TTL by this much.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `quota` config section: sizes such as "10MiB"; "0" for no quota.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QuotaConfig {
+    /// Quota on each KV namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kv: Option<String>,
+    /// Quota on the files area
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<String>,
+    /// Quotas of single KV namespaces, overriding `kv`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub namespaces: BTreeMap<String, String>,
+}
+
+/// Quotas in bytes; `None` for no quota.
+#[derive(Debug, Clone, Default)]
+pub struct Quotas {
+    pub kv: Option<u64>,
+    pub files: Option<u64>,
+    pub namespaces: BTreeMap<String, Option<u64>>,
+}
+
+impl QuotaConfig {
+    pub fn quotas(&self) -> Result<Quotas> {
+        let quota = |key: &str, value: Option<&String>, default: u64| -> Result<Option<u64>> {
+            let bytes = match value {
+                Some(value) => parse_size(value).with_context(|| format!("Invalid quota.{key}"))?,
+                None => default,
+            };
+            Ok(Some(bytes).filter(|&bytes| bytes > 0))
+        };
+        let mut namespaces = BTreeMap::new();
+        for (namespace, value) in &self.namespaces {
+            let key = format!("namespaces.{namespace}");
+            namespaces.insert(namespace.clone(), quota(&key, Some(value), 0)?);
+        }
+        Ok(Quotas {
+            kv: quota("kv", self.kv.as_ref(), DEFAULT_KV)?,
+            files: quota("files", self.files.as_ref(), DEFAULT_FILES)?,
+            namespaces,
+        })
+    }
+}
+
+impl Quotas {
+    /// Quota of a KV namespace.
+    pub fn kv(&self, namespace: &str) -> Option<u64> {
+        self.namespaces.get(namespace).copied().unwrap_or(self.kv)
+    }
+}
+
+/// A write refused because it would take a store over its quota.
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    /// `kv:<namespace>` or `files`
+    pub store: String,
+    /// Bytes the store would hold after the write
+    pub bytes: u64,
+    pub quota: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} would hold {} bytes, over its quota of {}",
+            self.store, self.bytes, self.quota
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Fail with [`QuotaExceeded`] if a write taking `store` from `before` to
+/// `after` bytes grows it past `quota`.
+pub fn check(store: &str, quota: Option<u64>, before: u64, after: u64) -> Result<()> {
+    match quota {
+        Some(quota) if after > quota && after > before => Err(QuotaExceeded {
+            store: store.to_string(),
+            bytes: after,
+            quota,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Size and number of the files below `dir`; symlinks count as nothing.
+pub fn dir_usage(dir: &Path) -> (u64, usize) {
+    let mut usage = (0, 0);
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => {
+                let (bytes, files) = dir_usage(&entry.path());
+                usage.0 += bytes;
+                usage.1 += files;
+            }
+            Ok(file_type) if file_type.is_file() => {
+                usage.0 += entry.metadata().map_or(0, |meta| meta.len());
+                usage.1 += 1;
+            }
+            _ => {}
+        }
+    }
+    usage
+}
+
+/// Size and number of the files in the files area, kept current by the
+/// writes and removals made through it. The lock is held across each change,
+/// so concurrent writes are checked against each other's sizes.
+#[derive(Default)]
+pub struct FilesTally(Mutex<Option<(u64, usize)>>);
+
+impl FilesTally {
+    /// The figures as last known; `None` until first measured.
+    pub fn current(&self) -> Option<(u64, usize)> {
+        *self.0.lock().unwrap()
+    }
+
+    /// The figures, measuring the files area at `root` if nothing has yet.
+    /// Walks the tree at first, so call it off the async workers.
+    pub fn measure(&self, root: &Path) -> (u64, usize) {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| dir_usage(root))
+    }
+
+    /// Make a change to the files area at `root` while holding the tally,
+    /// with `change` updating the figures it is given to match. A change
+    /// refused with [`QuotaExceeded`] made none; one that failed otherwise
+    /// may be half made, so the tree is measured again next time.
+    pub fn change<T>(
+        &self,
+        root: &Path,
+        change: impl FnOnce(&mut (u64, usize)) -> Result<T>,
+    ) -> Result<T> {
+        let mut tally = self.0.lock().unwrap();
+        let usage = tally.get_or_insert_with(|| dir_usage(root));
+        let changed = change(usage);
+        if changed.as_ref().is_err_and(|e| !e.is::<QuotaExceeded>()) {
+            *tally = None;
+        }
+        changed
+    }
+}
+
+/// What a KV namespace holds.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct NamespaceUsage {
+    pub namespace: String,
+    /// Size of the keys and their JSON values
+    pub bytes: u64,
+    pub keys: usize,
+    /// Keys with a TTL
+    pub expiring: usize,
+    /// Null when there is no quota
+    pub quota: Option<u64>,
+}
+
+/// What the files area holds.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct FilesUsage {
+    pub bytes: u64,
+    pub files: usize,
+    /// Files with a TTL
+    pub expiring: usize,
+    /// Null when there is no quota
+    pub quota: Option<u64>,
+}
+
+/// What `/api/v1/storage` reports.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct StorageUsage {
+    /// Namespaces holding keys, by name
+    pub kv: Vec<NamespaceUsage>,
+    pub files: FilesUsage,
+}
+
+/// Usage of the KV store in `db`, and of the files area as `files` counts it.
+pub fn usage(db: &StateDb, files: (u64, usize), quotas: &Quotas) -> Result<StorageUsage> {
+    let (kv, expiring_files) = {
+        let conn = db.conn();
+        let mut stmt = conn.prepare(
+            "SELECT namespace, SUM(length(CAST(key AS BLOB)) + length(CAST(value AS BLOB))),
+                    COUNT(*), COUNT(expires_at)
+             FROM kv GROUP BY namespace ORDER BY namespace",
+        )?;
+        let kv = stmt
+            .query_map([], |row| {
+                let namespace: String = row.get(0)?;
+                Ok(NamespaceUsage {
+                    quota: quotas.kv(&namespace),
+                    namespace,
+                    bytes: row.get(1)?,
+                    keys: row.get(2)?,
+                    expiring: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        let expiring: usize =
+            conn.query_row("SELECT COUNT(*) FROM file_expiry", [], |row| row.get(0))?;
+        (kv, expiring)
+    };
+    let (bytes, files) = files;
+    Ok(StorageUsage {
+        kv,
+        files: FilesUsage {
+            bytes,
+            files,
+            expiring: expiring_files,
+            quota: quotas.files,
+        },
+    })
+}
@@ -0,0 +1,294 @@
+//! Snapshots of the served content kept for rollback.
+//!
+//! With `keep_releases` set, every sync that changes the content saves it as
+//! a release in `<config_dir>/releases/<id>/`. Files are stored once, by
+//! SHA-256, in `releases/pool/` and hard-linked into each release that has
+//! them, so ten releases of a site where a few pages change cost little more
+//! than one. Releases past the limit are removed, and pool files no release
+//! uses any more with them.
+//!
+//! A rollback copies a release into the workspace and forgets the state of
+//! the source, so the next sync brings the latest content back in full.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{manifest::Manifest, now_unix, source};
+
+/// Name of the release description inside its directory.
+const MANIFEST: &str = "release.json";
+
+/// A release as described by its `release.json`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Release {
+    /// Creation time as `YYYYMMDD-HHMMSS`, local time
+    pub id: String,
+    /// Commit or source version of the content
+    pub version: String,
+    /// Source the content was synced from
+    pub url: String,
+    /// Unix time the release was saved
+    pub created: u64,
+    /// Number of files
+    pub files: usize,
+    /// Total size of the files in bytes
+    pub size: u64,
+    /// Hash in the pool of every file, by path relative to the workspace
+    pub manifest: BTreeMap<String, String>,
+}
+
+/// The release restored by the last rollback, saved as `restored.json`
+/// until the next sync.
+#[derive(Serialize, Deserialize)]
+struct Restored {
+    id: String,
+    version: String,
+}
+
+fn releases_dir(config_dir: &Path) -> PathBuf {
+    config_dir.join("releases")
+}
+
+fn pool_dir(config_dir: &Path) -> PathBuf {
+    releases_dir(config_dir).join("pool")
+}
+
+/// All releases, newest first.
+pub fn list(config_dir: &Path) -> Vec<Release> {
+    let Ok(entries) = fs::read_dir(releases_dir(config_dir)) else {
+        return Vec::new();
+    };
+    let mut releases: Vec<Release> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let data = fs::read(entry.path().join(MANIFEST)).ok()?;
+            serde_json::from_slice::<Release>(&data)
+                .ok()
+                .filter(|release| entry.file_name() == release.id.as_str())
+        })
+        .collect();
+    releases.sort_by(|a, b| b.created.cmp(&a.created).then(b.id.cmp(&a.id)));
+    releases
+}
+
+/// The release `id` names: its full id or a prefix of its version, e.g. a
+/// short commit.
+pub fn find(config_dir: &Path, id: &str) -> Result<Release> {
+    let releases = list(config_dir);
+    if let Some(release) = releases.iter().find(|release| release.id == id) {
+        return Ok(release.clone());
+    }
+    let mut matches = releases
+        .iter()
+        .filter(|release| !id.is_empty() && release.version.starts_with(id));
+    match (matches.next(), matches.next()) {
+        (Some(release), None) => Ok(release.clone()),
+        (Some(_), Some(_)) => anyhow::bail!("{id} matches more than one release"),
+        (None, _) => anyhow::bail!("no release {id}"),
+    }
+}
+
+/// Bytes the pool takes on disk, which all releases share.
+pub fn pool_size(config_dir: &Path) -> u64 {
+    let mut size = 0;
+    let mut pending = vec![pool_dir(config_dir)];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            match entry.metadata() {
+                Ok(meta) if meta.is_dir() => pending.push(entry.path()),
+                Ok(meta) => size += meta.len(),
+                Err(_) => {}
+            }
+        }
+    }
+    size
+}
+
+/// Version of the release a rollback put in the workspace, until the next
+/// sync replaces it.
+pub fn restored_version(config_dir: &Path) -> Option<String> {
+    let data = fs::read(releases_dir(config_dir).join("restored.json")).ok()?;
+    serde_json::from_slice::<Restored>(&data)
+        .ok()
+        .map(|restored| restored.version)
+}
+
+/// Note that a sync replaced the release a rollback restored, if any.
+pub fn synced(config_dir: &Path) {
+    let _ = fs::remove_file(releases_dir(config_dir).join("restored.json"));
+}
+
+/// After a successful sync: save the content, hashed into `files`, as a
+/// release unless the newest one already has it, and drop releases beyond
+/// `keep`. `keep` of 0 turns releases off but leaves existing ones alone.
+pub fn record(
+    config_dir: &Path,
+    url: &str,
+    version: Option<&str>,
+    files: &Manifest,
+    keep: usize,
+) -> Result<()> {
+    let Some(version) = version.filter(|_| keep > 0) else {
+        return Ok(());
+    };
+    if list(config_dir)
+        .first()
+        .is_none_or(|newest| newest.version != version)
+    {
+        save(config_dir, url, version, files)?;
+    }
+    prune(config_dir, keep)
+}
+
+/// Save the workspace as a new release.
+fn save(config_dir: &Path, url: &str, version: &str, files: &Manifest) -> Result<()> {
+    let web_dir = config_dir.join("web");
+    let created = now_unix();
+    let stamp = chrono::DateTime::from_timestamp(created as i64, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y%m%d-%H%M%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| created.to_string());
+    let mut id = stamp.clone();
+    for n in 2.. {
+        if !releases_dir(config_dir).join(&id).exists() {
+            break;
+        }
+        id = format!("{stamp}-{n}");
+    }
+
+    // Assembled under a temporary name, so a half-saved release is never listed
+    let dir = releases_dir(config_dir).join(format!("{id}.partial"));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    for (rel, entry) in files {
+        let pooled = pool_file(config_dir, &web_dir.join(rel), &entry.sha256)?;
+        let target = dir.join(rel);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // A filesystem without hard links gets a copy
+        if fs::hard_link(&pooled, &target).is_err() {
+            fs::copy(&pooled, &target)
+                .with_context(|| format!("Failed to save {}", target.display()))?;
+        }
+    }
+
+    let release = Release {
+        id: id.clone(),
+        version: version.to_string(),
+        url: url.to_string(),
+        created,
+        files: files.len(),
+        size: files.values().map(|entry| entry.size).sum(),
+        manifest: files
+            .iter()
+            .map(|(rel, entry)| (rel.clone(), entry.sha256.clone()))
+            .collect(),
+    };
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(MANIFEST), serde_json::to_vec_pretty(&release)?)?;
+    fs::rename(&dir, releases_dir(config_dir).join(&id))
+        .context("Failed to move the release in place")?;
+    tracing::info!(release = %id, %version, "release saved");
+    Ok(())
+}
+
+/// Remove all but the newest `keep` releases, then the pool files none of
+/// the rest uses.
+fn prune(config_dir: &Path, keep: usize) -> Result<()> {
+    let releases = list(config_dir);
+    for release in releases.iter().skip(keep) {
+        let dir = releases_dir(config_dir).join(&release.id);
+        fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+    }
+    let used: BTreeSet<&String> = releases
+        .iter()
+        .take(keep)
+        .flat_map(|release| release.manifest.values())
+        .collect();
+    for entry in fs::read_dir(pool_dir(config_dir))
+        .into_iter()
+        .flatten()
+        .flatten()
+    {
+        for file in fs::read_dir(entry.path()).into_iter().flatten().flatten() {
+            let name = file.file_name().to_string_lossy().into_owned();
+            if !used.contains(&name) {
+                let _ = fs::remove_file(file.path());
+            }
+        }
+        let _ = fs::remove_dir(entry.path());
+    }
+    Ok(())
+}
+
+/// Replace the workspace with a copy of `release`. The source's state is
+/// forgotten, so the next sync fetches the latest content afresh.
+pub fn restore(config_dir: &Path, release: &Release) -> Result<()> {
+    let dir = releases_dir(config_dir).join(&release.id);
+    let web_dir = config_dir.join("web");
+    let staging = config_dir.join("web.restoring");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    // Copies, not links: the workspace must never write through to the pool
+    for rel in release.manifest.keys() {
+        let target = staging.join(rel);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(dir.join(rel), &target).with_context(|| format!("Failed to restore {rel}"))?;
+    }
+
+    let previous = config_dir.join("web.previous");
+    if previous.exists() {
+        fs::remove_dir_all(&previous)?;
+    }
+    if web_dir.exists() {
+        fs::rename(&web_dir, &previous).context("Failed to move the old content aside")?;
+    }
+    if let Err(e) = fs::rename(&staging, &web_dir) {
+        let _ = fs::rename(&previous, &web_dir);
+        return Err(e).context("Failed to move the release in place");
+    }
+    let _ = fs::remove_dir_all(&previous);
+
+    source::forget(config_dir)?;
+    let restored = Restored {
+        id: release.id.clone(),
+        version: release.version.clone(),
+    };
+    fs::write(
+        releases_dir(config_dir).join("restored.json"),
+        serde_json::to_vec_pretty(&restored)?,
+    )?;
+    tracing::info!(release = %release.id, version = %release.version, "release restored");
+    Ok(())
+}
+
+/// Store `file` in the pool under `hash` unless it is there already, and
+/// return its path in the pool.
+fn pool_file(config_dir: &Path, file: &Path, hash: &str) -> Result<PathBuf> {
+    let pooled = pool_dir(config_dir).join(&hash[..2]).join(hash);
+    if pooled.exists() {
+        return Ok(pooled);
+    }
+    if let Some(dir) = pooled.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = pooled.with_extension("partial");
+    fs::copy(file, &partial).with_context(|| format!("Failed to store {}", file.display()))?;
+    fs::rename(&partial, &pooled)?;
+    Ok(pooled)
+}
@@ -0,0 +1,113 @@
+//! Requirements a served app declares in `tbl.requires.yaml` at the root of
+//! its repository:
+//!
+//! ```yaml
+//! tbl: ">=0.2"               # constraint on the version of tbl
+//! capabilities: [kv, files]  # optional subsystems the app uses
+//! binaries: [ffmpeg]         # programs it needs on the host's PATH
+//! ```
+//!
+//! They are checked after every sync and when the server starts. Unmet ones
+//! are logged and reported by `tbl sync` and `/api/v1/info`, and the app's
+//! pages are replaced by a page listing them.
+
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    capability::Capability,
+    semver::{Version, VersionReq},
+};
+
+pub const FILE: &str = "tbl.requires.yaml";
+
+#[derive(Deserialize, Debug, Default)]
+struct Requirements {
+    #[serde(default)]
+    tbl: Option<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+    #[serde(default)]
+    binaries: Vec<String>,
+    /// Keys a newer tbl may know
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_yaml::Value>,
+}
+
+/// What the app in `web_dir` needs but this instance, with the `disabled`
+/// capabilities, lacks, each as a message saying how to fix it; empty when
+/// it declares nothing.
+pub fn unmet(web_dir: &Path, disabled: &[Capability]) -> Vec<String> {
+    let data = match fs::read_to_string(web_dir.join(FILE)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => return vec![format!("Failed to read {FILE}: {e}")],
+    };
+    // An empty file parses as null
+    let requirements = match serde_yaml::from_str::<Option<Requirements>>(&data) {
+        Ok(requirements) => requirements.unwrap_or_default(),
+        Err(e) => {
+            return vec![format!(
+                "{FILE} is invalid ({e}); fix it in the app's repository"
+            )]
+        }
+    };
+
+    let mut problems = Vec::new();
+    if let Some(req) = &requirements.tbl {
+        let ours: Version = env!("CARGO_PKG_VERSION")
+            .parse()
+            .expect("the package version is a semantic version");
+        match req.parse::<VersionReq>() {
+            Ok(parsed) if parsed.matches(&ours) => {}
+            Ok(_) => problems.push(format!("Needs tbl {req}, this is {ours}; upgrade tbl")),
+            Err(e) => problems.push(format!(
+                "{FILE} asks for tbl {req:?}: {e:#}; fix it in the app's repository"
+            )),
+        }
+    }
+    for name in &requirements.capabilities {
+        match Capability::named(name) {
+            Some(capability) if capability.enabled(disabled) => {}
+            Some(capability) if capability.provided() => problems.push(format!(
+                "Needs the {name:?} capability, which is disabled here; remove it from `disable`"
+            )),
+            Some(_) => problems.push(format!(
+                "Needs the {name:?} capability, which this version of tbl does not provide"
+            )),
+            None => problems.push(format!(
+                "Needs the {name:?} capability, which this version of tbl lacks; upgrade tbl"
+            )),
+        }
+    }
+    for binary in &requirements.binaries {
+        if !on_path(binary) {
+            problems.push(format!(
+                "Needs the program {binary:?}, which is not on the PATH; install it on this machine"
+            ));
+        }
+    }
+    for key in requirements.other.keys() {
+        problems.push(format!(
+            "{FILE} lists {key:?}, which this version of tbl does not know; upgrade tbl"
+        ));
+    }
+    problems
+}
+
+/// Whether `program` is a file in a directory of `PATH`, or a path to one.
+fn on_path(program: &str) -> bool {
+    let names = if cfg!(windows) {
+        vec![program.to_string(), format!("{program}.exe")]
+    } else {
+        vec![program.to_string()]
+    };
+    if program.contains(['/', '\\']) {
+        return names.iter().any(|name| Path::new(name).is_file());
+    }
+    let Some(paths) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&paths).any(|dir| names.iter().any(|name| dir.join(name).is_file()))
+}
@@ -0,0 +1,571 @@
+//! S3 sources: the objects below `s3://bucket/prefix` served in place of a
+//! git repository.
+//!
+//! Works with AWS and S3-compatible stores (MinIO, Ceph, R2, ...), signing
+//! requests with SigV4 when credentials are configured and reading public
+//! buckets anonymously otherwise. A sync lists the prefix and downloads only
+//! the objects whose ETag changed since the last one, recorded in
+//! `<config_dir>/s3/state.json`; objects gone from the bucket are removed.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use hmac::{Hmac, Mac};
+use reqwest::blocking::{Client, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    secret::Secret,
+    source::{self, Context, SourceProvider},
+};
+
+/// Region used when neither the config nor the environment names one.
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// SHA-256 of an empty body, which every request here has.
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// `s3` config section. The standard `AWS_*` variables override it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct S3Config {
+    /// S3-compatible endpoint, e.g. `https://minio.example.com:9000`; AWS
+    /// when unset (`AWS_ENDPOINT_URL_S3`, `AWS_ENDPOINT_URL`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// `AWS_REGION`, `AWS_DEFAULT_REGION`; defaults to `us-east-1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Address buckets as `<endpoint>/<bucket>` rather than as a subdomain;
+    /// the default with a custom endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_style: Option<bool>,
+    /// `AWS_ACCESS_KEY_ID`; without one, requests are anonymous
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_key_id: Option<String>,
+    /// `AWS_SECRET_ACCESS_KEY`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_access_key: Option<Secret>,
+    /// `AWS_SESSION_TOKEN`, for temporary credentials
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<Secret>,
+}
+
+impl S3Config {
+    pub fn redact_secrets(&mut self) {
+        for secret in [&mut self.secret_access_key, &mut self.session_token] {
+            if secret.is_some() {
+                *secret = Some(Secret::new("<redacted>"));
+            }
+        }
+    }
+}
+
+/// Bucket prefixes on AWS or an S3-compatible store.
+pub struct S3;
+
+impl SourceProvider for S3 {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    fn handles(&self, url: &str) -> bool {
+        is_s3_url(url)
+    }
+
+    fn sync(&self, ctx: &Context, url: &str) -> Result<()> {
+        sync(ctx, url)
+    }
+
+    fn installed_url(&self, web_dir: &Path) -> Option<String> {
+        installed(web_dir).map(|state| state.url)
+    }
+
+    /// Digest of the keys and ETags of the objects synced.
+    fn version(&self, web_dir: &Path) -> Option<String> {
+        installed(web_dir).map(|state| state.version)
+    }
+}
+
+/// Whether `url` names an S3 prefix rather than a git repository.
+fn is_s3_url(url: &str) -> bool {
+    url.trim()
+        .get(..5)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("s3://"))
+}
+
+/// Bucket and key prefix of `s3://bucket/prefix`. The prefix names a folder,
+/// so it ends in `/` unless it is empty.
+fn parse_url(url: &str) -> Result<(String, String)> {
+    let rest = url.trim().get(5..).unwrap_or_default();
+    let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        anyhow::bail!("no bucket in {url}");
+    }
+    let prefix = prefix.trim_matches('/');
+    let prefix = if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{prefix}/")
+    };
+    Ok((bucket.to_string(), prefix))
+}
+
+/// What the workspace holds: the ETag of every file, by path.
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    url: String,
+    /// Digest of all paths and ETags, standing in for a commit
+    version: String,
+    objects: BTreeMap<String, String>,
+}
+
+impl State {
+    fn load(path: &Path) -> Option<Self> {
+        serde_json::from_slice(&fs::read(path).ok()?).ok()
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+fn state_path(web_dir: &Path) -> PathBuf {
+    web_dir.with_file_name("s3").join("state.json")
+}
+
+/// What was synced into `web_dir`, if it holds a bucket prefix.
+fn installed(web_dir: &Path) -> Option<State> {
+    if !web_dir.is_dir() || web_dir.join(".git").exists() {
+        return None;
+    }
+    State::load(&state_path(web_dir))
+}
+
+/// Bring the workspace in line with the objects below `url`, downloading
+/// only what changed. What was downloaded before a failure is kept and not
+/// downloaded again.
+fn sync(ctx: &Context, url: &str) -> Result<()> {
+    let (bucket, prefix) = parse_url(url)?;
+    // The blocking client refuses to run on a thread of the async runtime,
+    // which the sync before the server starts is on
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let store = Store::new(&ctx.settings.s3, &bucket)?;
+                sync_objects(&store, ctx, url, &prefix)
+            })
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+fn sync_objects(store: &Store, ctx: &Context, url: &str, prefix: &str) -> Result<()> {
+    let web_dir = &ctx.web_dir;
+    ctx.progress("listing");
+    let mut wanted = BTreeMap::new();
+    for object in store.list(prefix)? {
+        // Folder placeholders have no content of their own
+        let Some(path) = object
+            .key
+            .strip_prefix(prefix)
+            .filter(|p| !p.ends_with('/'))
+        else {
+            continue;
+        };
+        if relative_path(path).is_none() {
+            tracing::warn!("Skipping S3 object with an unsafe key: {}", object.key);
+            continue;
+        }
+        wanted.insert(path.to_string(), object.etag);
+    }
+
+    // Content of another source goes; it shares nothing with this one
+    let state_path = state_path(web_dir);
+    let mut state = match installed(web_dir).filter(|state| state.url == url) {
+        Some(state) => state,
+        None => {
+            if web_dir.exists() {
+                fs::remove_dir_all(web_dir)
+                    .with_context(|| format!("Failed to clear {}", web_dir.display()))?;
+            }
+            State {
+                url: url.to_string(),
+                ..State::default()
+            }
+        }
+    };
+    fs::create_dir_all(web_dir)?;
+    if let Some(dir) = state_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let changed: Vec<(&String, &String)> = wanted
+        .iter()
+        .filter(|(path, etag)| {
+            state.objects.get(*path) != Some(etag) || !web_dir.join(path).is_file()
+        })
+        .collect();
+    for (i, (path, etag)) in changed.iter().enumerate() {
+        ctx.progress(&format!("downloading {} of {}", i + 1, changed.len()));
+        let key = format!("{prefix}{path}");
+        if let Err(e) = store.download(&key, &web_dir.join(path)) {
+            let _ = state.save(&state_path);
+            return Err(e);
+        }
+        state.objects.insert((*path).clone(), (*etag).clone());
+    }
+
+    ctx.progress("removing");
+    let gone: Vec<String> = state
+        .objects
+        .keys()
+        .filter(|path| !wanted.contains_key(*path))
+        .cloned()
+        .collect();
+    for path in gone {
+        if let Some(rel) = relative_path(&path) {
+            let file = web_dir.join(&rel);
+            let _ = fs::remove_file(&file);
+            source::remove_empty_parents(web_dir, &file);
+        }
+        state.objects.remove(&path);
+    }
+
+    let mut digest = Sha256::new();
+    for (path, etag) in &state.objects {
+        digest.update(format!("{path}\0{etag}\n"));
+    }
+    state.version = hex::encode(digest.finalize())[..40].to_string();
+    state.save(&state_path)
+}
+
+/// `path` as a relative path that stays inside the web root.
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let rel = PathBuf::from(path);
+    let safe = rel
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    (safe && !path.is_empty()).then_some(rel)
+}
+
+struct Object {
+    key: String,
+    etag: String,
+}
+
+/// Credentials for signing requests.
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+/// A bucket, as reached through its endpoint.
+struct Store {
+    client: Client,
+    /// Scheme and host of every request, e.g. `https://bucket.s3.us-east-1.amazonaws.com`
+    origin: String,
+    /// `Host` header as signed
+    host: String,
+    /// `/bucket` for path-style addressing, empty otherwise
+    base_path: String,
+    region: String,
+    credentials: Option<Credentials>,
+}
+
+impl Store {
+    fn new(config: &S3Config, bucket: &str) -> Result<Self> {
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let region = env("AWS_REGION")
+            .or_else(|| env("AWS_DEFAULT_REGION"))
+            .or_else(|| config.region.clone())
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        let endpoint = env("AWS_ENDPOINT_URL_S3")
+            .or_else(|| env("AWS_ENDPOINT_URL"))
+            .or_else(|| config.endpoint.clone());
+
+        // Bucket names with dots don't match AWS's wildcard certificate
+        let path_style = config
+            .path_style
+            .unwrap_or(endpoint.is_some() || bucket.contains('.'));
+        let endpoint = endpoint.unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com"));
+        let endpoint = reqwest::Url::parse(&endpoint)
+            .with_context(|| format!("invalid S3 endpoint {endpoint}"))?;
+        let mut host = endpoint
+            .host_str()
+            .context("S3 endpoint has no host")?
+            .to_string();
+        if let Some(port) = endpoint.port() {
+            host = format!("{host}:{port}");
+        }
+        let (host, base_path) = if path_style {
+            (host, format!("/{}", uri_encode(bucket, false)))
+        } else {
+            (format!("{bucket}.{host}"), String::new())
+        };
+
+        let credentials = match (
+            env("AWS_ACCESS_KEY_ID").or_else(|| config.access_key_id.clone()),
+            env("AWS_SECRET_ACCESS_KEY").or_else(|| {
+                config
+                    .secret_access_key
+                    .as_ref()
+                    .map(|secret| secret.expose().to_string())
+            }),
+        ) {
+            (Some(access_key_id), Some(secret_access_key)) => Some(Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: env("AWS_SESSION_TOKEN").or_else(|| {
+                    config
+                        .session_token
+                        .as_ref()
+                        .map(|token| token.expose().to_string())
+                }),
+            }),
+            _ => None,
+        };
+
+        Ok(Self {
+            client: Client::builder()
+                .user_agent(concat!("tbl/", env!("CARGO_PKG_VERSION")))
+                .build()?,
+            origin: format!("{}://{host}", endpoint.scheme()),
+            host,
+            base_path,
+            region,
+            credentials,
+        })
+    }
+
+    /// Every object below `prefix`, following continuation tokens.
+    fn list(&self, prefix: &str) -> Result<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("list-type".to_string(), "2".to_string()),
+                ("prefix".to_string(), prefix.to_string()),
+            ];
+            if let Some(token) = &token {
+                query.push(("continuation-token".to_string(), token.clone()));
+            }
+            let body = self.get("/", &query)?.text()?;
+            for contents in elements(&body, "Contents") {
+                if let (Some(key), Some(etag)) = (text(contents, "Key"), text(contents, "ETag")) {
+                    objects.push(Object { key, etag });
+                }
+            }
+            token = text(&body, "NextContinuationToken");
+            if text(&body, "IsTruncated").as_deref() != Some("true") || token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+
+    /// Download the object `key` to `target`, replacing it only once complete.
+    fn download(&self, key: &str, target: &Path) -> Result<()> {
+        let mut response = self.get(&format!("/{}", uri_encode(key, false)), &[])?;
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = target.with_extension("tbl-download");
+        let written = fs::File::create(&partial)
+            .and_then(|mut file| {
+                std::io::copy(&mut response, &mut file)?;
+                file.flush()
+            })
+            .with_context(|| format!("Failed to download {key}"))
+            .and_then(|()| {
+                fs::rename(&partial, target)
+                    .with_context(|| format!("Failed to write {}", target.display()))
+            });
+        if written.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        written
+    }
+
+    /// Signed GET of `path` (below the bucket, already URI-encoded).
+    fn get(&self, path: &str, query: &[(String, String)]) -> Result<Response> {
+        let path = format!("{}{path}", self.base_path);
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("&");
+        let url = if query.is_empty() {
+            format!("{}{path}", self.origin)
+        } else {
+            format!("{}{path}?{query}", self.origin)
+        };
+
+        let mut request = self.client.get(&url);
+        if let Some(credentials) = &self.credentials {
+            let now = chrono::Utc::now();
+            for (name, value) in self.sign(credentials, &path, &query, now) {
+                request = request.header(name, value);
+            }
+        }
+        let response = request.send()?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().unwrap_or_default();
+        match (text(&body, "Code"), text(&body, "Message")) {
+            (Some(code), Some(message)) => anyhow::bail!("S3 answered {status}: {code}: {message}"),
+            _ => anyhow::bail!("S3 answered {status}"),
+        }
+    }
+
+    /// Headers that sign a GET of `path?query` with AWS Signature Version 4.
+    fn sign(
+        &self,
+        credentials: &Credentials,
+        path: &str,
+        query: &str,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Vec<(&'static str, String)> {
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &timestamp[..8];
+        let mut headers = vec![
+            ("host", self.host.clone()),
+            ("x-amz-content-sha256", EMPTY_SHA256.to_string()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let canonical_request =
+            format!("GET\n{path}\n{query}\n{canonical_headers}\n{signed_headers}\n{EMPTY_SHA256}");
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request))
+        );
+
+        let key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac_sha256(
+                format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+                date,
+            ),
+            |key, part| hmac_sha256(&key, part),
+        );
+        let signature = hex::encode(hmac_sha256(&key, &string_to_sign));
+
+        // `host` is set by the HTTP client from the URL
+        headers.remove(0);
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                credentials.access_key_id
+            ),
+        ));
+        headers
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode everything but unreserved characters, as SigV4 requires;
+/// `/` stays as is in paths.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Content of every `<tag>...</tag>` in `xml`. S3's responses are simple
+/// enough not to need a full XML parser.
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        found.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    found
+}
+
+/// Unescaped text of the first `<tag>` in `xml`.
+fn text(xml: &str, tag: &str) -> Option<String> {
+    elements(xml, tag).first().map(|value| unescape(value))
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
@@ -0,0 +1,397 @@
+//! Confining the daemon on Linux (`sandbox: strict`), so a served repository
+//! that turns out to be hostile, or a bug it triggers, can do less harm.
+//!
+//! - Landlock limits the filesystem: tbl's own directories are writable,
+//!   system directories (`/usr`, `/etc`, ...) and the files the config names
+//!   are readable, and everything else, e.g. the rest of the home directory,
+//!   is off limits.
+//! - A seccomp filter refuses system calls tbl and git never make, like
+//!   loading kernel modules, mounting, tracing other processes, or `bpf`.
+//!
+//! Commands tbl runs, git, hooks and notifiers, inherit both. Landlock only
+//! reaches the thread that asks for it and the threads it starts afterwards,
+//! so the daemon is confined before its async runtime starts any.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How the daemon is confined.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Sandbox {
+    /// Not at all
+    #[default]
+    Off,
+    /// Landlock and seccomp (Linux 5.19 or later)
+    Strict,
+}
+
+/// Paths the confined daemon may use, besides the system directories.
+#[derive(Debug, Default)]
+pub struct Rules {
+    /// Directories to create, change, and delete files in
+    pub read_write: Vec<PathBuf>,
+    /// Files and directories to read and run programs from
+    pub read_only: Vec<PathBuf>,
+}
+
+/// Confine this process for good. Fails rather than running unconfined
+/// when the kernel can't.
+#[cfg(target_os = "linux")]
+pub fn confine(rules: &Rules) -> Result<()> {
+    // Both need it to be used without root, and it keeps children from
+    // gaining privileges through setuid programs
+    // SAFETY: prctl with integer arguments
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    landlock::restrict(rules)?;
+    seccomp::install()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn confine(_rules: &Rules) -> Result<()> {
+    check()
+}
+
+/// Whether the daemon can be confined here, or why not.
+#[cfg(target_os = "linux")]
+pub fn check() -> Result<()> {
+    landlock::abi().map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check() -> Result<()> {
+    anyhow::bail!("sandbox: strict is only supported on Linux; set sandbox: off")
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+    use anyhow::{Context as _, Result};
+
+    use super::Rules;
+
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    /// Everything ABI 1 knows: the above plus removing and making entries
+    const ABI_1: u64 = (1 << 13) - 1;
+    /// Linking and renaming into another directory (ABI 2)
+    const REFER: u64 = 1 << 13;
+    /// Truncating files (ABI 3)
+    const TRUNCATE: u64 = 1 << 14;
+    /// Rights that apply to files rather than directories
+    const FILE_RIGHTS: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+    /// Where programs, libraries, and system settings live.
+    const SYSTEM_DIRS: &[&str] = &[
+        "/usr",
+        "/bin",
+        "/sbin",
+        "/lib",
+        "/lib32",
+        "/lib64",
+        "/opt",
+        "/nix",
+        "/etc",
+        "/proc",
+        "/sys",
+        // Where /etc/resolv.conf points with systemd-resolved
+        "/run/systemd/resolve",
+    ];
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// The Landlock ABI version of the kernel, if it is recent enough.
+    pub fn abi() -> Result<i64> {
+        // SAFETY: asking for the ABI version takes no attribute
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        // Without REFER every link or rename into another directory fails,
+        // which git does all the time
+        if abi < 2 {
+            anyhow::bail!(
+                "sandbox: strict needs Landlock ABI 2 (Linux 5.19) with landlock among the enabled LSMs; this kernel has {}",
+                if abi < 0 { "none".to_string() } else { format!("ABI {abi}") }
+            );
+        }
+        Ok(abi)
+    }
+
+    pub fn restrict(rules: &Rules) -> Result<()> {
+        let abi = abi()?;
+        let handled = ABI_1 | REFER | if abi >= 3 { TRUNCATE } else { 0 };
+        let read_only = (EXECUTE | READ_FILE | READ_DIR) & handled;
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: `attr` is valid for its size
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to create a Landlock ruleset");
+        }
+        let ruleset = fd as libc::c_int;
+        let result = (|| {
+            for dir in SYSTEM_DIRS {
+                add(ruleset, Path::new(dir), read_only)?;
+            }
+            // /dev/null and /dev/urandom, but making device nodes stays out
+            add(
+                ruleset,
+                Path::new("/dev"),
+                (READ_FILE | WRITE_FILE | READ_DIR | TRUNCATE) & handled,
+            )?;
+            for path in &rules.read_only {
+                add(ruleset, path, read_only)?;
+            }
+            for path in &rules.read_write {
+                add(ruleset, path, handled)?;
+            }
+            // SAFETY: `ruleset` is a Landlock ruleset descriptor
+            if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) } != 0 {
+                return Err(io::Error::last_os_error())
+                    .context("Failed to enforce the Landlock ruleset");
+            }
+            Ok(())
+        })();
+        // SAFETY: closing the descriptor created above
+        unsafe { libc::close(ruleset) };
+        result
+    }
+
+    /// Allow `access` beneath `path`, which is skipped if it doesn't exist.
+    fn add(ruleset: libc::c_int, path: &Path, access: u64) -> Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: `c_path` is NUL-terminated
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Ok(());
+        }
+        let allowed = if path.is_dir() {
+            access
+        } else {
+            access & FILE_RIGHTS
+        };
+        let attr = PathBeneathAttr {
+            allowed_access: allowed,
+            parent_fd: fd,
+        };
+        // SAFETY: `attr` is valid and `fd` is open
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset,
+                RULE_PATH_BENEATH,
+                &attr,
+                0,
+            )
+        };
+        let error = io::Error::last_os_error();
+        // SAFETY: closing the descriptor opened above
+        unsafe { libc::close(fd) };
+        if rc != 0 {
+            return Err(error).with_context(|| format!("Failed to allow {}", path.display()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod seccomp {
+    use std::io;
+
+    use anyhow::{Context as _, Result};
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Offsets in `struct seccomp_data`.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    /// System calls refused with `EPERM`: administration of the machine,
+    /// kernel modules, mounts and namespaces, debugging other processes, and
+    /// kernel interfaces that keep turning up in exploits.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_kcmp,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+        libc::SYS_quotactl,
+        libc::SYS_syslog,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_adjtimex,
+        libc::SYS_clock_adjtime,
+        libc::SYS_sethostname,
+        libc::SYS_setdomainname,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_open_tree,
+        libc::SYS_move_mount,
+        libc::SYS_fsopen,
+        libc::SYS_fsconfig,
+        libc::SYS_fsmount,
+        libc::SYS_fspick,
+        libc::SYS_mount_setattr,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_fanotify_init,
+        libc::SYS_vhangup,
+        libc::SYS_lookup_dcookie,
+        libc::SYS_nfsservctl,
+        libc::SYS_io_uring_setup,
+        libc::SYS_io_uring_enter,
+        libc::SYS_io_uring_register,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_iopl,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_ioperm,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_uselib,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_ustat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_sysfs,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS__sysctl,
+    ];
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn install() -> Result<()> {
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let load = |offset| bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset);
+        let ret = |action| bpf_stmt(libc::BPF_RET | libc::BPF_K, action);
+
+        // System calls of another ABI, e.g. 32-bit ones, would get past the
+        // numbers below
+        let mut filter = vec![
+            load(ARCH_OFFSET),
+            bpf_jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                AUDIT_ARCH,
+                1,
+                0,
+            ),
+            ret(deny),
+            load(NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([
+            // x32 system calls have this bit set
+            bpf_jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                0x4000_0000,
+                0,
+                1,
+            ),
+            ret(deny),
+        ]);
+        for nr in DENIED {
+            filter.push(bpf_jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                *nr as u32,
+                0,
+                1,
+            ));
+            filter.push(ret(deny));
+        }
+        filter.push(ret(libc::SECCOMP_RET_ALLOW));
+
+        let program = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+        // SAFETY: `program` points to `filter`, which outlives the call. TSYNC
+        // applies the filter to every thread of the process.
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to install the seccomp filter");
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn install() -> Result<()> {
+        eprintln!("  No seccomp filter for this architecture; only Landlock applies");
+        Ok(())
+    }
+
+    fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+}
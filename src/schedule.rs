@@ -0,0 +1,182 @@
+//! Serving windows such as `08:00-18:00 Mon-Fri`, evaluated in local time.
+//!
+//! A schedule is one or more windows separated by `;`. Each window is a time
+//! range, optionally followed by days (`Mon-Fri`, `Sat,Sun`, `daily`). Ranges
+//! whose end is before their start run past midnight (`22:00-06:00`).
+
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, Duration, Local, Timelike};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+const FULL_DAY_NAMES: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    spec: String,
+    windows: Vec<Window>,
+}
+
+#[derive(Debug, Clone)]
+struct Window {
+    /// Minutes since midnight
+    start: u32,
+    end: u32,
+    /// Indexed by days from Monday
+    days: [bool; 7],
+}
+
+impl Schedule {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let windows = spec
+            .split(';')
+            .map(str::trim)
+            .filter(|w| !w.is_empty())
+            .map(|w| Window::parse(w).with_context(|| format!("invalid serving window {w:?}")))
+            .collect::<Result<Vec<_>>>()?;
+        if windows.is_empty() {
+            bail!("empty schedule");
+        }
+        Ok(Self {
+            spec: spec.trim().to_string(),
+            windows,
+        })
+    }
+
+    pub fn is_open(&self, now: DateTime<Local>) -> bool {
+        let day = now.weekday().num_days_from_monday() as usize;
+        let minute = now.hour() * 60 + now.minute();
+        self.windows.iter().any(|w| w.contains(day, minute))
+    }
+
+    /// Start of the next window after `now`, within the coming week.
+    pub fn next_open(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        (0..=7)
+            .flat_map(|offset| {
+                let date = now.date_naive() + Duration::days(offset);
+                let day = date.weekday().num_days_from_monday() as usize;
+                self.windows
+                    .iter()
+                    .filter(move |w| w.days[day])
+                    .filter_map(move |w| {
+                        date.and_hms_opt(w.start / 60, w.start % 60, 0)?
+                            .and_local_timezone(Local)
+                            .earliest()
+                    })
+            })
+            .filter(|start| *start > now)
+            .min()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+impl Window {
+    fn parse(spec: &str) -> Result<Self> {
+        let mut parts = spec.split_whitespace();
+        let range = parts.next().context("missing time range")?;
+        let days = parts.collect::<Vec<_>>().join("");
+
+        let (start, end) = range
+            .split_once('-')
+            .context("time range must look like 08:00-18:00")?;
+        Ok(Self {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            days: parse_days(&days)?,
+        })
+    }
+
+    fn contains(&self, day: usize, minute: u32) -> bool {
+        let yesterday = (day + 6) % 7;
+        if self.start < self.end {
+            self.days[day] && (self.start..self.end).contains(&minute)
+        } else if self.start == self.end {
+            // Same start and end: the whole day
+            self.days[day]
+        } else {
+            // Overnight: the tail belongs to the day the window started
+            (self.days[day] && minute >= self.start) || (self.days[yesterday] && minute < self.end)
+        }
+    }
+}
+
+/// Seconds from `now` until the next `HH:MM` in local time: today, or
+/// tomorrow once it has passed.
+pub fn seconds_until(time: &str, now: DateTime<Local>) -> Result<u64> {
+    let target = parse_time(time)? * 60;
+    let day = MINUTES_PER_DAY * 60;
+    let secs = (target + day - now.num_seconds_from_midnight() % day) % day;
+    Ok(if secs == 0 { day } else { secs }.into())
+}
+
+/// `HH:MM` to minutes since midnight; `24:00` is accepted as an end time.
+fn parse_time(value: &str) -> Result<u32> {
+    let (hours, minutes) = value
+        .trim()
+        .split_once(':')
+        .with_context(|| format!("time {value:?} must look like HH:MM"))?;
+    let hours: u32 = hours
+        .parse()
+        .with_context(|| format!("bad hour in {value:?}"))?;
+    let minutes: u32 = minutes
+        .parse()
+        .with_context(|| format!("bad minute in {value:?}"))?;
+    let total = hours * 60 + minutes;
+    if minutes >= 60 || total > MINUTES_PER_DAY {
+        bail!("time {value:?} is out of range");
+    }
+    Ok(total % MINUTES_PER_DAY)
+}
+
+/// `Mon-Fri`, `Sat,Sun`, `Mon-Wed,Fri`, or empty/`daily` for every day.
+fn parse_days(value: &str) -> Result<[bool; 7]> {
+    let value = value.trim().to_ascii_lowercase();
+    if value.is_empty() || value == "daily" {
+        return Ok([true; 7]);
+    }
+
+    let mut days = [false; 7];
+    for part in value.split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (day_index(from)?, day_index(to)?);
+                let mut day = from;
+                loop {
+                    days[day] = true;
+                    if day == to {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => days[day_index(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+/// Index of a day named `Mon` or `Monday`, in any case.
+fn day_index(name: &str) -> Result<usize> {
+    let name = name.trim().to_ascii_lowercase();
+    DAY_NAMES
+        .iter()
+        .zip(FULL_DAY_NAMES)
+        .position(|(short, full)| name == *short || name == full)
+        .with_context(|| format!("unknown day {name:?} (use Mon, Tue, ... Sun)"))
+}
@@ -0,0 +1,340 @@
+//! Full-text search over the served site.
+//!
+//! After every clone or update the HTML and Markdown files of the checkout are
+//! read into an in-memory inverted index that answers `/api/v1/search`. Sites
+//! served by tbl are small, so the index is rebuilt from scratch each time
+//! rather than kept on disk or updated file by file.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Files larger than this are left out of the index.
+const MAX_FILE_SIZE: u64 = 2 * 1024 * 1024;
+/// Stop indexing after this many documents.
+const MAX_DOCUMENTS: usize = 10_000;
+/// A term in the title counts this many times.
+const TITLE_WEIGHT: u32 = 3;
+/// Words of context around the first match in a snippet.
+const SNIPPET_BEFORE: usize = 8;
+const SNIPPET_AFTER: usize = 24;
+
+#[derive(Default)]
+pub struct SearchIndex {
+    documents: Vec<Document>,
+    /// Term → (document, occurrences), documents in ascending order
+    terms: BTreeMap<String, Vec<(usize, u32)>>,
+    /// Estimate of the memory held
+    bytes: u64,
+}
+
+struct Document {
+    /// Path below the web root with `/` separators, e.g. `guide/setup.html`
+    path: String,
+    title: String,
+    text: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct SearchHit {
+    /// Path below `/web/`, e.g. `guide/setup.html`
+    pub path: String,
+    pub title: String,
+    /// Text around the first match
+    pub snippet: String,
+    pub score: f32,
+}
+
+impl SearchIndex {
+    /// Index the `.html`, `.htm`, and `.md` files under `web_root`, skipping
+    /// hidden files and directories such as `.git`. Files are left out once
+    /// the index holds `max_bytes`.
+    pub fn build(web_root: &Path, max_bytes: Option<u64>) -> Self {
+        let mut files = Vec::new();
+        collect_files(web_root, &mut files);
+        files.sort();
+
+        let mut index = Self::default();
+        let total = files.len().min(MAX_DOCUMENTS);
+        for (done, path) in files.into_iter().take(MAX_DOCUMENTS).enumerate() {
+            if max_bytes.is_some_and(|max| index.bytes >= max) {
+                tracing::warn!(
+                    "The search index is full (memory.search_index); {} of {total} files are left out",
+                    total - done
+                );
+                break;
+            }
+            let Ok(content) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let Some(rel) = relative_path(web_root, &path) else {
+                continue;
+            };
+            let markdown = path.extension().is_some_and(|ext| ext == "md");
+            let (title, text) = if markdown {
+                markdown_text(&content)
+            } else {
+                html_text(&content)
+            };
+            let title = title.unwrap_or_else(|| rel.clone());
+            index.add(Document {
+                path: rel,
+                title,
+                text,
+            });
+        }
+        index
+    }
+
+    fn add(&mut self, document: Document) {
+        let id = self.documents.len();
+        let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+        for term in tokenize(&document.title) {
+            *counts.entry(term).or_default() += TITLE_WEIGHT;
+        }
+        for term in tokenize(&document.text) {
+            *counts.entry(term).or_default() += 1;
+        }
+        let mut bytes = size_of::<Document>()
+            + document.path.len()
+            + document.title.len()
+            + document.text.len();
+        for (term, count) in counts {
+            if !self.terms.contains_key(&term) {
+                bytes += size_of::<(String, Vec<(usize, u32)>)>() + term.len();
+            }
+            self.terms.entry(term).or_default().push((id, count));
+            bytes += size_of::<(usize, u32)>();
+        }
+        self.documents.push(document);
+        self.bytes += bytes as u64;
+    }
+
+    pub fn document_count(&self) -> usize {
+        self.documents.len()
+    }
+
+    /// Estimate of the memory the index holds, in bytes.
+    pub fn memory_usage(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Documents containing every word of `query`, best first. The last word
+    /// also matches as a prefix, so results appear while typing.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        let words = tokenize(query);
+        if words.is_empty() {
+            return Vec::new();
+        }
+        let total = self.documents.len() as f32;
+
+        let mut scores: Option<BTreeMap<usize, f32>> = None;
+        for (i, word) in words.iter().enumerate() {
+            let prefix = i == words.len() - 1;
+            let mut matches: BTreeMap<usize, f32> = BTreeMap::new();
+            for (term, postings) in self.terms.range(word.clone()..) {
+                let exact = term == word;
+                if !(exact || prefix && term.starts_with(word.as_str())) {
+                    break;
+                }
+                let idf = (1.0 + total / postings.len() as f32).ln();
+                let weight = if exact { 1.0 } else { 0.5 };
+                for &(doc, count) in postings {
+                    *matches.entry(doc).or_default() += weight * count as f32 * idf;
+                }
+            }
+            scores = Some(match scores {
+                None => matches,
+                Some(previous) => previous
+                    .into_iter()
+                    .filter_map(|(doc, score)| matches.get(&doc).map(|s| (doc, score + s)))
+                    .collect(),
+            });
+        }
+
+        let mut ranked: Vec<(usize, f32)> = scores.unwrap_or_default().into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(doc, score)| {
+                let document = &self.documents[doc];
+                SearchHit {
+                    path: document.path.clone(),
+                    title: document.title.clone(),
+                    snippet: snippet(&document.text, &words),
+                    score,
+                }
+            })
+            .collect()
+    }
+}
+
+fn collect_files(dir: &Path, out: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            collect_files(&path, out);
+        } else if file_type.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == "html" || ext == "htm" || ext == "md")
+            && entry.metadata().is_ok_and(|m| m.len() <= MAX_FILE_SIZE)
+        {
+            out.push(path);
+        }
+    }
+}
+
+fn relative_path(root: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(root).ok()?;
+    let parts: Vec<_> = rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    Some(parts.join("/"))
+}
+
+/// Lowercase words of letters and digits.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Title (from `<title>`, else the first `<h1>`) and visible text of a page.
+fn html_text(html: &str) -> (Option<String>, String) {
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut title = None;
+    let mut h1 = None;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        text.push(' ');
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map_or("", |i| &rest[i + 3..]);
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = rest[1..end].trim().to_ascii_lowercase();
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .find(|s| !s.is_empty())
+            .unwrap_or_default()
+            .to_string();
+        rest = &rest[end + 1..];
+        if tag.starts_with('/') {
+            continue;
+        }
+        match name.as_str() {
+            // Skip the element's content entirely
+            "script" | "style" | "template" | "noscript" => {
+                let close = format!("</{name}");
+                rest = find_ignore_case(rest, &close).map_or("", |i| &rest[i..]);
+            }
+            "title" | "h1" => {
+                let close = format!("</{name}");
+                if let Some(i) = find_ignore_case(rest, &close) {
+                    let inner = collapse_whitespace(&decode_entities(&strip_tags(&rest[..i])));
+                    let slot = if name == "title" { &mut title } else { &mut h1 };
+                    if slot.is_none() && !inner.is_empty() {
+                        *slot = Some(inner);
+                    }
+                    // The title is not part of the page body
+                    if name == "title" {
+                        rest = &rest[i..];
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    text.push_str(rest);
+    (title.or(h1), collapse_whitespace(&decode_entities(&text)))
+}
+
+/// Title (the first `# ` heading) and text of a Markdown file.
+fn markdown_text(markdown: &str) -> (Option<String>, String) {
+    let title = markdown
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(|title| title.trim().to_string());
+    (title, collapse_whitespace(markdown))
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+pub fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+pub fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Words around the first word of `text` that matches one of `words`.
+fn snippet(text: &str, words: &[String]) -> String {
+    let all: Vec<&str> = text.split_whitespace().collect();
+    let first = all
+        .iter()
+        .position(|word| {
+            tokenize(word)
+                .iter()
+                .any(|token| words.iter().any(|w| token.starts_with(w.as_str())))
+        })
+        .unwrap_or(0);
+    let start = first.saturating_sub(SNIPPET_BEFORE);
+    let end = (first + SNIPPET_AFTER).min(all.len());
+    let mut snippet = all[start..end].join(" ");
+    if start > 0 {
+        snippet.insert_str(0, "… ");
+    }
+    if end < all.len() {
+        snippet.push_str(" …");
+    }
+    snippet
+}
@@ -0,0 +1,271 @@
+//! Semantic versions and the constraints catalog apps are pinned with, read
+//! the way Cargo reads them: `^2.1` (the default operator), `~2.1.3`,
+//! `=2.1`, `>=2, <3`, `2.*`, `*`.
+//!
+//! Pre-releases (`2.2.0-beta.1`) sort before their release, but an upper
+//! bound keeps them out along with it: `<3` and `^2` exclude `3.0.0-beta.1`.
+//! Whether they are offered at all is up to the app's channel.
+
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use anyhow::{Context as _, Result};
+
+/// A version like `2.1.0` or `v2.2.0-beta.1`; build metadata is ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Vec<Identifier>,
+}
+
+/// A dot-separated part of a pre-release; numbers sort before words.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Identifier {
+    Numeric(u64),
+    Alpha(String),
+}
+
+impl Version {
+    fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            pre: Vec::new(),
+        }
+    }
+
+    pub fn is_prerelease(&self) -> bool {
+        !self.pre.is_empty()
+    }
+
+    fn release(&self) -> Self {
+        Self::new(self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    /// Missing minor or patch numbers count as 0, so `2.1` is `2.1.0`.
+    fn from_str(s: &str) -> Result<Self> {
+        let numbers = s.split(['-', '+']).next().unwrap_or_default();
+        if numbers.contains(['*', 'x', 'X']) {
+            anyhow::bail!("{s:?} is not a version like 2.1.0");
+        }
+        Ok(s.parse::<Partial>()?.version())
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self.pre.cmp(&other.pre),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        for (i, id) in self.pre.iter().enumerate() {
+            f.write_str(if i == 0 { "-" } else { "." })?;
+            match id {
+                Identifier::Numeric(n) => write!(f, "{n}")?,
+                Identifier::Alpha(s) => f.write_str(s)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A version that may leave out its minor and patch numbers, as written in
+/// constraints.
+#[derive(Debug, Clone)]
+struct Partial {
+    major: u64,
+    minor: Option<u64>,
+    patch: Option<u64>,
+    pre: Vec<Identifier>,
+}
+
+impl Partial {
+    fn version(&self) -> Version {
+        Version {
+            pre: self.pre.clone(),
+            ..Version::new(self.major, self.minor.unwrap_or(0), self.patch.unwrap_or(0))
+        }
+    }
+}
+
+impl FromStr for Partial {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let s = s.strip_prefix(['v', 'V']).unwrap_or(s);
+        let s = s.split('+').next().unwrap_or_default();
+        let (numbers, pre) = s.split_once('-').unwrap_or((s, ""));
+        let mut parts = numbers.split('.');
+        let mut number = |required: bool| -> Result<Option<u64>> {
+            match parts.next() {
+                None if !required => Ok(None),
+                Some("*" | "x" | "X") if !required => Ok(None),
+                part => part
+                    .and_then(|part| part.parse().ok())
+                    .map(Some)
+                    .with_context(|| format!("{s:?} is not a version like 2.1.0")),
+            }
+        };
+        let major = number(true)?.unwrap_or_default();
+        let minor = number(false)?;
+        let patch = minor.map_or(Ok(None), |_| number(false))?;
+        if parts.next().is_some() {
+            anyhow::bail!("{s:?} is not a version like 2.1.0");
+        }
+        let pre = pre
+            .split('.')
+            .filter(|id| !id.is_empty())
+            .map(|id| match id.parse() {
+                Ok(n) => Identifier::Numeric(n),
+                Err(_) => Identifier::Alpha(id.to_string()),
+            })
+            .collect();
+        Ok(Self {
+            major,
+            minor,
+            patch,
+            pre,
+        })
+    }
+}
+
+/// A constraint, e.g. `^2.1` or `>=2, <3`: every comparator must match.
+#[derive(Debug, Clone)]
+pub struct VersionReq {
+    comparators: Vec<Comparator>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Tilde,
+    Caret,
+}
+
+#[derive(Debug, Clone)]
+struct Comparator {
+    op: Op,
+    version: Partial,
+}
+
+impl FromStr for VersionReq {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut comparators = Vec::new();
+        for part in s.split(',').map(str::trim) {
+            if part.is_empty() || part == "*" {
+                continue;
+            }
+            let (op, rest) = [
+                (">=", Op::GreaterEq),
+                ("<=", Op::LessEq),
+                (">", Op::Greater),
+                ("<", Op::Less),
+                ("=", Op::Exact),
+                ("~", Op::Tilde),
+                ("^", Op::Caret),
+            ]
+            .into_iter()
+            .find_map(|(prefix, op)| part.strip_prefix(prefix).map(|rest| (op, rest)))
+            .unwrap_or((Op::Caret, part));
+            let version: Partial = rest
+                .parse()
+                .with_context(|| format!("Invalid version constraint {s:?}"))?;
+            // `2.*` and `2.1.x` pin what they spell out
+            let op = if op == Op::Caret && rest.contains(['*', 'x', 'X']) {
+                Op::Exact
+            } else {
+                op
+            };
+            comparators.push(Comparator { op, version });
+        }
+        Ok(Self { comparators })
+    }
+}
+
+impl VersionReq {
+    pub fn matches(&self, version: &Version) -> bool {
+        self.comparators.iter().all(|c| c.matches(version))
+    }
+}
+
+impl Comparator {
+    fn matches(&self, v: &Version) -> bool {
+        let Partial {
+            major,
+            minor,
+            patch,
+            ..
+        } = self.version;
+        let low = self.version.version();
+        // First version past what a partial version spells out: 2.1 -> 2.2.0
+        let past = match (minor, patch) {
+            (None, _) => Version::new(major + 1, 0, 0),
+            (Some(minor), None) => Version::new(major, minor + 1, 0),
+            (Some(minor), Some(patch)) => Version::new(major, minor, patch + 1),
+        };
+        let below = |bound: &Version| {
+            // A pre-release of the bound is not below it
+            if bound.is_prerelease() {
+                v < bound
+            } else {
+                v.release() < *bound
+            }
+        };
+        match self.op {
+            Op::Exact if patch.is_some() => *v == low,
+            Op::Exact => *v >= low && below(&past),
+            Op::Greater if patch.is_some() => *v > low,
+            Op::Greater => *v >= past,
+            Op::GreaterEq => *v >= low,
+            Op::Less => below(&low),
+            Op::LessEq if patch.is_some() => *v <= low,
+            Op::LessEq => below(&past),
+            Op::Tilde => {
+                let upper = match minor {
+                    Some(minor) => Version::new(major, minor + 1, 0),
+                    None => Version::new(major + 1, 0, 0),
+                };
+                *v >= low && below(&upper)
+            }
+            Op::Caret => {
+                let upper = match (major, minor, patch) {
+                    (0, None, _) => Version::new(1, 0, 0),
+                    (0, Some(0), None) => Version::new(0, 1, 0),
+                    (0, Some(0), Some(patch)) => Version::new(0, 0, patch + 1),
+                    (0, Some(minor), _) => Version::new(0, minor + 1, 0),
+                    (major, ..) => Version::new(major + 1, 0, 0),
+                };
+                *v >= low && below(&upper)
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Sending large files with `sendfile(2)` over plain HTTP/1 on Linux, so
+//! their bytes go from the page cache to the socket without passing through
+//! tbl.
+//!
+//! hyper writes every response body itself and can't be handed a file.
+//! Instead, the response's body is zeros, which hyper queues without copying,
+//! and the connection stands in for them: once the response's headers are
+//! written, each write of body bytes becomes a `sendfile` of as many bytes of
+//! the file. The headers are checked first, so if a layer replaced the
+//! response, what hyper wrote goes out unchanged. TLS encrypts in userspace
+//! and HTTP/2 frames the body, so neither takes this path.
+
+use std::{
+    convert::Infallible,
+    fs::File,
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use axum::body::{Body, Bytes};
+use tokio::{
+    io::{AsyncWrite, Interest},
+    net::TcpStream,
+};
+
+/// Body bytes hyper is given per frame; they never reach the socket.
+static ZEROS: [u8; 1024 * 1024] = [0; 1024 * 1024];
+
+/// Longest response head checked before giving up on a response.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// A connection's file to send in place of the next response's body.
+#[derive(Clone, Default)]
+pub struct Handle(Arc<Mutex<Option<Pending>>>);
+
+struct Pending {
+    file: File,
+    offset: i64,
+    length: u64,
+    remaining: u64,
+    /// The response head written so far
+    head: Vec<u8>,
+    /// How much of the blank line ending the head was seen
+    matched: usize,
+    /// Whether the head is out and body writes are now sendfiles
+    body: bool,
+}
+
+impl Handle {
+    /// Whether this platform has `sendfile`.
+    pub fn supported() -> bool {
+        cfg!(target_os = "linux")
+    }
+
+    /// Send `length` bytes of `file` from `offset` in place of the body of
+    /// the response written next, returning that body. The response must be
+    /// a `200` or `206` saying `content-length: <length>`.
+    pub fn send(&self, file: File, offset: u64, length: u64) -> Body {
+        *self.0.lock().unwrap() = Some(Pending {
+            file,
+            offset: offset as i64,
+            length,
+            remaining: length,
+            head: Vec::new(),
+            matched: 0,
+            body: false,
+        });
+        Body::from_stream(futures_util::stream::unfold(length, |left| async move {
+            let n = left.min(ZEROS.len() as u64);
+            (n > 0).then(|| {
+                (
+                    Ok::<_, Infallible>(Bytes::from_static(&ZEROS[..n as usize])),
+                    left - n,
+                )
+            })
+        }))
+    }
+
+    /// Write `bufs` to `stream`, or `None` when nothing is pending and the
+    /// caller writes them itself.
+    pub fn poll_write(
+        &self,
+        stream: &mut TcpStream,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Option<Poll<io::Result<usize>>> {
+        let mut slot = self.0.lock().unwrap();
+        let pending = slot.as_mut()?;
+        if !pending.body {
+            // Write up to the end of the head, then look at what was written
+            let mut matched = pending.matched;
+            let mut head = Vec::with_capacity(bufs.len());
+            for buf in bufs {
+                match end_of_head(&mut matched, buf) {
+                    Some(end) => {
+                        head.push(IoSlice::new(&buf[..end]));
+                        break;
+                    }
+                    None => head.push(IoSlice::new(buf)),
+                }
+            }
+            let poll = Pin::new(&mut *stream).poll_write_vectored(cx, &head);
+            if let Poll::Ready(Ok(written)) = poll {
+                let mut left = written;
+                for buf in &head {
+                    let bytes = &buf[..left.min(buf.len())];
+                    left -= bytes.len();
+                    pending.head.extend_from_slice(bytes);
+                    if end_of_head(&mut pending.matched, bytes).is_some() {
+                        if is_response_for(&pending.head, pending.length) {
+                            pending.body = true;
+                        } else {
+                            *slot = None;
+                        }
+                        break;
+                    }
+                }
+                if slot.as_ref().is_some_and(|p| p.head.len() > MAX_HEAD) {
+                    *slot = None;
+                }
+            }
+            return Some(poll);
+        }
+
+        let offered = bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
+        let count = offered.min(pending.remaining) as usize;
+        if count == 0 {
+            return Some(Poll::Ready(Ok(0)));
+        }
+        let sent = loop {
+            match stream.poll_write_ready(cx) {
+                Poll::Pending => return Some(Poll::Pending),
+                Poll::Ready(Err(e)) => break Err(e),
+                Poll::Ready(Ok(())) => {}
+            }
+            match stream.try_io(Interest::WRITABLE, || {
+                sendfile(stream_fd(stream), &pending.file, &mut pending.offset, count)
+            }) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                result => break result,
+            }
+        };
+        match sent {
+            Ok(n) => {
+                pending.remaining -= n as u64;
+                if pending.remaining == 0 {
+                    *slot = None;
+                }
+            }
+            Err(_) => *slot = None,
+        }
+        Some(Poll::Ready(sent))
+    }
+}
+
+/// Feed `bytes` to the search for the blank line ending a response head,
+/// `matched` bytes of which were seen before; returns where it ends.
+fn end_of_head(matched: &mut usize, bytes: &[u8]) -> Option<usize> {
+    const END: &[u8] = b"\r\n\r\n";
+    for (i, &byte) in bytes.iter().enumerate() {
+        if byte == END[*matched] {
+            *matched += 1;
+            if *matched == END.len() {
+                return Some(i + 1);
+            }
+        } else {
+            *matched = usize::from(byte == b'\r');
+        }
+    }
+    None
+}
+
+/// Whether `head` is the `200` or `206` with a body of `length` bytes the
+/// file was meant for.
+fn is_response_for(head: &[u8], length: u64) -> bool {
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    let status = head.lines().next().unwrap_or_default();
+    status.starts_with("http/1.")
+        && matches!(status.split(' ').nth(1), Some("200" | "206"))
+        && head.contains(&format!("\r\ncontent-length: {length}\r\n"))
+        && !head.contains("\r\ntransfer-encoding:")
+}
+
+#[cfg(unix)]
+fn stream_fd(stream: &TcpStream) -> i32 {
+    use std::os::fd::AsRawFd;
+    stream.as_raw_fd()
+}
+
+#[cfg(not(unix))]
+fn stream_fd(_stream: &TcpStream) -> i32 {
+    -1
+}
+
+#[cfg(target_os = "linux")]
+fn sendfile(socket: i32, file: &File, offset: &mut i64, count: usize) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+    let sent = unsafe { libc::sendfile(socket, file.as_raw_fd(), offset, count) };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(sent as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sendfile(_socket: i32, _file: &File, _offset: &mut i64, _count: usize) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,184 @@
+//! SFTP sources: a directory on a remote host, `sftp://user@host/path`,
+//! served in place of a git repository.
+//!
+//! The directory is mirrored into the workspace with `rsync` over `ssh`, so
+//! an update only transfers files that changed and deletes the ones removed
+//! on the host. Authentication is by key only: ssh runs in batch mode and
+//! fails rather than prompt for a password or an unknown host key.
+
+use std::{path::Path, process::Command};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config_path, run_command,
+    source::{self, Context, Record, SourceProvider},
+};
+
+/// `sftp` config section.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SftpConfig {
+    /// Private key to log in with, e.g. `~/.ssh/tbl_deploy`; ssh's own
+    /// defaults and agent when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<String>,
+    /// `known_hosts` file holding the host's key, instead of
+    /// `~/.ssh/known_hosts`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub known_hosts_file: Option<String>,
+}
+
+/// Whether `url` names a directory on an SFTP host rather than a git
+/// repository.
+fn is_sftp_url(url: &str) -> bool {
+    url.trim()
+        .get(..7)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("sftp://"))
+}
+
+/// Where `sftp://[user@]host[:port]/path` points.
+struct Remote {
+    /// `[user@]host`
+    login: String,
+    port: Option<u16>,
+    /// Absolute path, or relative to the home directory for `/~/path`
+    path: String,
+}
+
+fn parse_url(url: &str) -> Result<Remote> {
+    let rest = url.trim().get(7..).unwrap_or_default();
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let (user, host) = match authority.rsplit_once('@') {
+        Some((user, _)) if user.contains(':') => {
+            anyhow::bail!("passwords are not supported in sftp:// URLs; use a key")
+        }
+        Some((user, host)) => (Some(user), host),
+        None => (None, authority),
+    };
+    let (host, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (
+            name,
+            Some(
+                port.parse()
+                    .with_context(|| format!("Invalid port in {url}"))?,
+            ),
+        ),
+        _ => (host, None),
+    };
+    if host.is_empty() {
+        anyhow::bail!("no host in {url}");
+    }
+    let path = match path.strip_prefix('~') {
+        Some(home) => match home.trim_start_matches('/') {
+            "" => ".".to_string(),
+            home => home.to_string(),
+        },
+        None => format!("/{path}"),
+    };
+    Ok(Remote {
+        login: match user {
+            Some(user) => format!("{user}@{host}"),
+            None => host.to_string(),
+        },
+        port,
+        path,
+    })
+}
+
+/// Directories on SFTP hosts.
+pub struct Sftp;
+
+impl SourceProvider for Sftp {
+    fn name(&self) -> &'static str {
+        "sftp"
+    }
+
+    fn handles(&self, url: &str) -> bool {
+        is_sftp_url(url)
+    }
+
+    fn sync(&self, ctx: &Context, url: &str) -> Result<()> {
+        sync(ctx, url)
+    }
+
+    fn installed_url(&self, web_dir: &Path) -> Option<String> {
+        Record::load(web_dir, self.name()).map(|record| record.url)
+    }
+
+    /// Digest of the files' names, sizes, and modification times.
+    fn version(&self, web_dir: &Path) -> Option<String> {
+        Record::load(web_dir, self.name()).map(|record| record.version)
+    }
+}
+
+/// Mirror the directory at `url` into the workspace. Changed files are
+/// swapped in together at the end of a transfer, so a failed one leaves the
+/// previous content in place.
+fn sync(ctx: &Context, url: &str) -> Result<()> {
+    let remote = parse_url(url)?;
+    let web_dir = &ctx.web_dir;
+    let config = &ctx.settings.sftp;
+    source::claim_workspace(web_dir, Sftp.name(), url)?;
+
+    let mut ssh = vec!["ssh".to_string(), "-oBatchMode=yes".to_string()];
+    if let Some(port) = remote.port {
+        ssh.push(format!("-p{port}"));
+    }
+    if let Some(key) = &config.identity_file {
+        let key = config_path(ctx.config_dir, key).context("Invalid sftp.identity_file")?;
+        ssh.push(format!("-i{}", key.display()));
+        ssh.push("-oIdentitiesOnly=yes".to_string());
+    }
+    if let Some(file) = &config.known_hosts_file {
+        let file = config_path(ctx.config_dir, file).context("Invalid sftp.known_hosts_file")?;
+        ssh.push(format!("-oUserKnownHostsFile={}", file.display()));
+    }
+
+    let mut target = web_dir.as_os_str().to_owned();
+    target.push("/");
+    let output = run_command(
+        ctx.step(
+            Command::new("rsync")
+                .arg("--recursive")
+                .arg("--links")
+                .arg("--safe-links")
+                .arg("--times")
+                .arg("--compress")
+                .arg("--delete")
+                .arg("--delay-updates")
+                .arg("--itemize-changes")
+                .arg("--exclude=/.git")
+                .arg("-e")
+                .arg(rsh_command(&ssh))
+                .arg(format!("{}:{}/", remote.login, remote.path))
+                .arg(target),
+            "syncing",
+        )?,
+        "rsync",
+    )?;
+    if !output.status.success() {
+        anyhow::bail!("rsync failed with status {}", output.status);
+    }
+
+    Record {
+        url: url.to_string(),
+        version: source::tree_digest(web_dir)?,
+    }
+    .save(web_dir, Sftp.name())
+}
+
+/// `ssh` and its arguments as one `rsync -e` value, which rsync splits at
+/// spaces outside quotes.
+fn rsh_command(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            if arg.contains([' ', '"', '\'']) {
+                format!("'{}'", arg.replace('\'', "'\"'\"'"))
+            } else {
+                arg.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,210 @@
+//! Desktop shortcuts that open the served app in its own window.
+//!
+//! `tbl shortcut create` writes a launcher for the current profile that runs
+//! `tbl open --app-mode`: a `.desktop` file on Linux, a Start Menu shortcut on
+//! Windows, and a small `.app` bundle on macOS.
+
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context, Result};
+
+/// What a shortcut launches and how it is labelled.
+pub struct Shortcut {
+    /// File name stem, e.g. `tbl-work`
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    /// Image file shown as the shortcut's icon; Windows takes `.ico` files
+    /// only and macOS bundles keep the default icon
+    pub icon: Option<PathBuf>,
+}
+
+impl Shortcut {
+    /// Write the shortcut where the desktop picks it up; returns its path.
+    pub fn create(&self) -> Result<PathBuf> {
+        create(self)
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn create(shortcut: &Shortcut) -> Result<PathBuf> {
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local").join("share")))
+        .context("Cannot determine the applications directory")?;
+    let dir = data_home.join("applications");
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let exec = std::iter::once(shortcut.program.to_string_lossy().into_owned())
+        .chain(shortcut.args.iter().cloned())
+        .map(|arg| desktop_quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut entry = format!(
+        "[Desktop Entry]\nType=Application\nName={}\nExec={exec}\nTerminal=false\nCategories=Network;\n",
+        desktop_value(&shortcut.name)
+    );
+    if let Some(description) = &shortcut.description {
+        entry.push_str(&format!("Comment={}\n", desktop_value(description)));
+    }
+    if let Some(icon) = &shortcut.icon {
+        entry.push_str(&format!(
+            "Icon={}\n",
+            desktop_value(&icon.to_string_lossy())
+        ));
+    }
+
+    let path = dir.join(format!("{}.desktop", shortcut.id));
+    write_executable(&path, &entry)?;
+    Ok(path)
+}
+
+/// Quote an `Exec` argument per the desktop entry spec.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn desktop_quote(arg: &str) -> String {
+    if arg
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_./=".contains(c))
+    {
+        return arg.to_string();
+    }
+    let escaped: String = arg
+        .chars()
+        .flat_map(|c| match c {
+            '"' | '`' | '$' | '\\' => vec!['\\', c],
+            '%' => vec!['%', '%'],
+            c => vec![c],
+        })
+        .collect();
+    // The whole value is unescaped once more as a string
+    format!("\"{}\"", escaped.replace('\\', "\\\\"))
+}
+
+/// Keep a value on one line.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn desktop_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', " ")
+}
+
+#[cfg(target_os = "macos")]
+fn create(shortcut: &Shortcut) -> Result<PathBuf> {
+    let home = dirs::home_dir().context("Cannot determine the home directory")?;
+    // Bundles want an `.icns` icon, so `shortcut.icon` goes unused
+    let _ = &shortcut.icon;
+    let bundle = home
+        .join("Applications")
+        .join(format!("{}.app", shortcut.name.replace('/', "-")));
+    let macos = bundle.join("Contents").join("MacOS");
+    fs::create_dir_all(&macos).with_context(|| format!("Failed to create {}", macos.display()))?;
+
+    let command = std::iter::once(shortcut.program.to_string_lossy().into_owned())
+        .chain(shortcut.args.iter().cloned())
+        .map(|arg| format!("'{}'", arg.replace('\'', r"'\''")))
+        .collect::<Vec<_>>()
+        .join(" ");
+    write_executable(
+        &macos.join(&shortcut.id),
+        &format!("#!/bin/sh\nexec {command}\n"),
+    )?;
+
+    let plist = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>CFBundleExecutable</key>
+  <string>{id}</string>
+  <key>CFBundleIdentifier</key>
+  <string>io.github.pexmor.{id}</string>
+  <key>CFBundleName</key>
+  <string>{name}</string>
+  <key>CFBundlePackageType</key>
+  <string>APPL</string>
+  <key>LSUIElement</key>
+  <true/>
+</dict>
+</plist>
+"#,
+        id = xml_escape(&shortcut.id),
+        name = xml_escape(&shortcut.name),
+    );
+    let path = bundle.join("Contents").join("Info.plist");
+    fs::write(&path, plist).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(bundle)
+}
+
+#[cfg(target_os = "macos")]
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(unix)]
+fn write_executable(path: &std::path::Path, content: &str) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::write(path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("Failed to make {} executable", path.display()))
+}
+
+#[cfg(windows)]
+fn create(shortcut: &Shortcut) -> Result<PathBuf> {
+    let programs = std::env::var_os("APPDATA")
+        .map(PathBuf::from)
+        .context("APPDATA is not set")?
+        .join(r"Microsoft\Windows\Start Menu\Programs");
+    fs::create_dir_all(&programs)
+        .with_context(|| format!("Failed to create {}", programs.display()))?;
+    let path = programs.join(format!(
+        "{}.lnk",
+        shortcut.name.replace(['\\', '/', ':'], "-")
+    ));
+
+    // Values go through the environment so quotes can't break the script
+    let args = shortcut
+        .args
+        .iter()
+        .map(|arg| format!("\"{arg}\""))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let script = "$s = (New-Object -ComObject WScript.Shell).CreateShortcut($env:TBL_LNK); \
+                  $s.TargetPath = $env:TBL_LNK_TARGET; $s.Arguments = $env:TBL_LNK_ARGS; \
+                  $s.Description = $env:TBL_LNK_DESCRIPTION; $s.WindowStyle = 7; \
+                  if ($env:TBL_LNK_ICON) { $s.IconLocation = $env:TBL_LNK_ICON }; $s.Save()";
+    let icon = shortcut.icon.as_ref().filter(|icon| {
+        icon.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("ico"))
+    });
+    let status = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .env("TBL_LNK", &path)
+        .env("TBL_LNK_TARGET", &shortcut.program)
+        .env("TBL_LNK_ARGS", args)
+        .env(
+            "TBL_LNK_ICON",
+            icon.map(PathBuf::as_os_str).unwrap_or_default(),
+        )
+        .env(
+            "TBL_LNK_DESCRIPTION",
+            shortcut.description.as_deref().unwrap_or_default(),
+        )
+        .status()
+        .context("Failed to run powershell")?;
+    if !status.success() {
+        anyhow::bail!(
+            "powershell exited with {status} while creating {}",
+            path.display()
+        );
+    }
+    Ok(path)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create(_shortcut: &Shortcut) -> Result<PathBuf> {
+    anyhow::bail!("shortcuts are not supported on this platform")
+}
@@ -0,0 +1,341 @@
+//! Where the served content comes from.
+//!
+//! Every kind of source — a git repository, a local directory, an archive,
+//! an app bundle, an S3 prefix, a directory on an SFTP host, another tbl
+//! instance — is a [`SourceProvider`] that fills the workspace from a URL it
+//! recognizes. The server only deals with sources through [`provider`] and
+//! [`installed_version`], so a new backend is a module implementing the
+//! trait, an entry in [`PROVIDERS`], and, if it needs settings, a config
+//! section carried in [`Settings`].
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{atomic::Ordering, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    archive::Archive, bundle::Bundle, git::Git, job_step, jobs::JobHandle, local::LocalDir,
+    peer::Peer, repo_identity, s3::S3, sftp::Sftp, TblConfig, GIT_CANCELLED,
+};
+
+/// Commits of history kept by default; enough to serve the latest content.
+pub const DEFAULT_CLONE_DEPTH: u32 = 1;
+
+/// Providers in the order they are asked whether they handle a URL. Git
+/// comes last and takes whatever no other provider claims.
+pub static PROVIDERS: &[&dyn SourceProvider] =
+    &[&Archive, &Bundle, &S3, &Sftp, &Peer, &LocalDir, &Git];
+
+/// A kind of source the workspace can be filled from.
+pub trait SourceProvider: Sync {
+    /// Short name for messages, e.g. `git` or `s3`, and the directory in the
+    /// config dir that holds the provider's state, if it needs any.
+    fn name(&self) -> &'static str;
+
+    /// Whether `url` is a source of this kind.
+    fn handles(&self, url: &str) -> bool;
+
+    /// Bring the workspace in line with `url`, replacing content of any
+    /// other source. A failed sync should leave something servable behind.
+    fn sync(&self, ctx: &Context, url: &str) -> Result<()>;
+
+    /// URL of the source `web_dir` was last synced from, if it is of this
+    /// kind.
+    fn installed_url(&self, web_dir: &Path) -> Option<String>;
+
+    /// What stands in for the commit of the content in `web_dir`, if it came
+    /// from a source of this kind; changes whenever the content does.
+    fn version(&self, web_dir: &Path) -> Option<String>;
+
+    /// Whether `web_dir` holds content of `url`, in any spelling, that can be
+    /// updated in place.
+    fn matches(&self, web_dir: &Path, url: &str) -> bool {
+        self.installed_url(web_dir)
+            .is_some_and(|installed| repo_identity(&installed) == repo_identity(url))
+    }
+
+    /// Whether the source is fetched with git, and so has history to
+    /// deepen and needs git installed.
+    fn uses_git(&self) -> bool {
+        false
+    }
+}
+
+/// The provider for `url`.
+pub fn provider(url: &str) -> &'static dyn SourceProvider {
+    PROVIDERS
+        .iter()
+        .copied()
+        .find(|provider| provider.handles(url))
+        .unwrap_or(&Git)
+}
+
+/// Fill the workspace from `url` with its provider, then forget what other
+/// providers recorded about earlier content, so the version is read from the
+/// right one.
+pub fn sync(ctx: &Context, url: &str) -> Result<()> {
+    let provider = provider(url);
+    provider.sync(ctx, url)?;
+    for other in PROVIDERS
+        .iter()
+        .filter(|other| other.name() != provider.name())
+    {
+        let dir = ctx.config_dir.join(other.name());
+        if dir.is_dir() {
+            fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to remove {}", dir.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Forget what every provider recorded about the workspace, e.g. after its
+/// content was replaced by other means, so the next sync starts afresh.
+pub fn forget(config_dir: &Path) -> Result<()> {
+    for provider in PROVIDERS {
+        let dir = config_dir.join(provider.name());
+        if dir.is_dir() {
+            fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to remove {}", dir.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Version of whatever content `web_dir` holds, from the provider that put
+/// it there.
+pub fn installed_version(web_dir: &Path) -> Option<String> {
+    PROVIDERS
+        .iter()
+        .find_map(|provider| provider.version(web_dir))
+}
+
+/// The config of every provider, and of what runs around a sync, taken from
+/// the profile's config.
+#[derive(Clone)]
+pub struct Settings {
+    /// Commits of history; 0 for all of it, which also deepens a shallow checkout
+    pub depth: u32,
+    /// Go through the shared mirror of the repository (see `mirror`)
+    pub cache: bool,
+    /// Endpoint and credentials for `s3://` sources
+    pub s3: crate::s3::S3Config,
+    /// Key and known hosts for `sftp://` sources
+    pub sftp: crate::sftp::SftpConfig,
+    /// Releases of the content kept for rollback (see `releases`); 0 for none
+    pub keep_releases: usize,
+    /// Commands run around each sync (see `hooks`)
+    pub hooks: crate::hooks::HooksConfig,
+    /// Tokens of the git hosts that serve private repositories (see `forge`)
+    pub providers: crate::forge::ProvidersConfig,
+    /// Equivalent URLs of git repositories, tried in order when the
+    /// repository's own fails
+    pub mirrors: BTreeMap<String, Vec<String>>,
+}
+
+impl Settings {
+    pub fn of(cfg: &TblConfig) -> Self {
+        Self {
+            depth: cfg.clone_depth.unwrap_or(DEFAULT_CLONE_DEPTH),
+            cache: cfg.git_cache.unwrap_or(false),
+            s3: cfg.s3.clone().unwrap_or_default(),
+            sftp: cfg.sftp.clone().unwrap_or_default(),
+            keep_releases: cfg.keep_releases.unwrap_or(0),
+            hooks: cfg.hooks.clone().unwrap_or_default(),
+            providers: cfg.providers.clone().unwrap_or_default(),
+            mirrors: cfg.git_mirrors.clone().unwrap_or_default(),
+        }
+    }
+
+    /// The mirrors of the repository at `url`, in the order to try them.
+    pub fn mirrors_of(&self, url: &str) -> Vec<&str> {
+        let identity = repo_identity(url);
+        self.mirrors
+            .iter()
+            .filter(|(primary, _)| repo_identity(primary) == identity)
+            .flat_map(|(_, mirrors)| mirrors.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+/// What a provider syncs into, with what settings, and for which job.
+pub struct Context<'a> {
+    pub config_dir: &'a Path,
+    /// The workspace, `<config_dir>/web`
+    pub web_dir: PathBuf,
+    pub settings: &'a Settings,
+    pub job: Option<&'a JobHandle>,
+    /// Mirror the content came from, when the URL itself failed
+    mirror_used: Mutex<Option<String>>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(config_dir: &'a Path, settings: &'a Settings, job: Option<&'a JobHandle>) -> Self {
+        Self {
+            config_dir,
+            web_dir: config_dir.join("web"),
+            settings,
+            job,
+            mirror_used: Mutex::new(None),
+        }
+    }
+
+    /// Note that the content came from `mirror` rather than the URL synced.
+    pub fn used_mirror(&self, mirror: &str) {
+        *self.mirror_used.lock().unwrap() = Some(mirror.to_string());
+    }
+
+    /// The mirror the content came from, if not the URL synced.
+    pub fn mirror_used(&self) -> Option<String> {
+        self.mirror_used.lock().unwrap().clone()
+    }
+
+    /// Report `step` as the job's progress.
+    pub fn progress(&self, step: &str) {
+        if let Some(job) = self.job {
+            job.progress(step);
+        }
+    }
+
+    /// Note `line` in the job log, or the daemon log without a job.
+    pub fn log(&self, line: &str) {
+        match self.job {
+            Some(job) => job.log(line),
+            None => tracing::info!("{line}"),
+        }
+    }
+
+    /// Warn about `line` in the daemon log, and the job log with a job.
+    pub fn warn(&self, line: &str) {
+        tracing::warn!("{line}");
+        if let Some(job) = self.job {
+            job.log(line);
+        }
+    }
+
+    /// Report `step` and send the output of `cmd` to the job's log.
+    pub fn step<'c>(&self, cmd: &'c mut Command, step: &str) -> Result<&'c mut Command> {
+        job_step(cmd, self.job, step)
+    }
+
+    /// Whether tbl is shutting down and the sync should stop.
+    pub fn cancelled(&self) -> bool {
+        GIT_CANCELLED.load(Ordering::Relaxed)
+    }
+}
+
+/// The URL and version of a source synced into the workspace, for providers
+/// that need nothing else; saved as `<config_dir>/<name>/state.json`.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Record {
+    pub url: String,
+    pub version: String,
+}
+
+impl Record {
+    fn path(web_dir: &Path, name: &str) -> PathBuf {
+        web_dir.with_file_name(name).join("state.json")
+    }
+
+    /// The record of provider `name` for `web_dir`, unless the workspace is
+    /// missing or a git checkout.
+    pub fn load(web_dir: &Path, name: &str) -> Option<Self> {
+        if !web_dir.is_dir() || web_dir.join(".git").exists() {
+            return None;
+        }
+        serde_json::from_slice(&fs::read(Self::path(web_dir, name)).ok()?).ok()
+    }
+
+    pub fn save(&self, web_dir: &Path, name: &str) -> Result<()> {
+        let path = Self::path(web_dir, name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Empty `web_dir` unless it holds content of `url` from provider `name`,
+/// which is then updated in place.
+pub fn claim_workspace(web_dir: &Path, name: &str, url: &str) -> Result<()> {
+    let ours = Record::load(web_dir, name).is_some_and(|record| record.url == url);
+    if web_dir.exists() && !ours {
+        fs::remove_dir_all(web_dir)
+            .with_context(|| format!("Failed to clear {}", web_dir.display()))?;
+    }
+    fs::create_dir_all(web_dir).with_context(|| format!("Failed to create {}", web_dir.display()))
+}
+
+/// Digest of the names, sizes, and modification times of the files below
+/// `dir`, as the version of content that has no commit of its own.
+pub fn tree_digest(dir: &Path) -> Result<String> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |time| time.as_secs());
+            let path = entry.path();
+            let name = path
+                .strip_prefix(dir)
+                .unwrap_or(&path)
+                .display()
+                .to_string();
+            files.push(format!("{name}\0{}\0{mtime}\n", meta.len()));
+        }
+    }
+    files.sort();
+
+    let mut digest = Sha256::new();
+    for file in files {
+        digest.update(file);
+    }
+    Ok(hex::encode(digest.finalize())[..40].to_string())
+}
+
+/// Move `dir` in place of `web_dir`, keeping the old content aside until the
+/// new is in, so the workspace is never half updated.
+pub fn swap_workspace(dir: &Path, web_dir: &Path) -> Result<()> {
+    let previous = web_dir.with_file_name("web.previous");
+    if previous.exists() {
+        fs::remove_dir_all(&previous)?;
+    }
+    if web_dir.exists() {
+        fs::rename(web_dir, &previous).context("Failed to move the old content aside")?;
+    }
+    if let Err(e) = fs::rename(dir, web_dir) {
+        let _ = fs::rename(&previous, web_dir);
+        return Err(e).context("Failed to move the new content in place");
+    }
+    let _ = fs::remove_dir_all(&previous);
+    Ok(())
+}
+
+/// Remove the directories between `file` and `root` that are empty now.
+pub fn remove_empty_parents(root: &Path, file: &Path) {
+    let mut dir = file.parent();
+    while let Some(current) = dir.filter(|dir| *dir != root && dir.starts_with(root)) {
+        if fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
@@ -0,0 +1,473 @@
+//! The server's state in one SQLite database, `state.db` in the config dir:
+//! browser sessions and the keys signing their tokens, job records and their
+//! output, the KV store, when stored files expire, the server's past runs,
+//! and an audit log of the changes made through the API.
+//!
+//! Every write is a transaction, so a crash or power loss leaves the state
+//! from before or after it, never a half-written file. The schema version is
+//! kept in `PRAGMA user_version`; opening an older database applies the
+//! [`MIGRATIONS`] it lacks. The first open imports the JSON files earlier
+//! versions kept (`data/kv/` and the run dir's `jobs/`) and renames them to
+//! `*.imported`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, Transaction};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::jobs;
+
+/// File name of the database in the config dir.
+pub const STATE_DB: &str = "state.db";
+
+/// Schema changes in order; the database's `user_version` counts how many
+/// have been applied.
+const MIGRATIONS: &[&str] = &[
+    // 1: sessions, jobs, KV store, audit log
+    "CREATE TABLE sessions (
+        id TEXT PRIMARY KEY,
+        created_at INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        user_agent TEXT
+    );
+    CREATE TABLE jobs (
+        id TEXT PRIMARY KEY,
+        kind TEXT NOT NULL,
+        state TEXT NOT NULL,
+        target TEXT,
+        created_at INTEGER NOT NULL,
+        started_at INTEGER,
+        finished_at INTEGER,
+        progress TEXT,
+        result TEXT,
+        error TEXT,
+        log TEXT
+    );
+    CREATE TABLE kv (
+        namespace TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (namespace, key)
+    ) WITHOUT ROWID;
+    CREATE TABLE audit (
+        id INTEGER PRIMARY KEY,
+        time INTEGER NOT NULL,
+        method TEXT NOT NULL,
+        path TEXT NOT NULL,
+        status INTEGER NOT NULL,
+        user TEXT,
+        session TEXT,
+        client TEXT
+    );
+    CREATE INDEX audit_time ON audit (time);",
+    // 2: TTLs of KV keys and stored files
+    "ALTER TABLE kv ADD COLUMN expires_at INTEGER;
+    CREATE INDEX kv_expires ON kv (expires_at) WHERE expires_at IS NOT NULL;
+    CREATE TABLE file_expiry (
+        path TEXT PRIMARY KEY,
+        expires_at INTEGER NOT NULL
+    ) WITHOUT ROWID;
+    CREATE INDEX file_expiry_time ON file_expiry (expires_at);",
+    // 3: keys signing access tokens, revoked tokens
+    "CREATE TABLE token_keys (
+        id INTEGER PRIMARY KEY,
+        secret BLOB NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER
+    );
+    CREATE TABLE revoked_tokens (
+        id TEXT PRIMARY KEY,
+        revoked_at INTEGER NOT NULL
+    ) WITHOUT ROWID;",
+    // 4: failed /bootstrap attempts
+    "CREATE TABLE bootstrap_failures (
+        id INTEGER PRIMARY KEY,
+        time INTEGER NOT NULL,
+        client TEXT NOT NULL
+    );
+    CREATE INDEX bootstrap_failures_client ON bootstrap_failures (client, time);",
+    // 5: server runs, and the totals of those no longer kept
+    "CREATE TABLE runs (
+        id INTEGER PRIMARY KEY,
+        started_at INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        stopped_at INTEGER,
+        stop_reason TEXT,
+        version TEXT NOT NULL
+    );
+    CREATE TABLE run_totals (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        first_started_at INTEGER NOT NULL,
+        runs INTEGER NOT NULL,
+        unclean INTEGER NOT NULL,
+        uptime_secs INTEGER NOT NULL
+    );",
+];
+
+/// Sessions kept; the oldest are removed beyond this.
+const MAX_SESSIONS: usize = 100;
+
+/// Audit records kept; the oldest are removed beyond this.
+const MAX_AUDIT: i64 = 10_000;
+
+/// Failed `/bootstrap` attempts kept; the oldest are removed beyond this.
+const MAX_BOOTSTRAP_FAILURES: i64 = 1000;
+
+/// How long a write waits for another process, e.g. `tbl jobs`, to finish
+/// its own.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Handle on `state.db`, shared by the stores built on it.
+pub struct StateDb {
+    conn: Mutex<Connection>,
+}
+
+/// A browser context that completed the bootstrap flow.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: u64,
+    pub last_seen: u64,
+    pub user_agent: Option<String>,
+}
+
+/// A request that changed something through the API.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct AuditRecord {
+    /// Increases with every record
+    pub id: i64,
+    /// Unix timestamp (seconds)
+    pub time: u64,
+    pub method: String,
+    pub path: String,
+    /// HTTP status of the response
+    pub status: u16,
+    /// Basic auth user, when basic auth is configured
+    pub user: Option<String>,
+    pub session: Option<String>,
+    /// Client address, behind `trusted_proxies` the forwarded one
+    pub client: Option<String>,
+}
+
+/// Which audit records to list, newest first.
+#[derive(Deserialize, Debug, Default)]
+pub struct AuditQuery {
+    /// Only records with a smaller ID, to page back
+    pub before: Option<i64>,
+    /// Only records at or after this Unix timestamp
+    pub since: Option<u64>,
+    /// Only requests to paths starting with this, e.g. `/api/v1/kv`
+    pub path: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl StateDb {
+    /// Open or create the database of the profile at `config_dir`, whose
+    /// run dir holds the job records of earlier versions.
+    pub fn open(config_dir: &Path, run_dir: &Path) -> Result<Arc<Self>> {
+        fs::create_dir_all(config_dir)?;
+        let path = config_dir.join(STATE_DB);
+        let mut conn =
+            Connection::open(&path).with_context(|| format!("failed to open {:?}", path))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // The WAL keeps readers off the writer's back; NORMAL sync can lose
+        // the last writes on power loss but never corrupts the database
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+
+        let imported = migrate(&mut conn, config_dir, run_dir)
+            .with_context(|| format!("failed to migrate {:?}", path))?;
+        for dir in imported {
+            let mut target = dir.clone().into_os_string();
+            target.push(".imported");
+            if let Err(e) = fs::rename(&dir, &target) {
+                tracing::warn!("Failed to rename {:?} after importing it: {e}", dir);
+            }
+        }
+        Ok(Arc::new(Self {
+            conn: Mutex::new(conn),
+        }))
+    }
+
+    pub fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Write a consistent copy of the database to `path`, which must not
+    /// exist, while others may go on writing.
+    pub fn snapshot(&self, path: &Path) -> Result<()> {
+        let path = path.to_str().context("snapshot path is not valid UTF-8")?;
+        self.conn().execute("VACUUM INTO ?1", params![path])?;
+        Ok(())
+    }
+
+    /// Record a new session, dropping the oldest beyond [`MAX_SESSIONS`].
+    pub fn create_session(&self, session: &SessionInfo) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO sessions (id, created_at, last_seen, user_agent) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                session.id,
+                session.created_at,
+                session.last_seen,
+                session.user_agent
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM sessions WHERE id NOT IN
+                (SELECT id FROM sessions ORDER BY created_at DESC, rowid DESC LIMIT ?1)",
+            params![MAX_SESSIONS],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Note that a session was seen at `now`; written at most once a
+    /// minute, since every request of the session does this.
+    pub fn touch_session(&self, id: &str, now: u64) -> Result<()> {
+        self.conn().execute(
+            "UPDATE sessions SET last_seen = ?2 WHERE id = ?1 AND last_seen < ?2 - 60",
+            params![id, now],
+        )?;
+        Ok(())
+    }
+
+    /// Forget a session, returning whether it was known.
+    pub fn delete_session(&self, id: &str) -> Result<bool> {
+        let deleted = self
+            .conn()
+            .execute("DELETE FROM sessions WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    /// Record a failed `/bootstrap` attempt by `client`, dropping the oldest
+    /// beyond [`MAX_BOOTSTRAP_FAILURES`].
+    pub fn record_bootstrap_failure(&self, client: &str, now: u64) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO bootstrap_failures (time, client) VALUES (?1, ?2)",
+            params![now, client],
+        )?;
+        let newest = tx.last_insert_rowid();
+        tx.execute(
+            "DELETE FROM bootstrap_failures WHERE id <= ?1",
+            params![newest - MAX_BOOTSTRAP_FAILURES],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// When `client` failed `/bootstrap` since `since`, newest first.
+    pub fn bootstrap_failures(&self, client: &str, since: u64) -> Result<Vec<u64>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT time FROM bootstrap_failures WHERE client = ?1 AND time >= ?2
+             ORDER BY time DESC, id DESC",
+        )?;
+        let times = stmt
+            .query_map(params![client, since], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(times)
+    }
+
+    /// Forget the failed attempts of `client`, once it got in.
+    pub fn clear_bootstrap_failures(&self, client: &str) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM bootstrap_failures WHERE client = ?1",
+            params![client],
+        )?;
+        Ok(())
+    }
+
+    /// Known sessions, oldest first.
+    pub fn sessions(&self) -> Result<Vec<SessionInfo>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, created_at, last_seen, user_agent FROM sessions
+             ORDER BY created_at, rowid",
+        )?;
+        let sessions = stmt
+            .query_map([], |row| {
+                Ok(SessionInfo {
+                    id: row.get(0)?,
+                    created_at: row.get(1)?,
+                    last_seen: row.get(2)?,
+                    user_agent: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(sessions)
+    }
+
+    /// Append to the audit log, which numbers the record itself (`record.id`
+    /// is ignored), dropping the oldest beyond [`MAX_AUDIT`].
+    pub fn audit(&self, record: &AuditRecord) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO audit (time, method, path, status, user, session, client)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                record.time,
+                record.method,
+                record.path,
+                record.status,
+                record.user,
+                record.session,
+                record.client
+            ],
+        )?;
+        let newest = tx.last_insert_rowid();
+        tx.execute(
+            "DELETE FROM audit WHERE id <= ?1",
+            params![newest - MAX_AUDIT],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Remove the file at `path` in the files area at `expires_at`, or never
+    /// when `None`.
+    pub fn set_file_expiry(&self, path: &str, expires_at: Option<u64>) -> Result<()> {
+        match expires_at {
+            Some(expires_at) => self.conn().execute(
+                "INSERT INTO file_expiry (path, expires_at) VALUES (?1, ?2)
+                 ON CONFLICT (path) DO UPDATE SET expires_at = excluded.expires_at",
+                params![path, expires_at],
+            )?,
+            None => self
+                .conn()
+                .execute("DELETE FROM file_expiry WHERE path = ?1", params![path])?,
+        };
+        Ok(())
+    }
+
+    /// Forget the expiry of `path` and of everything below it, once removed.
+    pub fn forget_file_expiry(&self, path: &str) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM file_expiry
+             WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+            params![path],
+        )?;
+        Ok(())
+    }
+
+    /// Paths in the files area that expired by `now`.
+    pub fn expired_files(&self, now: u64) -> Result<Vec<String>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT path FROM file_expiry WHERE expires_at <= ?1")?;
+        let paths = stmt
+            .query_map(params![now], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    /// Up to `limit` audit records matching `query`, newest first.
+    pub fn audit_records(&self, query: &AuditQuery, limit: usize) -> Result<Vec<AuditRecord>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, time, method, path, status, user, session, client FROM audit
+             WHERE (?1 IS NULL OR id < ?1)
+               AND (?2 IS NULL OR time >= ?2)
+               AND (?3 IS NULL OR substr(path, 1, length(?3)) = ?3)
+             ORDER BY id DESC LIMIT ?4",
+        )?;
+        let records = stmt
+            .query_map(
+                params![query.before, query.since, query.path, limit],
+                |row| {
+                    Ok(AuditRecord {
+                        id: row.get(0)?,
+                        time: row.get(1)?,
+                        method: row.get(2)?,
+                        path: row.get(3)?,
+                        status: row.get(4)?,
+                        user: row.get(5)?,
+                        session: row.get(6)?,
+                        client: row.get(7)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(records)
+    }
+}
+
+/// Apply the migrations the database lacks, each in a transaction with its
+/// version bump. Returns the legacy directories imported, to be renamed once
+/// the import is committed.
+fn migrate(conn: &mut Connection, config_dir: &Path, run_dir: &Path) -> Result<Vec<PathBuf>> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "the database is from a newer tbl (schema {version}, this one knows {})",
+            MIGRATIONS.len()
+        );
+    }
+    let mut imported = Vec::new();
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        if index == 0 {
+            imported = import_legacy(&tx, config_dir, run_dir)?;
+        }
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+    Ok(imported)
+}
+
+/// Copy the KV namespaces and job records earlier versions kept as JSON
+/// files into the new tables.
+fn import_legacy(tx: &Transaction, config_dir: &Path, run_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut imported = Vec::new();
+
+    let kv_dir = config_dir.join("data").join("kv");
+    if kv_dir.is_dir() {
+        for entry in fs::read_dir(&kv_dir)? {
+            let path = entry?.path();
+            let Some(namespace) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|_| path.extension().is_some_and(|ext| ext == "json"))
+            else {
+                continue;
+            };
+            let content = fs::read_to_string(&path)?;
+            let data: BTreeMap<String, serde_json::Value> = serde_json::from_str(&content)
+                .with_context(|| format!("corrupt kv namespace {:?}", path))?;
+            for (key, value) in data {
+                tx.execute(
+                    "INSERT INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)",
+                    params![namespace, key, value.to_string()],
+                )?;
+            }
+        }
+        imported.push(kv_dir);
+    }
+
+    let jobs_dir = run_dir.join("jobs");
+    if jobs_dir.is_dir() {
+        for job in jobs::load_legacy_jobs(&jobs_dir) {
+            jobs::insert(tx, &job)?;
+            let log = fs::read_to_string(jobs_dir.join(format!("{}.log", job.id))).ok();
+            tx.execute(
+                "UPDATE jobs SET log = ?2 WHERE id = ?1",
+                params![job.id, log],
+            )?;
+        }
+        imported.push(jobs_dir);
+    }
+
+    Ok(imported)
+}
@@ -0,0 +1,140 @@
+//! How times are written (`--utc`, `TBL_UTC`).
+//!
+//! Times written as text for programs, such as the daemon log, crash
+//! reports, and the API's string fields (`next_open`), get RFC 3339 in UTC.
+//! The API's timestamps stay numbers, Unix seconds, as clients already read
+//! them. What people read, such as command output, pages, and notifications,
+//! gets local time, or UTC marked as such with `--utc`. Every time tbl writes
+//! goes through here, so new outputs follow the same rules.
+
+use std::{fmt, sync::OnceLock};
+
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Utc};
+use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
+
+use crate::now_unix;
+
+/// Whether people get UTC rather than local time, set once at startup.
+static UTC: OnceLock<bool> = OnceLock::new();
+
+/// Show people UTC instead of local time with `--utc` or `TBL_UTC`.
+pub fn init(cli_utc: bool) {
+    let utc = cli_utc
+        || std::env::var("TBL_UTC")
+            .is_ok_and(|value| matches!(value.as_str(), "1" | "true" | "yes" | "on"));
+    let _ = UTC.set(utc);
+}
+
+fn utc() -> bool {
+    UTC.get().copied().unwrap_or(false)
+}
+
+/// Layouts of times for people.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// `Thu 2026-10-15 18:00`
+    Full,
+    /// `2026-10-15`
+    Date,
+    /// `Thu 18:00`, for times within a week
+    Weekday,
+    /// `18:00`, for times today
+    Clock,
+}
+
+impl Style {
+    fn pattern(self) -> &'static str {
+        match self {
+            Style::Full => "%a %Y-%m-%d %H:%M",
+            Style::Date => "%Y-%m-%d",
+            Style::Weekday => "%a %H:%M",
+            Style::Clock => "%H:%M",
+        }
+    }
+}
+
+/// `time` for people: local time, or UTC with `--utc`.
+pub fn human<Tz: TimeZone>(time: &DateTime<Tz>, style: Style) -> String {
+    if !utc() {
+        return time
+            .with_timezone(&Local)
+            .format(style.pattern())
+            .to_string();
+    }
+    let text = time.with_timezone(&Utc).format(style.pattern()).to_string();
+    if style == Style::Date {
+        text
+    } else {
+        format!("{text} UTC")
+    }
+}
+
+/// A Unix timestamp (seconds) for people.
+pub fn human_unix(timestamp: u64, style: Style) -> String {
+    match DateTime::from_timestamp(timestamp as i64, 0) {
+        Some(time) => human(&time, style),
+        None => timestamp.to_string(),
+    }
+}
+
+/// A Unix timestamp for people, as the time alone when it is today.
+pub fn human_soon(timestamp: u64) -> String {
+    let today = |time: DateTime<Utc>| {
+        if utc() {
+            time.date_naive() == Utc::now().date_naive()
+        } else {
+            time.with_timezone(&Local).date_naive() == Local::now().date_naive()
+        }
+    };
+    match DateTime::from_timestamp(timestamp as i64, 0) {
+        Some(time) if today(time) => human(&time, Style::Clock),
+        _ => human_unix(timestamp, Style::Full),
+    }
+}
+
+/// `time` for programs: RFC 3339 in UTC, e.g. `2026-10-15T16:00:00Z`.
+pub fn rfc3339<Tz: TimeZone>(time: &DateTime<Tz>) -> String {
+    time.with_timezone(&Utc)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// `time` for HTTP headers, e.g. `Thu, 15 Oct 2026 16:00:00 GMT`.
+pub fn http_date(time: std::time::SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// How long ago a Unix timestamp was, e.g. "3h ago".
+pub fn age(timestamp: u64) -> String {
+    let secs = now_unix().saturating_sub(timestamp);
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// A length of time, e.g. "3d 4h" or "12m".
+pub fn duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+/// Time of daemon log lines: RFC 3339 in UTC, to the millisecond.
+pub struct LogTime;
+
+impl FormatTime for LogTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        write!(
+            w,
+            "{}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+        )
+    }
+}
@@ -0,0 +1,110 @@
+//! Startup timings (`--timings`): how long each phase of starting the server
+//! took, to tell a slow clone from a slow port probe or browser.
+//!
+//! The daemon records its phases in `pid.yaml` on every start; `--timings`
+//! prints them, together with the time the launching command spent itself.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Measures consecutive phases, each from the end of the previous one.
+pub struct Timings {
+    started: Instant,
+    last: Instant,
+    report: Report,
+}
+
+/// The phases measured so far, as recorded in `pid.yaml`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Report {
+    pub phases: Vec<Phase>,
+    /// Set once startup is over, browser included
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_ms: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Phase {
+    pub name: String,
+    pub ms: f64,
+}
+
+impl Timings {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            last: now,
+            report: Report::default(),
+        }
+    }
+
+    /// Record the time since the previous phase as `name`.
+    pub fn phase(&mut self, name: &str) {
+        let now = Instant::now();
+        self.report.phases.push(Phase {
+            name: name.to_string(),
+            ms: millis(now - self.last),
+        });
+        self.last = now;
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The phases so far.
+    pub fn report(&self) -> Report {
+        self.report.clone()
+    }
+
+    /// The phases with the total time since the start.
+    pub fn finish(&mut self) -> Report {
+        self.report.total_ms = Some(millis(self.elapsed()));
+        self.report()
+    }
+}
+
+impl Report {
+    /// Print the phases, their share of `total_ms`, and whatever of it no
+    /// phase accounts for as `rest`.
+    pub fn print(&self, rest: &str) {
+        let total = self
+            .total_ms
+            .unwrap_or_else(|| self.phases.iter().map(|phase| phase.ms).sum());
+        let unaccounted = total - self.phases.iter().map(|phase| phase.ms).sum::<f64>();
+        let share = |ms: f64| {
+            if total > 0.0 {
+                ms / total * 100.0
+            } else {
+                0.0
+            }
+        };
+        println!();
+        println!("  Startup timings");
+        println!("  ───────────────────────────────────────");
+        for phase in &self.phases {
+            println!(
+                "  {:<14} {:>10.1} ms {:>5.1}%",
+                phase.name,
+                phase.ms,
+                share(phase.ms)
+            );
+        }
+        if unaccounted >= 0.1 {
+            println!(
+                "  {:<14} {:>10.1} ms {:>5.1}%",
+                rest,
+                unaccounted,
+                share(unaccounted)
+            );
+        }
+        println!("  {:<14} {:>10.1} ms", "total", total);
+        println!();
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 10_000.0).round() / 10.0
+}
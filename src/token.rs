@@ -0,0 +1,229 @@
+//! Signed access tokens (the login link and the `tbl_token` cookie).
+//!
+//! A token is a version byte, the ID of the server key that signed it, a
+//! random token ID, and an HMAC-SHA256 of those under that key, in URL-safe
+//! base64. Guessing one means guessing the MAC, and checking one takes no
+//! database lookup. Server keys live in the state database: rotating adds a
+//! key that signs from then on, while tokens of the older keys keep working
+//! for a grace period, so browsers don't all lose their sessions at once.
+//! Single tokens are revoked by ID; a browser's token has its session's ID.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rusqlite::params;
+use sha2::Sha256;
+
+use crate::{now_unix, secret::Secret, state::StateDb};
+
+/// First byte of every token, for telling formats apart later.
+pub const VERSION: u8 = 1;
+
+/// How long tokens of the previous keys keep working after a rotation
+/// unless `--grace` says otherwise.
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+const ID_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+const SIGNED_LEN: usize = 1 + 4 + ID_LEN;
+
+struct Key {
+    id: u32,
+    secret: Vec<u8>,
+    /// Unix timestamp from which its tokens no longer work; `None` for the
+    /// current key
+    expires_at: Option<u64>,
+}
+
+/// Issues and checks tokens with the keys in the state database.
+///
+/// Keys and revoked IDs are read once and then kept in memory, so changes
+/// by another process reach a running server only through its API.
+pub struct Tokens {
+    db: Arc<StateDb>,
+    /// Oldest first; the last one signs
+    keys: RwLock<Vec<Key>>,
+    revoked: RwLock<HashSet<String>>,
+}
+
+impl Tokens {
+    /// Load the keys, creating the first one, and drop those whose grace
+    /// period is over along with the revocations only they needed.
+    pub fn load(db: Arc<StateDb>) -> Result<Self> {
+        let now = now_unix();
+        let (keys, revoked) = {
+            let mut conn = db.conn();
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM token_keys WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+                params![now],
+            )?;
+            let count: i64 =
+                tx.query_row("SELECT COUNT(*) FROM token_keys", [], |row| row.get(0))?;
+            if count == 0 {
+                tx.execute(
+                    "INSERT INTO token_keys (secret, created_at) VALUES (?1, ?2)",
+                    params![new_secret(), now],
+                )?;
+            }
+            // A token revoked before every kept key existed was signed
+            // with a key that is gone
+            tx.execute(
+                "DELETE FROM revoked_tokens
+                 WHERE revoked_at < (SELECT MIN(created_at) FROM token_keys)",
+                [],
+            )?;
+            let keys = tx
+                .prepare("SELECT id, secret, expires_at FROM token_keys ORDER BY id")?
+                .query_map([], |row| {
+                    Ok(Key {
+                        id: row.get(0)?,
+                        secret: row.get(1)?,
+                        expires_at: row.get(2)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let revoked = tx
+                .prepare("SELECT id FROM revoked_tokens")?
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<HashSet<String>>>()?;
+            tx.commit()?;
+            (keys, revoked)
+        };
+        Ok(Self {
+            db,
+            keys: RwLock::new(keys),
+            revoked: RwLock::new(revoked),
+        })
+    }
+
+    /// A token with a new random ID.
+    pub fn issue(&self) -> Secret {
+        let mut id = [0u8; ID_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut id);
+        self.sign(id)
+    }
+
+    /// A token with the ID `id`, a session ID as `create_session` makes
+    /// them; `None` when it isn't one.
+    pub fn issue_for(&self, id: &str) -> Option<Secret> {
+        let id: [u8; ID_LEN] = hex::decode(id).ok()?.try_into().ok()?;
+        Some(self.sign(id))
+    }
+
+    fn sign(&self, id: [u8; ID_LEN]) -> Secret {
+        let keys = self.keys.read().unwrap();
+        let key = keys.last().expect("Tokens::load creates a key");
+        let mut token = Vec::with_capacity(SIGNED_LEN + MAC_LEN);
+        token.push(VERSION);
+        token.extend_from_slice(&key.id.to_be_bytes());
+        token.extend_from_slice(&id);
+        let mac = mac(&key.secret, &token).finalize().into_bytes();
+        token.extend_from_slice(&mac);
+        Secret::new(URL_SAFE_NO_PAD.encode(token))
+    }
+
+    /// The ID of `token` if this server signed it with a key still in use
+    /// and it isn't revoked.
+    pub fn verify(&self, token: &str) -> Option<String> {
+        let bytes = URL_SAFE_NO_PAD.decode(token).ok()?;
+        if bytes.len() != SIGNED_LEN + MAC_LEN || bytes[0] != VERSION {
+            return None;
+        }
+        let (signed, tag) = bytes.split_at(SIGNED_LEN);
+        let key_id = u32::from_be_bytes(signed[1..5].try_into().ok()?);
+        {
+            let keys = self.keys.read().unwrap();
+            let key = keys.iter().find(|key| key.id == key_id)?;
+            if key.expires_at.is_some_and(|expires| expires <= now_unix()) {
+                return None;
+            }
+            mac(&key.secret, signed).verify_slice(tag).ok()?;
+        }
+        let id = hex::encode(&signed[5..]);
+        (!self.revoked.read().unwrap().contains(&id)).then_some(id)
+    }
+
+    /// Stop accepting the token with ID `id`.
+    pub fn revoke(&self, id: &str) -> Result<()> {
+        self.db.conn().execute(
+            "INSERT OR IGNORE INTO revoked_tokens (id, revoked_at) VALUES (?1, ?2)",
+            params![id, now_unix()],
+        )?;
+        self.revoked.write().unwrap().insert(id.to_string());
+        Ok(())
+    }
+
+    /// Revoke `token` itself, if it is valid.
+    pub fn revoke_token(&self, token: &Secret) -> Result<()> {
+        match self.verify(token.expose()) {
+            Some(id) => self.revoke(&id),
+            None => Ok(()),
+        }
+    }
+
+    /// Sign with a new key from now on, returning its ID; tokens of the
+    /// current keys keep working for `grace`.
+    pub fn rotate(&self, grace: Duration) -> Result<u32> {
+        let now = now_unix();
+        let expires_at = now + grace.as_secs();
+        let secret = new_secret();
+        let id = {
+            let mut conn = self.db.conn();
+            let tx = conn.transaction()?;
+            tx.execute(
+                "UPDATE token_keys SET expires_at = ?1
+                 WHERE expires_at IS NULL OR expires_at > ?1",
+                params![expires_at],
+            )?;
+            tx.execute(
+                "INSERT INTO token_keys (secret, created_at) VALUES (?1, ?2)",
+                params![secret, now],
+            )?;
+            let id = u32::try_from(tx.last_insert_rowid()).context("too many token keys")?;
+            tx.commit()?;
+            id
+        };
+        let mut keys = self.keys.write().unwrap();
+        for key in keys.iter_mut() {
+            key.expires_at = Some(key.expires_at.map_or(expires_at, |e| e.min(expires_at)));
+        }
+        keys.push(Key {
+            id,
+            secret,
+            expires_at: None,
+        });
+        Ok(id)
+    }
+}
+
+/// Whether `given` is the secret `expected`, such as the `peer_token`,
+/// compared in constant time. Their MACs are compared rather than the
+/// strings, so neither the time taken nor an early length mismatch tells how
+/// close `given` came.
+pub fn same_secret(given: &str, expected: &str) -> bool {
+    let tag = mac(expected.as_bytes(), expected.as_bytes()).finalize();
+    mac(expected.as_bytes(), given.as_bytes())
+        .verify_slice(&tag.into_bytes())
+        .is_ok()
+}
+
+fn new_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; KEY_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+fn mac(secret: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac
+}
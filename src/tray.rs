@@ -0,0 +1,279 @@
+//! System tray icon (`--tray`, built with the `tray` feature).
+//!
+//! The icon is a StatusNotifierItem on the D-Bus session bus, the protocol
+//! KDE, GNOME (with the AppIndicator extension), and most other Linux panels
+//! show, with its menu exported over `com.canonical.dbusmenu`. Menu entries
+//! are handed to a callback on a thread of their own, so a slow sync doesn't
+//! block the bus; what the callback returns is shown as a desktop
+//! notification.
+
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc},
+};
+
+use anyhow::{Context, Result};
+use zbus::{
+    blocking::{connection, Connection},
+    interface,
+    zvariant::{ObjectPath, OwnedValue, StructureBuilder, Value},
+};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+/// Themed icon looked up by the panel.
+const ICON_NAME: &str = "applications-internet";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Open,
+    Sync,
+    Status,
+    Quit,
+}
+
+/// Menu entries by dbusmenu item ID; `None` is a separator.
+const MENU: &[(i32, Option<(&str, Action)>)] = &[
+    (1, Some(("Open", Action::Open))),
+    (2, Some(("Sync now", Action::Sync))),
+    (3, Some(("Status", Action::Status))),
+    (4, None),
+    (5, Some(("Quit", Action::Quit))),
+];
+
+type Handler = dyn Fn(Action) -> Result<Option<String>> + Send + Sync;
+
+/// Show the icon until Quit is chosen and its action has run.
+pub fn run(
+    title: String,
+    handler: impl Fn(Action) -> Result<Option<String>> + Send + Sync + 'static,
+) -> Result<()> {
+    let (quit_tx, quit_rx) = mpsc::channel();
+    let dispatcher = Dispatcher {
+        title: title.clone(),
+        handler: Arc::new(handler),
+        quit: quit_tx,
+    };
+
+    let name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+    let conn = connection::Builder::session()?
+        .name(name.as_str())?
+        .serve_at(
+            ITEM_PATH,
+            StatusNotifierItem {
+                title,
+                dispatcher: dispatcher.clone(),
+            },
+        )?
+        .serve_at(MENU_PATH, DbusMenu { dispatcher })?
+        .build()
+        .context("Failed to connect to the D-Bus session bus")?;
+    conn.call_method(
+        Some("org.kde.StatusNotifierWatcher"),
+        "/StatusNotifierWatcher",
+        Some("org.kde.StatusNotifierWatcher"),
+        "RegisterStatusNotifierItem",
+        &(name.as_str(),),
+    )
+    .context("No system tray found (the desktop has no StatusNotifierWatcher)")?;
+
+    let _ = quit_rx.recv();
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Dispatcher {
+    title: String,
+    handler: Arc<Handler>,
+    quit: mpsc::Sender<()>,
+}
+
+impl Dispatcher {
+    fn dispatch(&self, action: Action) {
+        let this = self.clone();
+        std::thread::spawn(move || {
+            let message = match (this.handler)(action) {
+                Ok(message) => message,
+                Err(e) => Some(format!("{e:#}")),
+            };
+            if let Some(message) = message {
+                if let Err(e) = notify(&this.title, &message) {
+                    tracing::warn!("Failed to show notification: {e:#}");
+                }
+            }
+            if action == Action::Quit {
+                let _ = this.quit.send(());
+            }
+        });
+    }
+}
+
+/// Show `message` through `org.freedesktop.Notifications`.
+fn notify(title: &str, message: &str) -> Result<()> {
+    let conn = Connection::session()?;
+    conn.call_method(
+        Some("org.freedesktop.Notifications"),
+        "/org/freedesktop/Notifications",
+        Some("org.freedesktop.Notifications"),
+        "Notify",
+        &(
+            title,
+            0u32,
+            ICON_NAME,
+            title,
+            message,
+            Vec::<&str>::new(),
+            HashMap::<&str, Value>::new(),
+            -1i32,
+        ),
+    )?;
+    Ok(())
+}
+
+struct StatusNotifierItem {
+    title: String,
+    dispatcher: Dispatcher,
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl StatusNotifierItem {
+    /// Left click opens the app.
+    fn activate(&self, _x: i32, _y: i32) {
+        self.dispatcher.dispatch(Action::Open);
+    }
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    fn scroll(&self, _delta: i32, _orientation: &str) {}
+
+    #[zbus(property)]
+    fn category(&self) -> &str {
+        "ApplicationStatus"
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> &str {
+        "tbl"
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> &str {
+        &self.title
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "Active"
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> &str {
+        ICON_NAME
+    }
+
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn menu(&self) -> ObjectPath<'static> {
+        ObjectPath::from_static_str_unchecked(MENU_PATH)
+    }
+}
+
+struct DbusMenu {
+    dispatcher: Dispatcher,
+}
+
+/// A dbusmenu layout node: `(id, properties, children)`.
+type Layout = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl DbusMenu {
+    /// The whole menu; it is small and never changes, so `parent_id` and
+    /// `recursion_depth` are ignored and the revision stays at 1.
+    fn get_layout(
+        &self,
+        _parent_id: i32,
+        _recursion_depth: i32,
+        _property_names: Vec<String>,
+    ) -> zbus::fdo::Result<(u32, Layout)> {
+        let children = MENU
+            .iter()
+            .map(|(id, entry)| {
+                let node = StructureBuilder::new()
+                    .add_field(*id)
+                    .add_field(item_properties(*entry))
+                    .add_field(Vec::<OwnedValue>::new())
+                    .build();
+                OwnedValue::try_from(Value::from(node))
+                    .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+            })
+            .collect::<zbus::fdo::Result<Vec<_>>>()?;
+        let root = HashMap::from([("children-display".to_string(), owned("submenu"))]);
+        Ok((1, (0, root, children)))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        _property_names: Vec<String>,
+    ) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+        MENU.iter()
+            .filter(|(id, _)| ids.is_empty() || ids.contains(id))
+            .map(|(id, entry)| (*id, item_properties(*entry)))
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: &str) -> zbus::fdo::Result<OwnedValue> {
+        MENU.iter()
+            .find(|(item, _)| *item == id)
+            .and_then(|(_, entry)| item_properties(*entry).remove(name))
+            .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("no property {name} on {id}")))
+    }
+
+    fn event(&self, id: i32, event_id: &str, _data: Value<'_>, _timestamp: u32) {
+        if event_id != "clicked" {
+            return;
+        }
+        if let Some((_, Some((_, action)))) = MENU.iter().find(|(item, _)| *item == id) {
+            self.dispatcher.dispatch(*action);
+        }
+    }
+
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> &str {
+        "ltr"
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "normal"
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+fn item_properties(entry: Option<(&str, Action)>) -> HashMap<String, OwnedValue> {
+    match entry {
+        Some((label, _)) => HashMap::from([("label".to_string(), owned(label))]),
+        None => HashMap::from([("type".to_string(), owned("separator"))]),
+    }
+}
+
+fn owned(value: &str) -> OwnedValue {
+    OwnedValue::from(zbus::zvariant::Str::from(value.to_string()))
+}
@@ -0,0 +1,175 @@
+//! How long the server has been up across restarts (`/api/v1/uptime`).
+//!
+//! Every start adds a run to the state database, which a heartbeat every
+//! [`HEARTBEAT`] keeps current and a clean stop closes with its reason. A run
+//! that was never closed ended with a crash, a kill, or a power loss, and
+//! counts as up until its last heartbeat. Beyond [`MAX_RUNS`] the oldest runs
+//! are folded into totals, so the first start and the uptime in all stay.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::state::StateDb;
+
+/// How often a running server records that it is still up; a crash loses
+/// at most this much uptime.
+pub const HEARTBEAT: Duration = Duration::from_secs(60);
+
+/// Runs kept one by one; older ones only count in the totals.
+pub const MAX_RUNS: usize = 500;
+
+/// A start of the server and how it ended.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct Run {
+    pub started_at: u64,
+    /// When it stopped, or its last heartbeat if it never stopped cleanly;
+    /// null for the current run
+    pub ended_at: Option<u64>,
+    /// `reason` of its `server.stopping` event; null if it never stopped cleanly
+    pub stop_reason: Option<String>,
+    /// Whether it ended without stopping cleanly: a crash, a kill, or a
+    /// power loss
+    pub unclean: bool,
+    pub uptime_secs: u64,
+    /// tbl version it ran
+    pub version: String,
+}
+
+/// Stability of the instance since its first start.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct UptimeStats {
+    /// Unix timestamp (seconds) of the first start recorded
+    pub first_started_at: u64,
+    /// Starts after the first
+    pub restarts: u64,
+    /// Runs that ended without stopping cleanly
+    pub unclean_stops: u64,
+    /// Time up across all runs, in seconds
+    pub total_uptime_secs: u64,
+    /// Share of the time since the first start that the server was up,
+    /// from 0 to 1
+    pub availability: f64,
+}
+
+/// Record a start at `now`, returning the run's ID.
+pub fn start(db: &StateDb, now: u64) -> Result<i64> {
+    let mut conn = db.conn();
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO runs (started_at, last_seen, version) VALUES (?1, ?1, ?2)",
+        params![now, env!("CARGO_PKG_VERSION")],
+    )?;
+    let run = tx.last_insert_rowid();
+    let oldest_kept = run - MAX_RUNS as i64;
+    let (count, first, unclean, uptime): (i64, Option<u64>, i64, Option<u64>) = tx.query_row(
+        "SELECT COUNT(*), MIN(started_at), COUNT(*) - COUNT(stopped_at),
+                SUM(MAX(COALESCE(stopped_at, last_seen) - started_at, 0))
+         FROM runs WHERE id <= ?1",
+        params![oldest_kept],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+    if count > 0 {
+        tx.execute(
+            "INSERT INTO run_totals (id, first_started_at, runs, unclean, uptime_secs)
+             VALUES (1, ?1, ?2, ?3, ?4)
+             ON CONFLICT (id) DO UPDATE SET
+                first_started_at = MIN(first_started_at, excluded.first_started_at),
+                runs = runs + excluded.runs,
+                unclean = unclean + excluded.unclean,
+                uptime_secs = uptime_secs + excluded.uptime_secs",
+            params![first.unwrap_or(now), count, unclean, uptime.unwrap_or(0)],
+        )?;
+        tx.execute("DELETE FROM runs WHERE id <= ?1", params![oldest_kept])?;
+    }
+    tx.commit()?;
+    Ok(run)
+}
+
+/// Note that `run` is still up at `now`.
+pub fn heartbeat(db: &StateDb, run: i64, now: u64) -> Result<()> {
+    db.conn().execute(
+        "UPDATE runs SET last_seen = ?2 WHERE id = ?1",
+        params![run, now],
+    )?;
+    Ok(())
+}
+
+/// Close `run` as stopped cleanly at `now` for `reason`.
+pub fn stop(db: &StateDb, run: i64, now: u64, reason: &str) -> Result<()> {
+    db.conn().execute(
+        "UPDATE runs SET last_seen = ?2, stopped_at = ?2, stop_reason = ?3 WHERE id = ?1",
+        params![run, now, reason],
+    )?;
+    Ok(())
+}
+
+/// Kept runs, newest first, at most `limit`. With `running`, the newest is
+/// the current one and still going at `now`.
+pub fn runs(db: &StateDb, running: bool, now: u64, limit: usize) -> Result<Vec<Run>> {
+    let conn = db.conn();
+    let mut stmt = conn.prepare(
+        "SELECT started_at, last_seen, stopped_at, stop_reason, version
+         FROM runs ORDER BY id DESC LIMIT ?1",
+    )?;
+    let runs = stmt
+        .query_map(params![limit as i64], |row| {
+            let started_at: u64 = row.get(0)?;
+            let last_seen: u64 = row.get(1)?;
+            let stopped_at: Option<u64> = row.get(2)?;
+            Ok(Run {
+                started_at,
+                ended_at: Some(stopped_at.unwrap_or(last_seen)),
+                stop_reason: row.get(3)?,
+                unclean: stopped_at.is_none(),
+                uptime_secs: stopped_at.unwrap_or(last_seen).saturating_sub(started_at),
+                version: row.get(4)?,
+            })
+        })?
+        .enumerate()
+        .map(|(i, run)| {
+            run.map(|mut run| {
+                if running && i == 0 {
+                    run.ended_at = None;
+                    run.unclean = false;
+                    run.uptime_secs = now.saturating_sub(run.started_at);
+                }
+                run
+            })
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(runs)
+}
+
+/// Totals over every run, kept or folded. With `running`, the newest run is
+/// the current one and still going at `now`.
+pub fn stats(db: &StateDb, running: bool, now: u64) -> Result<UptimeStats> {
+    let kept = runs(db, running, now, MAX_RUNS)?;
+    let totals: Option<(u64, u64, u64, u64)> = db
+        .conn()
+        .query_row(
+            "SELECT first_started_at, runs, unclean, uptime_secs FROM run_totals WHERE id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()?;
+    let (first, folded, unclean, uptime) = totals.unwrap_or((now, 0, 0, 0));
+    let first_started_at = kept.iter().map(|run| run.started_at).fold(first, u64::min);
+    let starts = folded + kept.len() as u64;
+    let total_uptime_secs = uptime + kept.iter().map(|run| run.uptime_secs).sum::<u64>();
+    let span = now.saturating_sub(first_started_at);
+    Ok(UptimeStats {
+        first_started_at,
+        restarts: starts.saturating_sub(1),
+        unclean_stops: unclean + kept.iter().filter(|run| run.unclean).count() as u64,
+        total_uptime_secs,
+        availability: if span == 0 {
+            1.0
+        } else {
+            (total_uptime_secs as f64 / span as f64).min(1.0)
+        },
+    })
+}
@@ -0,0 +1,313 @@
+//! Encrypted config file (`config.enc`).
+//!
+//! The file holds the JSON config sealed with AES-256-GCM under a key derived
+//! from a passphrase with argon2id, so a config carrying tokens can be synced
+//! between machines. The passphrase comes from `TBL_CONFIG_PASSPHRASE`, the
+//! OS keyring or a prompt, and is kept for the rest of the process so the
+//! config can be encrypted again when it is saved.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{Mutex, OnceLock},
+};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use rand::RngCore;
+
+use crate::secret::Secret;
+
+pub const FILE_NAME: &str = "config.enc";
+pub const PASSPHRASE_ENV: &str = "TBL_CONFIG_PASSPHRASE";
+/// Set on a daemon whose passphrase is written to its stdin.
+const PASSPHRASE_STDIN_ENV: &str = "TBL_CONFIG_PASSPHRASE_STDIN";
+const KEYRING_SERVICE: &str = "tbl";
+const MAGIC: &[u8] = b"tbl-config-v1\n";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PROMPT_ATTEMPTS: usize = 3;
+
+/// Unlocked `config.enc` files, by config directory.
+static UNLOCKED: Mutex<BTreeMap<PathBuf, Unlocked>> = Mutex::new(BTreeMap::new());
+/// Why a `config.enc` could not be opened, so later reads fail without
+/// asking again.
+static FAILED: Mutex<BTreeMap<PathBuf, String>> = Mutex::new(BTreeMap::new());
+
+/// The passphrase that opened a `config.enc` and the key derived from it, so
+/// argon2 runs once per process rather than on every config read.
+#[derive(Clone)]
+struct Unlocked {
+    passphrase: Secret,
+    salt: [u8; SALT_LEN],
+    key: [u8; 32],
+}
+
+pub fn is_encrypted(config_dir: &Path) -> bool {
+    config_dir.join(FILE_NAME).exists()
+}
+
+/// Decrypt `config.enc` in `config_dir`, finding the passphrase on first use.
+pub fn load(config_dir: &Path) -> Result<Vec<u8>> {
+    let path = config_dir.join(FILE_NAME);
+    let data = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if let Some(unlocked) = unlocked(config_dir) {
+        if data.get(MAGIC.len()..MAGIC.len() + SALT_LEN) == Some(&unlocked.salt[..]) {
+            return open_with_key(&data, &unlocked.key);
+        }
+        let (plaintext, unlocked) = open(&data, &unlocked.passphrase)?;
+        remember(config_dir, unlocked);
+        return Ok(plaintext);
+    }
+    if let Some(reason) = FAILED.lock().ok().and_then(|f| f.get(config_dir).cloned()) {
+        bail!(reason);
+    }
+
+    let result = unlock(config_dir, &data);
+    if let Err(e) = &result {
+        if let Ok(mut failed) = FAILED.lock() {
+            failed.insert(config_dir.to_path_buf(), format!("{e:#}"));
+        }
+    }
+    result
+}
+
+/// Try the environment, the daemon's stdin, the keyring and then a prompt.
+fn unlock(config_dir: &Path, data: &[u8]) -> Result<Vec<u8>> {
+    let path = config_dir.join(FILE_NAME);
+    let mut candidates = Vec::new();
+    if let Ok(value) = std::env::var(PASSPHRASE_ENV) {
+        candidates.push(Secret::transient(value));
+    }
+    if let Some(value) = stdin_passphrase() {
+        candidates.push(value.clone());
+    }
+    if let Some(value) = keyring_get(config_dir) {
+        candidates.push(value);
+    }
+    let tried = !candidates.is_empty();
+    for candidate in candidates {
+        if let Ok((plaintext, unlocked)) = open(data, &candidate) {
+            remember(config_dir, unlocked);
+            return Ok(plaintext);
+        }
+    }
+
+    if !io::stdin().is_terminal() {
+        if tried {
+            bail!("Wrong passphrase for {}", path.display());
+        }
+        bail!(
+            "{} is encrypted; set {} or store the passphrase in the OS keyring",
+            path.display(),
+            PASSPHRASE_ENV
+        );
+    }
+    for _ in 0..PROMPT_ATTEMPTS {
+        let candidate = prompt(&format!("  Passphrase for {}: ", path.display()))?;
+        match open(data, &candidate) {
+            Ok((plaintext, unlocked)) => {
+                remember(config_dir, unlocked);
+                return Ok(plaintext);
+            }
+            Err(e) => eprintln!("  {e:#}"),
+        }
+    }
+    bail!("Could not decrypt {}", path.display())
+}
+
+/// Encrypt `plaintext` into `config.enc` with the passphrase that opened it.
+pub fn save(config_dir: &Path, plaintext: &[u8]) -> Result<()> {
+    let unlocked = unlocked(config_dir)
+        .with_context(|| format!("{FILE_NAME} is locked; restart tbl to unlock it"))?;
+    write_file(config_dir, &seal(plaintext, &unlocked)?)
+}
+
+/// Encrypt `plaintext` into `config.enc` under a new `passphrase`.
+pub fn write(config_dir: &Path, plaintext: &[u8], passphrase: &Secret) -> Result<()> {
+    let mut salt = [0u8; SALT_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    let unlocked = Unlocked {
+        passphrase: passphrase.clone(),
+        salt,
+        key: derive_key(passphrase, &salt)?,
+    };
+    write_file(config_dir, &seal(plaintext, &unlocked)?)?;
+    remember(config_dir, unlocked);
+    Ok(())
+}
+
+fn write_file(config_dir: &Path, data: &[u8]) -> Result<()> {
+    let path = config_dir.join(FILE_NAME);
+    let tmp = config_dir.join(format!("{FILE_NAME}.tmp"));
+    fs::write(&tmp, data).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, &path).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// The passphrase that opened `config.enc` in `config_dir`, if any.
+pub fn passphrase(config_dir: &Path) -> Option<Secret> {
+    unlocked(config_dir).map(|unlocked| unlocked.passphrase)
+}
+
+fn unlocked(config_dir: &Path) -> Option<Unlocked> {
+    UNLOCKED.lock().ok()?.get(config_dir).cloned()
+}
+
+fn remember(config_dir: &Path, unlocked: Unlocked) {
+    if let Ok(mut all) = UNLOCKED.lock() {
+        all.insert(config_dir.to_path_buf(), unlocked);
+    }
+}
+
+/// Read a passphrase from the terminal without echo.
+pub fn prompt(label: &str) -> Result<Secret> {
+    let value = rpassword::prompt_password(label).context("Failed to read passphrase")?;
+    if value.is_empty() {
+        bail!("Passphrase is empty");
+    }
+    Ok(Secret::transient(value))
+}
+
+/// Spawn `cmd`, writing `passphrase` to its stdin so a detached daemon can
+/// open `config.enc` without a terminal or the passphrase in its environment.
+pub fn spawn_with_passphrase(cmd: &mut Command, passphrase: Option<&Secret>) -> io::Result<Child> {
+    let Some(passphrase) = passphrase else {
+        return cmd.spawn();
+    };
+    cmd.env(PASSPHRASE_STDIN_ENV, "1").stdin(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "{}", passphrase.expose())?;
+    }
+    Ok(child)
+}
+
+/// The passphrase handed over by [`spawn_with_passphrase`], read once.
+pub fn stdin_passphrase() -> Option<&'static Secret> {
+    static PASSPHRASE: OnceLock<Option<Secret>> = OnceLock::new();
+    PASSPHRASE
+        .get_or_init(|| {
+            std::env::var_os(PASSPHRASE_STDIN_ENV)?;
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line).ok()?;
+            let value = line.trim_end_matches(['\r', '\n']);
+            (!value.is_empty()).then(|| Secret::transient(value))
+        })
+        .as_ref()
+}
+
+// ============================================================================
+// OS Keyring
+// ============================================================================
+
+/// Keyring entries are per config directory, so each profile has its own.
+fn keyring_entry(config_dir: &Path) -> Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, &config_dir.display().to_string())
+        .map_err(|e| anyhow!("OS keyring unavailable: {e}"))
+}
+
+pub fn keyring_get(config_dir: &Path) -> Option<Secret> {
+    keyring_entry(config_dir)
+        .ok()?
+        .get_password()
+        .ok()
+        .map(Secret::new)
+}
+
+pub fn keyring_set(config_dir: &Path, passphrase: &Secret) -> Result<()> {
+    keyring_entry(config_dir)?
+        .set_password(passphrase.expose())
+        .map_err(|e| anyhow!("Failed to store passphrase in the OS keyring: {e}"))
+}
+
+pub fn keyring_delete(config_dir: &Path) -> bool {
+    keyring_entry(config_dir).is_ok_and(|entry| entry.delete_credential().is_ok())
+}
+
+/// Provider tokens sit next to the passphrase, as `<config_dir> <provider>`.
+fn token_entry(config_dir: &Path, provider: &str) -> Result<keyring::Entry> {
+    keyring::Entry::new(
+        KEYRING_SERVICE,
+        &format!("{} {provider}", config_dir.display()),
+    )
+    .map_err(|e| anyhow!("OS keyring unavailable: {e}"))
+}
+
+/// Token stored by `tbl login <provider>`, as a transient secret so it is
+/// not written to the config file.
+pub fn token_get(config_dir: &Path, provider: &str) -> Option<Secret> {
+    token_entry(config_dir, provider)
+        .ok()?
+        .get_password()
+        .ok()
+        .map(Secret::transient)
+}
+
+pub fn token_set(config_dir: &Path, provider: &str, token: &Secret) -> Result<()> {
+    token_entry(config_dir, provider)?
+        .set_password(token.expose())
+        .map_err(|e| anyhow!("Failed to store the {provider} token in the OS keyring: {e}"))
+}
+
+pub fn token_delete(config_dir: &Path, provider: &str) -> bool {
+    token_entry(config_dir, provider).is_ok_and(|entry| entry.delete_credential().is_ok())
+}
+
+// ============================================================================
+// Encryption
+// ============================================================================
+
+/// `MAGIC | salt | nonce | AES-256-GCM(plaintext)`
+fn seal(plaintext: &[u8], unlocked: &Unlocked) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    let ciphertext = Aes256Gcm::new(&unlocked.key.into())
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow!("Failed to encrypt config"))?;
+
+    let mut out = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&unlocked.salt);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Split a `config.enc` into salt, nonce and ciphertext.
+fn parts(data: &[u8]) -> Result<(&[u8], &[u8], &[u8])> {
+    let body = data
+        .strip_prefix(MAGIC)
+        .filter(|body| body.len() > SALT_LEN + NONCE_LEN)
+        .with_context(|| format!("Not a tbl {FILE_NAME} file"))?;
+    let (salt, rest) = body.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    Ok((salt, nonce, ciphertext))
+}
+
+fn open(data: &[u8], passphrase: &Secret) -> Result<(Vec<u8>, Unlocked)> {
+    let (salt, _, _) = parts(data)?;
+    let unlocked = Unlocked {
+        passphrase: passphrase.clone(),
+        salt: salt.try_into()?,
+        key: derive_key(passphrase, salt)?,
+    };
+    Ok((open_with_key(data, &unlocked.key)?, unlocked))
+}
+
+fn open_with_key(data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>> {
+    let (_, nonce, ciphertext) = parts(data)?;
+    Aes256Gcm::new(key.into())
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Wrong passphrase or corrupted {FILE_NAME}"))
+}
+
+fn derive_key(passphrase: &Secret, salt: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.expose().as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Failed to derive key: {e}"))?;
+    Ok(key)
+}